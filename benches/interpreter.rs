@@ -0,0 +1,131 @@
+// Interpreter throughput on small synthetic programs.
+// Run with `cargo bench`, timings are the best of several runs.
+
+use std::time::{Duration, Instant};
+
+use wasmic::{
+    embedding::Instanciable,
+    instr::{BlockType, Instr},
+    modules::{Export, ExportDesc, Func, Module},
+    runtime::{Engine, ModuleInstance, Store},
+    typed::TypedFunc,
+    types,
+};
+
+const RUNS: usize = 10;
+
+// Sums the integers from local 0 down to 1 into local 1
+fn sum_loop() -> Vec<Instr> {
+    vec![
+        Instr::Block(BlockType::Empty),
+        Instr::Loop(BlockType::Empty),
+        Instr::LocalGet(0),
+        Instr::I32Eqz,
+        Instr::BrIf(1),
+        Instr::LocalGet(1),
+        Instr::LocalGet(0),
+        Instr::I32Add,
+        Instr::LocalSet(1),
+        Instr::LocalGet(0),
+        Instr::I32Const(1),
+        Instr::I32Sub,
+        Instr::LocalSet(0),
+        Instr::Br(0),
+        Instr::End,
+        Instr::End,
+        Instr::LocalGet(1),
+    ]
+}
+
+// Same as `sum_loop`, with the loop body split into nested blocks that are left
+// through branches
+fn nested_blocks() -> Vec<Instr> {
+    vec![
+        Instr::Block(BlockType::Empty),
+        Instr::Loop(BlockType::Empty),
+        Instr::LocalGet(0),
+        Instr::I32Eqz,
+        Instr::BrIf(1),
+        Instr::Block(BlockType::Empty),
+        Instr::Block(BlockType::Empty),
+        Instr::LocalGet(1),
+        Instr::LocalGet(0),
+        Instr::I32Add,
+        Instr::LocalSet(1),
+        Instr::Br(1),
+        Instr::End,
+        Instr::Unreachable,
+        Instr::End,
+        Instr::LocalGet(0),
+        Instr::I32Const(1),
+        Instr::I32Sub,
+        Instr::LocalSet(0),
+        Instr::Br(0),
+        Instr::End,
+        Instr::End,
+        Instr::LocalGet(1),
+    ]
+}
+
+// Instantiates `body` as a function taking the iteration count in local 0, local 1 being the
+// running sum it returns
+fn instantiate(store: &mut Store, body: Vec<Instr>) -> TypedFunc<'_, u32, u32> {
+    let i32 = types::Value::Num(types::Number::I32);
+    let module = Module {
+        types: vec![types::Function {
+            input: vec![i32],
+            output: vec![i32],
+        }],
+        funcs: vec![Func {
+            functype: 0,
+            locals: vec![i32],
+            body,
+        }],
+        tables: vec![],
+        mems: vec![],
+        globals: vec![],
+        tags: vec![],
+        elems: vec![],
+        datas: vec![],
+        start: None,
+        imports: vec![],
+        exports: vec![Export {
+            name: "run".to_string(),
+            desc: ExportDesc::Func(0),
+        }],
+    };
+    let instance = ModuleInstance::instantiate(store, &module, vec![]).unwrap();
+    let func = store
+        .instance(instance)
+        .unwrap()
+        .export_func("run")
+        .unwrap();
+    TypedFunc::new(store, func).unwrap()
+}
+
+fn bench(name: &str, engine: Engine, program: Vec<Instr>, iterations: u32) {
+    let mut store = Store::new();
+    store.config.engine = engine;
+    let func = instantiate(&mut store, program);
+    let expected = (0..=iterations).fold(0u32, |acc, i| acc.wrapping_add(i));
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let res = func.call(iterations).unwrap();
+        best = best.min(start.elapsed());
+        assert_eq!(res, expected);
+    }
+    println!(
+        "{name:<16} {:>10.2?} ({:.1} ns/iteration)",
+        best,
+        best.as_nanos() as f64 / iterations as f64
+    );
+}
+
+fn main() {
+    for engine in [Engine::Stack, Engine::Register] {
+        println!("{engine:?}");
+        bench("sum_loop", engine, sum_loop(), 1_000_000);
+        bench("nested_blocks", engine, nested_blocks(), 1_000_000);
+    }
+}
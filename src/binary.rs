@@ -0,0 +1,1026 @@
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    config::Features,
+    err,
+    instr::{Access, Atomic, BlockType, Catch, Expr, Instr, MemArg, RmwOp},
+    modules::{
+        Data, DataMode, ElemMode, Element, Export, ExportDesc, Func, Global, Import, ImportDesc,
+        Mem, Module, Table, Tag,
+    },
+    types::{self, Index},
+};
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+const MAX_LOCALS: usize = 50_000;
+
+// Decodes a module in the binary format (sec 5). Only the instructions the interpreter
+// implements are recognized, and those of proposals disabled in `features` are rejected.
+pub fn decode(bytes: &[u8], features: &Features) -> Result<Module, err::Err> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != MAGIC || reader.take(4)? != VERSION {
+        return Result::Err(err::Err::ModuleDecode);
+    }
+    let mut module = Module {
+        types: vec![],
+        funcs: vec![],
+        tables: vec![],
+        mems: vec![],
+        globals: vec![],
+        tags: vec![],
+        elems: vec![],
+        datas: vec![],
+        start: None,
+        imports: vec![],
+        exports: vec![],
+    };
+    let mut functypes = vec![];
+    let mut codes = None;
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.u32()? as usize;
+        let mut section = Reader {
+            bytes: reader.take(len)?,
+            pos: 0,
+        };
+        match id {
+            0 => continue, // Custom sections are ignored
+            1 => module.types = section.vec(Reader::functype)?,
+            2 => module.imports = section.vec(Reader::import)?,
+            3 => functypes = section.vec(Reader::index)?,
+            4 => module.tables = section.vec(Reader::table)?,
+            5 => module.mems = section.vec(Reader::mem)?,
+            6 => module.globals = section.vec(Reader::global)?,
+            7 => module.exports = section.vec(Reader::export)?,
+            8 => module.start = Some(section.index()?),
+            9 => module.elems = section.vec(Reader::elem)?,
+            10 => codes = Some(section.vec(Reader::code)?),
+            11 => module.datas = section.vec(Reader::data)?,
+            12 => {
+                section.u32()?; // Data count, only needed by single pass validators
+            }
+            13 => module.tags = section.vec(Reader::tag)?,
+            _ => return Result::Err(err::Err::ModuleDecode),
+        }
+        if !section.is_empty() {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+    }
+    let codes = codes.unwrap_or_default();
+    if codes.len() != functypes.len() {
+        return Result::Err(err::Err::ModuleDecode);
+    }
+    module.funcs = functypes
+        .into_iter()
+        .zip(codes)
+        .map(|(functype, (locals, body))| Func {
+            functype,
+            locals,
+            body,
+        })
+        .collect();
+    module.check_features(features)?;
+    Result::Ok(module)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], err::Err> {
+        let end = self.pos.checked_add(len).ok_or(err::Err::ModuleDecode)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(err::Err::ModuleDecode)?;
+        self.pos = end;
+        Result::Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, err::Err> {
+        Result::Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, err::Err> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or(err::Err::ModuleDecode)
+    }
+
+    // LEB128 integers of at most `bits` bits (sec 5.2.2)
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64, err::Err> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            result |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if shift >= bits {
+                // The last byte may not continue, its unused bits extend the value
+                let used = bits + 7 - shift;
+                let unused = (byte & 0x7f) >> used;
+                let negative = signed && (byte >> (used - 1)) & 1 != 0;
+                let extension = if negative { 0x7f >> used } else { 0 };
+                if byte & 0x80 != 0 || unused != extension {
+                    return Result::Err(err::Err::ModuleDecode);
+                }
+                break;
+            }
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let width = shift.min(bits);
+        if signed && width < 64 {
+            let unused = 64 - width;
+            result = ((result << unused) as i64 >> unused) as u64;
+        } else if width < 64 {
+            result &= (1 << width) - 1;
+        }
+        Result::Ok(result)
+    }
+
+    fn u32(&mut self) -> Result<u32, err::Err> {
+        Result::Ok(self.leb(32, false)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64, err::Err> {
+        self.leb(64, false)
+    }
+
+    fn index(&mut self) -> Result<Index, err::Err> {
+        Result::Ok(self.u32()? as Index)
+    }
+
+    fn vec<T>(&mut self, f: impl Fn(&mut Self) -> Result<T, err::Err>) -> Result<Vec<T>, err::Err> {
+        let len = self.u32()?;
+        // Each element takes at least a byte, which bounds the allocation
+        let mut items = Vec::with_capacity((len as usize).min(self.bytes.len() - self.pos));
+        for _ in 0..len {
+            items.push(f(self)?);
+        }
+        Result::Ok(items)
+    }
+
+    fn name(&mut self) -> Result<String, err::Err> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        let name = core::str::from_utf8(bytes).map_err(|_| err::Err::ModuleDecode)?;
+        Result::Ok(String::from(name))
+    }
+
+    // Types
+
+    fn heap(&mut self) -> Result<types::Heap, err::Err> {
+        match self.peek()? {
+            0x70 => self.byte().map(|_| types::Heap::Func),
+            0x6f => self.byte().map(|_| types::Heap::Extern),
+            0x69 => self.byte().map(|_| types::Heap::Exn),
+            _ => match self.leb(33, true)? as i64 {
+                idx if idx >= 0 => Result::Ok(types::Heap::Type(idx as Index)),
+                _ => Result::Err(err::Err::ModuleDecode),
+            },
+        }
+    }
+
+    fn reftype(&mut self) -> Result<types::Ref, err::Err> {
+        match self.byte()? {
+            0x70 => Result::Ok(types::Ref::FUNC),
+            0x6f => Result::Ok(types::Ref::EXTERN),
+            0x69 => Result::Ok(types::Ref::EXN),
+            0x63 => Result::Ok(types::Ref::null(self.heap()?)),
+            0x64 => Result::Ok(types::Ref::non_null(self.heap()?)),
+            _ => Result::Err(err::Err::ModuleDecode),
+        }
+    }
+
+    fn valtype(&mut self) -> Result<types::Value, err::Err> {
+        let num = match self.peek()? {
+            0x7f => types::Number::I32,
+            0x7e => types::Number::I64,
+            0x7d => types::Number::F32,
+            0x7c => types::Number::F64,
+            0x7b => {
+                self.byte()?;
+                return Result::Ok(types::Value::Vec(types::Vector::Unimplemented));
+            }
+            _ => return Result::Ok(types::Value::Ref(self.reftype()?)),
+        };
+        self.byte()?;
+        Result::Ok(types::Value::Num(num))
+    }
+
+    fn functype(&mut self) -> Result<types::Function, err::Err> {
+        if self.byte()? != 0x60 {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        Result::Ok(types::Function {
+            input: self.vec(Reader::valtype)?,
+            output: self.vec(Reader::valtype)?,
+        })
+    }
+
+    fn limits(&mut self, wide: bool) -> Result<(types::Limits, u8), err::Err> {
+        let flags = self.byte()?;
+        if flags & !0x07 != 0 {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        let bound = |reader: &mut Self| match wide && flags & 0x04 != 0 {
+            true => reader.u64(),
+            false => reader.u32().map(u64::from),
+        };
+        let min = bound(self)?;
+        let max = match flags & 0x01 {
+            0 => None,
+            _ => Some(bound(self)?),
+        };
+        let int = |bound: u64| types::Int::try_from(bound).map_err(|_| err::Err::ModuleDecode);
+        let limits = types::Limits {
+            min: int(min)?,
+            max: max.map(int).transpose()?,
+        };
+        Result::Ok((limits, flags))
+    }
+
+    fn tabletype(&mut self) -> Result<types::Table, err::Err> {
+        let reftype = self.reftype()?;
+        let (limits, flags) = self.limits(false)?;
+        if flags & !0x01 != 0 {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        Result::Ok(types::Table { limits, reftype })
+    }
+
+    // Flags tell whether a maximum follows, and whether the memory is shared or 64-bit
+    fn memtype(&mut self) -> Result<types::Mem, err::Err> {
+        let (limits, flags) = self.limits(true)?;
+        Result::Ok(types::Mem {
+            limits,
+            index: match flags & 0x04 {
+                0 => types::IndexType::I32,
+                _ => types::IndexType::I64,
+            },
+            shared: flags & 0x02 != 0,
+        })
+    }
+
+    fn globaltype(&mut self) -> Result<types::Global, err::Err> {
+        let val = self.valtype()?;
+        let mutable = match self.byte()? {
+            0x00 => types::Mut::Const,
+            0x01 => types::Mut::Var,
+            _ => return Result::Err(err::Err::ModuleDecode),
+        };
+        Result::Ok(types::Global { mutable, val })
+    }
+
+    fn tagtype(&mut self) -> Result<Index, err::Err> {
+        // The only attribute is that of exceptions
+        if self.byte()? != 0x00 {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        self.index()
+    }
+
+    // Sections
+
+    fn import(&mut self) -> Result<Import, err::Err> {
+        let module = self.name()?;
+        let name = self.name()?;
+        let desc = match self.byte()? {
+            0x00 => ImportDesc::Func(self.index()?),
+            0x01 => ImportDesc::Table(self.tabletype()?),
+            0x02 => ImportDesc::Mem(self.memtype()?),
+            0x03 => ImportDesc::Global(self.globaltype()?),
+            0x04 => ImportDesc::Tag(self.tagtype()?),
+            _ => return Result::Err(err::Err::ModuleDecode),
+        };
+        Result::Ok(Import { module, name, desc })
+    }
+
+    fn table(&mut self) -> Result<Table, err::Err> {
+        Result::Ok(Table {
+            tabletype: self.tabletype()?,
+        })
+    }
+
+    fn mem(&mut self) -> Result<Mem, err::Err> {
+        Result::Ok(Mem {
+            memtype: self.memtype()?,
+        })
+    }
+
+    fn global(&mut self) -> Result<Global, err::Err> {
+        Result::Ok(Global {
+            globaltype: self.globaltype()?,
+            init: self.expr()?,
+        })
+    }
+
+    fn tag(&mut self) -> Result<Tag, err::Err> {
+        Result::Ok(Tag {
+            tagtype: self.tagtype()?,
+        })
+    }
+
+    fn export(&mut self) -> Result<Export, err::Err> {
+        let name = self.name()?;
+        let desc = match self.byte()? {
+            0x00 => ExportDesc::Func(self.index()?),
+            0x01 => ExportDesc::Table(self.index()?),
+            0x02 => ExportDesc::Mem(self.index()?),
+            0x03 => ExportDesc::Global(self.index()?),
+            0x04 => ExportDesc::Tag(self.index()?),
+            _ => return Result::Err(err::Err::ModuleDecode),
+        };
+        Result::Ok(Export { name, desc })
+    }
+
+    // The bits of the flags tell whether the segment is passive or declarative, whether it
+    // has a table index, and whether its elements are expressions rather than function indices
+    fn elem(&mut self) -> Result<Element, err::Err> {
+        let flags = self.u32()?;
+        if flags > 7 {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        let mode = match flags & 0x03 {
+            0 => ElemMode::Active(0, self.expr()?),
+            2 => ElemMode::Active(self.index()?, self.expr()?),
+            1 => ElemMode::Passive,
+            _ => ElemMode::Declarative,
+        };
+        let exprs = flags & 0x04 != 0;
+        let elemtype = match (flags & 0x03, exprs) {
+            (0, _) => types::Ref::FUNC,
+            (_, true) => self.reftype()?,
+            // Only functions can be given by index
+            (_, false) => match self.byte()? {
+                0x00 => types::Ref::FUNC,
+                _ => return Result::Err(err::Err::ModuleDecode),
+            },
+        };
+        let init = match exprs {
+            true => self.vec(Reader::expr)?,
+            false => self.vec(|reader| Result::Ok(vec![Instr::RefFunc(reader.index()?)]))?,
+        };
+        Result::Ok(Element {
+            elemtype,
+            init,
+            mode,
+        })
+    }
+
+    fn code(&mut self) -> Result<(Vec<types::Value>, Expr), err::Err> {
+        let len = self.u32()? as usize;
+        let mut code = Reader {
+            bytes: self.take(len)?,
+            pos: 0,
+        };
+        let mut locals = vec![];
+        for (count, valtype) in code.vec(|reader| Result::Ok((reader.u32()?, reader.valtype()?)))? {
+            // Counts are bounded as in other engines, so that a few bytes cannot make the
+            // decoder allocate gigabytes
+            if locals.len() + count as usize > MAX_LOCALS {
+                return Result::Err(err::Err::ModuleDecode);
+            }
+            locals.extend(core::iter::repeat_n(valtype, count as usize));
+        }
+        let body = code.expr()?;
+        if !code.is_empty() {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        Result::Ok((locals, body))
+    }
+
+    fn data(&mut self) -> Result<Data, err::Err> {
+        let mode = match self.u32()? {
+            0 => DataMode::Active(0, self.expr()?),
+            1 => DataMode::Passive,
+            2 => DataMode::Active(self.index()?, self.expr()?),
+            _ => return Result::Err(err::Err::ModuleDecode),
+        };
+        let len = self.u32()? as usize;
+        Result::Ok(Data {
+            init: self.take(len)?.to_vec(),
+            mode,
+        })
+    }
+
+    // Instructions
+
+    // Instructions up to the `End` closing the expression, which is left out
+    fn expr(&mut self) -> Result<Expr, err::Err> {
+        let mut expr = vec![];
+        let mut depth = 0usize;
+        loop {
+            let instr = self.instr()?;
+            match instr {
+                Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::TryTable(_) => depth += 1,
+                Instr::End if depth == 0 => return Result::Ok(expr),
+                Instr::End => depth -= 1,
+                _ => {}
+            }
+            expr.push(instr);
+            // The catch clauses of a `try_table` follow its block type
+            if let Instr::TryTable(_) = instr {
+                let catches = self.vec(Reader::catch)?;
+                expr.extend(catches.into_iter().map(Instr::Catch));
+            }
+        }
+    }
+
+    fn catch(&mut self) -> Result<Catch, err::Err> {
+        match self.byte()? {
+            0x00 => Result::Ok(Catch::Tag(self.index()?, self.index()?)),
+            0x01 => Result::Ok(Catch::TagRef(self.index()?, self.index()?)),
+            0x02 => Result::Ok(Catch::All(self.index()?)),
+            0x03 => Result::Ok(Catch::AllRef(self.index()?)),
+            _ => Result::Err(err::Err::ModuleDecode),
+        }
+    }
+
+    fn blocktype(&mut self) -> Result<BlockType, err::Err> {
+        match self.peek()? {
+            0x40 => self.byte().map(|_| BlockType::Empty),
+            0x7f | 0x7e | 0x7d | 0x7c | 0x7b | 0x70 | 0x6f | 0x69 | 0x63 | 0x64 => {
+                Result::Ok(BlockType::Value(self.valtype()?))
+            }
+            _ => match self.leb(33, true)? as i64 {
+                idx if idx >= 0 => Result::Ok(BlockType::Index(idx as Index)),
+                _ => Result::Err(err::Err::ModuleDecode),
+            },
+        }
+    }
+
+    // Bit 6 of the alignment tells that a memory index follows (multi-memory)
+    fn memarg(&mut self) -> Result<MemArg, err::Err> {
+        let flags = self.u32()?;
+        let mem = match flags & 0x40 {
+            0 => 0,
+            _ => self.index()?,
+        };
+        let align = flags & !0x40;
+        if align >= 64 {
+            return Result::Err(err::Err::ModuleDecode);
+        }
+        Result::Ok(MemArg {
+            offset: self.u64()?,
+            align,
+            mem,
+        })
+    }
+
+    // Loads and stores come in the order of `Access`, stores only in its unsigned variants
+    fn instr(&mut self) -> Result<Instr, err::Err> {
+        const LOADS: [Access; 14] = [
+            Access::I32,
+            Access::I64,
+            Access::F32,
+            Access::F64,
+            Access::I32S8,
+            Access::I32U8,
+            Access::I32S16,
+            Access::I32U16,
+            Access::I64S8,
+            Access::I64U8,
+            Access::I64S16,
+            Access::I64U16,
+            Access::I64S32,
+            Access::I64U32,
+        ];
+        const STORES: [Access; 9] = [
+            Access::I32,
+            Access::I64,
+            Access::F32,
+            Access::F64,
+            Access::I32U8,
+            Access::I32U16,
+            Access::I64U8,
+            Access::I64U16,
+            Access::I64U32,
+        ];
+        let instr = match self.byte()? {
+            0x00 => Instr::Unreachable,
+            0x01 => Instr::Nop,
+            0x02 => Instr::Block(self.blocktype()?),
+            0x03 => Instr::Loop(self.blocktype()?),
+            0x04 => Instr::If(self.blocktype()?),
+            0x05 => Instr::Else,
+            0x08 => Instr::Throw(self.index()?),
+            0x0a => Instr::ThrowRef,
+            0x0b => Instr::End,
+            0x0c => Instr::Br(self.index()?),
+            0x0d => Instr::BrIf(self.index()?),
+            0x0f => Instr::Return,
+            0x10 => Instr::Call(self.index()?),
+            0x12 => Instr::ReturnCall(self.index()?),
+            0x13 => Instr::ReturnCallIndirect(self.index()?, self.index()?),
+            0x14 => Instr::CallRef(self.index()?),
+            0x15 => Instr::ReturnCallRef(self.index()?),
+            0x1f => Instr::TryTable(self.blocktype()?),
+            0x20 => Instr::LocalGet(self.index()?),
+            0x21 => Instr::LocalSet(self.index()?),
+            0x22 => Instr::LocalTee(self.index()?),
+            0x23 => Instr::GlobalGet(self.index()?),
+            0x24 => Instr::GlobalSet(self.index()?),
+            op @ 0x28..=0x35 => Instr::Load(LOADS[op as usize - 0x28], self.memarg()?),
+            op @ 0x36..=0x3e => Instr::Store(STORES[op as usize - 0x36], self.memarg()?),
+            0x3f => Instr::MemorySize(self.index()?),
+            0x40 => Instr::MemoryGrow(self.index()?),
+            0x41 => Instr::I32Const(self.leb(32, true)? as u32),
+            0x42 => Instr::I64Const(self.leb(64, true)?),
+            0x43 => {
+                let bytes = self.take(4)?.try_into().unwrap();
+                Instr::F32Const(f32::from_le_bytes(bytes))
+            }
+            0x44 => {
+                let bytes = self.take(8)?.try_into().unwrap();
+                Instr::F64Const(f64::from_le_bytes(bytes))
+            }
+            0x45 => Instr::I32Eqz,
+            0x46 => Instr::I32Eq,
+            0x47 => Instr::I32Ne,
+            0x48 => Instr::I32LtS,
+            0x49 => Instr::I32LtU,
+            0x4a => Instr::I32GtS,
+            0x4b => Instr::I32GtU,
+            0x4c => Instr::I32LeS,
+            0x4d => Instr::I32LeU,
+            0x4e => Instr::I32GeS,
+            0x4f => Instr::I32GeU,
+            0x50 => Instr::I64Eqz,
+            0x51 => Instr::I64Eq,
+            0x52 => Instr::I64Ne,
+            0x53 => Instr::I64LtS,
+            0x54 => Instr::I64LtU,
+            0x55 => Instr::I64GtS,
+            0x56 => Instr::I64GtU,
+            0x57 => Instr::I64LeS,
+            0x58 => Instr::I64LeU,
+            0x59 => Instr::I64GeS,
+            0x5a => Instr::I64GeU,
+            0x67 => Instr::I32Clz,
+            0x68 => Instr::I32Ctz,
+            0x69 => Instr::I32PopCnt,
+            0x6a => Instr::I32Add,
+            0x6b => Instr::I32Sub,
+            0x6c => Instr::I32Mul,
+            0x6d => Instr::I32DivS,
+            0x6e => Instr::I32DivU,
+            0x6f => Instr::I32RemS,
+            0x70 => Instr::I32RemU,
+            0x71 => Instr::I32And,
+            0x72 => Instr::I32Or,
+            0x73 => Instr::I32Xor,
+            0x74 => Instr::I32Shl,
+            0x75 => Instr::I32ShrS,
+            0x76 => Instr::I32ShrU,
+            0x77 => Instr::I32Rotl,
+            0x78 => Instr::I32Rotr,
+            0x79 => Instr::I64Clz,
+            0x7a => Instr::I64Ctz,
+            0x7b => Instr::I64PopCnt,
+            0x7c => Instr::I64Add,
+            0x7d => Instr::I64Sub,
+            0x7e => Instr::I64Mul,
+            0x7f => Instr::I64DivS,
+            0x80 => Instr::I64DivU,
+            0x81 => Instr::I64RemS,
+            0x82 => Instr::I64RemU,
+            0x83 => Instr::I64And,
+            0x84 => Instr::I64Or,
+            0x85 => Instr::I64Xor,
+            0x86 => Instr::I64Shl,
+            0x87 => Instr::I64ShrS,
+            0x88 => Instr::I64ShrU,
+            0x89 => Instr::I64Rotl,
+            0x8a => Instr::I64Rotr,
+            0xc0 => Instr::I32Extend8S,
+            0xc1 => Instr::I32Extend16S,
+            0xc2 => Instr::I64Extend8S,
+            0xc3 => Instr::I64Extend16S,
+            0xc4 => Instr::I64Extend32S,
+            0xd0 => Instr::RefNull(self.heap()?),
+            0xd2 => Instr::RefFunc(self.index()?),
+            0xd4 => Instr::RefAsNonNull,
+            0xd5 => Instr::BrOnNull(self.index()?),
+            0xd6 => Instr::BrOnNonNull(self.index()?),
+            0xfc => match self.u32()? {
+                0 => Instr::I32TruncSatF32S,
+                1 => Instr::I32TruncSatF32U,
+                2 => Instr::I32TruncSatF64S,
+                3 => Instr::I32TruncSatF64U,
+                4 => Instr::I64TruncSatF32S,
+                5 => Instr::I64TruncSatF32U,
+                6 => Instr::I64TruncSatF64S,
+                7 => Instr::I64TruncSatF64U,
+                8 => Instr::MemoryInit(self.index()?, self.index()?),
+                9 => Instr::DataDrop(self.index()?),
+                10 => Instr::MemoryCopy(self.index()?, self.index()?),
+                11 => Instr::MemoryFill(self.index()?),
+                15 => Instr::TableGrow(self.index()?),
+                16 => Instr::TableSize(self.index()?),
+                _ => return Result::Err(err::Err::ModuleDecode),
+            },
+            0xfe => self.atomic()?,
+            _ => return Result::Err(err::Err::ModuleDecode),
+        };
+        Result::Ok(instr)
+    }
+
+    // Accesses of each width come in the order of `Atomic`, read-modify-write operations in
+    // the order of `RmwOp`
+    fn atomic(&mut self) -> Result<Instr, err::Err> {
+        const ATOMICS: [Atomic; 7] = [
+            Atomic::I32,
+            Atomic::I64,
+            Atomic::I32U8,
+            Atomic::I32U16,
+            Atomic::I64U8,
+            Atomic::I64U16,
+            Atomic::I64U32,
+        ];
+        const RMWS: [RmwOp; 6] = [
+            RmwOp::Add,
+            RmwOp::Sub,
+            RmwOp::And,
+            RmwOp::Or,
+            RmwOp::Xor,
+            RmwOp::Xchg,
+        ];
+        let instr = match self.u32()? {
+            0x00 => Instr::MemoryAtomicNotify(self.memarg()?),
+            0x01 => Instr::MemoryAtomicWait32(self.memarg()?),
+            0x02 => Instr::MemoryAtomicWait64(self.memarg()?),
+            0x03 => match self.byte()? {
+                0x00 => Instr::AtomicFence,
+                _ => return Result::Err(err::Err::ModuleDecode),
+            },
+            op @ 0x10..=0x16 => Instr::AtomicLoad(ATOMICS[op as usize - 0x10], self.memarg()?),
+            op @ 0x17..=0x1d => Instr::AtomicStore(ATOMICS[op as usize - 0x17], self.memarg()?),
+            op @ 0x1e..=0x47 => {
+                let op = op as usize - 0x1e;
+                Instr::AtomicRmw(RMWS[op / 7], ATOMICS[op % 7], self.memarg()?)
+            }
+            op @ 0x48..=0x4e => Instr::AtomicCmpxchg(ATOMICS[op as usize - 0x48], self.memarg()?),
+            _ => return Result::Err(err::Err::ModuleDecode),
+        };
+        Result::Ok(instr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{Config, Feature},
+        embedding::{Instanciable, Module as _, Store as _},
+        runtime::{self, ModuleInstance, Num, Val},
+    };
+
+    use super::*;
+
+    fn store() -> runtime::Store {
+        runtime::Store::with_config(Config::default())
+    }
+
+    fn call(
+        store: &runtime::Store,
+        instance: runtime::InstanceId,
+        name: &str,
+        args: Vec<Val>,
+    ) -> Val {
+        let func = store.instance(instance).unwrap().export_func(name).unwrap();
+        store.invoke(func, args).unwrap().remove(0)
+    }
+
+    #[test]
+    fn numeric_proposals() -> Result<(), err::Err> {
+        let source = r#"
+            (module
+              (func (export "extend8") (param i32) (result i32)
+                local.get 0
+                i32.extend8_s)
+              (func (export "extend32") (param i64) (result i64)
+                local.get 0
+                i64.extend32_s)
+              (func (export "trunc_s") (param f32) (result i32)
+                local.get 0
+                i32.trunc_sat_f32_s)
+              (func (export "trunc_u") (param f64) (result i64)
+                local.get 0
+                i64.trunc_sat_f64_u))
+        "#;
+        assert!(matches!(
+            Module::parse(source, &Features::mvp()),
+            Err(err::Err::FeatureDisabled(Feature::SignExtension))
+        ));
+        let module = Module::parse(source, &Features::default())?;
+        module.validate(&Features::default())?;
+
+        let mut store = store();
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let i32 = |val: i32| Val::Num(Num::I32(val as u32));
+        let i64 = |val: i64| Val::Num(Num::I64(val as u64));
+        assert_eq!(
+            call(&store, instance, "extend8", vec![i32(0x17f)]),
+            i32(127)
+        );
+        assert_eq!(
+            call(&store, instance, "extend8", vec![i32(0x80)]),
+            i32(-128)
+        );
+        assert_eq!(
+            call(&store, instance, "extend32", vec![i64(0xffff_ffff)]),
+            i64(-1)
+        );
+        let f32 = |val: f32| vec![Val::Num(Num::F32(val))];
+        assert_eq!(call(&store, instance, "trunc_s", f32(-2.5)), i32(-2));
+        assert_eq!(call(&store, instance, "trunc_s", f32(1e10)), i32(i32::MAX));
+        assert_eq!(call(&store, instance, "trunc_s", f32(f32::NAN)), i32(0));
+        let f64 = |val: f64| vec![Val::Num(Num::F64(val))];
+        assert_eq!(call(&store, instance, "trunc_u", f64(-1.)), i64(0));
+        assert_eq!(call(&store, instance, "trunc_u", f64(1e30)), i64(-1));
+        Ok(())
+    }
+
+    #[test]
+    fn control_and_exceptions() -> Result<(), err::Err> {
+        let source = r#"
+            (module
+              (type $t (func (param i32) (result i32)))
+              (tag $e (param i32))
+              (func $double (type $t)
+                local.get 0
+                local.get 0
+                i32.add)
+              (func (export "catch") (param i32) (result i32)
+                block $h (result i32)
+                  try_table (catch $e $h)
+                    local.get 0
+                    throw $e
+                  end
+                  i32.const -1
+                end)
+              (func (export "call_ref") (param i32) (result i32)
+                local.get 0
+                ref.func $double
+                call_ref $t)
+              (elem declare func $double))
+        "#;
+        let features = *Features::default()
+            .set(Feature::ExceptionHandling, true)
+            .set(Feature::FunctionReferences, true);
+        let module = Module::parse(source, &features)?;
+        module.validate(&features)?;
+        let config = Config {
+            features,
+            ..Config::default()
+        };
+        let mut store = runtime::Store::with_config(config);
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let i32 = |val: i32| Val::Num(Num::I32(val as u32));
+        assert_eq!(call(&store, instance, "catch", vec![i32(7)]), i32(7));
+        assert_eq!(call(&store, instance, "call_ref", vec![i32(-3)]), i32(-6));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_accesses() -> Result<(), err::Err> {
+        let source = r#"
+            (module
+              (memory i64 1)
+              (func (export "store") (param i64 i64)
+                local.get 0
+                local.get 1
+                i64.store offset=1)
+              (func (export "load8_s") (param i64) (result i32)
+                local.get 0
+                i32.load8_s)
+              (func (export "load16_u") (param i64) (result i64)
+                local.get 0
+                i64.load16_u)
+              (func (export "load32_s") (param i64) (result i64)
+                local.get 0
+                i64.load32_s)
+              (func (export "float") (param i64 f64) (result f64)
+                local.get 0
+                local.get 1
+                f64.store
+                local.get 0
+                f64.load))
+        "#;
+        let features = *Features::default().set(Feature::Memory64, true);
+        let module = Module::parse(source, &features)?;
+        module.validate(&features)?;
+        let config = Config {
+            features,
+            ..Config::default()
+        };
+        let mut store = runtime::Store::with_config(config);
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let i32 = |val: i32| Val::Num(Num::I32(val as u32));
+        let i64 = |val: i64| Val::Num(Num::I64(val as u64));
+        let func = |name| store.instance(instance).unwrap().export_func(name).unwrap();
+
+        store.invoke(func("store"), vec![i64(7), i64(0x1234_80ff_fffe)])?;
+        assert_eq!(call(&store, instance, "load8_s", vec![i64(8)]), i32(-2));
+        assert_eq!(
+            call(&store, instance, "load16_u", vec![i64(9)]),
+            i64(0xffff)
+        );
+        assert_eq!(
+            call(&store, instance, "load32_s", vec![i64(8)]),
+            i64(0xffff_ffff_80ff_fffe_u64 as i64)
+        );
+        let f64 = |val: f64| Val::Num(Num::F64(val));
+        assert_eq!(
+            call(&store, instance, "float", vec![i64(16), f64(-0.5)]),
+            f64(-0.5)
+        );
+        // Effective addresses are 64 bits, and out of bounds past the end of memory
+        let res = store.invoke(func("load16_u"), vec![i64(65535)]);
+        assert!(matches!(res, Err(err::Err::OutOfBoundMemoryAccess)));
+        let res = store.invoke(func("store"), vec![i64(-1), i64(0)]);
+        assert!(matches!(res, Err(err::Err::OutOfBoundMemoryAccess)));
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_memory() -> Result<(), err::Err> {
+        let source = r#"
+            (module
+              (memory 1)
+              (memory $scratch 1)
+              (data $greeting "hello")
+              (func (export "init") (param i32 i32 i32)
+                local.get 0
+                local.get 1
+                local.get 2
+                memory.init $scratch $greeting)
+              (func (export "fill") (param i32 i32 i32)
+                local.get 0
+                local.get 1
+                local.get 2
+                memory.fill $scratch)
+              (func (export "drop")
+                data.drop $greeting)
+              (func (export "load") (param i32) (result i32)
+                local.get 0
+                i32.load8_u $scratch))
+        "#;
+        let features = *Features::default().set(Feature::MultiMemory, true);
+        let module = Module::parse(source, &features)?;
+        module.validate(&features)?;
+        let config = Config {
+            features,
+            ..Config::default()
+        };
+        let mut store = runtime::Store::with_config(config);
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let i32 = |val: u32| Val::Num(Num::I32(val));
+        let func = |name| store.instance(instance).unwrap().export_func(name).unwrap();
+        let load = |offset| call(&store, instance, "load", vec![i32(offset)]);
+
+        store.invoke(func("init"), vec![i32(10), i32(1), i32(3)])?;
+        assert_eq!(load(10), i32(b'e' as u32));
+        assert_eq!(load(12), i32(b'l' as u32));
+        store.invoke(func("fill"), vec![i32(11), i32(0x1ff), i32(2)])?;
+        assert_eq!(load(10), i32(b'e' as u32));
+        assert_eq!(load(12), i32(0xff));
+        let res = store.invoke(func("init"), vec![i32(0), i32(3), i32(3)]);
+        assert!(matches!(res, Err(err::Err::OutOfBoundMemoryAccess)));
+        let res = store.invoke(func("fill"), vec![i32(65535), i32(0), i32(2)]);
+        assert!(matches!(res, Err(err::Err::OutOfBoundMemoryAccess)));
+        // Only empty ranges of dropped segments can be copied
+        store.invoke(func("drop"), vec![])?;
+        store.invoke(func("init"), vec![i32(0), i32(0), i32(0)])?;
+        let res = store.invoke(func("init"), vec![i32(0), i32(0), i32(1)]);
+        assert!(matches!(res, Err(err::Err::OutOfBoundMemoryAccess)));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() {
+        let decode = |bytes: &[u8]| decode(bytes, &Features::default());
+        assert!(matches!(
+            decode(b"\0asn\x01\0\0\0"),
+            Err(err::Err::ModuleDecode)
+        ));
+        assert!(decode(b"\0asm\x01\0\0\0").is_ok());
+        // A type section announcing more bytes than there are
+        assert!(matches!(
+            decode(b"\0asm\x01\0\0\0\x01\x05\x01\x60\0"),
+            Err(err::Err::ModuleDecode)
+        ));
+        // Over long and overflowing LEB128 integers
+        let mut reader = Reader {
+            bytes: &[0xff, 0xff, 0xff, 0xff, 0x7f],
+            pos: 0,
+        };
+        assert_eq!(reader.leb(32, true).unwrap() as i32, -1);
+        let mut reader = Reader {
+            bytes: &[0xff, 0xff, 0xff, 0xff, 0x1f],
+            pos: 0,
+        };
+        assert!(reader.leb(32, false).is_err());
+        let mut reader = Reader {
+            bytes: &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00],
+            pos: 0,
+        };
+        assert!(reader.leb(32, false).is_err());
+        assert!(matches!(
+            Module::parse("(module", &Features::default()),
+            Err(err::Err::ModuleParse)
+        ));
+    }
+
+    #[test]
+    fn validation() -> Result<(), err::Err> {
+        let features = Features::default();
+        assert!(!features.simd);
+        let module = Module::parse("(module (func call 3))", &features)?;
+        assert!(matches!(
+            module.validate(&features),
+            Err(err::Err::InvalidCode)
+        ));
+        let module = Module::parse("(module (func (export \"f\")))", &features)?;
+        module.validate(&features)?;
+        // Sizes beyond the 4GiB addressable by 32-bit memories
+        let module = Module::parse("(module (memory 70000))", &features)?;
+        assert!(matches!(
+            module.validate(&features),
+            Err(err::Err::InvalidLimit(_))
+        ));
+        let mut module = Module::parse("(module (memory 2 1))", &features)?;
+        assert!(matches!(
+            module.validate(&features),
+            Err(err::Err::InvalidLimit(_))
+        ));
+        module.mems.clear();
+        module.types.push(types::Function {
+            input: vec![types::Value::Ref(types::Ref::null(types::Heap::Type(0)))],
+            output: vec![],
+        });
+        assert!(matches!(
+            module.validate(&Features::all()),
+            Err(err::Err::InvalidIndex(0))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn indices_in_range() -> Result<(), err::Err> {
+        let features = Features::all();
+        let sources = [
+            "(module (global i32 (i32.const 0)) (func (result i32) global.get 1))",
+            "(module (global i32 (i32.const 0)) (func i32.const 1 global.set 0))",
+            "(module (func (result funcref) ref.func 7))",
+            "(module (table 1 funcref) (func (result i32) table.size 1))",
+            "(module (func (result i32) memory.size))",
+            "(module (memory 1) (func (result i32) i32.const 0 i32.load 1))",
+            "(module (memory 1) (func i32.const 0 i32.const 0 i32.const 0 memory.fill 1))",
+            "(module (memory 1) (func i32.const 0 i32.const 0 i32.const 0 memory.copy 0 1))",
+            "(module (memory 1) (data \"\") (func i32.const 0 i32.const 0 i32.const 0 memory.init 1 0))",
+            "(module (tag) (func throw 1))",
+            "(module (func i32.const 5 throw_ref))",
+        ];
+        for source in sources {
+            let module = Module::parse(source, &features)?;
+            assert!(matches!(
+                module.validate(&features),
+                Err(err::Err::InvalidCode)
+            ));
+            // Nor are they instantiated
+            let mut store = store();
+            store.config.features = features;
+            assert!(ModuleInstance::instantiate(&mut store, &module, vec![]).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn constant_initializers() -> Result<(), err::Err> {
+        let features = Features::all();
+        let valid = "(module (global i32 (i32.const 1)) (global i32 (i32.mul (global.get 0) (i32.const 2))))";
+        Module::parse(valid, &features)?.validate(&features)?;
+        let sources = [
+            "(module (global i32 (i64.const 0)))",
+            "(module (global (mut i32) (i32.const 0)) (global i32 (global.get 0)))",
+            "(module (global i32 (global.get 1)) (global i32 (i32.const 0)))",
+            "(module (global i32 (i32.const 0) (i32.eqz)))",
+        ];
+        for source in sources {
+            let module = Module::parse(source, &features)?;
+            assert!(module.validate(&features).is_err());
+        }
+        Ok(())
+    }
+}
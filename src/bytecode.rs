@@ -0,0 +1,1906 @@
+use alloc::vec::Vec;
+
+use crate::{
+    err,
+    instr::{Access, Atomic, BlockType, Catch, Instr, InstrClass, MemArg, RmwOp},
+    runtime::{Engine, FuelCosts},
+    types,
+    validation::{Context, Subtypable},
+};
+
+// Internal code format executed by the interpreter.
+// Function bodies are lowered once at instantiation: structured control is replaced by
+// jumps to precomputed targets, and branches carry the stack adjustment they perform,
+// so that no instruction has to look for its matching `End` at run time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    // Numeric
+    I32Const(u32),
+    I32Clz,
+    I32Ctz,
+    I32PopCnt,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32DivU,
+    I32DivS,
+    I32RemU,
+    I32RemS,
+    I32Not,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrU,
+    I32ShrS,
+    I32Rotl,
+    I32Rotr,
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtU,
+    I32LtS,
+    I32GtU,
+    I32GtS,
+    I32LeU,
+    I32LeS,
+    I32GeU,
+    I32GeS,
+
+    I64Const(u64),
+    I64Clz,
+    I64Ctz,
+    I64PopCnt,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivU,
+    I64DivS,
+    I64RemU,
+    I64RemS,
+    I64Not,
+    I64And,
+    I64Or,
+    I64Xor,
+    I64Shl,
+    I64ShrU,
+    I64ShrS,
+    I64Rotl,
+    I64Rotr,
+    I64Eqz,
+    I64Eq,
+    I64Ne,
+    I64LtU,
+    I64LtS,
+    I64GtU,
+    I64GtS,
+    I64LeU,
+    I64LeS,
+    I64GeU,
+    I64GeS,
+
+    F32Const(f32),
+    F64Const(f64),
+
+    I32Extend8S,
+    I32Extend16S,
+    I64Extend8S,
+    I64Extend16S,
+    I64Extend32S,
+    I32TruncSatF32U,
+    I32TruncSatF32S,
+    I32TruncSatF64U,
+    I32TruncSatF64S,
+    I64TruncSatF32U,
+    I64TruncSatF32S,
+    I64TruncSatF64U,
+    I64TruncSatF64S,
+
+    // Reference
+    RefNull, // Null references are untyped once validated
+    RefFunc(u32),
+    RefAsNonNull,
+
+    // Var, locals are addressed by their slot in the frame
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+
+    // Table
+    TableSize(u32),
+    TableGrow(u32),
+
+    // Memory
+    Load {
+        access: Access,
+        mem: u32,
+        offset: u64,
+    },
+    Store {
+        access: Access,
+        mem: u32,
+        offset: u64,
+    },
+    MemorySize(u32),
+    MemoryGrow(u32),
+    MemoryFill(u32),
+    MemoryCopy {
+        dst: u32,
+        src: u32,
+    },
+    MemoryInit {
+        data: u32,
+        mem: u32,
+    },
+    DataDrop(u32),
+    AtomicLoad {
+        atomic: Atomic,
+        mem: u32,
+        offset: u64,
+    },
+    AtomicStore {
+        atomic: Atomic,
+        mem: u32,
+        offset: u64,
+    },
+    AtomicRmw {
+        rmw: RmwOp,
+        atomic: Atomic,
+        mem: u32,
+        offset: u64,
+    },
+    AtomicCmpxchg {
+        atomic: Atomic,
+        mem: u32,
+        offset: u64,
+    },
+    MemoryAtomicNotify {
+        mem: u32,
+        offset: u64,
+    },
+    // Waits on an i32 or an i64 value
+    MemoryAtomicWait {
+        wide: bool,
+        mem: u32,
+        offset: u64,
+    },
+    AtomicFence,
+
+    // Control
+    Nop,
+    Unreachable,
+    Br(Branch),
+    BrIf(Branch),
+    BrIfEqz(u32),        // Jumps to the target if the popped condition is zero
+    BrOnNull(Branch),    // The reference is dropped when branching, kept otherwise
+    BrOnNonNull(Branch), // The reference is kept when branching, dropped otherwise
+    Return,
+    Call(u32),
+    CallRef(u32), // Calls the popped function reference, which must have the given type
+    ReturnCall(u32),
+    ReturnCallIndirect {
+        typeidx: u32,
+        table: u32,
+    },
+    ReturnCallRef(u32),
+    Throw(u32),
+    ThrowRef,
+
+    // Superinstructions, only produced by `fuse`.
+    // Operands are read from local slots or immediates instead of the stack.
+    BinLL {
+        op: BinOp,
+        lhs: u32,
+        rhs: u32,
+    },
+    BinLLSet {
+        op: BinOp,
+        lhs: u32,
+        rhs: u32,
+        dst: u32,
+    },
+    I32BinLC {
+        op: BinOp,
+        lhs: u32,
+        imm: u32,
+    },
+    I32BinLCSet {
+        op: BinOp,
+        lhs: u32,
+        imm: u32,
+        dst: u32,
+    },
+    I32BinC {
+        op: BinOp,
+        imm: u32,
+    },
+    BrIfRel {
+        op: RelOp,
+        branch: Branch,
+    },
+    BrIfNotRel {
+        op: RelOp,
+        target: u32,
+    },
+    BrUnless(Branch), // `i32.eqz` followed by `br_if`
+}
+
+// Binary operators that can be fused with the instructions producing their operands
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrU,
+    I32ShrS,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64And,
+    I64Or,
+    I64Xor,
+    I64Shl,
+    I64ShrU,
+    I64ShrS,
+}
+
+// Relational operators that can be fused with the branch consuming their result
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RelOp {
+    I32Eq,
+    I32Ne,
+    I32LtU,
+    I32LtS,
+    I32GtU,
+    I32GtS,
+    I32LeU,
+    I32LeS,
+    I32GeU,
+    I32GeS,
+    I64Eq,
+    I64Ne,
+    I64LtU,
+    I64LtS,
+    I64GtU,
+    I64GtS,
+    I64LeU,
+    I64LeS,
+    I64GeU,
+    I64GeS,
+}
+
+impl BinOp {
+    fn of(op: Op) -> Option<BinOp> {
+        match op {
+            Op::I32Add => Some(BinOp::I32Add),
+            Op::I32Sub => Some(BinOp::I32Sub),
+            Op::I32Mul => Some(BinOp::I32Mul),
+            Op::I32And => Some(BinOp::I32And),
+            Op::I32Or => Some(BinOp::I32Or),
+            Op::I32Xor => Some(BinOp::I32Xor),
+            Op::I32Shl => Some(BinOp::I32Shl),
+            Op::I32ShrU => Some(BinOp::I32ShrU),
+            Op::I32ShrS => Some(BinOp::I32ShrS),
+            Op::I64Add => Some(BinOp::I64Add),
+            Op::I64Sub => Some(BinOp::I64Sub),
+            Op::I64Mul => Some(BinOp::I64Mul),
+            Op::I64And => Some(BinOp::I64And),
+            Op::I64Or => Some(BinOp::I64Or),
+            Op::I64Xor => Some(BinOp::I64Xor),
+            Op::I64Shl => Some(BinOp::I64Shl),
+            Op::I64ShrU => Some(BinOp::I64ShrU),
+            Op::I64ShrS => Some(BinOp::I64ShrS),
+            _ => None,
+        }
+    }
+
+    fn is_i32(&self) -> bool {
+        matches!(
+            self,
+            BinOp::I32Add
+                | BinOp::I32Sub
+                | BinOp::I32Mul
+                | BinOp::I32And
+                | BinOp::I32Or
+                | BinOp::I32Xor
+                | BinOp::I32Shl
+                | BinOp::I32ShrU
+                | BinOp::I32ShrS
+        )
+    }
+}
+
+impl RelOp {
+    fn of(op: Op) -> Option<RelOp> {
+        match op {
+            Op::I32Eq => Some(RelOp::I32Eq),
+            Op::I32Ne => Some(RelOp::I32Ne),
+            Op::I32LtU => Some(RelOp::I32LtU),
+            Op::I32LtS => Some(RelOp::I32LtS),
+            Op::I32GtU => Some(RelOp::I32GtU),
+            Op::I32GtS => Some(RelOp::I32GtS),
+            Op::I32LeU => Some(RelOp::I32LeU),
+            Op::I32LeS => Some(RelOp::I32LeS),
+            Op::I32GeU => Some(RelOp::I32GeU),
+            Op::I32GeS => Some(RelOp::I32GeS),
+            Op::I64Eq => Some(RelOp::I64Eq),
+            Op::I64Ne => Some(RelOp::I64Ne),
+            Op::I64LtU => Some(RelOp::I64LtU),
+            Op::I64LtS => Some(RelOp::I64LtS),
+            Op::I64GtU => Some(RelOp::I64GtU),
+            Op::I64GtS => Some(RelOp::I64GtS),
+            Op::I64LeU => Some(RelOp::I64LeU),
+            Op::I64LeS => Some(RelOp::I64LeS),
+            Op::I64GeU => Some(RelOp::I64GeU),
+            Op::I64GeS => Some(RelOp::I64GeS),
+            _ => None,
+        }
+    }
+}
+
+// A resolved branch: the `keep` topmost values are kept, the `drop` values below them
+// are discarded, then execution continues at `target`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Branch {
+    pub target: u32,
+    pub drop: u32,
+    pub keep: u32,
+}
+
+impl Op {
+    pub fn class(&self) -> InstrClass {
+        match self {
+            Op::RefNull | Op::RefFunc(_) | Op::RefAsNonNull => InstrClass::Reference,
+            Op::LocalGet(_)
+            | Op::LocalSet(_)
+            | Op::LocalTee(_)
+            | Op::GlobalGet(_)
+            | Op::GlobalSet(_) => InstrClass::Variable,
+            Op::TableSize(_) | Op::TableGrow(_) => InstrClass::Table,
+            Op::Load { .. }
+            | Op::Store { .. }
+            | Op::MemorySize(_)
+            | Op::MemoryGrow(_)
+            | Op::MemoryFill(_)
+            | Op::MemoryCopy { .. }
+            | Op::MemoryInit { .. }
+            | Op::DataDrop(_)
+            | Op::AtomicLoad { .. }
+            | Op::AtomicStore { .. }
+            | Op::AtomicRmw { .. }
+            | Op::AtomicCmpxchg { .. }
+            | Op::MemoryAtomicNotify { .. }
+            | Op::MemoryAtomicWait { .. }
+            | Op::AtomicFence => InstrClass::Memory,
+            Op::Nop
+            | Op::Unreachable
+            | Op::Br(_)
+            | Op::BrIf(_)
+            | Op::BrIfEqz(_)
+            | Op::BrOnNull(_)
+            | Op::BrOnNonNull(_)
+            | Op::Return
+            | Op::Call(_)
+            | Op::CallRef(_)
+            | Op::ReturnCall(_)
+            | Op::ReturnCallIndirect { .. }
+            | Op::ReturnCallRef(_)
+            | Op::Throw(_)
+            | Op::ThrowRef
+            | Op::BrIfRel { .. }
+            | Op::BrIfNotRel { .. }
+            | Op::BrUnless(_) => InstrClass::Control,
+            _ => InstrClass::Numeric,
+        }
+    }
+
+    // Fuel cost of the op, a superinstruction costs as much as the instructions it replaces
+    pub fn cost(&self, costs: &FuelCosts) -> u64 {
+        let numeric = costs.cost(InstrClass::Numeric);
+        let variable = costs.cost(InstrClass::Variable);
+        let control = costs.cost(InstrClass::Control);
+        match self {
+            Op::BinLL { .. } => 2 * variable + numeric,
+            Op::BinLLSet { .. } => 3 * variable + numeric,
+            Op::I32BinLC { .. } => variable + 2 * numeric,
+            Op::I32BinLCSet { .. } => 2 * variable + 2 * numeric,
+            Op::I32BinC { .. } => 2 * numeric,
+            Op::BrIfRel { .. } | Op::BrIfNotRel { .. } | Op::BrUnless(_) => numeric + control,
+            _ => costs.cost(self.class()),
+        }
+    }
+
+    fn target(&self) -> Option<u32> {
+        match self {
+            Op::Br(branch)
+            | Op::BrIf(branch)
+            | Op::BrOnNull(branch)
+            | Op::BrOnNonNull(branch)
+            | Op::BrIfRel { branch, .. }
+            | Op::BrUnless(branch) => Some(branch.target),
+            Op::BrIfEqz(target) | Op::BrIfNotRel { target, .. } => Some(*target),
+            _ => None,
+        }
+    }
+
+    fn retarget(&mut self, map: &[u32]) {
+        match self {
+            Op::Br(branch)
+            | Op::BrIf(branch)
+            | Op::BrOnNull(branch)
+            | Op::BrOnNonNull(branch)
+            | Op::BrIfRel { branch, .. }
+            | Op::BrUnless(branch) => branch.target = map[branch.target as usize],
+            Op::BrIfEqz(target) | Op::BrIfNotRel { target, .. } => *target = map[*target as usize],
+            _ => {}
+        }
+    }
+}
+
+pub struct Code {
+    pub ops: Vec<Op>,
+    pub max_height: usize, // Maximum number of operands on the stack at any point
+    pub handlers: Vec<Handler>, // Innermost first
+}
+
+// Catch clauses of a `try_table` whose body spans the ops from `start` to `end`. Exceptions
+// thrown there unwind the operands down to `height` slots above the base of the frame, locals
+// included, then push their payload and jump to the landing pad of the first matching clause.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handler {
+    pub start: u32,
+    pub end: u32,
+    pub height: u32,
+    pub catches: Vec<HandlerCatch>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandlerCatch {
+    pub tag: Option<u32>, // None catches all exceptions
+    pub with_ref: bool,   // Whether the exception is pushed after its payload
+    pub landing: u32,     // A branch to the label of the clause
+}
+
+enum BlockKind {
+    Block,
+    Loop,
+    If(Option<usize>), // Position of the jump to the `else` branch, none in dead code
+    Else,
+    Try {
+        skip: Option<usize>, // Position of the jump over the landing pads, once one is emitted
+        catches: Vec<HandlerCatch>,
+    },
+}
+
+struct Block {
+    kind: BlockKind,
+    height: usize, // Height below the parameters of the block
+    params: types::Result,
+    results: types::Result,
+    start: usize,
+    fixups: Vec<usize>, // Branches to patch with the position following the block
+    sets: usize,        // Number of locals initialized before the block
+    live: bool,         // Whether the block is entered from reachable code, dead ones emit no op
+    // Set once the rest of the block cannot be reached, its operands then being of any type
+    unreachable: bool,
+}
+
+struct Compiler<'a> {
+    context: &'a Context,
+    // Of the function, which returns and tail calls must match. Code run outside of a function
+    // has none, its results being the operands it leaves.
+    results: Option<&'a types::Result>,
+    locals: Vec<types::Value>, // Parameters first
+    // Locals which may be read. Those without a default value are initialized by being set,
+    // until the end of the enclosing block, `sets` listing them in order.
+    initialized: Vec<bool>,
+    sets: Vec<usize>,
+    ops: Vec<Op>,
+    handlers: Vec<Handler>,
+    blocks: Vec<Block>,
+    // Types of the operands, those popped below the operands of an unreachable block are unknown
+    operands: Vec<Option<types::Value>>,
+    max_height: usize,
+}
+
+const I32: types::Value = types::Value::Num(types::Number::I32);
+const I64: types::Value = types::Value::Num(types::Number::I64);
+const F32: types::Value = types::Value::Num(types::Number::F32);
+const F64: types::Value = types::Value::Num(types::Number::F64);
+
+// Validates and lowers the body of a function of type `functype`, whose locals follow its
+// parameters. Indices in the body refer to the entities of `context`, the types of the
+// enclosing module, its functions, tables, memories, globals, tags and segments.
+pub fn compile(
+    context: &Context,
+    functype: &types::Function,
+    locals: &[types::Value],
+    body: &[Instr],
+) -> Result<Code, err::Err> {
+    lower(
+        context,
+        &functype.input,
+        locals,
+        Some(&functype.output),
+        body,
+    )
+}
+
+pub fn compile_for(
+    engine: Engine,
+    context: &Context,
+    functype: &types::Function,
+    locals: &[types::Value],
+    body: &[Instr],
+) -> Result<Code, err::Err> {
+    let code = compile(context, functype, locals, body)?;
+    Result::Ok(optimize(engine, code))
+}
+
+// Validates and lowers code run outside of a function, whose locals are `params` and whose
+// results are the operands it leaves, whatever their types
+#[cfg(test)]
+pub fn compile_program(
+    engine: Engine,
+    context: &Context,
+    params: &[types::Value],
+    body: &[Instr],
+) -> Result<Code, err::Err> {
+    let code = lower(context, params, &[], None, body)?;
+    Result::Ok(optimize(engine, code))
+}
+
+fn optimize(engine: Engine, code: Code) -> Code {
+    match engine {
+        Engine::Stack => code,
+        Engine::Register => fuse(code),
+    }
+}
+
+fn lower(
+    context: &Context,
+    params: &[types::Value],
+    locals: &[types::Value],
+    results: Option<&types::Result>,
+    body: &[Instr],
+) -> Result<Code, err::Err> {
+    for valtype in locals {
+        if let types::Value::Ref(reftype) = valtype {
+            check_heap(&context.types, reftype.heap)?;
+        }
+    }
+    let initialized = params.iter().map(|_| true);
+    let initialized = initialized.chain(locals.iter().map(types::Value::is_defaultable));
+    let mut compiler = Compiler {
+        context,
+        results,
+        locals: params.iter().chain(locals).copied().collect(),
+        initialized: initialized.collect(),
+        sets: vec![],
+        ops: vec![],
+        handlers: vec![],
+        blocks: vec![Block {
+            kind: BlockKind::Block,
+            height: 0,
+            params: vec![],
+            results: results.cloned().unwrap_or_default(),
+            start: 0,
+            fixups: vec![],
+            sets: 0,
+            live: true,
+            unreachable: false,
+        }],
+        operands: vec![],
+        max_height: 0,
+    };
+    let mut instrs = body.iter();
+    for instr in &mut instrs {
+        compiler.lower(*instr)?;
+        if compiler.blocks.is_empty() {
+            break;
+        }
+    }
+    // Nothing follows the end of the function, whose body may leave its `End` out
+    if instrs.next().is_some() {
+        return Result::Err(err::Err::InvalidCode);
+    }
+    if compiler.blocks.len() == 1 {
+        compiler.lower(Instr::End)?;
+    }
+    if !compiler.blocks.is_empty() {
+        return Result::Err(err::Err::InvalidCode);
+    }
+    Result::Ok(Code {
+        ops: compiler.ops,
+        max_height: compiler.max_height,
+        handlers: compiler.handlers,
+    })
+}
+
+// Rewrites common instruction sequences into superinstructions that address locals and
+// immediates directly. Sequences spanning a branch target or the bounds of a handler are left
+// untouched.
+pub fn fuse(code: Code) -> Code {
+    let ops = &code.ops;
+    let mut targets = vec![false; ops.len() + 1];
+    for op in ops {
+        if let Some(target) = op.target() {
+            targets[target as usize] = true;
+        }
+    }
+    for handler in &code.handlers {
+        targets[handler.start as usize] = true;
+        targets[handler.end as usize] = true;
+        for catch in &handler.catches {
+            targets[catch.landing as usize] = true;
+        }
+    }
+
+    let mut fused = vec![];
+    let mut map = vec![0; ops.len() + 1]; // Position of each op in the fused code
+    let mut pc = 0;
+    while pc < ops.len() {
+        let (op, len) = fuse_at(&ops[pc..], &targets[pc..]);
+        for pos in &mut map[pc..pc + len] {
+            *pos = fused.len() as u32;
+        }
+        fused.push(op);
+        pc += len;
+    }
+    map[ops.len()] = fused.len() as u32;
+    for op in &mut fused {
+        op.retarget(&map);
+    }
+    let mut handlers = code.handlers;
+    for handler in &mut handlers {
+        handler.start = map[handler.start as usize];
+        handler.end = map[handler.end as usize];
+        for catch in &mut handler.catches {
+            catch.landing = map[catch.landing as usize];
+        }
+    }
+
+    Code {
+        ops: fused,
+        max_height: code.max_height,
+        handlers,
+    }
+}
+
+// Returns the longest superinstruction starting at `ops[0]`, and the number of ops it replaces
+fn fuse_at(ops: &[Op], targets: &[bool]) -> (Op, usize) {
+    let window = |len: usize| ops.len() >= len && !targets[1..len].contains(&true);
+
+    if window(4) {
+        match (ops[0], ops[1], BinOp::of(ops[2]), ops[3]) {
+            (Op::LocalGet(lhs), Op::LocalGet(rhs), Some(op), Op::LocalSet(dst)) => {
+                return (Op::BinLLSet { op, lhs, rhs, dst }, 4)
+            }
+            (Op::LocalGet(lhs), Op::I32Const(imm), Some(op), Op::LocalSet(dst)) if op.is_i32() => {
+                return (Op::I32BinLCSet { op, lhs, imm, dst }, 4)
+            }
+            _ => {}
+        }
+    }
+    if window(3) {
+        match (ops[0], ops[1], BinOp::of(ops[2])) {
+            (Op::LocalGet(lhs), Op::LocalGet(rhs), Some(op)) => {
+                return (Op::BinLL { op, lhs, rhs }, 3)
+            }
+            (Op::LocalGet(lhs), Op::I32Const(imm), Some(op)) if op.is_i32() => {
+                return (Op::I32BinLC { op, lhs, imm }, 3)
+            }
+            _ => {}
+        }
+    }
+    if window(2) {
+        match (ops[0], ops[1]) {
+            (Op::I32Const(imm), next) => {
+                if let Some(op) = BinOp::of(next).filter(BinOp::is_i32) {
+                    return (Op::I32BinC { op, imm }, 2);
+                }
+            }
+            (Op::I32Eqz, Op::BrIf(branch)) => return (Op::BrUnless(branch), 2),
+            (rel, Op::BrIf(branch)) => {
+                if let Some(op) = RelOp::of(rel) {
+                    return (Op::BrIfRel { op, branch }, 2);
+                }
+            }
+            (rel, Op::BrIfEqz(target)) => {
+                if let Some(op) = RelOp::of(rel) {
+                    return (Op::BrIfNotRel { op, target }, 2);
+                }
+            }
+            _ => {}
+        }
+    }
+    (ops[0], 1)
+}
+
+// Type indices must refer to types of the module
+fn check_heap(types: &[types::Function], heap: types::Heap) -> Result<(), err::Err> {
+    match heap {
+        types::Heap::Type(idx) if idx >= types.len() => Result::Err(err::Err::InvalidCode),
+        _ => Result::Ok(()),
+    }
+}
+
+// Memory and offset of a plain access of `size` bytes, aligned at most naturally
+fn plain_memarg(memarg: MemArg, size: usize) -> Result<(u32, u64), err::Err> {
+    match 1usize.checked_shl(memarg.align) {
+        Some(align) if align <= size => Result::Ok((memarg.mem as u32, memarg.offset)),
+        _ => Result::Err(err::Err::InvalidCode),
+    }
+}
+
+// Memory and offset of an atomic access of `size` bytes, whose alignment must be natural
+fn atomic_memarg(memarg: MemArg, size: usize) -> Result<(u32, u64), err::Err> {
+    if 1usize.checked_shl(memarg.align) != Some(size) {
+        return Result::Err(err::Err::InvalidCode);
+    }
+    Result::Ok((memarg.mem as u32, memarg.offset))
+}
+
+// Type of the values a plain access loads or stores
+fn access_type(access: Access) -> types::Value {
+    match access {
+        Access::I32 | Access::I32S8 | Access::I32U8 | Access::I32S16 | Access::I32U16 => I32,
+        Access::F32 => F32,
+        Access::F64 => F64,
+        _ => I64,
+    }
+}
+
+fn atomic_type(atomic: Atomic) -> types::Value {
+    match atomic.is_i64() {
+        true => I64,
+        false => I32,
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn lower(&mut self, instr: Instr) -> Result<(), err::Err> {
+        let context = self.context;
+        match instr {
+            Instr::I32Const(val) => self.op(Op::I32Const(val), &[], &[I32]),
+            Instr::I32Clz => self.op(Op::I32Clz, &[I32], &[I32]),
+            Instr::I32Ctz => self.op(Op::I32Ctz, &[I32], &[I32]),
+            Instr::I32PopCnt => self.op(Op::I32PopCnt, &[I32], &[I32]),
+            Instr::I32Add => self.op(Op::I32Add, &[I32, I32], &[I32]),
+            Instr::I32Sub => self.op(Op::I32Sub, &[I32, I32], &[I32]),
+            Instr::I32Mul => self.op(Op::I32Mul, &[I32, I32], &[I32]),
+            Instr::I32DivU => self.op(Op::I32DivU, &[I32, I32], &[I32]),
+            Instr::I32DivS => self.op(Op::I32DivS, &[I32, I32], &[I32]),
+            Instr::I32RemU => self.op(Op::I32RemU, &[I32, I32], &[I32]),
+            Instr::I32RemS => self.op(Op::I32RemS, &[I32, I32], &[I32]),
+            Instr::I32Not => self.op(Op::I32Not, &[I32], &[I32]),
+            Instr::I32And => self.op(Op::I32And, &[I32, I32], &[I32]),
+            Instr::I32Or => self.op(Op::I32Or, &[I32, I32], &[I32]),
+            Instr::I32Xor => self.op(Op::I32Xor, &[I32, I32], &[I32]),
+            Instr::I32Shl => self.op(Op::I32Shl, &[I32, I32], &[I32]),
+            Instr::I32ShrU => self.op(Op::I32ShrU, &[I32, I32], &[I32]),
+            Instr::I32ShrS => self.op(Op::I32ShrS, &[I32, I32], &[I32]),
+            Instr::I32Rotl => self.op(Op::I32Rotl, &[I32, I32], &[I32]),
+            Instr::I32Rotr => self.op(Op::I32Rotr, &[I32, I32], &[I32]),
+            Instr::I32Eqz => self.op(Op::I32Eqz, &[I32], &[I32]),
+            Instr::I32Eq => self.op(Op::I32Eq, &[I32, I32], &[I32]),
+            Instr::I32Ne => self.op(Op::I32Ne, &[I32, I32], &[I32]),
+            Instr::I32LtU => self.op(Op::I32LtU, &[I32, I32], &[I32]),
+            Instr::I32LtS => self.op(Op::I32LtS, &[I32, I32], &[I32]),
+            Instr::I32GtU => self.op(Op::I32GtU, &[I32, I32], &[I32]),
+            Instr::I32GtS => self.op(Op::I32GtS, &[I32, I32], &[I32]),
+            Instr::I32LeU => self.op(Op::I32LeU, &[I32, I32], &[I32]),
+            Instr::I32LeS => self.op(Op::I32LeS, &[I32, I32], &[I32]),
+            Instr::I32GeU => self.op(Op::I32GeU, &[I32, I32], &[I32]),
+            Instr::I32GeS => self.op(Op::I32GeS, &[I32, I32], &[I32]),
+
+            Instr::I64Const(val) => self.op(Op::I64Const(val), &[], &[I64]),
+            Instr::I64Clz => self.op(Op::I64Clz, &[I64], &[I64]),
+            Instr::I64Ctz => self.op(Op::I64Ctz, &[I64], &[I64]),
+            Instr::I64PopCnt => self.op(Op::I64PopCnt, &[I64], &[I64]),
+            Instr::I64Add => self.op(Op::I64Add, &[I64, I64], &[I64]),
+            Instr::I64Sub => self.op(Op::I64Sub, &[I64, I64], &[I64]),
+            Instr::I64Mul => self.op(Op::I64Mul, &[I64, I64], &[I64]),
+            Instr::I64DivU => self.op(Op::I64DivU, &[I64, I64], &[I64]),
+            Instr::I64DivS => self.op(Op::I64DivS, &[I64, I64], &[I64]),
+            Instr::I64RemU => self.op(Op::I64RemU, &[I64, I64], &[I64]),
+            Instr::I64RemS => self.op(Op::I64RemS, &[I64, I64], &[I64]),
+            Instr::I64Not => self.op(Op::I64Not, &[I64], &[I64]),
+            Instr::I64And => self.op(Op::I64And, &[I64, I64], &[I64]),
+            Instr::I64Or => self.op(Op::I64Or, &[I64, I64], &[I64]),
+            Instr::I64Xor => self.op(Op::I64Xor, &[I64, I64], &[I64]),
+            Instr::I64Shl => self.op(Op::I64Shl, &[I64, I64], &[I64]),
+            Instr::I64ShrU => self.op(Op::I64ShrU, &[I64, I64], &[I64]),
+            Instr::I64ShrS => self.op(Op::I64ShrS, &[I64, I64], &[I64]),
+            Instr::I64Rotl => self.op(Op::I64Rotl, &[I64, I64], &[I64]),
+            Instr::I64Rotr => self.op(Op::I64Rotr, &[I64, I64], &[I64]),
+            Instr::I64Eqz => self.op(Op::I64Eqz, &[I64], &[I32]),
+            Instr::I64Eq => self.op(Op::I64Eq, &[I64, I64], &[I32]),
+            Instr::I64Ne => self.op(Op::I64Ne, &[I64, I64], &[I32]),
+            Instr::I64LtU => self.op(Op::I64LtU, &[I64, I64], &[I32]),
+            Instr::I64LtS => self.op(Op::I64LtS, &[I64, I64], &[I32]),
+            Instr::I64GtU => self.op(Op::I64GtU, &[I64, I64], &[I32]),
+            Instr::I64GtS => self.op(Op::I64GtS, &[I64, I64], &[I32]),
+            Instr::I64LeU => self.op(Op::I64LeU, &[I64, I64], &[I32]),
+            Instr::I64LeS => self.op(Op::I64LeS, &[I64, I64], &[I32]),
+            Instr::I64GeU => self.op(Op::I64GeU, &[I64, I64], &[I32]),
+            Instr::I64GeS => self.op(Op::I64GeS, &[I64, I64], &[I32]),
+
+            Instr::F32Const(val) => self.op(Op::F32Const(val), &[], &[F32]),
+            Instr::F64Const(val) => self.op(Op::F64Const(val), &[], &[F64]),
+
+            Instr::I32Extend8S => self.op(Op::I32Extend8S, &[I32], &[I32]),
+            Instr::I32Extend16S => self.op(Op::I32Extend16S, &[I32], &[I32]),
+            Instr::I64Extend8S => self.op(Op::I64Extend8S, &[I64], &[I64]),
+            Instr::I64Extend16S => self.op(Op::I64Extend16S, &[I64], &[I64]),
+            Instr::I64Extend32S => self.op(Op::I64Extend32S, &[I64], &[I64]),
+            Instr::I32TruncSatF32U => self.op(Op::I32TruncSatF32U, &[F32], &[I32]),
+            Instr::I32TruncSatF32S => self.op(Op::I32TruncSatF32S, &[F32], &[I32]),
+            Instr::I32TruncSatF64U => self.op(Op::I32TruncSatF64U, &[F64], &[I32]),
+            Instr::I32TruncSatF64S => self.op(Op::I32TruncSatF64S, &[F64], &[I32]),
+            Instr::I64TruncSatF32U => self.op(Op::I64TruncSatF32U, &[F32], &[I64]),
+            Instr::I64TruncSatF32S => self.op(Op::I64TruncSatF32S, &[F32], &[I64]),
+            Instr::I64TruncSatF64U => self.op(Op::I64TruncSatF64U, &[F64], &[I64]),
+            Instr::I64TruncSatF64S => self.op(Op::I64TruncSatF64S, &[F64], &[I64]),
+
+            Instr::RefNull(heap) => {
+                check_heap(&context.types, heap)?;
+                let reftype = types::Ref::null(heap);
+                self.op(Op::RefNull, &[], &[types::Value::Ref(reftype)])
+            }
+            Instr::RefFunc(idx) => {
+                let functype = context.funcs.get(idx).ok_or(err::Err::InvalidCode)?;
+                let reftype = types::Ref::non_null(self.heap(functype));
+                self.op(Op::RefFunc(idx as u32), &[], &[types::Value::Ref(reftype)])
+            }
+            Instr::RefAsNonNull => {
+                let reftype = self.pop_ref()?;
+                self.push_operand(
+                    reftype.map(|reftype| types::Value::Ref(types::Ref::non_null(reftype.heap))),
+                );
+                self.emit(Op::RefAsNonNull);
+                Result::Ok(())
+            }
+
+            Instr::LocalGet(idx) => {
+                let valtype = self.local(idx)?;
+                if !self.initialized[idx] {
+                    return Result::Err(err::Err::InvalidCode);
+                }
+                self.op(Op::LocalGet(idx as u32), &[], &[valtype])
+            }
+            Instr::LocalSet(idx) => {
+                let valtype = self.local(idx)?;
+                self.initialize(idx);
+                self.op(Op::LocalSet(idx as u32), &[valtype], &[])
+            }
+            Instr::LocalTee(idx) => {
+                let valtype = self.local(idx)?;
+                self.initialize(idx);
+                self.op(Op::LocalTee(idx as u32), &[valtype], &[valtype])
+            }
+            Instr::GlobalGet(idx) => {
+                let globaltype = context.globals.get(idx).ok_or(err::Err::InvalidCode)?;
+                self.op(Op::GlobalGet(idx as u32), &[], &[globaltype.val])
+            }
+            Instr::GlobalSet(idx) => {
+                let globaltype = context.globals.get(idx).ok_or(err::Err::InvalidCode)?;
+                if globaltype.mutable != types::Mut::Var {
+                    return Result::Err(err::Err::InvalidCode);
+                }
+                self.op(Op::GlobalSet(idx as u32), &[globaltype.val], &[])
+            }
+
+            Instr::TableSize(idx) => {
+                context.tables.get(idx).ok_or(err::Err::InvalidCode)?;
+                self.op(Op::TableSize(idx as u32), &[], &[I32])
+            }
+            Instr::TableGrow(idx) => {
+                let tabletype = context.tables.get(idx).ok_or(err::Err::InvalidCode)?;
+                let init = types::Value::Ref(tabletype.reftype);
+                self.op(Op::TableGrow(idx as u32), &[init, I32], &[I32])
+            }
+
+            Instr::Load(access, memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = plain_memarg(memarg, access.size())?;
+                let op = Op::Load {
+                    access,
+                    mem,
+                    offset,
+                };
+                self.op(op, &[address], &[access_type(access)])
+            }
+            Instr::Store(access, memarg) => {
+                if access.is_signed() {
+                    return Result::Err(err::Err::InvalidCode);
+                }
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = plain_memarg(memarg, access.size())?;
+                let op = Op::Store {
+                    access,
+                    mem,
+                    offset,
+                };
+                self.op(op, &[address, access_type(access)], &[])
+            }
+            Instr::MemorySize(idx) => {
+                let address = self.address(idx)?;
+                self.op(Op::MemorySize(idx as u32), &[], &[address])
+            }
+            Instr::MemoryGrow(idx) => {
+                let address = self.address(idx)?;
+                self.op(Op::MemoryGrow(idx as u32), &[address], &[address])
+            }
+            Instr::MemoryFill(idx) => {
+                let address = self.address(idx)?;
+                self.op(Op::MemoryFill(idx as u32), &[address, I32, address], &[])
+            }
+            Instr::MemoryCopy(dst, src) => {
+                let (dst_address, src_address) = (self.address(dst)?, self.address(src)?);
+                // The length is i64 only when both memories have i64 addresses
+                let len = match (dst_address, src_address) {
+                    (I64, I64) => I64,
+                    _ => I32,
+                };
+                let op = Op::MemoryCopy {
+                    dst: dst as u32,
+                    src: src as u32,
+                };
+                self.op(op, &[dst_address, src_address, len], &[])
+            }
+            Instr::MemoryInit(data, mem) => {
+                context.data.get(data).ok_or(err::Err::InvalidCode)?;
+                let address = self.address(mem)?;
+                let op = Op::MemoryInit {
+                    data: data as u32,
+                    mem: mem as u32,
+                };
+                self.op(op, &[address, I32, I32], &[])
+            }
+            Instr::DataDrop(idx) => {
+                context.data.get(idx).ok_or(err::Err::InvalidCode)?;
+                self.op(Op::DataDrop(idx as u32), &[], &[])
+            }
+            Instr::AtomicLoad(atomic, memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, atomic.size())?;
+                let op = Op::AtomicLoad {
+                    atomic,
+                    mem,
+                    offset,
+                };
+                self.op(op, &[address], &[atomic_type(atomic)])
+            }
+            Instr::AtomicStore(atomic, memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, atomic.size())?;
+                let op = Op::AtomicStore {
+                    atomic,
+                    mem,
+                    offset,
+                };
+                self.op(op, &[address, atomic_type(atomic)], &[])
+            }
+            Instr::AtomicRmw(rmw, atomic, memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, atomic.size())?;
+                let op = Op::AtomicRmw {
+                    rmw,
+                    atomic,
+                    mem,
+                    offset,
+                };
+                let valtype = atomic_type(atomic);
+                self.op(op, &[address, valtype], &[valtype])
+            }
+            Instr::AtomicCmpxchg(atomic, memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, atomic.size())?;
+                let op = Op::AtomicCmpxchg {
+                    atomic,
+                    mem,
+                    offset,
+                };
+                let valtype = atomic_type(atomic);
+                self.op(op, &[address, valtype, valtype], &[valtype])
+            }
+            Instr::MemoryAtomicNotify(memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, 4)?;
+                self.op(
+                    Op::MemoryAtomicNotify { mem, offset },
+                    &[address, I32],
+                    &[I32],
+                )
+            }
+            Instr::MemoryAtomicWait32(memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, 4)?;
+                let op = Op::MemoryAtomicWait {
+                    wide: false,
+                    mem,
+                    offset,
+                };
+                self.op(op, &[address, I32, I64], &[I32])
+            }
+            Instr::MemoryAtomicWait64(memarg) => {
+                let address = self.address(memarg.mem)?;
+                let (mem, offset) = atomic_memarg(memarg, 8)?;
+                let op = Op::MemoryAtomicWait {
+                    wide: true,
+                    mem,
+                    offset,
+                };
+                self.op(op, &[address, I64, I64], &[I32])
+            }
+            Instr::AtomicFence => self.op(Op::AtomicFence, &[], &[]),
+
+            Instr::Nop => self.op(Op::Nop, &[], &[]),
+            Instr::Unreachable => {
+                self.emit(Op::Unreachable);
+                self.set_unreachable();
+                Result::Ok(())
+            }
+            Instr::Block(blocktype) => self.enter(BlockKind::Block, blocktype),
+            Instr::Loop(blocktype) => self.enter(BlockKind::Loop, blocktype),
+            Instr::If(blocktype) => {
+                self.pop_expect(I32)?;
+                let jump = match self.reachable() {
+                    true => {
+                        self.ops.push(Op::BrIfEqz(0));
+                        Some(self.ops.len() - 1)
+                    }
+                    false => None,
+                };
+                self.enter(BlockKind::If(jump), blocktype)
+            }
+            Instr::Else => {
+                let jump = match self.blocks.last().map(|block| &block.kind) {
+                    Some(BlockKind::If(jump)) => *jump,
+                    _ => return Result::Err(err::Err::InvalidCode),
+                };
+                self.check_results()?;
+                if self.reachable() {
+                    let end_of_then = self.ops.len();
+                    self.blocks.last_mut().unwrap().fixups.push(end_of_then);
+                    self.ops.push(Op::Br(Branch {
+                        target: 0,
+                        drop: 0,
+                        keep: 0,
+                    }));
+                }
+                if let Some(jump) = jump {
+                    self.ops[jump] = Op::BrIfEqz(self.ops.len() as u32);
+                }
+                let block = self.blocks.last_mut().unwrap();
+                block.kind = BlockKind::Else;
+                block.unreachable = false;
+                let (sets, params) = (block.sets, block.params.clone());
+                self.uninitialize(sets);
+                self.push_types(&params);
+                Result::Ok(())
+            }
+            Instr::End => {
+                // Code run outside of a function leaves its operands as they are
+                let outermost = self.blocks.len() == 1;
+                if !outermost || self.results.is_some() {
+                    self.check_results()?;
+                }
+                let block = self.blocks.pop().ok_or(err::Err::InvalidCode)?;
+                // Without an `else` branch, the parameters are passed through as the results
+                if let BlockKind::If(_) = block.kind {
+                    if !block.params.is_subtype(&block.results, &context.types) {
+                        return Result::Err(err::Err::InvalidCode);
+                    }
+                }
+                self.patch(&block);
+                self.uninitialize(block.sets);
+                if let BlockKind::Try { catches, .. } = block.kind {
+                    if !catches.is_empty() {
+                        self.handlers.push(Handler {
+                            start: block.start as u32,
+                            end: self.ops.len() as u32,
+                            height: (self.locals.len() + block.height) as u32,
+                            catches,
+                        });
+                    }
+                }
+                if !outermost {
+                    self.push_types(&block.results);
+                }
+                Result::Ok(())
+            }
+            Instr::Br(label_idx) => {
+                self.check_label(label_idx)?;
+                self.emit_branch(label_idx, Op::Br);
+                self.set_unreachable();
+                Result::Ok(())
+            }
+            Instr::BrIf(label_idx) => {
+                self.pop_expect(I32)?;
+                self.check_label(label_idx)?;
+                self.emit_branch(label_idx, Op::BrIf);
+                Result::Ok(())
+            }
+            Instr::BrOnNull(label_idx) => {
+                let reftype = self.pop_ref()?;
+                self.check_label(label_idx)?;
+                self.emit_branch(label_idx, Op::BrOnNull);
+                self.push_operand(
+                    reftype.map(|reftype| types::Value::Ref(types::Ref::non_null(reftype.heap))),
+                );
+                Result::Ok(())
+            }
+            Instr::BrOnNonNull(label_idx) => {
+                // The label takes the non-null reference on top of its other values
+                let reftype = self.pop_ref()?;
+                let labeltypes = self.label_types(label_idx)?;
+                if !matches!(labeltypes.last(), Some(types::Value::Ref(_))) {
+                    return Result::Err(err::Err::InvalidCode);
+                }
+                self.push_operand(
+                    reftype.map(|reftype| types::Value::Ref(types::Ref::non_null(reftype.heap))),
+                );
+                self.check_label(label_idx)?;
+                self.emit_branch(label_idx, Op::BrOnNonNull);
+                self.pop_operand()?;
+                Result::Ok(())
+            }
+            Instr::Return => {
+                if let Some(results) = self.results {
+                    self.pop_types(results)?;
+                }
+                self.emit(Op::Return);
+                self.set_unreachable();
+                Result::Ok(())
+            }
+            Instr::Call(idx) => {
+                let functype = context.funcs.get(idx).ok_or(err::Err::InvalidCode)?;
+                self.op(Op::Call(idx as u32), &functype.input, &functype.output)
+            }
+            Instr::CallRef(typeidx) => {
+                let functype = context.types.get(typeidx).ok_or(err::Err::InvalidCode)?;
+                // The reference comes on top of the arguments
+                let funcref = types::Value::Ref(types::Ref::null(types::Heap::Type(typeidx)));
+                self.pop_expect(funcref)?;
+                self.op(
+                    Op::CallRef(typeidx as u32),
+                    &functype.input,
+                    &functype.output,
+                )
+            }
+            Instr::ReturnCall(idx) => {
+                let functype = context.funcs.get(idx).ok_or(err::Err::InvalidCode)?;
+                self.tail_call(Op::ReturnCall(idx as u32), functype, None)
+            }
+            Instr::ReturnCallIndirect(typeidx, table) => {
+                let functype = context.types.get(typeidx).ok_or(err::Err::InvalidCode)?;
+                let tabletype = context.tables.get(table).ok_or(err::Err::InvalidCode)?;
+                if !tabletype
+                    .reftype
+                    .is_subtype(&types::Ref::FUNC, &context.types)
+                {
+                    return Result::Err(err::Err::InvalidCode);
+                }
+                let op = Op::ReturnCallIndirect {
+                    typeidx: typeidx as u32,
+                    table: table as u32,
+                };
+                // The index of the callee in the table comes on top of the arguments
+                self.tail_call(op, functype, Some(I32))
+            }
+            Instr::ReturnCallRef(typeidx) => {
+                let functype = context.types.get(typeidx).ok_or(err::Err::InvalidCode)?;
+                let funcref = types::Value::Ref(types::Ref::null(types::Heap::Type(typeidx)));
+                self.tail_call(Op::ReturnCallRef(typeidx as u32), functype, Some(funcref))
+            }
+            Instr::Throw(idx) => {
+                let tagtype = context.tags.get(idx).ok_or(err::Err::InvalidCode)?;
+                self.op(Op::Throw(idx as u32), &tagtype.input, &[])?;
+                self.set_unreachable();
+                Result::Ok(())
+            }
+            Instr::ThrowRef => {
+                self.op(Op::ThrowRef, &[types::Value::Ref(types::Ref::EXN)], &[])?;
+                self.set_unreachable();
+                Result::Ok(())
+            }
+            Instr::TryTable(blocktype) => {
+                let kind = BlockKind::Try {
+                    skip: None,
+                    catches: vec![],
+                };
+                self.enter(kind, blocktype)
+            }
+            Instr::Catch(catch) => self.catch(catch),
+        }
+    }
+
+    // Emits `op`, which pops operands of types `input` and pushes ones of types `output`
+    fn op(
+        &mut self,
+        op: Op,
+        input: &[types::Value],
+        output: &[types::Value],
+    ) -> Result<(), err::Err> {
+        self.pop_types(input)?;
+        self.push_types(output);
+        self.emit(op);
+        Result::Ok(())
+    }
+
+    // Ops of unreachable code are left out
+    fn emit(&mut self, op: Op) {
+        if self.reachable() {
+            self.ops.push(op);
+        }
+    }
+
+    fn reachable(&self) -> bool {
+        self.blocks
+            .last()
+            .is_some_and(|block| block.live && !block.unreachable)
+    }
+
+    // Discards the operands of the innermost block, whose rest cannot be reached
+    fn set_unreachable(&mut self) {
+        if let Some(block) = self.blocks.last_mut() {
+            self.operands.truncate(block.height);
+            block.unreachable = true;
+        }
+    }
+
+    // Emits a call replacing the current function, whose results the callee's must match.
+    // `operand` is the type of the operand designating the callee, on top of the arguments.
+    fn tail_call(
+        &mut self,
+        op: Op,
+        functype: &types::Function,
+        operand: Option<types::Value>,
+    ) -> Result<(), err::Err> {
+        let results = self.results.ok_or(err::Err::InvalidCode)?;
+        if !functype.output.is_subtype(results, &self.context.types) {
+            return Result::Err(err::Err::InvalidCode);
+        }
+        if let Some(operand) = operand {
+            self.pop_expect(operand)?;
+        }
+        self.op(op, &functype.input, &[])?;
+        self.set_unreachable();
+        Result::Ok(())
+    }
+
+    fn push_operand(&mut self, valtype: Option<types::Value>) {
+        self.operands.push(valtype);
+        self.max_height = self.max_height.max(self.operands.len());
+    }
+
+    fn push_types(&mut self, valtypes: &[types::Value]) {
+        for valtype in valtypes {
+            self.push_operand(Some(*valtype));
+        }
+    }
+
+    // Pops an operand of the innermost block, of any type if it is unreachable and has none left
+    fn pop_operand(&mut self) -> Result<Option<types::Value>, err::Err> {
+        let block = self.blocks.last().ok_or(err::Err::InvalidCode)?;
+        if self.operands.len() > block.height {
+            return Result::Ok(self.operands.pop().unwrap());
+        }
+        match block.unreachable {
+            true => Result::Ok(None),
+            false => Result::Err(err::Err::InvalidCode),
+        }
+    }
+
+    fn pop_expect(&mut self, expected: types::Value) -> Result<(), err::Err> {
+        match self.pop_operand()? {
+            Some(valtype) if !valtype.is_subtype(&expected, &self.context.types) => {
+                Result::Err(err::Err::InvalidCode)
+            }
+            _ => Result::Ok(()),
+        }
+    }
+
+    fn pop_types(&mut self, expected: &[types::Value]) -> Result<(), err::Err> {
+        for valtype in expected.iter().rev() {
+            self.pop_expect(*valtype)?;
+        }
+        Result::Ok(())
+    }
+
+    // Pops a reference of any type
+    fn pop_ref(&mut self) -> Result<Option<types::Ref>, err::Err> {
+        match self.pop_operand()? {
+            Some(types::Value::Ref(reftype)) => Result::Ok(Some(reftype)),
+            Some(_) => Result::Err(err::Err::InvalidCode),
+            None => Result::Ok(None),
+        }
+    }
+
+    // Checks that the operands of the innermost block are its results, and only those
+    fn check_results(&mut self) -> Result<(), err::Err> {
+        let block = self.blocks.last().ok_or(err::Err::InvalidCode)?;
+        let (results, height) = (block.results.clone(), block.height);
+        self.pop_types(&results)?;
+        if self.operands.len() != height {
+            return Result::Err(err::Err::InvalidCode);
+        }
+        Result::Ok(())
+    }
+
+    fn local(&self, idx: types::Index) -> Result<types::Value, err::Err> {
+        self.locals.get(idx).copied().ok_or(err::Err::InvalidCode)
+    }
+
+    // Type of the addresses of the memory at `idx`
+    fn address(&self, idx: types::Index) -> Result<types::Value, err::Err> {
+        let memtype = self.context.mems.get(idx).ok_or(err::Err::InvalidCode)?;
+        match memtype.index {
+            types::IndexType::I32 => Result::Ok(I32),
+            types::IndexType::I64 => Result::Ok(I64),
+        }
+    }
+
+    // Heap type of references to functions of type `functype`, which defined types are
+    // equivalent to
+    fn heap(&self, functype: &types::Function) -> types::Heap {
+        let types = &self.context.types;
+        match types
+            .iter()
+            .position(|deftype| deftype.is_subtype(functype, types))
+        {
+            Some(idx) => types::Heap::Type(idx),
+            None => types::Heap::Func,
+        }
+    }
+
+    fn initialize(&mut self, idx: types::Index) {
+        if !self.initialized[idx] {
+            self.initialized[idx] = true;
+            self.sets.push(idx);
+        }
+    }
+
+    // Forgets the locals initialized since `sets` of them were
+    fn uninitialize(&mut self, sets: usize) {
+        for idx in self.sets.drain(sets..) {
+            self.initialized[idx] = false;
+        }
+    }
+
+    // Opens a block, its parameters being the topmost operands
+    fn enter(&mut self, kind: BlockKind, blocktype: BlockType) -> Result<(), err::Err> {
+        let (params, results) = match blocktype {
+            BlockType::Empty => (vec![], vec![]),
+            BlockType::Value(valtype) => {
+                if let types::Value::Ref(reftype) = valtype {
+                    check_heap(&self.context.types, reftype.heap)?;
+                }
+                (vec![], vec![valtype])
+            }
+            BlockType::Index(idx) => {
+                let functype = self.context.types.get(idx).ok_or(err::Err::InvalidCode)?;
+                (functype.input.clone(), functype.output.clone())
+            }
+        };
+        self.pop_types(&params)?;
+        let live = self.reachable();
+        self.blocks.push(Block {
+            kind,
+            height: self.operands.len(),
+            params: params.clone(),
+            results,
+            start: self.ops.len(),
+            fixups: vec![],
+            sets: self.sets.len(),
+            live,
+            unreachable: false,
+        });
+        self.push_types(&params);
+        Result::Ok(())
+    }
+
+    // Types of the values passed to the `label_idx`-th enclosing block
+    fn label_types(&self, label_idx: types::Index) -> Result<types::Result, err::Err> {
+        let depth = self.blocks.len();
+        if label_idx >= depth {
+            return Result::Err(err::Err::InvalidCode);
+        }
+        let block = &self.blocks[depth - 1 - label_idx];
+        match block.kind {
+            BlockKind::Loop => Result::Ok(block.params.clone()),
+            _ => Result::Ok(block.results.clone()),
+        }
+    }
+
+    // Checks that the topmost operands can be passed to the label, leaving them on the stack
+    fn check_label(&mut self, label_idx: types::Index) -> Result<(), err::Err> {
+        let labeltypes = self.label_types(label_idx)?;
+        self.pop_types(&labeltypes)?;
+        self.push_types(&labeltypes);
+        Result::Ok(())
+    }
+
+    fn emit_branch(&mut self, label_idx: types::Index, op: impl Fn(Branch) -> Op) {
+        if self.reachable() {
+            let branch = self.branch(label_idx);
+            self.ops.push(op(branch));
+        }
+    }
+
+    // Resolves a branch to the `label_idx`-th enclosing block from the current height, the
+    // operands having been checked against the label. Forward branches are recorded to be
+    // patched once the block ends.
+    fn branch(&mut self, label_idx: types::Index) -> Branch {
+        let pos = self.ops.len();
+        let height = self.operands.len();
+        let depth = self.blocks.len();
+        let block = &mut self.blocks[depth - 1 - label_idx];
+        let (keep, target) = match block.kind {
+            BlockKind::Loop => (block.params.len(), block.start),
+            _ => {
+                block.fixups.push(pos);
+                (block.results.len(), 0)
+            }
+        };
+        Branch {
+            target: target as u32,
+            drop: (height - block.height - keep) as u32,
+            keep: keep as u32,
+        }
+    }
+
+    // Emits the landing pad of a clause of the innermost `try_table`: a branch to the label of
+    // the clause, taken with the payload on top of the operands below the block. Landing pads
+    // precede the body of the block, which a jump skips over them to.
+    fn catch(&mut self, catch: Catch) -> Result<(), err::Err> {
+        let mut block = self.blocks.pop().ok_or(err::Err::InvalidCode)?;
+        let BlockKind::Try { skip, catches } = &mut block.kind else {
+            return Result::Err(err::Err::InvalidCode);
+        };
+        // Clauses come before the body
+        if block.start != self.ops.len() {
+            return Result::Err(err::Err::InvalidCode);
+        }
+        let (tag, label, with_ref) = match catch {
+            Catch::Tag(tag, label) => (Some(tag), label, false),
+            Catch::TagRef(tag, label) => (Some(tag), label, true),
+            Catch::All(label) => (None, label, false),
+            Catch::AllRef(label) => (None, label, true),
+        };
+        let mut payload = match tag {
+            Some(tag) => {
+                let tagtype = self.context.tags.get(tag).ok_or(err::Err::InvalidCode)?;
+                tagtype.input.clone()
+            }
+            None => vec![],
+        };
+        if with_ref {
+            payload.push(types::Value::Ref(types::Ref::non_null(types::Heap::Exn)));
+        }
+
+        // Labels are those around the block
+        let operands = self.operands.split_off(block.height);
+        self.push_types(&payload);
+        let checked = self.check_label(label);
+        if checked.is_ok() && block.live {
+            let skip = *skip.get_or_insert_with(|| {
+                self.ops.push(Op::Br(Branch {
+                    target: 0,
+                    drop: 0,
+                    keep: 0,
+                }));
+                self.ops.len() - 1
+            });
+            let landing = self.ops.len();
+            let branch = self.branch(label);
+            self.ops.push(Op::Br(branch));
+            catches.push(HandlerCatch {
+                tag: tag.map(|tag| tag as u32),
+                with_ref,
+                landing: landing as u32,
+            });
+            block.start = self.ops.len();
+            self.ops[skip] = Op::Br(Branch {
+                target: block.start as u32,
+                drop: 0,
+                keep: 0,
+            });
+        }
+        self.operands.truncate(block.height);
+        self.operands.extend(operands);
+        checked?;
+        self.blocks.push(block);
+        Result::Ok(())
+    }
+
+    // Points the forward branches of a block that just ended to the next instruction
+    fn patch(&mut self, block: &Block) {
+        let end = self.ops.len() as u32;
+        if let BlockKind::If(Some(jump)) = block.kind {
+            self.ops[jump] = Op::BrIfEqz(end);
+        }
+        for fixup in &block.fixups {
+            match &mut self.ops[*fixup] {
+                Op::Br(branch)
+                | Op::BrIf(branch)
+                | Op::BrOnNull(branch)
+                | Op::BrOnNonNull(branch) => branch.target = end,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::i32_function;
+
+    use super::*;
+
+    #[test]
+    fn branch_targets() -> Result<(), err::Err> {
+        let code = compile(
+            &Context::default(),
+            &i32_function(1, 1),
+            &[],
+            &[
+                Instr::Block(BlockType::Value(types::Value::Num(types::Number::I32))),
+                Instr::I32Const(1),
+                Instr::I32Const(2),
+                Instr::LocalGet(0),
+                Instr::BrIf(0),
+                Instr::Loop(BlockType::Empty),
+                Instr::Br(0),
+                Instr::End,
+                Instr::Unreachable,
+                Instr::End,
+            ],
+        )?;
+
+        assert_eq!(
+            code.ops,
+            vec![
+                Op::I32Const(1),
+                Op::I32Const(2),
+                Op::LocalGet(0),
+                Op::BrIf(Branch {
+                    target: 6,
+                    drop: 1,
+                    keep: 1
+                }),
+                Op::Br(Branch {
+                    target: 4,
+                    drop: 0,
+                    keep: 0
+                }),
+                Op::Unreachable,
+            ]
+        );
+        assert_eq!(code.max_height, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn if_else_jumps() -> Result<(), err::Err> {
+        let code = compile(
+            &Context::default(),
+            &i32_function(1, 1),
+            &[],
+            &[
+                Instr::LocalGet(0),
+                Instr::If(BlockType::Value(types::Value::Num(types::Number::I32))),
+                Instr::I32Const(1),
+                Instr::Else,
+                Instr::I32Const(2),
+                Instr::End,
+            ],
+        )?;
+
+        assert_eq!(
+            code.ops,
+            vec![
+                Op::LocalGet(0),
+                Op::BrIfEqz(4),
+                Op::I32Const(1),
+                Op::Br(Branch {
+                    target: 5,
+                    drop: 0,
+                    keep: 0
+                }),
+                Op::I32Const(2),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dead_code_is_skipped() -> Result<(), err::Err> {
+        let code = compile(
+            &Context::default(),
+            &i32_function(0, 0),
+            &[],
+            &[
+                Instr::Block(BlockType::Empty),
+                Instr::Br(0),
+                Instr::Block(BlockType::Empty),
+                Instr::I32Const(1),
+                Instr::BrIf(0),
+                Instr::End,
+                Instr::End,
+                Instr::Nop,
+            ],
+        )?;
+
+        assert_eq!(
+            code.ops,
+            vec![
+                Op::Br(Branch {
+                    target: 1,
+                    drop: 0,
+                    keep: 0
+                }),
+                Op::Nop,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_code() {
+        assert!(compile(
+            &Context::default(),
+            &i32_function(0, 0),
+            &[],
+            &[Instr::LocalGet(0)]
+        )
+        .is_err());
+        assert!(compile(
+            &Context::default(),
+            &i32_function(0, 0),
+            &[],
+            &[Instr::Br(1)]
+        )
+        .is_err());
+        assert!(compile(
+            &Context::default(),
+            &i32_function(0, 0),
+            &[],
+            &[Instr::I32Add]
+        )
+        .is_err());
+        assert!(compile(
+            &Context::default(),
+            &i32_function(0, 0),
+            &[],
+            &[Instr::Call(0)]
+        )
+        .is_err());
+        // Alignments beyond the natural one
+        let memarg = |align| MemArg {
+            offset: 0,
+            align,
+            mem: 0,
+        };
+        let context = Context {
+            mems: vec![types::Mem {
+                limits: types::Limits { min: 1, max: None },
+                index: types::IndexType::I32,
+                shared: false,
+            }],
+            ..Context::default()
+        };
+        let load = |access, align| {
+            let body = [Instr::I32Const(0), Instr::Load(access, memarg(align))];
+            compile(&context, &i32_function(0, 1), &[], &body)
+        };
+        assert!(load(Access::I32, 2).is_ok());
+        assert!(load(Access::I32, 3).is_err());
+        assert!(load(Access::I32U8, 1).is_err());
+    }
+
+    #[test]
+    fn ill_typed_operands() {
+        let externref = types::Function {
+            input: vec![],
+            output: vec![types::Value::Ref(types::Ref::EXTERN)],
+        };
+        let body = [Instr::I64Const(1), Instr::F32Const(2.0), Instr::I32Add];
+        assert!(compile(&Context::default(), &i32_function(0, 1), &[], &body).is_err());
+        // Which would forge a reference out of an integer
+        let body = [Instr::I64Const(1)];
+        assert!(compile(&Context::default(), &externref, &[], &body).is_err());
+        let body = [Instr::RefNull(types::Heap::Func)];
+        assert!(compile(&Context::default(), &externref, &[], &body).is_err());
+        let body = [Instr::RefNull(types::Heap::Extern)];
+        assert!(compile(&Context::default(), &externref, &[], &body).is_ok());
+        // Operands of unreachable code are of any type
+        let body = [Instr::Unreachable, Instr::I32Add];
+        assert!(compile(&Context::default(), &externref, &[], &body).is_err());
+        let body = [Instr::Unreachable, Instr::I32Const(0), Instr::I32Add];
+        assert!(compile(&Context::default(), &i32_function(0, 1), &[], &body).is_ok());
+    }
+
+    #[test]
+    fn tail_call_results() {
+        let functype = i32_function(0, 1);
+        let i64_result = types::Function {
+            input: vec![],
+            output: vec![types::Value::Num(types::Number::I64)],
+        };
+        let funcs = [functype.clone(), i64_result, i32_function(0, 2)];
+        let context = Context {
+            funcs: funcs.to_vec(),
+            ..Context::default()
+        };
+        let compile = |idx| compile(&context, &functype, &[], &[Instr::ReturnCall(idx)]);
+
+        assert!(compile(0).is_ok());
+        // Results must have the types of the caller's, not only their number
+        assert!(compile(1).is_err());
+        assert!(compile(2).is_err());
+    }
+
+    #[test]
+    fn non_nullable_locals_are_set_before_use() {
+        let functype = types::Function {
+            input: vec![],
+            output: vec![],
+        };
+        let nullable = types::Value::Ref(types::Ref::FUNC);
+        let non_null = types::Value::Ref(types::Ref::non_null(types::Heap::Func));
+        let compile =
+            |body: &[Instr]| compile(&Context::default(), &functype, &[nullable, non_null], body);
+        let set = [
+            Instr::RefNull(types::Heap::Func),
+            Instr::RefAsNonNull,
+            Instr::LocalSet(1),
+        ];
+        let get = [Instr::LocalGet(1), Instr::LocalSet(0)];
+
+        assert!(compile(&[Instr::LocalGet(0), Instr::LocalSet(0)]).is_ok());
+        assert!(compile(&get).is_err());
+        assert!(compile(&[&set[..], &get].concat()).is_ok());
+        // Initialization lasts until the end of the block
+        let block = [
+            Instr::Block(BlockType::Empty),
+            Instr::Block(BlockType::Empty),
+        ];
+        let end = [Instr::End];
+        let body = [&block[..], &set, &get, &end, &get, &end].concat();
+        assert!(compile(&body).is_err());
+        let body = [&block[..1], &set, &block[1..], &get, &end, &get, &end].concat();
+        assert!(compile(&body).is_ok());
+        // Nor does it carry over to the `else` branch
+        let body = [
+            &[Instr::I32Const(0), Instr::If(BlockType::Empty)],
+            &set[..],
+            &[Instr::Else],
+            &get,
+            &end,
+        ]
+        .concat();
+        assert!(compile(&body).is_err());
+        // Type indices must refer to types of the module
+        let body = [Instr::RefNull(types::Heap::Type(0)), Instr::LocalSet(0)];
+        assert!(compile(&body).is_err());
+    }
+
+    #[test]
+    fn fused_ops_fit_in_16_bytes() {
+        assert!(core::mem::size_of::<Op>() <= 16);
+    }
+
+    #[test]
+    fn fuse_superinstructions() -> Result<(), err::Err> {
+        let code = compile(
+            &Context::default(),
+            &i32_function(2, 1),
+            &[],
+            &[
+                Instr::Block(BlockType::Empty),
+                Instr::Loop(BlockType::Empty),
+                Instr::LocalGet(0),
+                Instr::I32Const(10),
+                Instr::I32GeU,
+                Instr::BrIf(1),
+                Instr::LocalGet(1),
+                Instr::LocalGet(0),
+                Instr::I32Add,
+                Instr::LocalSet(1),
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::I32Add,
+                Instr::LocalSet(0),
+                Instr::Br(0),
+                Instr::End,
+                Instr::End,
+                Instr::LocalGet(1),
+                Instr::I32Const(2),
+                Instr::I32Mul,
+            ],
+        )?;
+
+        assert_eq!(
+            fuse(code).ops,
+            vec![
+                Op::LocalGet(0),
+                Op::I32Const(10),
+                Op::BrIfRel {
+                    op: RelOp::I32GeU,
+                    branch: Branch {
+                        target: 6,
+                        drop: 0,
+                        keep: 0
+                    }
+                },
+                Op::BinLLSet {
+                    op: BinOp::I32Add,
+                    lhs: 1,
+                    rhs: 0,
+                    dst: 1
+                },
+                Op::I32BinLCSet {
+                    op: BinOp::I32Add,
+                    lhs: 0,
+                    imm: 1,
+                    dst: 0
+                },
+                Op::Br(Branch {
+                    target: 0,
+                    drop: 0,
+                    keep: 0
+                }),
+                Op::I32BinLC {
+                    op: BinOp::I32Mul,
+                    lhs: 1,
+                    imm: 2
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fusion_stops_at_branch_targets() {
+        // A loop jumping back between the two local.get
+        let code = Code {
+            handlers: vec![],
+            ops: vec![
+                Op::LocalGet(0),
+                Op::LocalGet(1),
+                Op::I32Add,
+                Op::LocalSet(0),
+                Op::Br(Branch {
+                    target: 1,
+                    drop: 0,
+                    keep: 0,
+                }),
+            ],
+            max_height: 2,
+        };
+
+        assert_eq!(
+            fuse(code).ops,
+            vec![
+                Op::LocalGet(0),
+                Op::LocalGet(1),
+                Op::I32Add,
+                Op::LocalSet(0),
+                Op::Br(Branch {
+                    target: 1,
+                    drop: 0,
+                    keep: 0
+                }),
+            ]
+        );
+    }
+}
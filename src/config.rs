@@ -0,0 +1,179 @@
+use crate::runtime::{Engine, FuelCosts, StackLimits};
+
+// Proposals standardized after the MVP, which modules may only use when enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    SignExtension,
+    SaturatingFloatToInt,
+    MultiValue,
+    BulkMemory,
+    ReferenceTypes,
+    Simd,
+    TailCall,
+    Memory64,
+    MultiMemory,
+    ExtendedConst,
+    Threads,
+    ExceptionHandling,
+    FunctionReferences,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 13] = [
+        Feature::SignExtension,
+        Feature::SaturatingFloatToInt,
+        Feature::MultiValue,
+        Feature::BulkMemory,
+        Feature::ReferenceTypes,
+        Feature::Simd,
+        Feature::TailCall,
+        Feature::Memory64,
+        Feature::MultiMemory,
+        Feature::ExtendedConst,
+        Feature::Threads,
+        Feature::ExceptionHandling,
+        Feature::FunctionReferences,
+    ];
+}
+
+// Set of enabled proposals. The default is WebAssembly 2.0 without SIMD, which is not
+// implemented, later proposals being disabled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Features {
+    pub sign_extension: bool,
+    pub saturating_float_to_int: bool,
+    pub multi_value: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+    pub simd: bool,
+    pub tail_call: bool,
+    pub memory64: bool,
+    pub multi_memory: bool,
+    pub extended_const: bool,
+    pub threads: bool,
+    pub exception_handling: bool,
+    pub function_references: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            sign_extension: true,
+            saturating_float_to_int: true,
+            multi_value: true,
+            bulk_memory: true,
+            reference_types: true,
+            ..Features::mvp()
+        }
+    }
+}
+
+impl Features {
+    pub fn mvp() -> Features {
+        Features {
+            sign_extension: false,
+            saturating_float_to_int: false,
+            multi_value: false,
+            bulk_memory: false,
+            reference_types: false,
+            simd: false,
+            tail_call: false,
+            memory64: false,
+            multi_memory: false,
+            extended_const: false,
+            threads: false,
+            exception_handling: false,
+            function_references: false,
+        }
+    }
+
+    pub fn all() -> Features {
+        let mut features = Features::mvp();
+        for feature in Feature::ALL {
+            features.set(feature, true);
+        }
+        features
+    }
+
+    fn flag(&mut self, feature: Feature) -> &mut bool {
+        match feature {
+            Feature::SignExtension => &mut self.sign_extension,
+            Feature::SaturatingFloatToInt => &mut self.saturating_float_to_int,
+            Feature::MultiValue => &mut self.multi_value,
+            Feature::BulkMemory => &mut self.bulk_memory,
+            Feature::ReferenceTypes => &mut self.reference_types,
+            Feature::Simd => &mut self.simd,
+            Feature::TailCall => &mut self.tail_call,
+            Feature::Memory64 => &mut self.memory64,
+            Feature::MultiMemory => &mut self.multi_memory,
+            Feature::ExtendedConst => &mut self.extended_const,
+            Feature::Threads => &mut self.threads,
+            Feature::ExceptionHandling => &mut self.exception_handling,
+            Feature::FunctionReferences => &mut self.function_references,
+        }
+    }
+
+    pub fn enabled(&self, feature: Feature) -> bool {
+        let mut features = *self;
+        *features.flag(feature)
+    }
+
+    pub fn set(&mut self, feature: Feature, enabled: bool) -> &mut Self {
+        *self.flag(feature) = enabled;
+        self
+    }
+
+    // First feature of `required` which is not enabled
+    pub fn missing(&self, required: &Features) -> Option<Feature> {
+        Feature::ALL
+            .into_iter()
+            .find(|feature| required.enabled(*feature) && !self.enabled(*feature))
+    }
+}
+
+// Settings of a store, fixed when it is created. Features are checked when modules are decoded,
+// validated and instantiated, the rest applies to execution.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Config {
+    pub features: Features,
+    pub fuel: Option<u64>, // Initial fuel, None leaving execution unmetered
+    pub fuel_costs: FuelCosts,
+    pub stack_limits: StackLimits,
+    pub engine: Engine,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    pub fn features(&mut self, features: Features) -> &mut Self {
+        self.features = features;
+        self
+    }
+
+    pub fn feature(&mut self, feature: Feature, enabled: bool) -> &mut Self {
+        self.features.set(feature, enabled);
+        self
+    }
+
+    pub fn fuel(&mut self, fuel: Option<u64>) -> &mut Self {
+        self.fuel = fuel;
+        self
+    }
+
+    pub fn fuel_costs(&mut self, fuel_costs: FuelCosts) -> &mut Self {
+        self.fuel_costs = fuel_costs;
+        self
+    }
+
+    pub fn stack_limits(&mut self, stack_limits: StackLimits) -> &mut Self {
+        self.stack_limits = stack_limits;
+        self
+    }
+
+    pub fn engine(&mut self, engine: Engine) -> &mut Self {
+        self.engine = engine;
+        self
+    }
+}
@@ -1,341 +1,1903 @@
-use core::cell::RefCell;
+use core::cell::{self, RefCell};
 
 use alloc::{string::ToString, vec::Vec};
 
 use crate::{
+    config::Features,
     err::Err,
+    host::IntoHostFunc,
+    instr::Instr,
+    linker,
+    memory::LeBytes,
     modules::{self, HostFunc},
-    runtime,
-    types::{self, Addr, Limits},
-    validation::Validable,
+    runtime::{self, FuncId, GlobalId, InstanceId, MemId, TableId, TagId},
+    shared::SharedMemory,
+    types,
+    validation::{Context, Validable},
+    vm,
 };
 
+// Objects are designated by handles, which are checked against the store they are used with
 pub trait Store {
     fn new() -> Self;
 
     // Functions
-    fn func_alloc(&mut self, functype: types::Function, hostfunc: modules::HostFunc) -> Addr;
-    fn func_type(&self, addr: Addr) -> types::Function;
-    fn invoke(&mut self, addr: Addr, values: Vec<runtime::Val>) -> Result<Vec<runtime::Val>, Err>;
+    fn func_alloc(&mut self, functype: types::Function, hostfunc: modules::HostFunc) -> FuncId;
+    // Allocates a host function whose signature is derived from the Rust types of `func`
+    fn func_wrap<Params, Results>(&mut self, func: impl IntoHostFunc<Params, Results>) -> FuncId {
+        let (functype, hostfunc) = func.into_host_func();
+        self.func_alloc(functype, hostfunc)
+    }
+    fn func_type(&self, func: FuncId) -> Result<types::Function, Err>;
+    fn invoke(&self, func: FuncId, values: Vec<runtime::Val>) -> Result<Vec<runtime::Val>, Err>;
 
     //Tables
-    fn table_alloc(&mut self, tabletype: types::Table) -> Addr;
-    fn table_type(&self, addr: Addr) -> types::Table;
-    fn table_read(&self, addr: Addr, index: usize) -> Result<runtime::Ref, Err>;
-    fn table_write(&mut self, addr: Addr, index: usize, value: runtime::Ref) -> Result<(), Err>;
-    fn table_size(&self, addr: Addr) -> usize;
-    fn table_grow(&mut self, addr: Addr, n: types::Int, init: runtime::Ref) -> Result<(), Err>;
+    fn table_alloc(&mut self, tabletype: types::Table) -> TableId;
+    fn table_type(&self, table: TableId) -> Result<types::Table, Err>;
+    fn table_read(&self, table: TableId, index: usize) -> Result<runtime::Ref, Err>;
+    fn table_write(&mut self, table: TableId, index: usize, value: runtime::Ref)
+        -> Result<(), Err>;
+    fn table_size(&self, table: TableId) -> Result<usize, Err>;
+    fn table_grow(&mut self, table: TableId, n: types::Int, init: runtime::Ref) -> Result<(), Err>;
 
     // Memories
-    fn mem_alloc(&mut self, memtyp: types::Mem) -> Addr;
-    fn mem_type(&self, addr: Addr) -> types::Mem;
-    fn mem_read(&self, addr: Addr, index: types::Index) -> Result<types::Byte, Err>;
-    fn mem_write(&mut self, addr: Addr, index: types::Index, value: types::Byte)
+    // Memory types must be valid, shared ones declaring their maximum
+    fn mem_alloc(&mut self, memtyp: types::Mem) -> Result<MemId, Err>;
+    // Shared memories are allocated once and then added to the stores of each thread using them
+    fn mem_alloc_shared(&mut self, memory: SharedMemory) -> MemId;
+    fn mem_shared(&self, mem: MemId) -> Result<SharedMemory, Err>;
+    fn mem_type(&self, mem: MemId) -> Result<types::Mem, Err>;
+    fn mem_read(&self, mem: MemId, index: types::Index) -> Result<types::Byte, Err>;
+    fn mem_write(&mut self, mem: MemId, index: types::Index, value: types::Byte)
         -> Result<(), Err>;
-    fn mem_size(&self, addr: Addr) -> types::Int;
-    fn mem_grow(&mut self, addr: Addr, n: types::Int, init: runtime::Ref) -> Result<(), Err>;
+    fn mem_size(&self, mem: MemId) -> Result<types::Int, Err>;
+    fn mem_grow(&mut self, mem: MemId, n: types::Int) -> Result<(), Err>;
+    // Bulk and typed accesses only need a shared store, so that host functions can use them
+    // through their `Caller`. Offsets are in bytes.
+    fn mem_read_bytes(&self, mem: MemId, offset: usize, buf: &mut [u8]) -> Result<(), Err>;
+    fn mem_write_bytes(&self, mem: MemId, offset: usize, bytes: &[u8]) -> Result<(), Err>;
+    fn mem_load<T: LeBytes>(&self, mem: MemId, offset: usize) -> Result<T, Err>;
+    fn mem_store<T: LeBytes>(&self, mem: MemId, offset: usize, value: T) -> Result<(), Err>;
+    // Views of the whole memory, which cannot grow while they are held. Shared memories have none.
+    fn mem_data(&self, mem: MemId) -> Result<cell::Ref<'_, [u8]>, Err>;
+    fn mem_data_mut(&self, mem: MemId) -> Result<cell::RefMut<'_, [u8]>, Err>;
 
     // Globals
-    fn global_alloc(&mut self, globtype: types::Global) -> Addr;
-    fn global_type(&self, addr: Addr) -> types::Global;
-    fn global_read(&self, addr: Addr) -> Result<runtime::Val, Err>;
-    fn global_write(&mut self, addr: Addr, value: runtime::Val) -> Result<(), Err>;
+    fn global_alloc(&mut self, globtype: types::Global) -> GlobalId;
+    fn global_type(&self, global: GlobalId) -> Result<types::Global, Err>;
+    fn global_read(&self, global: GlobalId) -> Result<runtime::Val, Err>;
+    fn global_write(&mut self, global: GlobalId, value: runtime::Val) -> Result<(), Err>;
+
+    // Tags, which host functions can throw exceptions with
+    fn tag_alloc(&mut self, tagtype: types::Function) -> TagId;
+    fn tag_type(&self, tag: TagId) -> Result<types::Function, Err>;
 }
 
 pub trait Module: Sized {
-    fn decode(bytes_: &Vec<u8>) -> Result<Self, Err>;
-    fn parse(source: &str) -> Result<Self, Err>;
-    fn validate(&self) -> Result<(), Err>;
+    // Instructions and types of disabled proposals are rejected
+    fn decode(bytes_: &[u8], features: &Features) -> Result<Self, Err>;
+    fn parse(source: &str, features: &Features) -> Result<Self, Err>;
+    fn validate(&self, features: &Features) -> Result<(), Err>;
 }
 
-pub trait Instanciable<'a>: Sized {
+pub trait Instanciable: Sized {
     fn export(&self, name: &str) -> Result<runtime::ExternalVal, Err>;
+
+    // Lookups of an export of a given kind
+
+    fn export_func(&self, name: &str) -> Result<FuncId, Err> {
+        match self.export(name)? {
+            runtime::ExternalVal::Fun(func) => Result::Ok(func),
+            _ => Result::Err(Err::ModuleInstanceExportNotAFunction(name.to_string())),
+        }
+    }
+
+    fn export_table(&self, name: &str) -> Result<TableId, Err> {
+        match self.export(name)? {
+            runtime::ExternalVal::Table(table) => Result::Ok(table),
+            _ => Result::Err(Err::ModuleInstanceExportNotATable(name.to_string())),
+        }
+    }
+
+    fn export_mem(&self, name: &str) -> Result<MemId, Err> {
+        match self.export(name)? {
+            runtime::ExternalVal::Mem(mem) => Result::Ok(mem),
+            _ => Result::Err(Err::ModuleInstanceExportNotAMemory(name.to_string())),
+        }
+    }
+
+    fn export_global(&self, name: &str) -> Result<GlobalId, Err> {
+        match self.export(name)? {
+            runtime::ExternalVal::Global(global) => Result::Ok(global),
+            _ => Result::Err(Err::ModuleInstanceExportNotAGlobal(name.to_string())),
+        }
+    }
+
+    fn export_tag(&self, name: &str) -> Result<TagId, Err> {
+        match self.export(name)? {
+            runtime::ExternalVal::Tag(tag) => Result::Ok(tag),
+            _ => Result::Err(Err::ModuleInstanceExportNotATag(name.to_string())),
+        }
+    }
+
     fn instantiate(
-        store: &'a mut runtime::Store<'a>,
+        store: &mut runtime::Store,
         module: &modules::Module,
         externvals: Vec<runtime::ExternalVal>,
-    ) -> Result<&'a RefCell<Self>, Err>;
+    ) -> Result<InstanceId, Err>;
 }
 
-impl<'a> Instanciable<'a> for runtime::ModuleInstance {
+impl Instanciable for runtime::ModuleInstance {
     fn export(&self, name: &str) -> Result<runtime::ExternalVal, crate::err::Err> {
         for export in &self.exports {
             if export.name == name {
                 return Result::Ok(export.value);
             }
         }
-        return Result::Err(Err::ModuleInstanceExportNotFound(name.to_string()));
+        Result::Err(Err::ModuleInstanceExportNotFound(name.to_string()))
     }
 
     fn instantiate(
-        store: &'a mut runtime::Store<'a>,
+        store: &mut runtime::Store,
         module: &modules::Module,
         externvals: Vec<runtime::ExternalVal>,
-    ) -> Result<&'a RefCell<runtime::ModuleInstance>, Err> {
-        let mut instance = runtime::ModuleInstance {
-            types: vec![],
-            funct: vec![],
-            tables: vec![],
-            mems: vec![],
-            globals: vec![],
-            elems: vec![],
-            datas: vec![],
-            exports: vec![],
-        };
-
-        // TODO 4.5.4
+    ) -> Result<InstanceId, Err> {
+        // Only valid modules are instantiated, their code relying on it
+        Module::validate(module, &store.config.features)?;
+        let mut instance = runtime::ModuleInstance::new();
 
+        // 4.5.4
+        if externvals.len() != module.imports.len() {
+            return Result::Err(Err::ExternalValCountMismatch);
+        }
         for externval in &externvals {
-            match externval {
-                &runtime::ExternalVal::Fun(addr) => {
-                    if store.funcinstances.len() <= addr {
-                        return Result::Err(Err::UndefinedFunction(addr));
-                    }
+            match *externval {
+                runtime::ExternalVal::Fun(func) => {
+                    store.func(func)?;
+                    instance.funct.push(func.0.addr);
                 }
-                &runtime::ExternalVal::Global(addr) => {
-                    if store.globals.len() <= addr {
-                        return Result::Err(Err::UndefinedGlobal(addr));
-                    }
+                runtime::ExternalVal::Global(global) => {
+                    store.global(global)?;
+                    instance.globals.push(global.0.addr);
                 }
-                &runtime::ExternalVal::Mem(addr) => {
-                    if store.mems.len() <= addr {
-                        return Result::Err(Err::UndefinedMem(addr));
-                    }
+                runtime::ExternalVal::Mem(mem) => {
+                    store.mem(mem)?;
+                    instance.mems.push(mem.0.addr);
                 }
-                &runtime::ExternalVal::Table(addr) => {
-                    if store.tables.len() <= addr {
-                        return Result::Err(Err::UndefinedTable(addr));
-                    }
+                runtime::ExternalVal::Table(table) => {
+                    store.table(table)?;
+                    instance.tables.push(table.0.addr);
                 }
+                runtime::ExternalVal::Tag(tag) => {
+                    store.tag(tag)?;
+                    instance.tags.push(tag.0.addr);
+                }
+            }
+        }
+        // Type indices of the module are replaced by those of the store
+        let types = store.register_types(&module.types)?;
+        let mut incompatible = vec![];
+        for (import, externval) in module.imports.iter().zip(&externvals) {
+            if !linker::matches_import(store, &types, &import.desc, *externval)? {
+                incompatible.push(linker::UnresolvedImport {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                    reason: linker::UnresolvedReason::IncompatibleType,
+                });
             }
         }
+        if !incompatible.is_empty() {
+            return Result::Err(Err::UnresolvedImports(incompatible));
+        }
 
-        instance.types = module.types.clone();
+        store.check_counts(1, module.mems.len(), module.tables.len())?;
 
-        store.modules.push(RefCell::new(instance));
-        let instance_ref = &store.modules[store.modules.len() - 1];
+        instance.types = types
+            .iter()
+            .map(|idx| store.types.borrow()[*idx].clone())
+            .collect();
 
+        // The instance is pushed once complete, at this address
+        let instance_addr = store.modules.next_addr();
+
+        // Every instance is built before any is added to the store, so that a failed
+        // instantiation leaves the store unchanged
+        let tag_insts: Vec<_> = module
+            .tags
+            .iter()
+            .map(|tag| runtime::TagInstance {
+                tagtype: instance.types[tag.tagtype].clone(),
+            })
+            .collect();
+
+        let context = module.context();
+        let mut func_insts = vec![];
         for func in &module.funcs {
-            let func_inst = runtime::InternalFuncInstance {
-                functype: module.types[func.functype].clone(),
-                module: &instance_ref,
-                code: func.clone(),
-            };
-            store
-                .funcinstances
-                .push(runtime::FuncInstance::Internal(func_inst));
-            let addr = store.funcinstances.len() - 1;
-            instance_ref.borrow_mut().funct.push(addr);
+            func_insts.push(runtime::InternalFuncInstance::new(
+                instance.types[func.functype].clone(),
+                instance_addr,
+                func.clone(),
+                &context,
+                store.config.engine,
+            )?);
         }
 
+        let mut table_insts = vec![];
         for table in &module.tables {
-            let table_inst = RefCell::new(runtime::Table {
-                tabletype: table.tabletype,
-                elem: vec![], // TODO allocate min values
-            });
-            store.tables.push(table_inst);
-            instance_ref
-                .borrow_mut()
-                .tables
-                .push(store.tables.len() - 1);
+            let tabletype = types::Table {
+                reftype: table.tabletype.reftype.remap(&types)?,
+                ..table.tabletype
+            };
+            let (min, max) = (tabletype.limits.min, tabletype.limits.max);
+            if !store.table_growing(0, min, max)? {
+                return Result::Err(Err::TableLimitExceeded);
+            }
+            let mut elem = vec![];
+            elem.try_reserve_exact(min)
+                .map_err(|_| Err::TableLimitExceeded)?;
+            elem.resize(min, runtime::Ref::Null(tabletype.reftype.heap));
+            table_insts.push(runtime::Table { tabletype, elem });
         }
 
+        let mut mem_insts = vec![];
         for mem in &module.mems {
-            let mem_inst = RefCell::new(runtime::Mem {
-                memtype: mem.memtype,
-                data: vec![], // TODO allocate min memory pages with 00
-            });
-            store.mems.push(mem_inst);
-            instance_ref.borrow_mut().mems.push(store.mems.len() - 1);
+            let limits = mem.memtype.limits;
+            let maximum = limits.max.map(|max| max.saturating_mul(runtime::PAGE_SIZE));
+            // Shared memories are allocated up to their maximum when created
+            let reserved = match (mem.memtype.shared, maximum) {
+                (true, Some(maximum)) => maximum,
+                _ => limits
+                    .min
+                    .checked_mul(runtime::PAGE_SIZE)
+                    .ok_or(Err::InvalidLimit(limits))?,
+            };
+            if !store.memory_growing(0, reserved, maximum)? {
+                return Result::Err(Err::MemoryLimitExceeded);
+            }
+            mem_insts.push(runtime::Mem::new(mem.memtype)?);
         }
 
+        let mut globaltypes = vec![];
         for global in &module.globals {
-            let glob_inst = RefCell::new(runtime::Global {
-                globaltype: global.globaltype,
-                value: runtime::Val::Num(runtime::Num::I32(0)), // TODO execute global.init
+            globaltypes.push(types::Global {
+                val: global.globaltype.val.remap(&types)?,
+                ..global.globaltype
             });
-            store.globals.push(glob_inst);
-            instance_ref
-                .borrow_mut()
-                .globals
-                .push(store.globals.len() - 1);
         }
 
+        let mut elem_insts = vec![];
         for elem in &module.elems {
-            let elem_inst = RefCell::new(runtime::Elem {
-                elemtype: elem.elemtype,
+            elem_insts.push(runtime::Elem {
+                elemtype: elem.elemtype.remap(&types)?,
                 elem: vec![], // TODO copy elements from module according to mode
             });
-            store.elems.push(elem_inst);
-            instance_ref.borrow_mut().elems.push(store.elems.len() - 1);
         }
 
+        for tag_inst in tag_insts {
+            instance.tags.push(store.tags.push(tag_inst));
+        }
+        for func_inst in func_insts {
+            let addr = store
+                .funcinstances
+                .push(runtime::FuncInstance::Internal(func_inst));
+            instance.funct.push(addr);
+        }
+        for table_inst in table_insts {
+            instance
+                .tables
+                .push(store.tables.push(RefCell::new(table_inst)));
+        }
+        for mem_inst in mem_insts {
+            instance.mems.push(store.mems.push(RefCell::new(mem_inst)));
+        }
+        // Initializers may refer to the functions and the earlier globals of the instance
+        for (global, globaltype) in module.globals.iter().zip(globaltypes) {
+            let glob_inst = runtime::Global {
+                globaltype,
+                value: eval_const(store, &instance, &types, &global.init),
+            };
+            instance
+                .globals
+                .push(store.globals.push(RefCell::new(glob_inst)));
+        }
+        for elem_inst in elem_insts {
+            instance
+                .elems
+                .push(store.elems.push(RefCell::new(elem_inst)));
+        }
         for data in &module.datas {
             let data_inst = RefCell::new(runtime::Data {
                 data: data.init.clone(),
             });
-            store.datas.push(data_inst);
-            instance_ref.borrow_mut().datas.push(store.datas.len() - 1);
+            instance.datas.push(store.datas.push(data_inst));
         }
 
         // TODO Export Value Typing validation (4.5.2)
         for export in &module.exports {
-            match export.desc {
+            let value = match export.desc {
                 modules::ExportDesc::Func(idx) => {
-                    instance_ref.borrow_mut().exports.push(runtime::Export {
-                        name: export.name.clone(),
-                        value: runtime::ExternalVal::Fun(instance_ref.borrow().funct[idx]),
-                    });
+                    runtime::ExternalVal::Fun(store.func_id(instance.funct[idx]))
                 }
                 modules::ExportDesc::Table(idx) => {
-                    instance_ref.borrow_mut().exports.push(runtime::Export {
-                        name: export.name.clone(),
-                        value: runtime::ExternalVal::Table(instance_ref.borrow().tables[idx]),
-                    });
+                    runtime::ExternalVal::Table(store.table_id(instance.tables[idx]))
                 }
                 modules::ExportDesc::Mem(idx) => {
-                    instance_ref.borrow_mut().exports.push(runtime::Export {
-                        name: export.name.clone(),
-                        value: runtime::ExternalVal::Mem(instance_ref.borrow().mems[idx]),
-                    });
+                    runtime::ExternalVal::Mem(store.mem_id(instance.mems[idx]))
                 }
                 modules::ExportDesc::Global(idx) => {
-                    instance_ref.borrow_mut().exports.push(runtime::Export {
-                        name: export.name.clone(),
-                        value: runtime::ExternalVal::Global(instance_ref.borrow().globals[idx]),
-                    });
+                    runtime::ExternalVal::Global(store.global_id(instance.globals[idx]))
                 }
-            }
+                modules::ExportDesc::Tag(idx) => {
+                    runtime::ExternalVal::Tag(store.tag_id(instance.tags[idx]))
+                }
+            };
+            instance.exports.push(runtime::Export {
+                name: export.name.clone(),
+                value,
+            });
         }
 
-        return Ok(&store.modules[store.modules.len() - 1]);
+        store.modules.push(instance);
+        store.modules.pin(instance_addr);
+        Result::Ok(store.instance_id(instance_addr))
     }
 }
 
-impl<'a> Store for runtime::Store<'a> {
+// Evaluates a constant expression of a validated module, whose type indices `types` maps to
+// those of the store
+fn eval_const(
+    store: &runtime::Store,
+    instance: &runtime::ModuleInstance,
+    types: &[types::Index],
+    expr: &[Instr],
+) -> runtime::Val {
+    use runtime::{Num, Val};
+
+    let mut stack = vec![];
+    for instr in expr {
+        let val = match *instr {
+            Instr::I32Const(val) => Val::Num(Num::I32(val)),
+            Instr::I64Const(val) => Val::Num(Num::I64(val)),
+            Instr::F32Const(val) => Val::Num(Num::F32(val)),
+            Instr::F64Const(val) => Val::Num(Num::F64(val)),
+            Instr::RefNull(heap) => {
+                let heap = heap.remap(types).unwrap_or(heap);
+                Val::Ref(runtime::Ref::Null(heap))
+            }
+            Instr::RefFunc(idx) => Val::Ref(store.func_id(instance.funct[idx]).into()),
+            Instr::GlobalGet(idx) => store.globals[instance.globals[idx]].borrow().value,
+            _ => {
+                let (Some(val2), Some(val1)) = (stack.pop(), stack.pop()) else {
+                    unreachable!()
+                };
+                match (instr, val1, val2) {
+                    (Instr::I32Add, Val::Num(Num::I32(a)), Val::Num(Num::I32(b))) => {
+                        Val::Num(Num::I32(a.wrapping_add(b)))
+                    }
+                    (Instr::I32Sub, Val::Num(Num::I32(a)), Val::Num(Num::I32(b))) => {
+                        Val::Num(Num::I32(a.wrapping_sub(b)))
+                    }
+                    (Instr::I32Mul, Val::Num(Num::I32(a)), Val::Num(Num::I32(b))) => {
+                        Val::Num(Num::I32(a.wrapping_mul(b)))
+                    }
+                    (Instr::I64Add, Val::Num(Num::I64(a)), Val::Num(Num::I64(b))) => {
+                        Val::Num(Num::I64(a.wrapping_add(b)))
+                    }
+                    (Instr::I64Sub, Val::Num(Num::I64(a)), Val::Num(Num::I64(b))) => {
+                        Val::Num(Num::I64(a.wrapping_sub(b)))
+                    }
+                    (Instr::I64Mul, Val::Num(Num::I64(a)), Val::Num(Num::I64(b))) => {
+                        Val::Num(Num::I64(a.wrapping_mul(b)))
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        };
+        stack.push(val);
+    }
+    stack.pop().unwrap()
+}
+
+impl Store for runtime::Store {
     fn new() -> Self {
-        runtime::Store {
-            modules: vec![],
-            funcinstances: vec![],
-            tables: vec![],
-            mems: vec![],
-            globals: vec![],
-            elems: vec![],
-            datas: vec![],
-        }
+        runtime::Store::new()
     }
 
-    fn func_alloc(&mut self, functype: types::Function, hostfunc: HostFunc) -> Addr {
-        let func_inst = runtime::HostFuncInstance { functype };
-        self.funcinstances
+    fn func_alloc(&mut self, functype: types::Function, hostfunc: HostFunc) -> FuncId {
+        let func_inst = runtime::HostFuncInstance { functype, hostfunc };
+        let addr = self
+            .funcinstances
             .push(runtime::FuncInstance::Host(func_inst));
-        return self.funcinstances.len() - 1;
+        self.funcinstances.pin(addr);
+        self.func_id(addr)
     }
 
-    fn func_type(&self, addr: Addr) -> types::Function {
-        match &self.funcinstances[addr] {
-            runtime::FuncInstance::Internal(f) => f.functype.clone(),
-            runtime::FuncInstance::Host(f) => f.functype.clone(),
-        }
+    fn func_type(&self, func: FuncId) -> Result<types::Function, Err> {
+        Result::Ok(self.func(func)?.functype().clone())
     }
 
-    fn invoke(&mut self, addr: Addr, values: Vec<runtime::Val>) -> Result<Vec<runtime::Val>, Err> {
-        todo!()
+    // Values are only tagged here, execution itself works on untagged slots
+    fn invoke(&self, func: FuncId, values: Vec<runtime::Val>) -> Result<Vec<runtime::Val>, Err> {
+        let functype = self.func(func)?.functype();
+        if values.len() != functype.input.len()
+            || values
+                .iter()
+                .zip(&functype.input)
+                .any(|(val, valtype)| !self.val_matches(val, valtype))
+        {
+            return Result::Err(Err::InvokeArgumentsMismatch);
+        }
+        let mut args = vec![];
+        for val in values {
+            args.push(val.to_slot()?);
+        }
+        let results = vm::invoke(self, func.0.addr, &args)?;
+        Result::Ok(
+            results
+                .into_iter()
+                .zip(&functype.output)
+                .map(|(slot, valtype)| runtime::Val::from_slot(slot, *valtype, self))
+                .collect(),
+        )
     }
 
-    fn table_alloc(&mut self, tabletype: types::Table) -> Addr {
+    fn table_alloc(&mut self, tabletype: types::Table) -> TableId {
         let table_inst = RefCell::new(runtime::Table {
             tabletype,
-            elem: vec![],
+            elem: vec![runtime::Ref::Null(tabletype.reftype.heap); tabletype.limits.min],
         });
-        self.tables.push(table_inst);
-        return self.tables.len() - 1;
+        let addr = self.tables.push(table_inst);
+        self.tables.pin(addr);
+        self.table_id(addr)
     }
 
-    fn table_type(&self, addr: Addr) -> types::Table {
-        self.tables[addr].borrow().tabletype
+    fn table_type(&self, table: TableId) -> Result<types::Table, Err> {
+        Result::Ok(self.table(table)?.borrow().tabletype)
     }
 
-    fn table_read(&self, addr: Addr, index: usize) -> Result<runtime::Ref, Err> {
-        self.tables[addr]
+    fn table_read(&self, table: TableId, index: usize) -> Result<runtime::Ref, Err> {
+        self.table(table)?
             .borrow()
             .elem
             .get(index)
-            .map(|val| *val)
+            .copied()
             .ok_or(Err::OutOfBoundTableAccess)
     }
 
-    fn table_write(&mut self, addr: Addr, index: usize, value: runtime::Ref) -> Result<(), Err> {
-        if index >= self.tables[addr].borrow().elem.len() {
+    fn table_write(
+        &mut self,
+        table: TableId,
+        index: usize,
+        value: runtime::Ref,
+    ) -> Result<(), Err> {
+        let mut table = self.table(table)?.borrow_mut();
+        if index >= table.elem.len() {
             return Result::Err(Err::OutOfBoundTableAccess);
         }
-        self.tables[addr].borrow_mut().elem[index] = value;
+        self.check_ref(value, table.tabletype.reftype)?;
+        table.elem[index] = value;
         Result::Ok(())
     }
 
-    fn table_size(&self, addr: Addr) -> usize {
-        self.tables[addr].borrow().elem.len()
+    fn table_size(&self, table: TableId) -> Result<usize, Err> {
+        Result::Ok(self.table(table)?.borrow().elem.len())
     }
 
-    fn table_grow(&mut self, addr: Addr, n: types::Int, init: runtime::Ref) -> Result<(), Err> {
-        let mut table = self.tables[addr].borrow_mut();
-        match table.elem.len().checked_add(n) {
-            None => Result::Err(Err::IntegerOverflow),
-            _ => {
-                let mut newLimits = table.tabletype.limits;
-                newLimits.min += n;
-                // TODO check new limit is valid
-                // if !newLimits.is_valid(context, Option::None) {
-                //     Result::Err(Err::InvalidLimit(newLimits))
-                // } else {
-                //     table.elem.extend_from_slice(&vec![init; n]);
-                //     table.tabletype = types::Table { limits: newLimits };
-                //     Ok(())
-                // }
-                table.elem.extend_from_slice(&vec![init; n]);
-                table.tabletype = types::Table { limits: newLimits };
-                Ok(())
+    fn table_grow(&mut self, table: TableId, n: types::Int, init: runtime::Ref) -> Result<(), Err> {
+        self.check_ref(init, self.table(table)?.borrow().tabletype.reftype)?;
+        match self.grow_table(table.0.addr, n, init)? {
+            Some(_) => Result::Ok(()),
+            None => Result::Err(Err::TableLimitExceeded),
+        }
+    }
+
+    fn mem_alloc(&mut self, memtyp: types::Mem) -> Result<MemId, Err> {
+        if !memtyp.is_valid(&Context::default(), None) {
+            return Result::Err(Err::InvalidLimit(memtyp.limits));
+        }
+        let mem_inst = runtime::Mem::new(memtyp)?;
+        let addr = self.mems.push(RefCell::new(mem_inst));
+        self.mems.pin(addr);
+        Result::Ok(self.mem_id(addr))
+    }
+
+    fn mem_alloc_shared(&mut self, memory: SharedMemory) -> MemId {
+        let mem_inst = RefCell::new(runtime::Mem {
+            memtype: memory.ty(),
+            data: runtime::MemData::Shared(memory),
+        });
+        let addr = self.mems.push(mem_inst);
+        self.mems.pin(addr);
+        self.mem_id(addr)
+    }
+
+    fn mem_shared(&self, mem: MemId) -> Result<SharedMemory, Err> {
+        let mem = self.mem(mem)?.borrow();
+        mem.shared().cloned().ok_or(Err::MemoryNotShared)
+    }
+
+    fn mem_type(&self, mem: MemId) -> Result<types::Mem, Err> {
+        Result::Ok(self.mem(mem)?.borrow().memtype)
+    }
+
+    fn mem_read(&self, mem: MemId, index: types::Index) -> Result<u8, Err> {
+        self.mem_load(mem, index)
+    }
+
+    fn mem_write(&mut self, mem: MemId, index: types::Index, value: u8) -> Result<(), Err> {
+        self.mem_store(mem, index, value)
+    }
+
+    fn mem_size(&self, mem: MemId) -> Result<types::Int, Err> {
+        Result::Ok(self.mem(mem)?.borrow().size() / runtime::PAGE_SIZE)
+    }
+
+    fn mem_grow(&mut self, mem: MemId, n: types::Int) -> Result<(), Err> {
+        self.mem(mem)?;
+        match self.grow_memory(mem.0.addr, n)? {
+            Some(_) => Result::Ok(()),
+            None => Result::Err(Err::MemoryLimitExceeded),
+        }
+    }
+
+    fn mem_read_bytes(&self, mem: MemId, offset: usize, buf: &mut [u8]) -> Result<(), Err> {
+        let mem = self
+            .mem(mem)?
+            .try_borrow()
+            .map_err(|_| Err::MemoryBorrowed)?;
+        mem.read(offset, buf)
+    }
+
+    fn mem_write_bytes(&self, mem: MemId, offset: usize, bytes: &[u8]) -> Result<(), Err> {
+        let mut mem = self
+            .mem(mem)?
+            .try_borrow_mut()
+            .map_err(|_| Err::MemoryBorrowed)?;
+        mem.write(offset, bytes)
+    }
+
+    fn mem_load<T: LeBytes>(&self, mem: MemId, offset: usize) -> Result<T, Err> {
+        let mem = self
+            .mem(mem)?
+            .try_borrow()
+            .map_err(|_| Err::MemoryBorrowed)?;
+        mem.load(offset)
+    }
+
+    fn mem_store<T: LeBytes>(&self, mem: MemId, offset: usize, value: T) -> Result<(), Err> {
+        let mut mem = self
+            .mem(mem)?
+            .try_borrow_mut()
+            .map_err(|_| Err::MemoryBorrowed)?;
+        mem.store(offset, value)
+    }
+
+    fn mem_data(&self, mem: MemId) -> Result<cell::Ref<'_, [u8]>, Err> {
+        let mem = self
+            .mem(mem)?
+            .try_borrow()
+            .map_err(|_| Err::MemoryBorrowed)?;
+        cell::Ref::filter_map(mem, |mem| match &mem.data {
+            runtime::MemData::Owned(data) => Some(&data[..]),
+            runtime::MemData::Shared(_) => None,
+        })
+        .map_err(|_| Err::MemoryShared)
+    }
+
+    fn mem_data_mut(&self, mem: MemId) -> Result<cell::RefMut<'_, [u8]>, Err> {
+        let mem = self
+            .mem(mem)?
+            .try_borrow_mut()
+            .map_err(|_| Err::MemoryBorrowed)?;
+        cell::RefMut::filter_map(mem, |mem| match &mut mem.data {
+            runtime::MemData::Owned(data) => Some(&mut data[..]),
+            runtime::MemData::Shared(_) => None,
+        })
+        .map_err(|_| Err::MemoryShared)
+    }
+
+    fn global_alloc(&mut self, globtype: types::Global) -> GlobalId {
+        let glob_inst = RefCell::new(runtime::Global {
+            globaltype: globtype,
+            value: runtime::Val::default(globtype.val),
+        });
+        let addr = self.globals.push(glob_inst);
+        self.globals.pin(addr);
+        self.global_id(addr)
+    }
+
+    fn global_type(&self, global: GlobalId) -> Result<types::Global, Err> {
+        Result::Ok(self.global(global)?.borrow().globaltype)
+    }
+
+    fn global_read(&self, global: GlobalId) -> Result<runtime::Val, Err> {
+        Result::Ok(self.global(global)?.borrow().value)
+    }
+
+    fn global_write(&mut self, global: GlobalId, value: runtime::Val) -> Result<(), Err> {
+        let mut global = self.global(global)?.borrow_mut();
+        if global.globaltype.mutable == types::Mut::Const {
+            return Result::Err(Err::ImmutableGlobal);
+        }
+        match value {
+            runtime::Val::Ref(r) => {
+                let types::Value::Ref(reftype) = global.globaltype.val else {
+                    return Result::Err(Err::GlobalTypeMismatch);
+                };
+                self.check_ref(r, reftype)?;
             }
+            _ if value.valtype() != global.globaltype.val => {
+                return Result::Err(Err::GlobalTypeMismatch)
+            }
+            _ => {}
         }
+        global.value = value;
+        Result::Ok(())
+    }
+
+    fn tag_alloc(&mut self, tagtype: types::Function) -> TagId {
+        let addr = self.tags.push(runtime::TagInstance { tagtype });
+        self.tags.pin(addr);
+        self.tag_id(addr)
     }
 
-    fn mem_alloc(&mut self, memtyp: types::Mem) -> Addr {
-        todo!()
+    fn tag_type(&self, tag: TagId) -> Result<types::Function, Err> {
+        Result::Ok(self.tag(tag)?.tagtype.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::{
+        config::{Config, Feature},
+        host,
+        instr::Instr,
+        linker::Linker,
+        modules::{Export, ExportDesc, Func, Import, ImportDesc},
+        runtime::{ModuleInstance, Num, Ref, Val},
+        typed,
+    };
+
+    use super::*;
+
+    fn module(
+        types: Vec<types::Function>,
+        funcs: Vec<Func>,
+        exports: Vec<Export>,
+    ) -> modules::Module {
+        modules::Module {
+            types,
+            funcs,
+            tables: vec![],
+            mems: vec![],
+            globals: vec![],
+            tags: vec![],
+            elems: vec![],
+            datas: vec![],
+            start: None,
+            imports: vec![],
+            exports,
+        }
     }
 
-    fn mem_type(&self, addr: Addr) -> types::Mem {
-        todo!()
+    fn export_func(name: &str, idx: types::Index) -> Export {
+        Export {
+            name: name.to_string(),
+            desc: ExportDesc::Func(idx),
+        }
     }
 
-    fn mem_read(&self, addr: Addr, index: types::Index) -> Result<u8, Err> {
-        todo!()
+    fn exported_func(store: &runtime::Store, instance: InstanceId, name: &str) -> FuncId {
+        store.instance(instance).unwrap().export_func(name).unwrap()
     }
 
-    fn mem_write(&mut self, addr: Addr, index: types::Index, value: u8) -> Result<(), Err> {
-        todo!()
+    #[test]
+    fn invoke() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        // Swaps an f64 and a funcref
+        let functype = types::Function {
+            input: vec![
+                types::Value::Num(types::Number::F64),
+                types::Value::Ref(types::Ref::FUNC),
+            ],
+            output: vec![
+                types::Value::Ref(types::Ref::FUNC),
+                types::Value::Num(types::Number::F64),
+            ],
+        };
+        let module = module(
+            vec![functype],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(1), Instr::LocalGet(0)],
+            }],
+            vec![export_func("swap", 0)],
+        );
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let swap = exported_func(&store, instance, "swap");
+
+        let res = store.invoke(swap, vec![Val::Num(Num::F64(-1.5)), Val::Ref(swap.into())])?;
+        assert_eq!(res, vec![Val::Ref(swap.into()), Val::Num(Num::F64(-1.5))]);
+
+        let res = store.invoke(
+            swap,
+            vec![
+                Val::Num(Num::F64(0.)),
+                Val::Ref(Ref::Null(types::Heap::Func)),
+            ],
+        )?;
+        assert_eq!(
+            res,
+            vec![
+                Val::Ref(Ref::Null(types::Heap::Func)),
+                Val::Num(Num::F64(0.))
+            ]
+        );
+
+        let res = store.invoke(swap, vec![Val::Num(Num::F64(0.))]);
+        assert!(matches!(res, Err(Err::InvokeArgumentsMismatch)));
+        let res = store.invoke(swap, vec![Val::Num(Num::F32(0.)), Val::Ref(swap.into())]);
+        assert!(matches!(res, Err(Err::InvokeArgumentsMismatch)));
+        Ok(())
     }
 
-    fn mem_size(&self, addr: Addr) -> types::Int {
-        todo!()
+    #[test]
+    fn instantiate_and_invoke_several_modules() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        let mut linker = Linker::new();
+        let unary = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+
+        let lib = module(
+            vec![unary.clone()],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0), Instr::I32Const(1), Instr::I32Add],
+            }],
+            vec![export_func("incr", 0)],
+        );
+        let lib = linker.instantiate(&mut store, &lib)?;
+        linker.instance(&store, "lib", lib)?;
+
+        // Calls the imported function twice
+        let mut app = module(
+            vec![unary],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0), Instr::Call(0), Instr::Call(0)],
+            }],
+            vec![export_func("incr2", 1)],
+        );
+        app.imports.push(Import {
+            module: "lib".to_string(),
+            name: "incr".to_string(),
+            desc: ImportDesc::Func(0),
+        });
+        let app = linker.instantiate(&mut store, &app)?;
+
+        let incr = exported_func(&store, lib, "incr");
+        let incr2 = exported_func(&store, app, "incr2");
+        assert_eq!(
+            store.invoke(incr, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(2))]
+        );
+        assert_eq!(
+            store.invoke(incr2, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(3))]
+        );
+
+        assert!(matches!(
+            store.invoke(store.func_id(store.funcinstances.len()), vec![]),
+            Err(Err::UndefinedFunction(_))
+        ));
+        assert!(matches!(
+            store.instance(store.instance_id(2)),
+            Err(Err::UndefinedInstance(2))
+        ));
+        Ok(())
     }
 
-    fn mem_grow(&mut self, addr: Addr, n: types::Int, init: runtime::Ref) -> Result<(), Err> {
-        todo!()
+    #[test]
+    fn introspection() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        let mut linker = Linker::new();
+        let unary = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+        let limits = types::Limits { min: 1, max: None };
+
+        // Imports a function and a memory, exports them next to a function of its own
+        let mut plugin = module(
+            vec![
+                types::Function {
+                    input: vec![],
+                    output: vec![],
+                },
+                unary.clone(),
+            ],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![],
+            }],
+            vec![export_func("incr", 0), export_func("init", 1)],
+        );
+        plugin.imports.push(Import {
+            module: "env".to_string(),
+            name: "incr".to_string(),
+            desc: ImportDesc::Func(1),
+        });
+        plugin.imports.push(Import {
+            module: "env".to_string(),
+            name: "memory".to_string(),
+            desc: ImportDesc::Mem(types::Mem {
+                limits,
+                index: types::IndexType::I32,
+                shared: false,
+            }),
+        });
+        plugin.exports.push(Export {
+            name: "memory".to_string(),
+            desc: ExportDesc::Mem(0),
+        });
+
+        let imports = plugin.imports().collect::<Result<Vec<_>, Err>>()?;
+        assert_eq!((imports[0].module, imports[0].name), ("env", "incr"));
+        assert!(matches!(&imports[0].ty, types::Extern::Func(functype) if *functype == unary));
+        assert!(matches!(
+            imports[1].ty,
+            types::Extern::Mem(types::Mem {
+                limits: types::Limits { min: 1, max: None },
+                index: types::IndexType::I32,
+                shared: false
+            })
+        ));
+        let exports = plugin.exports().collect::<Result<Vec<_>, Err>>()?;
+        assert_eq!(exports.len(), 3);
+        assert!(matches!(&exports[0].ty, types::Extern::Func(functype) if *functype == unary));
+        assert!(
+            matches!(&exports[1].ty, types::Extern::Func(functype) if functype.input.is_empty())
+        );
+        assert!(matches!(
+            (exports[2].name, &exports[2].ty),
+            ("memory", types::Extern::Mem(_))
+        ));
+
+        // Indices are not validated beforehand
+        plugin.exports.push(export_func("missing", 2));
+        assert!(matches!(
+            plugin.exports().last(),
+            Some(Err(Err::InvalidIndex(2)))
+        ));
+        plugin.exports.pop();
+
+        let incr = store.func_wrap(|a: u32| a + 1);
+        let memory = store.mem_alloc(types::Mem {
+            limits,
+            index: types::IndexType::I32,
+            shared: false,
+        })?;
+        linker.define("env", "incr", runtime::ExternalVal::Fun(incr))?;
+        linker.define("env", "memory", runtime::ExternalVal::Mem(memory))?;
+        let plugin = linker.instantiate(&mut store, &plugin)?;
+        let instance = store.instance(plugin)?;
+        assert_eq!(instance.export_func("incr")?, incr);
+        assert_eq!(instance.export_mem("memory")?, memory);
+        assert!(matches!(
+            instance.export_mem("init"),
+            Err(Err::ModuleInstanceExportNotAMemory(_))
+        ));
+        assert!(matches!(
+            instance.export_global("memory"),
+            Err(Err::ModuleInstanceExportNotAGlobal(_))
+        ));
+        assert!(matches!(
+            instance.export_table("none"),
+            Err(Err::ModuleInstanceExportNotFound(_))
+        ));
+        Ok(())
     }
 
-    fn global_alloc(&mut self, globtype: types::Global) -> Addr {
-        todo!()
+    #[test]
+    fn configured_features() -> Result<(), Err> {
+        let pair = types::Function {
+            input: vec![],
+            output: vec![types::Value::Num(types::Number::I32); 2],
+        };
+        let multi_value = module(
+            vec![pair],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::I32Const(1), Instr::I32Const(2)],
+            }],
+            vec![export_func("pair", 0)],
+        );
+        let mut funcref = module(
+            vec![types::Function {
+                input: vec![],
+                output: vec![types::Value::Ref(types::Ref::FUNC)],
+            }],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::RefNull(types::Heap::Func)],
+            }],
+            vec![],
+        );
+        assert_eq!(
+            Features::mvp().missing(&multi_value.features()),
+            Some(Feature::MultiValue)
+        );
+        assert_eq!(Features::default().missing(&funcref.features()), None);
+        let limits = types::Limits { min: 0, max: None };
+        for _ in 0..2 {
+            funcref.mems.push(modules::Mem {
+                memtype: types::Mem {
+                    limits,
+                    index: types::IndexType::I32,
+                    shared: false,
+                },
+            });
+        }
+        assert_eq!(
+            Features::default().missing(&funcref.features()),
+            Some(Feature::MultiMemory)
+        );
+        assert_eq!(Features::all().missing(&funcref.features()), None);
+        // As do accesses to memories other than the first
+        let size = module(
+            vec![types::Function {
+                input: vec![],
+                output: vec![types::Value::Num(types::Number::I32)],
+            }],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::MemorySize(1)],
+            }],
+            vec![],
+        );
+        assert_eq!(
+            Features::default().missing(&size.features()),
+            Some(Feature::MultiMemory)
+        );
+
+        let mut config = Config::new();
+        config
+            .features(Features::mvp())
+            .feature(Feature::MultiValue, true)
+            .fuel(Some(10))
+            .engine(runtime::Engine::Register);
+        let mut store = runtime::Store::with_config(config);
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &funcref, vec![]),
+            Err(Err::FeatureDisabled(Feature::ReferenceTypes))
+        ));
+        let instance = ModuleInstance::instantiate(&mut store, &multi_value, vec![])?;
+        let pair = exported_func(&store, instance, "pair");
+        assert_eq!(
+            store.invoke(pair, vec![])?,
+            vec![Val::Num(Num::I32(1)), Val::Num(Num::I32(2))]
+        );
+        assert_eq!(store.fuel_remaining(), Some(8));
+        let typed = typed::get_typed_func::<(), (u32, u32)>(&store, instance, "pair")?;
+        assert_eq!(typed.call(())?, (1, 2));
+        Ok(())
     }
 
-    fn global_type(&self, addr: Addr) -> types::Global {
-        todo!()
+    #[test]
+    fn handles_from_other_store() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        let mut other: runtime::Store = Store::new();
+        let incr = other.func_wrap(|a: u32| a + 1);
+        let memory = other.mem_alloc(types::Mem {
+            limits: types::Limits { min: 1, max: None },
+            index: types::IndexType::I32,
+            shared: false,
+        })?;
+        store.func_wrap(|a: u32| a + 1);
+        store.mem_alloc(types::Mem {
+            limits: types::Limits { min: 1, max: None },
+            index: types::IndexType::I32,
+            shared: false,
+        })?;
+
+        // The same addresses are defined in both stores
+        assert!(matches!(
+            store.invoke(incr, vec![Val::Num(Num::I32(1))]),
+            Err(Err::HandleFromOtherStore(id)) if id == other.id()
+        ));
+        assert!(matches!(
+            store.mem_type(memory),
+            Err(Err::HandleFromOtherStore(_))
+        ));
+        assert_eq!(
+            other.invoke(incr, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(2))]
+        );
+
+        let mut app = module(vec![], vec![], vec![]);
+        app.imports.push(Import {
+            module: "env".to_string(),
+            name: "memory".to_string(),
+            desc: ImportDesc::Mem(types::Mem {
+                limits: types::Limits { min: 1, max: None },
+                index: types::IndexType::I32,
+                shared: false,
+            }),
+        });
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &app, vec![runtime::ExternalVal::Mem(memory)]),
+            Err(Err::HandleFromOtherStore(_))
+        ));
+        assert!(ModuleInstance::instantiate(
+            &mut other,
+            &app,
+            vec![runtime::ExternalVal::Mem(memory)]
+        )
+        .is_ok());
+        Ok(())
     }
 
-    fn global_read(&self, addr: Addr) -> Result<runtime::Val, Err> {
-        todo!()
+    #[test]
+    fn remove_instances() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        let mut linker = Linker::new();
+        let unary = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+        let double = store.func_wrap(|a: u32| a * 2);
+        linker.define("env", "double", runtime::ExternalVal::Fun(double))?;
+
+        let mut lib = module(
+            vec![unary.clone()],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![
+                    Instr::LocalGet(0),
+                    Instr::Call(0),
+                    Instr::I32Const(1),
+                    Instr::I32Add,
+                ],
+            }],
+            vec![export_func("incr", 1)],
+        );
+        lib.imports.push(Import {
+            module: "env".to_string(),
+            name: "double".to_string(),
+            desc: ImportDesc::Func(0),
+        });
+        let mut app = module(
+            vec![unary],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0), Instr::Call(0), Instr::Call(0)],
+            }],
+            vec![export_func("incr2", 1)],
+        );
+        app.imports.push(Import {
+            module: "lib".to_string(),
+            name: "incr".to_string(),
+            desc: ImportDesc::Func(0),
+        });
+
+        let lib_id = linker.instantiate(&mut store, &lib)?;
+        let incr = exported_func(&store, lib_id, "incr");
+        let mut linker_app = Linker::new();
+        linker_app.instance(&store, "lib", lib_id)?;
+        let app_id = linker_app.instantiate(&mut store, &app)?;
+        let incr2 = exported_func(&store, app_id, "incr2");
+
+        // The function lib exports to app outlives lib
+        store.remove_instance(lib_id)?;
+        assert!(matches!(store.instance(lib_id), Err(Err::StaleHandle(_))));
+        assert!(matches!(
+            store.remove_instance(lib_id),
+            Err(Err::StaleHandle(_))
+        ));
+        assert_eq!(
+            store.invoke(incr2, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(7))]
+        );
+        assert_eq!(
+            store.invoke(incr, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(3))]
+        );
+        assert_eq!(store.funcinstances.live(), 3);
+
+        // Only the host function allocated by the embedder is left
+        store.remove_instance(app_id)?;
+        assert!(matches!(
+            store.invoke(incr, vec![]),
+            Err(Err::StaleHandle(_))
+        ));
+        assert!(matches!(
+            store.invoke(incr2, vec![]),
+            Err(Err::StaleHandle(_))
+        ));
+        assert_eq!(store.modules.live(), 0);
+        assert_eq!(store.funcinstances.live(), 1);
+        assert_eq!(
+            store.invoke(double, vec![Val::Num(Num::I32(4))])?,
+            vec![Val::Num(Num::I32(8))]
+        );
+
+        // Instantiating a module per request reuses the reclaimed slots
+        for i in 0..10 {
+            let lib_id = linker.instantiate(&mut store, &lib)?;
+            let incr = exported_func(&store, lib_id, "incr");
+            assert_eq!(
+                store.invoke(incr, vec![Val::Num(Num::I32(i))])?,
+                vec![Val::Num(Num::I32(2 * i + 1))]
+            );
+            store.remove_instance(lib_id)?;
+        }
+        assert_eq!(store.modules.len(), 2);
+        assert_eq!(store.funcinstances.len(), 3);
+        Ok(())
     }
 
-    fn global_write(&mut self, addr: Addr, value: runtime::Val) -> Result<(), Err> {
-        todo!()
+    #[test]
+    fn stale_function_references() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        let unary = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+        let incr = |by: u32| {
+            module(
+                vec![unary.clone()],
+                vec![Func {
+                    functype: 0,
+                    locals: vec![],
+                    body: vec![Instr::LocalGet(0), Instr::I32Const(by), Instr::I32Add],
+                }],
+                vec![export_func("incr", 0)],
+            )
+        };
+        let is_null = store.func_wrap(|func: typed::FuncRef| func.is_null() as u32);
+        let table = store.table_alloc(types::Table {
+            limits: types::Limits { min: 1, max: None },
+            reftype: types::Ref::FUNC,
+        });
+        let global = store.global_alloc(types::Global {
+            mutable: types::Mut::Var,
+            val: types::Value::Ref(types::Ref::FUNC),
+        });
+
+        let old = ModuleInstance::instantiate(&mut store, &incr(1), vec![])?;
+        let held = Ref::from(exported_func(&store, old, "incr"));
+        store.remove_instance(old)?;
+        store.collect();
+
+        // The function instantiated next reuses the slot of the one the reference was taken from
+        let new = ModuleInstance::instantiate(&mut store, &incr(2), vec![])?;
+        let reused = exported_func(&store, new, "incr");
+        let (Ref::Func(stale), Ref::Func(live)) = (held, Ref::from(reused)) else {
+            unreachable!()
+        };
+        assert_eq!(stale.addr, live.addr);
+
+        assert!(matches!(
+            store.invoke(is_null, vec![Val::Ref(held)]),
+            Err(Err::InvokeArgumentsMismatch)
+        ));
+        assert!(matches!(
+            store.table_write(table, 0, held),
+            Err(Err::StaleHandle(_))
+        ));
+        assert!(matches!(
+            store.table_grow(table, 1, held),
+            Err(Err::StaleHandle(_))
+        ));
+        assert!(matches!(
+            store.global_write(global, Val::Ref(held)),
+            Err(Err::StaleHandle(_))
+        ));
+        assert!(matches!(
+            store.invoke(FuncId(stale), vec![Val::Num(Num::I32(1))]),
+            Err(Err::StaleHandle(_))
+        ));
+        assert_eq!(
+            store.invoke(reused, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(3))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn limited_instantiation() -> Result<(), Err> {
+        let mut store =
+            runtime::Store::with_config(*Config::new().feature(Feature::MultiMemory, true));
+        store.set_limiter(runtime::StoreLimits {
+            memory_size: Some(2 * runtime::PAGE_SIZE),
+            instances: 2,
+            memories: 2,
+            ..Default::default()
+        });
+        let memory = |min| modules::Mem {
+            memtype: types::Mem {
+                limits: types::Limits { min, max: None },
+                index: types::IndexType::I32,
+                shared: false,
+            },
+        };
+        let table = || modules::Table {
+            tabletype: types::Table {
+                limits: types::Limits { min: 4, max: None },
+                reftype: types::Ref::FUNC,
+            },
+        };
+
+        let mut lib = module(vec![], vec![], vec![]);
+        lib.mems.push(memory(1));
+        lib.tables.push(table());
+        let lib = ModuleInstance::instantiate(&mut store, &lib, vec![])?;
+        let usage = store.usage();
+        assert_eq!(usage.instances, 1);
+        assert_eq!(usage.memory_bytes, runtime::PAGE_SIZE);
+        assert_eq!(usage.table_bytes, 4 * core::mem::size_of::<Ref>());
+
+        // A failed instantiation adds nothing to the store
+        let mut large = module(
+            vec![types::Function {
+                input: vec![],
+                output: vec![],
+            }],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![],
+            }],
+            vec![],
+        );
+        large.tables.push(table());
+        large.mems.push(memory(3));
+        for _ in 0..3 {
+            assert!(matches!(
+                ModuleInstance::instantiate(&mut store, &large, vec![]),
+                Err(Err::MemoryLimitExceeded)
+            ));
+        }
+        assert_eq!(store.funcinstances.live(), 0);
+        let usage = store.usage();
+        assert_eq!((usage.memories, usage.tables), (1, 1));
+        assert_eq!(usage.table_bytes, 4 * core::mem::size_of::<Ref>());
+        let mut two = module(vec![], vec![], vec![]);
+        two.mems.push(memory(1));
+        two.mems.push(memory(1));
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &two, vec![]),
+            Err(Err::MemoryLimitExceeded)
+        ));
+
+        ModuleInstance::instantiate(&mut store, &module(vec![], vec![], vec![]), vec![])?;
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &module(vec![], vec![], vec![]), vec![]),
+            Err(Err::InstanceLimitExceeded)
+        ));
+
+        // Removing an instance gives its resources back
+        store.remove_instance(lib)?;
+        assert_eq!(store.usage().memory_bytes, 0);
+        ModuleInstance::instantiate(&mut store, &two, vec![])?;
+        assert_eq!(store.usage().memory_bytes, 2 * runtime::PAGE_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn limited_shared_memory() -> Result<(), Err> {
+        let mut store = runtime::Store::with_config(*Config::new().feature(Feature::Threads, true));
+        store.set_limiter(runtime::StoreLimits {
+            memory_size: Some(2 * runtime::PAGE_SIZE),
+            ..Default::default()
+        });
+        let shared = |max| {
+            let mut module = module(vec![], vec![], vec![]);
+            module.mems.push(modules::Mem {
+                memtype: types::Mem {
+                    limits: types::Limits {
+                        min: 1,
+                        max: Some(max),
+                    },
+                    index: types::IndexType::I32,
+                    shared: true,
+                },
+            });
+            module
+        };
+
+        // The whole reservation is charged, not only the initial size
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &shared(4), vec![]),
+            Err(Err::MemoryLimitExceeded)
+        ));
+        assert_eq!(store.usage().memories, 0);
+        let instance = ModuleInstance::instantiate(&mut store, &shared(2), vec![])?;
+        assert_eq!(store.usage().memory_bytes, 2 * runtime::PAGE_SIZE);
+        let mem = store.instance(instance)?.mems[0];
+        assert_eq!(store.mem_at(mem)?.size(), runtime::PAGE_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn host_memory_access() -> Result<(), Err> {
+        let mut store: runtime::Store = Store::new();
+        let memory = store.mem_alloc(types::Mem {
+            limits: types::Limits { min: 1, max: None },
+            index: types::IndexType::I32,
+            shared: false,
+        })?;
+        let end = runtime::PAGE_SIZE;
+
+        // Invalid types are rejected rather than allocated
+        let invalid = |min, max, shared| types::Mem {
+            limits: types::Limits { min, max },
+            index: types::IndexType::I32,
+            shared,
+        };
+        for memtype in [invalid(70000, None, false), invalid(1, None, true)] {
+            assert!(matches!(
+                store.mem_alloc(memtype),
+                Err(Err::InvalidLimit(_))
+            ));
+        }
+
+        store.mem_write_bytes(memory, 16, b"hello")?;
+        store.mem_store(memory, 32, 0xdead_beefu32)?;
+        store.mem_store(memory, end - 8, 0.25f64)?;
+        let mut buf = [0; 5];
+        store.mem_read_bytes(memory, 16, &mut buf)?;
+        assert_eq!(&buf, b"hello");
+        assert_eq!(store.mem_load::<u16>(memory, 34)?, 0xdead);
+        assert_eq!(store.mem_load::<f64>(memory, end - 8)?, 0.25);
+        assert_eq!(store.mem_read(memory, 32)?, 0xef);
+
+        assert!(matches!(
+            store.mem_write_bytes(memory, end - 2, b"abc"),
+            Err(Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            store.mem_load::<u64>(memory, end - 4),
+            Err(Err::OutOfBoundMemoryAccess)
+        ));
+
+        // Views are checked against the current size and lock the memory while held
+        {
+            let data = store.mem_data(memory)?;
+            assert_eq!(data.len(), end);
+            assert_eq!(&data[16..21], b"hello");
+            assert!(matches!(
+                store.mem_store(memory, 0, 1u8),
+                Err(Err::MemoryBorrowed)
+            ));
+        }
+        store.mem_data_mut(memory)?[16..21].copy_from_slice(b"HELLO");
+        store.mem_grow(memory, 1)?;
+        store.mem_store(memory, end, 7u8)?;
+        assert_eq!(store.mem_data(memory)?.len(), 2 * end);
+
+        // Host functions access memory through their caller
+        let checksum = store.func_wrap(move |caller: host::Caller, ptr: u32, len: u32| {
+            let mut bytes = vec![0; len as usize];
+            caller
+                .store()
+                .mem_read_bytes(memory, ptr as usize, &mut bytes)?;
+            Ok(bytes.iter().map(|b| *b as u32).sum::<u32>())
+        });
+        assert_eq!(
+            store.invoke(
+                checksum,
+                vec![Val::Num(Num::I32(16)), Val::Num(Num::I32(5))]
+            )?,
+            vec![Val::Num(Num::I32(b"HELLO".iter().map(|b| *b as u32).sum()))]
+        );
+        assert!(matches!(
+            store.invoke(
+                checksum,
+                vec![Val::Num(Num::I32(end as u32 * 2)), Val::Num(Num::I32(1))]
+            ),
+            Err(Err::TrapHost(_))
+        ));
+
+        // Guest code cannot grow a memory the embedder holds a view of
+        let i32 = types::Value::Num(types::Number::I32);
+        let mut app = module(
+            vec![types::Function {
+                input: vec![i32],
+                output: vec![i32],
+            }],
+            vec![Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0), Instr::MemoryGrow(0)],
+            }],
+            vec![export_func("grow", 0)],
+        );
+        app.imports.push(Import {
+            module: "env".to_string(),
+            name: "memory".to_string(),
+            desc: ImportDesc::Mem(types::Mem {
+                limits: types::Limits { min: 1, max: None },
+                index: types::IndexType::I32,
+                shared: false,
+            }),
+        });
+        let instance =
+            ModuleInstance::instantiate(&mut store, &app, vec![runtime::ExternalVal::Mem(memory)])?;
+        let grow = exported_func(&store, instance, "grow");
+        {
+            let _data = store.mem_data(memory)?;
+            assert!(matches!(
+                store.invoke(grow, vec![Val::Num(Num::I32(1))]),
+                Err(Err::MemoryBorrowed)
+            ));
+        }
+        assert_eq!(
+            store.invoke(grow, vec![Val::Num(Num::I32(1))])?,
+            vec![Val::Num(Num::I32(2))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn exceptions_are_freed() -> Result<(), Err> {
+        let source = r#"
+            (module
+              (tag $e)
+              (global $kept (mut exnref) (ref.null exn))
+              (func $catch (result exnref)
+                block $h (result exnref)
+                  try_table (catch_all_ref $h)
+                    throw $e
+                  end
+                  unreachable
+                end)
+              (func (export "make") (result exnref)
+                call $catch)
+              (func (export "keep")
+                call $catch
+                global.set $kept)
+              (func (export "kept") (result exnref)
+                global.get $kept))
+        "#;
+        let features = *Features::default().set(Feature::ExceptionHandling, true);
+        let module = modules::Module::parse(source, &features)?;
+        let mut store = runtime::Store::with_config(*Config::new().features(features));
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let call = |name| store.invoke(exported_func(&store, instance, name), vec![]);
+
+        // Returned exceptions outlive the call, until the next one returns
+        let made = call("make")?;
+        let Val::Ref(made) = made[0] else {
+            panic!("not a reference")
+        };
+        assert!(store.is_live(&made));
+        call("keep")?;
+        assert!(!store.is_live(&made));
+        let Ref::Exn(freed) = made else {
+            panic!("not an exception")
+        };
+        assert!(matches!(store.exn(freed.addr), Err(Err::UndefinedExn(_))));
+        // Those stored in globals are kept
+        let kept = call("kept")?;
+        let Val::Ref(kept) = kept[0] else {
+            panic!("not a reference")
+        };
+        call("make")?;
+        assert!(store.is_live(&kept));
+        Ok(())
+    }
+
+    #[test]
+    fn exceptions() -> Result<(), Err> {
+        use crate::{instr::BlockType, instr::Catch, runtime::Exception, vm::Trap};
+
+        let i32 = types::Value::Num(types::Number::I32);
+        let mut app = module(
+            vec![
+                types::Function {
+                    input: vec![i32],
+                    output: vec![],
+                },
+                types::Function {
+                    input: vec![i32],
+                    output: vec![i32],
+                },
+            ],
+            vec![
+                // throw: raises the module's own tag
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![Instr::LocalGet(0), Instr::Throw(1)],
+                },
+                // catch: unwinds a call frame and the operands left inside the try
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![
+                        Instr::I32Const(1000),
+                        Instr::Block(BlockType::Value(i32)),
+                        Instr::TryTable(BlockType::Value(i32)),
+                        Instr::Catch(Catch::Tag(1, 0)),
+                        Instr::I32Const(3),
+                        Instr::LocalGet(0),
+                        Instr::Call(1),
+                        Instr::I32Add,
+                        Instr::End,
+                        Instr::I32Const(7),
+                        Instr::I32Add,
+                        Instr::End,
+                        Instr::I32Add,
+                    ],
+                },
+                // host: catches what the imported host function throws
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![
+                        Instr::Block(BlockType::Value(i32)),
+                        Instr::TryTable(BlockType::Value(i32)),
+                        Instr::Catch(Catch::Tag(0, 0)),
+                        Instr::LocalGet(0),
+                        Instr::Call(0),
+                        Instr::End,
+                        Instr::End,
+                    ],
+                },
+                // rethrow: catches anything and throws it again
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![
+                        Instr::Block(BlockType::Value(types::Value::Ref(types::Ref::EXN))),
+                        Instr::TryTable(BlockType::Empty),
+                        Instr::Catch(Catch::AllRef(0)),
+                        Instr::LocalGet(0),
+                        Instr::Throw(1),
+                        Instr::End,
+                        Instr::Unreachable,
+                        Instr::End,
+                        Instr::ThrowRef,
+                    ],
+                },
+                // null: throw_ref traps on a null exnref
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![Instr::RefNull(types::Heap::Exn), Instr::ThrowRef],
+                },
+            ],
+            vec![
+                export_func("throw", 1),
+                export_func("catch", 2),
+                export_func("host", 3),
+                export_func("rethrow", 4),
+                export_func("null", 5),
+                Export {
+                    name: "oops".to_string(),
+                    desc: ExportDesc::Tag(1),
+                },
+            ],
+        );
+        app.tags = vec![modules::Tag { tagtype: 0 }];
+        app.imports = vec![
+            Import {
+                module: "env".to_string(),
+                name: "err".to_string(),
+                desc: ImportDesc::Tag(0),
+            },
+            Import {
+                module: "env".to_string(),
+                name: "fail".to_string(),
+                desc: ImportDesc::Func(1),
+            },
+        ];
+
+        let mut store: runtime::Store = Store::new();
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &app, vec![]),
+            Err(Err::FeatureDisabled(Feature::ExceptionHandling))
+        ));
+
+        for engine in [runtime::Engine::Stack, runtime::Engine::Register] {
+            let mut store = runtime::Store::with_config(
+                *Config::new()
+                    .feature(Feature::ExceptionHandling, true)
+                    .engine(engine),
+            );
+            let err = store.tag_alloc(types::Function {
+                input: vec![i32],
+                output: vec![],
+            });
+            let fail = store.func_wrap(move |a: u32| -> Result<u32, Trap> {
+                Err(Trap::throw(Exception::new(
+                    err,
+                    vec![Val::Num(Num::I32(a))],
+                )))
+            });
+            let instance = ModuleInstance::instantiate(
+                &mut store,
+                &app,
+                vec![
+                    runtime::ExternalVal::Tag(err),
+                    runtime::ExternalVal::Fun(fail),
+                ],
+            )?;
+            let oops = store.instance(instance)?.export_tag("oops")?;
+            let arg = vec![Val::Num(Num::I32(42))];
+            let uncaught = |res: Result<Vec<Val>, Err>, tag| match res {
+                Err(Err::UncaughtException(exception)) => {
+                    exception == Exception::new(tag, vec![Val::Num(Num::I32(42))])
+                }
+                _ => false,
+            };
+
+            let catch = exported_func(&store, instance, "catch");
+            assert_eq!(
+                store.invoke(catch, arg.clone())?,
+                vec![Val::Num(Num::I32(1042))]
+            );
+            let host = exported_func(&store, instance, "host");
+            assert_eq!(store.invoke(host, arg.clone())?, arg);
+
+            let throw = exported_func(&store, instance, "throw");
+            assert!(uncaught(store.invoke(throw, arg.clone()), oops));
+            let rethrow = exported_func(&store, instance, "rethrow");
+            assert!(uncaught(store.invoke(rethrow, arg.clone()), oops));
+            assert!(uncaught(store.invoke(fail, arg.clone()), err));
+            let null = exported_func(&store, instance, "null");
+            assert!(matches!(
+                store.invoke(null, arg),
+                Err(Err::TrapNullReference)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn function_references() -> Result<(), Err> {
+        use crate::instr::BlockType;
+
+        let i32 = types::Value::Num(types::Number::I32);
+        let unary = types::Ref::non_null(types::Heap::Type(0));
+        let app = module(
+            vec![
+                types::Function {
+                    input: vec![i32],
+                    output: vec![i32],
+                },
+                types::Function {
+                    input: vec![
+                        types::Value::Ref(types::Ref::null(types::Heap::Type(0))),
+                        i32,
+                    ],
+                    output: vec![i32],
+                },
+            ],
+            vec![
+                // double
+                Func {
+                    functype: 0,
+                    locals: vec![],
+                    body: vec![Instr::LocalGet(0), Instr::LocalGet(0), Instr::I32Add],
+                },
+                // apply: calls the function if any, or returns -1
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![
+                        Instr::LocalGet(1),
+                        Instr::Block(BlockType::Value(types::Value::Ref(unary))),
+                        Instr::LocalGet(0),
+                        Instr::BrOnNonNull(0),
+                        Instr::I32Const(-1i32 as u32),
+                        Instr::Return,
+                        Instr::End,
+                        Instr::CallRef(0),
+                    ],
+                },
+                // tail: calls the function in place of the caller, trapping on null
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![
+                        Instr::LocalGet(1),
+                        Instr::LocalGet(0),
+                        Instr::RefAsNonNull,
+                        Instr::ReturnCallRef(0),
+                    ],
+                },
+                // is_null
+                Func {
+                    functype: 1,
+                    locals: vec![types::Value::Ref(unary)],
+                    body: vec![
+                        Instr::Block(BlockType::Value(i32)),
+                        Instr::I32Const(1),
+                        Instr::LocalGet(0),
+                        Instr::BrOnNull(0),
+                        Instr::LocalSet(2),
+                        Instr::I32Const(1),
+                        Instr::I32Sub,
+                        Instr::End,
+                    ],
+                },
+                // local: doubles through a non-nullable local
+                Func {
+                    functype: 0,
+                    locals: vec![types::Value::Ref(unary)],
+                    body: vec![
+                        Instr::RefFunc(0),
+                        Instr::LocalSet(1),
+                        Instr::LocalGet(0),
+                        Instr::LocalGet(1),
+                        Instr::CallRef(0),
+                    ],
+                },
+            ],
+            vec![
+                export_func("double", 0),
+                export_func("apply", 1),
+                export_func("tail", 2),
+                export_func("is_null", 3),
+                export_func("local", 4),
+            ],
+        );
+        // The callee of `call_ref` must have the type it is called with
+        let mistyped = module(
+            app.types.clone(),
+            vec![
+                app.funcs[1].clone(),
+                Func {
+                    functype: 0,
+                    locals: vec![],
+                    body: vec![Instr::LocalGet(0), Instr::RefFunc(0), Instr::CallRef(0)],
+                },
+            ],
+            vec![],
+        );
+        assert!(matches!(
+            mistyped.validate(&Features::all()),
+            Err(Err::InvalidCode)
+        ));
+
+        let mut store: runtime::Store = Store::new();
+        assert!(matches!(
+            ModuleInstance::instantiate(&mut store, &app, vec![]),
+            Err(Err::FeatureDisabled(Feature::FunctionReferences))
+        ));
+
+        for engine in [runtime::Engine::Stack, runtime::Engine::Register] {
+            let mut store = runtime::Store::with_config(
+                *Config::new()
+                    .feature(Feature::FunctionReferences, true)
+                    .feature(Feature::TailCall, true)
+                    .engine(engine),
+            );
+            let instance = ModuleInstance::instantiate(&mut store, &app, vec![])?;
+            let double = exported_func(&store, instance, "double");
+            let apply = exported_func(&store, instance, "apply");
+            let args = |func: Ref| vec![Val::Ref(func), Val::Num(Num::I32(21))];
+            let (func, null) = (Ref::from(double), Ref::Null(types::Heap::Func));
+            let dangling = Ref::Func(runtime::Handle {
+                store: store.id(),
+                generation: 0,
+                addr: 12345,
+            });
+            let i32 = |val: i32| vec![Val::Num(Num::I32(val as u32))];
+
+            assert_eq!(store.invoke(apply, args(func))?, i32(42));
+            assert_eq!(store.invoke(apply, args(null))?, i32(-1));
+            let tail = exported_func(&store, instance, "tail");
+            assert_eq!(store.invoke(tail, args(func))?, i32(42));
+            assert!(matches!(
+                store.invoke(tail, args(null)),
+                Err(Err::TrapNullReference)
+            ));
+            let is_null = exported_func(&store, instance, "is_null");
+            assert_eq!(store.invoke(is_null, args(func))?, i32(0));
+            assert_eq!(store.invoke(is_null, args(null))?, i32(1));
+            let local = exported_func(&store, instance, "local");
+            assert_eq!(store.invoke(local, i32(4))?, i32(8));
+
+            // Function references are checked against their type
+            assert!(matches!(
+                store.invoke(apply, args(apply.into())),
+                Err(Err::InvokeArgumentsMismatch)
+            ));
+            // and must refer to a live function
+            assert!(matches!(
+                store.invoke(apply, args(dangling)),
+                Err(Err::InvokeArgumentsMismatch)
+            ));
+            let table = store.table_alloc(types::Table {
+                limits: types::Limits { min: 1, max: None },
+                reftype: types::Ref::FUNC,
+            });
+            assert!(matches!(
+                store.table_write(table, 0, dangling),
+                Err(Err::StaleHandle(12345))
+            ));
+            assert!(matches!(
+                store.table_write(table, 0, Ref::Null(types::Heap::Extern)),
+                Err(Err::RefTypeMismatch)
+            ));
+            assert!(matches!(
+                store.table_grow(table, 1, dangling),
+                Err(Err::StaleHandle(12345))
+            ));
+            store.table_write(table, 0, func)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn globals() -> Result<(), Err> {
+        use core::cell::Cell;
+
+        let source = r#"
+            (module
+              (global (export "func") funcref (ref.null func))
+              (global $extern (export "extern") (mut externref) (ref.null extern))
+              (global $base i32 (i32.const 40))
+              (global (export "sum") i32 (i32.add (global.get $base) (i32.const 2)))
+              (global (export "get_ref") funcref (ref.func $get))
+              (func $get (export "get") (result externref)
+                global.get $extern))
+        "#;
+        let features = Features::all();
+        let module = modules::Module::parse(source, &features)?;
+        let mut store = runtime::Store::with_config(*Config::new().features(features));
+        let instance = ModuleInstance::instantiate(&mut store, &module, vec![])?;
+        let global = |name| store.instance(instance)?.export_global(name);
+        let (func, extern_, sum) = (global("func")?, global("extern")?, global("sum")?);
+        let get = exported_func(&store, instance, "get");
+
+        // Initializers are evaluated, reference globals defaulting to null of their type
+        let null_func = Val::Ref(Ref::Null(types::Heap::Func));
+        assert_eq!(store.global_read(func)?, null_func);
+        let null_extern = Val::Ref(Ref::Null(types::Heap::Extern));
+        assert_eq!(store.global_read(extern_)?, null_extern);
+        assert_eq!(store.global_read(sum)?, Val::Num(Num::I32(42)));
+        let get_ref = store.global_read(global("get_ref")?)?;
+        assert_eq!(get_ref, Val::Ref(get.into()));
+
+        // Extern references stored by the host are read back by code, and kept alive
+        let r = store.extern_new(Cell::new(7u32));
+        store.global_write(extern_, r.into())?;
+        assert_eq!(store.global_read(extern_)?, r.into());
+        store.extern_release(r);
+        store.collect();
+        let read = typed::TypedFunc::<(), typed::ExternRef>::new(&store, get)?.call(())?;
+        assert_eq!(store.extern_data::<Cell<u32>>(read).unwrap().get(), 7);
+
+        // Values must match the type of mutable globals
+        assert!(matches!(
+            store.global_write(sum, Val::Num(Num::I32(0))),
+            Err(Err::ImmutableGlobal)
+        ));
+        assert!(matches!(
+            store.global_write(extern_, Val::Num(Num::I32(0))),
+            Err(Err::GlobalTypeMismatch)
+        ));
+        assert!(matches!(
+            store.global_write(extern_, null_func),
+            Err(Err::RefTypeMismatch)
+        ));
+        Ok(())
     }
 }
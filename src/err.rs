@@ -1,23 +1,72 @@
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 
-use crate::types::{self, Addr};
+use crate::{
+    config::Feature,
+    linker::UnresolvedImport,
+    runtime,
+    types::{self, Addr},
+};
 
 #[derive(Debug)]
 pub enum Err {
     ModuleDecode,
     ModuleParse,
+    InvalidCode,
+    FeatureDisabled(Feature),
     ModuleInstanceExportNotFound(String),
+    ModuleInstanceExportNotAFunction(String),
+    ModuleInstanceExportNotATable(String),
+    ModuleInstanceExportNotAMemory(String),
+    ModuleInstanceExportNotAGlobal(String),
+    ModuleInstanceExportNotATag(String),
+    InvalidIndex(types::Index),
     OutOfBoundTableAccess,
+    OutOfBoundMemoryAccess,
+    MemoryBorrowed,
+    MemoryShared,
+    MemoryNotShared,
+    InvalidUtf8,
+    InvalidUtf16,
     TrapUnreachable,
+    TrapOutOfFuel,
+    TrapCallStackExhausted,
+    TrapUndefinedElement,
+    TrapUninitializedElement,
+    TrapIndirectCallTypeMismatch,
+    TrapUnalignedAtomic,
+    TrapExpectedSharedMemory,
+    TrapAtomicWaitUnsupported,
+    TrapNullReference,
+    TrapHost(String),
+    UncaughtException(runtime::Exception),
     InvariantViolatedAllResultsAreValues,
     AssertFailedEnoughVauesToReturn,
     AssertFailedFrameOnTopOfStack,
+    AssertFailedLabelOnStack,
     AssertFailedFuncInstanceExists,
     AssertFailedEnoughStackValuesForFunctionCall,
+    UndefinedInstance(Addr),
+    HandleFromOtherStore(runtime::StoreId),
+    StaleHandle(Addr),
+    InstanceLimitExceeded,
+    MemoryLimitExceeded,
+    TableLimitExceeded,
     UndefinedFunction(Addr),
     UndefinedGlobal(Addr),
     UndefinedMem(Addr),
+    UndefinedData(Addr),
     UndefinedTable(Addr),
+    UndefinedTag(Addr),
+    UndefinedExn(Addr),
     IntegerOverflow,
     InvalidLimit(types::Limits),
+    UnsupportedValueType(types::Value),
+    InvokeArgumentsMismatch,
+    FuncTypeMismatch,
+    RefTypeMismatch,
+    GlobalTypeMismatch,
+    ImmutableGlobal,
+    ExternalValCountMismatch,
+    UnresolvedImports(Vec<UnresolvedImport>),
+    LinkerDuplicateDefinition(String, String),
 }
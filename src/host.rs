@@ -0,0 +1,330 @@
+use core::marker::PhantomData;
+
+use alloc::vec::Vec;
+
+use crate::{
+    embedding::Instanciable,
+    err,
+    modules::HostFunc,
+    runtime::{ExternalVal, InstanceId, Slot, Store},
+    typed::WasmValType,
+    types,
+    vm::Trap,
+};
+
+// The context a host function is called in
+pub struct Caller<'c> {
+    store: &'c Store,
+    instance: Option<InstanceId>,
+}
+
+impl<'c> Caller<'c> {
+    pub(crate) fn new(store: &'c Store, instance: Option<InstanceId>) -> Self {
+        Caller { store, instance }
+    }
+
+    pub fn store(&self) -> &'c Store {
+        self.store
+    }
+
+    // The instance of the calling function, `None` when called directly by the embedder
+    pub fn instance(&self) -> Option<InstanceId> {
+        self.instance
+    }
+
+    pub fn get_export(&self, name: &str) -> Option<ExternalVal> {
+        self.store.instance(self.instance?).ok()?.export(name).ok()
+    }
+}
+
+// The results of a host function, a single `WasmValType` or a tuple of them
+pub trait HostResults {
+    fn valtypes() -> Vec<types::Value>;
+    fn push_slots(self, slots: &mut Vec<Slot>, store: &Store) -> Result<(), err::Err>;
+}
+
+impl<T: WasmValType> HostResults for T {
+    fn valtypes() -> Vec<types::Value> {
+        vec![T::VALTYPE]
+    }
+    fn push_slots(self, slots: &mut Vec<Slot>, store: &Store) -> Result<(), err::Err> {
+        slots.push(self.into_slot(store)?);
+        Result::Ok(())
+    }
+}
+
+// What a host function returns, its results or a trap
+pub trait HostReturn {
+    type Results: HostResults;
+    fn into_results(self) -> Result<Self::Results, Trap>;
+}
+
+impl<T: HostResults> HostReturn for T {
+    type Results = T;
+    fn into_results(self) -> Result<T, Trap> {
+        Result::Ok(self)
+    }
+}
+
+impl<T: HostResults> HostReturn for Result<T, Trap> {
+    type Results = T;
+    fn into_results(self) -> Result<T, Trap> {
+        self
+    }
+}
+
+// Marks the parameters of host functions taking a `Caller` first
+pub struct WithCaller<Params>(PhantomData<Params>);
+
+// Rust functions that can be allocated as host functions. `Params` is the tuple of their
+// parameter types, and `Results` their return type.
+pub trait IntoHostFunc<Params, Results> {
+    fn into_host_func(self) -> (types::Function, HostFunc);
+}
+
+macro_rules! impl_host_func {
+    ($len:expr; $($A:ident $a:ident),*) => {
+        impl<$($A: WasmValType),*> HostResults for ($($A,)*) {
+            fn valtypes() -> Vec<types::Value> {
+                vec![$($A::VALTYPE),*]
+            }
+            #[allow(unused_variables)]
+            fn push_slots(self, slots: &mut Vec<Slot>, store: &Store) -> Result<(), err::Err> {
+                let ($($a,)*) = self;
+                $(slots.push($a.into_slot(store)?);)*
+                Result::Ok(())
+            }
+        }
+
+        impl<F, $($A: WasmValType,)* R: HostReturn> IntoHostFunc<($($A,)*), R> for F
+        where
+            F: Fn($($A),*) -> R + 'static,
+        {
+            fn into_host_func(self) -> (types::Function, HostFunc) {
+                let functype = types::Function {
+                    input: vec![$($A::VALTYPE),*],
+                    output: R::Results::valtypes(),
+                };
+                let hostfunc = HostFunc::new(move |caller, slots| {
+                    let store = caller.store();
+                    #[allow(unused_mut, unused_variables)]
+                    let mut args = slots.drain(slots.len() - $len..);
+                    $(let $a = $A::from_slot(args.next().unwrap(), $A::VALTYPE, store);)*
+                    drop(args);
+                    self($($a),*).into_results()?.push_slots(slots, store)?;
+                    Result::Ok(())
+                });
+                (functype, hostfunc)
+            }
+        }
+
+        impl<F, $($A: WasmValType,)* R: HostReturn> IntoHostFunc<WithCaller<($($A,)*)>, R> for F
+        where
+            F: Fn(Caller, $($A),*) -> R + 'static,
+        {
+            fn into_host_func(self) -> (types::Function, HostFunc) {
+                let functype = types::Function {
+                    input: vec![$($A::VALTYPE),*],
+                    output: R::Results::valtypes(),
+                };
+                let hostfunc = HostFunc::new(move |caller, slots| {
+                    let store = caller.store();
+                    #[allow(unused_mut, unused_variables)]
+                    let mut args = slots.drain(slots.len() - $len..);
+                    $(let $a = $A::from_slot(args.next().unwrap(), $A::VALTYPE, store);)*
+                    drop(args);
+                    let results = self(caller, $($a),*).into_results()?;
+                    results.push_slots(slots, store)?;
+                    Result::Ok(())
+                });
+                (functype, hostfunc)
+            }
+        }
+    };
+}
+
+impl_host_func!(0;);
+impl_host_func!(1; A a);
+impl_host_func!(2; A a, B b);
+impl_host_func!(3; A a, B b, C c);
+impl_host_func!(4; A a, B b, C c, D d);
+impl_host_func!(5; A a, B b, C c, D d, E e);
+impl_host_func!(6; A a, B b, C c, D d, E e, F2 f);
+impl_host_func!(7; A a, B b, C c, D d, E e, F2 f, G g);
+impl_host_func!(8; A a, B b, C c, D d, E e, F2 f, G g, H h);
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    use crate::{
+        embedding::Store as _,
+        err,
+        instr::Instr,
+        modules::Func,
+        runtime::{FuncInstance, InternalFuncInstance, ModuleInstance, Num, Ref, Val},
+        typed::{ExternRef, TypedFunc},
+        validation::Context,
+    };
+
+    use super::*;
+
+    fn i32_val(val: u32) -> Val {
+        Val::Num(Num::I32(val))
+    }
+
+    #[test]
+    fn derived_signatures() {
+        let mut store = Store::new();
+        let add =
+            store.func_wrap(|a: i32, b: i64| -> Result<f32, Trap> { Ok((a as i64 + b) as f32) });
+        let swap = store.func_wrap(|a: u32, b: ExternRef| (b, a));
+        let log = store.func_wrap(|_caller: Caller, _ptr: u32, _len: u32| {});
+
+        let functype = store.func(add).unwrap().functype();
+        assert_eq!(
+            functype.input,
+            vec![
+                types::Value::Num(types::Number::I32),
+                types::Value::Num(types::Number::I64)
+            ]
+        );
+        assert_eq!(functype.output, vec![types::Value::Num(types::Number::F32)]);
+        let functype = store.func(swap).unwrap().functype();
+        assert_eq!(
+            functype.output,
+            vec![
+                types::Value::Ref(types::Ref::EXTERN),
+                types::Value::Num(types::Number::I32)
+            ]
+        );
+        let functype = store.func(log).unwrap().functype();
+        assert_eq!(functype.input.len(), 2);
+        assert!(functype.output.is_empty());
+    }
+
+    #[test]
+    fn invoke_host_func() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let add =
+            store.func_wrap(|a: i32, b: i64| -> Result<f32, Trap> { Ok((a as i64 + b) as f32) });
+        let divmod = store.func_wrap(|a: u32, b: u32| -> Result<(u32, u32), Trap> {
+            if b == 0 {
+                return Err(Trap::new("division by zero"));
+            }
+            Ok((a / b, a % b))
+        });
+
+        let res = store.invoke(add, vec![i32_val(-3i32 as u32), Val::Num(Num::I64(10))])?;
+        assert_eq!(res, vec![Val::Num(Num::F32(7.))]);
+        let res = store.invoke(divmod, vec![i32_val(7), i32_val(2)])?;
+        assert_eq!(res, vec![i32_val(3), i32_val(1)]);
+        let res = store.invoke(divmod, vec![i32_val(7), i32_val(0)]);
+        assert!(matches!(res, Err(err::Err::TrapHost(message)) if message == "division by zero"));
+        Ok(())
+    }
+
+    #[test]
+    fn called_from_wasm() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        store.func_wrap(move |caller: Caller, x: u32| {
+            counter.set(counter.get() + 1);
+            (x * 2, caller.instance().is_some() as u32)
+        });
+        let mut instance = ModuleInstance::new();
+        instance.funct = vec![0, 1];
+        store.modules.push(instance);
+        let module = 0;
+        // Sums the results of the host function on its parameter
+        let host_type = store.funcinstances[0].functype().clone();
+        let functype = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+        let functypes = [host_type, functype];
+        let func = InternalFuncInstance::new(
+            functypes[1].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0), Instr::Call(0), Instr::I32Add],
+            },
+            &Context {
+                types: functypes[1..].to_vec(),
+                funcs: functypes.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        assert_eq!(
+            store.invoke(store.func_id(1), vec![i32_val(20)])?,
+            vec![i32_val(41)]
+        );
+        assert_eq!(
+            store.invoke(store.func_id(0), vec![i32_val(20)])?,
+            vec![i32_val(40), i32_val(0)]
+        );
+        assert_eq!(calls.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn extern_refs() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let counter = store
+            .func_wrap(|caller: Caller, start: u32| caller.store().extern_new(Cell::new(start)));
+        let bump = store.func_wrap(|caller: Caller, r: ExternRef| -> Result<u32, Trap> {
+            let counter = caller
+                .store()
+                .extern_data::<Cell<u32>>(r)
+                .ok_or(Trap::new("not a counter"))?;
+            counter.set(counter.get() + 1);
+            Ok(counter.get())
+        });
+        let table = store.table_alloc(types::Table {
+            limits: types::Limits { min: 1, max: None },
+            reftype: types::Ref::EXTERN,
+        });
+
+        let r = TypedFunc::<u32, ExternRef>::new(&store, counter)?.call(41)?;
+        let arg = vec![r.into()];
+        assert_eq!(store.invoke(bump, arg.clone())?, vec![i32_val(42)]);
+        assert!(store.extern_data::<u32>(r).is_none());
+        let other = store.extern_new("not a counter");
+        let res = store.invoke(bump, vec![other.into()]);
+        assert!(matches!(res, Err(err::Err::TrapHost(_))));
+
+        // Released references live on while a table holds them
+        let data = Rc::downgrade(&store.extern_data::<Cell<u32>>(r).unwrap());
+        store.table_write(table, 0, r.into())?;
+        store.extern_release(r);
+        store.extern_release(other);
+        store.collect();
+        assert_eq!(store.invoke(bump, arg)?, vec![i32_val(43)]);
+        assert!(store.extern_data::<&str>(other).is_none());
+
+        store.table_write(table, 0, Ref::Null(types::Heap::Extern))?;
+        store.collect();
+        assert!(store.extern_data::<Cell<u32>>(r).is_none());
+        assert!(data.upgrade().is_none());
+
+        // Stale references do not reach the data reusing their slot, nor those of another store
+        let reused = store.extern_new(Cell::new(0u32));
+        assert!(store.extern_data::<Cell<u32>>(reused).is_some());
+        assert!(store.extern_data::<Cell<u32>>(r).is_none());
+        let other = Store::new();
+        assert!(other.extern_data::<Cell<u32>>(reused).is_none());
+        let bump = TypedFunc::<ExternRef, u32>::new(&store, bump)?;
+        assert!(matches!(bump.call(r), Err(err::Err::StaleHandle(_))));
+        assert_eq!(bump.call(reused)?, 1);
+        Ok(())
+    }
+}
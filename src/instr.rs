@@ -1,10 +1,133 @@
 use alloc::vec::Vec;
 
 use crate::{
+    config::Feature,
     types::{self, Index},
     validation::{Context, Validable},
 };
 
+// Type of the values a block takes from the stack and leaves on it. Blocks with parameters or
+// several results refer to a function type of the module.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockType {
+    Empty,
+    Value(types::Value),
+    Index(Index),
+}
+
+// Immediate of memory accesses: the offset added to the address operand, the alignment as a
+// power of two and the memory accessed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemArg {
+    pub offset: u64,
+    pub align: u32,
+    pub mem: Index,
+}
+
+// Value type and width of plain accesses. Narrow loads are sign- or zero-extended, narrow stores
+// wrap the value and are given as the unsigned variants.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    I32,
+    I64,
+    F32,
+    F64,
+    I32S8,
+    I32U8,
+    I32S16,
+    I32U16,
+    I64S8,
+    I64U8,
+    I64S16,
+    I64U16,
+    I64S32,
+    I64U32,
+}
+
+impl Access {
+    // In bytes
+    pub fn size(self) -> usize {
+        match self {
+            Access::I32S8 | Access::I32U8 | Access::I64S8 | Access::I64U8 => 1,
+            Access::I32S16 | Access::I32U16 | Access::I64S16 | Access::I64U16 => 2,
+            Access::I32 | Access::F32 | Access::I64S32 | Access::I64U32 => 4,
+            Access::I64 | Access::F64 => 8,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            Access::I32S8 | Access::I32S16 | Access::I64S8 | Access::I64S16 | Access::I64S32
+        )
+    }
+}
+
+// Value type and width of atomic accesses, narrow values being zero-extended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Atomic {
+    I32,
+    I64,
+    I32U8,
+    I32U16,
+    I64U8,
+    I64U16,
+    I64U32,
+}
+
+impl Atomic {
+    // In bytes
+    pub fn size(self) -> usize {
+        match self {
+            Atomic::I32U8 | Atomic::I64U8 => 1,
+            Atomic::I32U16 | Atomic::I64U16 => 2,
+            Atomic::I32 | Atomic::I64U32 => 4,
+            Atomic::I64 => 8,
+        }
+    }
+
+    pub fn is_i64(self) -> bool {
+        matches!(
+            self,
+            Atomic::I64 | Atomic::I64U8 | Atomic::I64U16 | Atomic::I64U32
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RmwOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+}
+
+impl RmwOp {
+    // Value replacing `old`, to be truncated to the width of the access
+    pub fn apply(self, old: u64, operand: u64) -> u64 {
+        match self {
+            RmwOp::Add => old.wrapping_add(operand),
+            RmwOp::Sub => old.wrapping_sub(operand),
+            RmwOp::And => old & operand,
+            RmwOp::Or => old | operand,
+            RmwOp::Xor => old ^ operand,
+            RmwOp::Xchg => operand,
+        }
+    }
+}
+
+// Clause of a `try_table`, branching to a label with the payload of the exceptions it catches.
+// `Ref` clauses also pass the caught exception, as an `exnref`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Catch {
+    Tag(Index, Index), // Tag and label
+    TagRef(Index, Index),
+    All(Index),
+    AllRef(Index),
+}
+
 #[derive(Clone, Copy)]
 pub enum Instr {
     // Numeric
@@ -106,11 +229,11 @@ pub enum Instr {
     //    F64Gt,
     //    F64Le,
     //
-    //    I32Extend8S,
-    //    I64Extend8S,
-    //    I32Extend16S,
-    //    I64Extend16S,
-    //    I64Extend32,
+    I32Extend8S,
+    I64Extend8S,
+    I32Extend16S,
+    I64Extend16S,
+    I64Extend32S,
     //    I32WrapI64,
     //    I64ExtendI32U,
     //    I64ExtendI32S,
@@ -122,14 +245,14 @@ pub enum Instr {
     //    I64TrunkI32S,
     //    I64TrunkI64U,
     //    I64TrunkI64S,
-    //    I32TrunkSatI32U,
-    //    I32TrunkSatI32S,
-    //    I32TrunkSatI64U,
-    //    I32TrunkSatI64S,
-    //    I64TrunkSatI32U,
-    //    I64TrunkSatI32S,
-    //    I64TrunkSatI64U,
-    //    I64TrunkSatI64S,
+    I32TruncSatF32U,
+    I32TruncSatF32S,
+    I32TruncSatF64U,
+    I32TruncSatF64S,
+    I64TruncSatF32U,
+    I64TruncSatF32S,
+    I64TruncSatF64U,
+    I64TruncSatF64S,
     //    F32DemoteF64,
     //    F64PromoteF32,
     //    F32ConvertI32U,
@@ -335,9 +458,10 @@ pub enum Instr {
     //    F64x2PromoteLowF32x4,
 
     ////Reference
-    RefNull(types::Ref),
+    RefNull(types::Heap),
     //RefIsNull,
     RefFunc(Index),
+    RefAsNonNull,
     //// Param
     //ParamDrop,
     //ParamSelect(Option<types::Value>),
@@ -352,8 +476,8 @@ pub enum Instr {
     // Table
     //TableGet(Index),
     //TableSet(Index),
-    //TableSize(Index),
-    //TableGrow(Index),
+    TableSize(Index),
+    TableGrow(Index),
     //TableFill(Index),
     //TableCopy(Index, Index),
     //TableInit(Index, Index),
@@ -501,29 +625,52 @@ pub enum Instr {
     //V128store32LaneAlign(u32, Index),
     //V128store64LaneOffset(u32, Index),
     //V128store64LaneAlign(u32, Index),
-
-    //MemorySize,
-    //MemoryGrow,
-    //MemoryFill,
-    //MemoryCopy,
-    //MemoryInit(Index),
-    //DataDrop(Index),
+    // Alignments are hints, at most the natural one
+    Load(Access, MemArg),
+    Store(Access, MemArg),
+    MemorySize(Index),
+    MemoryGrow(Index),
+    MemoryFill(Index),
+    MemoryCopy(Index, Index), // Destination and source memories
+    MemoryInit(Index, Index), // Data segment and memory
+    DataDrop(Index),
+    // Atomic accesses trap unless aligned, their alignment must be their natural one
+    AtomicLoad(Atomic, MemArg),
+    AtomicStore(Atomic, MemArg),
+    AtomicRmw(RmwOp, Atomic, MemArg),
+    AtomicCmpxchg(Atomic, MemArg),
+    MemoryAtomicNotify(MemArg),
+    MemoryAtomicWait32(MemArg),
+    MemoryAtomicWait64(MemArg),
+    AtomicFence,
 
     //// Control
     Nop,
     Unreachable,
-    //BlockIdx(Index, Vec<Instr>),
-    //BlockVal(Option<types::Value>, Vec<Instr>),
-    //LoopIdx(Index, Vec<Instr>),
-    //LoopVal(Option<types::Value>, Vec<Instr>),
-    //IfIdx(Index, Vec<Instr>, Vec<Instr>),
-    //IfVal(Option<types::Value>, Vec<Instr>, Vec<Instr>),
-    //Br(Index),
-    //BrIf(Index),
+    // Structured instructions are kept flat as in the binary format,
+    // their body extends up to the matching `Else` or `End`
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    Br(Index),
+    BrIf(Index),
     //BrTable(Vec<Index>, Index),
+    BrOnNull(Index),
+    BrOnNonNull(Index),
     Return,
     Call(Index),
     //CallIndirect(Index, Index),
+    CallRef(Index), // Type of the called function
+    ReturnCall(Index),
+    ReturnCallIndirect(Index, Index), // Type and table indices
+    ReturnCallRef(Index),
+    Throw(Index),
+    ThrowRef,
+    // The catch clauses of a `try_table` directly follow it, before its body
+    TryTable(BlockType),
+    Catch(Catch),
     // Administrative
     //Trap,
     //Ref(Addr),
@@ -533,7 +680,141 @@ pub enum Instr {
     //Frame(Frame, Vec<Instr>),
 }
 
+// Coarse instruction categories, used to price instructions for fuel metering
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstrClass {
+    Numeric,
+    Reference,
+    Variable,
+    Table,
+    Memory,
+    Control,
+}
+
 impl Instr {
+    pub fn class(&self) -> InstrClass {
+        match self {
+            Instr::RefNull(_) | Instr::RefFunc(_) | Instr::RefAsNonNull => InstrClass::Reference,
+            Instr::LocalGet(_)
+            | Instr::LocalSet(_)
+            | Instr::LocalTee(_)
+            | Instr::GlobalGet(_)
+            | Instr::GlobalSet(_) => InstrClass::Variable,
+            Instr::TableSize(_) | Instr::TableGrow(_) => InstrClass::Table,
+            Instr::Load(..)
+            | Instr::Store(..)
+            | Instr::MemorySize(_)
+            | Instr::MemoryGrow(_)
+            | Instr::MemoryFill(_)
+            | Instr::MemoryCopy(..)
+            | Instr::MemoryInit(..)
+            | Instr::DataDrop(_)
+            | Instr::AtomicLoad(..)
+            | Instr::AtomicStore(..)
+            | Instr::AtomicRmw(..)
+            | Instr::AtomicCmpxchg(..)
+            | Instr::MemoryAtomicNotify(_)
+            | Instr::MemoryAtomicWait32(_)
+            | Instr::MemoryAtomicWait64(_)
+            | Instr::AtomicFence => InstrClass::Memory,
+            Instr::Nop
+            | Instr::Unreachable
+            | Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::If(_)
+            | Instr::Else
+            | Instr::End
+            | Instr::Br(_)
+            | Instr::BrIf(_)
+            | Instr::BrOnNull(_)
+            | Instr::BrOnNonNull(_)
+            | Instr::Return
+            | Instr::Call(_)
+            | Instr::CallRef(_)
+            | Instr::ReturnCall(_)
+            | Instr::ReturnCallIndirect(..)
+            | Instr::ReturnCallRef(_)
+            | Instr::Throw(_)
+            | Instr::ThrowRef
+            | Instr::TryTable(_)
+            | Instr::Catch(_) => InstrClass::Control,
+            _ => InstrClass::Numeric,
+        }
+    }
+
+    // Proposal introducing the instruction, None for the MVP
+    pub fn feature(&self) -> Option<Feature> {
+        match self {
+            // Memories other than the first, whose instructions otherwise need bulk memory
+            Instr::Load(_, memarg)
+            | Instr::Store(_, memarg)
+            | Instr::AtomicLoad(_, memarg)
+            | Instr::AtomicStore(_, memarg)
+            | Instr::AtomicRmw(_, _, memarg)
+            | Instr::AtomicCmpxchg(_, memarg)
+            | Instr::MemoryAtomicNotify(memarg)
+            | Instr::MemoryAtomicWait32(memarg)
+            | Instr::MemoryAtomicWait64(memarg)
+                if memarg.mem != 0 =>
+            {
+                Some(Feature::MultiMemory)
+            }
+            Instr::MemorySize(mem)
+            | Instr::MemoryGrow(mem)
+            | Instr::MemoryFill(mem)
+            | Instr::MemoryInit(_, mem)
+                if *mem != 0 =>
+            {
+                Some(Feature::MultiMemory)
+            }
+            Instr::MemoryCopy(dst, src) if *dst != 0 || *src != 0 => Some(Feature::MultiMemory),
+            Instr::RefNull(types::Heap::Type(_))
+            | Instr::RefAsNonNull
+            | Instr::BrOnNull(_)
+            | Instr::BrOnNonNull(_)
+            | Instr::CallRef(_)
+            | Instr::ReturnCallRef(_) => Some(Feature::FunctionReferences),
+            Instr::RefNull(_) | Instr::RefFunc(_) | Instr::TableSize(_) | Instr::TableGrow(_) => {
+                Some(Feature::ReferenceTypes)
+            }
+            Instr::Block(BlockType::Index(_))
+            | Instr::Loop(BlockType::Index(_))
+            | Instr::If(BlockType::Index(_)) => Some(Feature::MultiValue),
+            Instr::ReturnCall(_) | Instr::ReturnCallIndirect(..) => Some(Feature::TailCall),
+            Instr::Throw(_) | Instr::ThrowRef | Instr::TryTable(_) | Instr::Catch(_) => {
+                Some(Feature::ExceptionHandling)
+            }
+            Instr::I32Extend8S
+            | Instr::I64Extend8S
+            | Instr::I32Extend16S
+            | Instr::I64Extend16S
+            | Instr::I64Extend32S => Some(Feature::SignExtension),
+            Instr::I32TruncSatF32U
+            | Instr::I32TruncSatF32S
+            | Instr::I32TruncSatF64U
+            | Instr::I32TruncSatF64S
+            | Instr::I64TruncSatF32U
+            | Instr::I64TruncSatF32S
+            | Instr::I64TruncSatF64U
+            | Instr::I64TruncSatF64S => Some(Feature::SaturatingFloatToInt),
+            Instr::MemoryFill(_)
+            | Instr::MemoryCopy(..)
+            | Instr::MemoryInit(..)
+            | Instr::DataDrop(_) => Some(Feature::BulkMemory),
+            Instr::AtomicLoad(..)
+            | Instr::AtomicStore(..)
+            | Instr::AtomicRmw(..)
+            | Instr::AtomicCmpxchg(..)
+            | Instr::MemoryAtomicNotify(_)
+            | Instr::MemoryAtomicWait32(_)
+            | Instr::MemoryAtomicWait64(_)
+            | Instr::AtomicFence => Some(Feature::Threads),
+            _ => None,
+        }
+    }
+
+    // Arithmetic instructions come with extended constant expressions, which are enabled
+    // separately
     pub fn is_constant(&self, context: &Context) -> bool {
         match self {
             Instr::I32Const(_)
@@ -542,10 +823,17 @@ impl Instr {
             | Instr::F64Const(_)
             //| Instr::V128Const(_)
             | Instr::RefNull(_)
-            | Instr::RefFunc(_) => true,
-            Instr::GlobalGet(val) => {
-                unimplemented!()
-            }
+            | Instr::RefFunc(_)
+            | Instr::I32Add
+            | Instr::I32Sub
+            | Instr::I32Mul
+            | Instr::I64Add
+            | Instr::I64Sub
+            | Instr::I64Mul => true,
+            Instr::GlobalGet(idx) => context
+                .globals
+                .get(*idx)
+                .is_some_and(|global| global.mutable == types::Mut::Const),
             _ => false,
         }
     }
@@ -556,7 +844,7 @@ pub type Expr = Vec<Instr>;
 // Validation
 
 impl Validable for Expr {
-    fn is_valid(&self, context: &Context, k: Option<types::Int>) -> bool {
+    fn is_valid(&self, _context: &Context, _k: Option<types::Int>) -> bool {
         todo!()
     }
 }
@@ -3,15 +3,25 @@
 
 #[macro_use]
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 //extern crate wasmic_macro;
 
-mod embedding;
-mod err;
-mod instr;
-mod modules;
+mod binary;
+mod bytecode;
+pub mod config;
+pub mod embedding;
+pub mod err;
+pub mod host;
+pub mod instr;
+pub mod linker;
+pub mod memory;
+pub mod modules;
 mod numeric;
-mod runtime;
-mod types;
+pub mod runtime;
+pub mod shared;
+pub mod typed;
+pub mod types;
 mod validation;
 mod vm;
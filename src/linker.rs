@@ -0,0 +1,482 @@
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    embedding::{Instanciable, Store as _},
+    err::Err,
+    host::IntoHostFunc,
+    modules::{ImportDesc, Module},
+    runtime::{ExternalVal, InstanceId, ModuleInstance, Store, PAGE_SIZE},
+    types,
+    validation::Subtypable,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum UnresolvedReason {
+    Undefined,
+    IncompatibleType,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnresolvedImport {
+    pub module: String,
+    pub name: String,
+    pub reason: UnresolvedReason,
+}
+
+// Resolves the imports of modules by name, against host definitions and the exports of
+// registered instances.
+// Defining a name twice is an error, unless shadowing is allowed, in which case the latest
+// definition replaces the previous one. Modules instantiated before keep what they resolved to.
+#[derive(Default)]
+pub struct Linker {
+    definitions: BTreeMap<String, BTreeMap<String, ExternalVal>>,
+    allow_shadowing: bool,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    pub fn allow_shadowing(&mut self, allow: bool) -> &mut Self {
+        self.allow_shadowing = allow;
+        self
+    }
+
+    pub fn define(
+        &mut self,
+        module: &str,
+        name: &str,
+        externval: ExternalVal,
+    ) -> Result<&mut Self, Err> {
+        let names = self.definitions.entry(module.to_string()).or_default();
+        if names.contains_key(name) && !self.allow_shadowing {
+            return Result::Err(Err::LinkerDuplicateDefinition(
+                module.to_string(),
+                name.to_string(),
+            ));
+        }
+        names.insert(name.to_string(), externval);
+        Result::Ok(self)
+    }
+
+    // Allocates `func` in `store` and defines it
+    pub fn func_wrap<Params, Results>(
+        &mut self,
+        store: &mut Store,
+        module: &str,
+        name: &str,
+        func: impl IntoHostFunc<Params, Results>,
+    ) -> Result<&mut Self, Err> {
+        if self.get(module, name).is_some() && !self.allow_shadowing {
+            return Result::Err(Err::LinkerDuplicateDefinition(
+                module.to_string(),
+                name.to_string(),
+            ));
+        }
+        let addr = store.func_wrap(func);
+        self.define(module, name, ExternalVal::Fun(addr))
+    }
+
+    // Defines all the exports of `instance` under the module name `module`
+    pub fn instance(
+        &mut self,
+        store: &Store,
+        module: &str,
+        instance: InstanceId,
+    ) -> Result<&mut Self, Err> {
+        let instance = store.instance(instance)?;
+        if !self.allow_shadowing {
+            for export in &instance.exports {
+                if self.get(module, &export.name).is_some() {
+                    return Result::Err(Err::LinkerDuplicateDefinition(
+                        module.to_string(),
+                        export.name.clone(),
+                    ));
+                }
+            }
+        }
+        for export in &instance.exports {
+            self.define(module, &export.name, export.value)?;
+        }
+        Result::Ok(self)
+    }
+
+    pub fn get(&self, module: &str, name: &str) -> Option<ExternalVal> {
+        self.definitions.get(module)?.get(name).copied()
+    }
+
+    // The external values to instantiate `module` with, in import order.
+    // Every import that is undefined or of an incompatible type is reported.
+    pub fn resolve(&self, store: &Store, module: &Module) -> Result<Vec<ExternalVal>, Err> {
+        let mut externvals = vec![];
+        let mut unresolved = vec![];
+        let types = store.register_types(&module.types)?;
+        for import in &module.imports {
+            let reason = match self.get(&import.module, &import.name) {
+                None => UnresolvedReason::Undefined,
+                Some(externval) => {
+                    if matches_import(store, &types, &import.desc, externval)? {
+                        externvals.push(externval);
+                        continue;
+                    }
+                    UnresolvedReason::IncompatibleType
+                }
+            };
+            unresolved.push(UnresolvedImport {
+                module: import.module.clone(),
+                name: import.name.clone(),
+                reason,
+            });
+        }
+        if !unresolved.is_empty() {
+            return Result::Err(Err::UnresolvedImports(unresolved));
+        }
+        Result::Ok(externvals)
+    }
+
+    pub fn instantiate(&self, store: &mut Store, module: &Module) -> Result<InstanceId, Err> {
+        let externvals = self.resolve(store, module)?;
+        ModuleInstance::instantiate(store, module, externvals)
+    }
+}
+
+// Whether `externval` can be imported as `desc` by a module, whose types have been registered
+// in the store at `types` (sec 4.5.4)
+pub(crate) fn matches_import(
+    store: &Store,
+    types: &[types::Addr],
+    desc: &ImportDesc,
+    externval: ExternalVal,
+) -> Result<bool, Err> {
+    let defs = &store.types.borrow();
+    let functype = |idx: types::Index| types.get(idx).map(|idx| &defs[*idx]);
+    let matches = match (desc, externval) {
+        (ImportDesc::Func(idx), ExternalVal::Fun(func)) => match functype(*idx) {
+            Some(functype) => store.func(func)?.functype().is_subtype(functype, defs),
+            None => false,
+        },
+        (ImportDesc::Table(tabletype), ExternalVal::Table(table)) => {
+            let actual = store.table(table)?.borrow().tabletype;
+            let tabletype = types::Table {
+                reftype: tabletype.reftype.remap(types)?,
+                ..*tabletype
+            };
+            actual.is_subtype(&tabletype, defs)
+        }
+        (ImportDesc::Mem(memtype), ExternalVal::Mem(mem)) => {
+            let mem = store.mem(mem)?.borrow();
+            // Shared memories may have been grown by other threads
+            let limits = types::Limits {
+                min: mem.size() / PAGE_SIZE,
+                ..mem.memtype.limits
+            };
+            mem.memtype.index == memtype.index
+                && mem.memtype.shared == memtype.shared
+                && limits.is_subtype(&memtype.limits, defs)
+        }
+        (ImportDesc::Global(globaltype), ExternalVal::Global(global)) => {
+            let actual = store.global(global)?.borrow().globaltype;
+            let globaltype = types::Global {
+                val: globaltype.val.remap(types)?,
+                ..*globaltype
+            };
+            actual.is_subtype(&globaltype, defs)
+        }
+        // Payloads are both thrown and caught, their types must be equivalent
+        (ImportDesc::Tag(idx), ExternalVal::Tag(tag)) => {
+            functype(*idx) == Some(&store.tag(tag)?.tagtype)
+        }
+        _ => false,
+    };
+    Result::Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::{
+        instr::Instr,
+        modules::{Export, ExportDesc, Func, Import},
+        runtime,
+        types::{self, i32_function},
+    };
+
+    use super::*;
+
+    fn import(module: &str, name: &str, desc: ImportDesc) -> Import {
+        Import {
+            module: module.to_string(),
+            name: name.to_string(),
+            desc,
+        }
+    }
+
+    fn module(types: Vec<types::Function>, imports: Vec<Import>) -> Module {
+        Module {
+            types,
+            funcs: vec![],
+            tables: vec![],
+            mems: vec![],
+            globals: vec![],
+            tags: vec![],
+            elems: vec![],
+            datas: vec![],
+            start: None,
+            imports,
+            exports: vec![],
+        }
+    }
+
+    fn global_i32(mutable: types::Mut) -> types::Global {
+        types::Global {
+            mutable,
+            val: types::Value::Num(types::Number::I32),
+        }
+    }
+
+    fn mem(min: usize, max: Option<usize>) -> types::Mem {
+        types::Mem {
+            limits: types::Limits { min, max },
+            index: types::IndexType::I32,
+            shared: false,
+        }
+    }
+
+    #[test]
+    fn resolve_by_name() -> Result<(), Err> {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        linker.func_wrap(&mut store, "env", "add", |a: u32, b: u32| a + b)?;
+        let global = store.global_alloc(global_i32(types::Mut::Var));
+        linker.define("env", "counter", ExternalVal::Global(global))?;
+        let memory = store.mem_alloc(mem(1, Some(2)))?;
+        linker.define("env", "memory", ExternalVal::Mem(memory))?;
+
+        let module = module(
+            vec![i32_function(2, 1)],
+            vec![
+                import("env", "memory", ImportDesc::Mem(mem(1, None))),
+                import("env", "add", ImportDesc::Func(0)),
+                import(
+                    "env",
+                    "counter",
+                    ImportDesc::Global(global_i32(types::Mut::Var)),
+                ),
+            ],
+        );
+        let externvals = linker.resolve(&store, &module)?;
+        assert_eq!(
+            externvals,
+            vec![
+                ExternalVal::Mem(memory),
+                ExternalVal::Fun(store.func_id(0)),
+                ExternalVal::Global(global),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_all_unresolved_imports() -> Result<(), Err> {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        linker.func_wrap(&mut store, "env", "add", |a: u32, b: u32| a + b)?;
+        let global = store.global_alloc(global_i32(types::Mut::Const));
+        linker.define("env", "counter", ExternalVal::Global(global))?;
+        let memory = store.mem_alloc(mem(1, None))?;
+        linker.define("env", "memory", ExternalVal::Mem(memory))?;
+
+        let module = module(
+            vec![i32_function(1, 1)],
+            vec![
+                import("env", "add", ImportDesc::Func(0)),
+                import("env", "sub", ImportDesc::Func(0)),
+                import(
+                    "env",
+                    "counter",
+                    ImportDesc::Global(global_i32(types::Mut::Var)),
+                ),
+                import("env", "memory", ImportDesc::Mem(mem(1, Some(2)))),
+                import("other", "add", ImportDesc::Func(0)),
+                import("env", "counter", ImportDesc::Mem(mem(0, None))),
+            ],
+        );
+        let unresolved = |module: &str, name: &str, reason| UnresolvedImport {
+            module: module.to_string(),
+            name: name.to_string(),
+            reason,
+        };
+        match linker.resolve(&store, &module) {
+            Err(Err::UnresolvedImports(imports)) => assert_eq!(
+                imports,
+                vec![
+                    unresolved("env", "add", UnresolvedReason::IncompatibleType),
+                    unresolved("env", "sub", UnresolvedReason::Undefined),
+                    unresolved("env", "counter", UnresolvedReason::IncompatibleType),
+                    unresolved("env", "memory", UnresolvedReason::IncompatibleType),
+                    unresolved("other", "add", UnresolvedReason::Undefined),
+                    unresolved("env", "counter", UnresolvedReason::IncompatibleType),
+                ]
+            ),
+            _ => panic!("imports should not resolve"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shadowing() -> Result<(), Err> {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        linker.func_wrap(&mut store, "env", "f", || 1u32)?;
+        assert!(matches!(
+            linker.func_wrap(&mut store, "env", "f", || 2u32),
+            Err(Err::LinkerDuplicateDefinition(..))
+        ));
+        assert!(matches!(
+            linker.define("env", "f", ExternalVal::Fun(store.func_id(0))),
+            Err(Err::LinkerDuplicateDefinition(..))
+        ));
+        assert_eq!(store.funcinstances.len(), 1);
+
+        let mut instance = ModuleInstance::new();
+        instance.exports.push(runtime::Export {
+            name: "f".to_string(),
+            value: ExternalVal::Table(store.table_id(0)),
+        });
+        instance.exports.push(runtime::Export {
+            name: "g".to_string(),
+            value: ExternalVal::Table(store.table_id(1)),
+        });
+        store.modules.push(instance);
+        let instance = store.instance_id(0);
+        assert!(matches!(
+            linker.instance(&store, "env", instance),
+            Err(Err::LinkerDuplicateDefinition(..))
+        ));
+        // A failed registration defines nothing
+        assert!(linker.get("env", "g").is_none());
+
+        linker.allow_shadowing(true);
+        linker.func_wrap(&mut store, "env", "f", || 2u32)?;
+        assert_eq!(
+            linker.get("env", "f"),
+            Some(ExternalVal::Fun(store.func_id(1)))
+        );
+        linker.instance(&store, "env", instance)?;
+        assert_eq!(
+            linker.get("env", "f"),
+            Some(ExternalVal::Table(store.table_id(0)))
+        );
+        assert_eq!(
+            linker.get("env", "g"),
+            Some(ExternalVal::Table(store.table_id(1)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn instantiate() -> Result<(), Err> {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        linker.func_wrap(&mut store, "env", "double", |a: u32| a * 2)?;
+        store.func_wrap(|| 0u32);
+        linker.func_wrap(&mut store, "env", "triple", |a: u32| a * 3)?;
+
+        // Exports a function calling both imports
+        let mut module = module(
+            vec![i32_function(1, 1)],
+            vec![
+                import("env", "triple", ImportDesc::Func(0)),
+                import("env", "double", ImportDesc::Func(0)),
+            ],
+        );
+        module.funcs.push(Func {
+            functype: 0,
+            locals: vec![],
+            body: vec![Instr::LocalGet(0), Instr::Call(0), Instr::Call(1)],
+        });
+        module.exports.push(Export {
+            name: "f".to_string(),
+            desc: ExportDesc::Func(2),
+        });
+
+        let instance = linker.instantiate(&mut store, &module)?;
+        let instance = store.instance(instance)?;
+        assert_eq!(instance.funct, vec![2, 0, 3]);
+        assert_eq!(instance.export("f")?, ExternalVal::Fun(store.func_id(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn typed_function_references() -> Result<(), Err> {
+        let mut config = crate::config::Config::new();
+        config.feature(crate::config::Feature::FunctionReferences, true);
+        let mut store = Store::with_config(config);
+        let mut linker = Linker::new();
+        let i32 = types::Value::Num(types::Number::I32);
+        let apply = |nullable, heap| types::Function {
+            input: vec![types::Value::Ref(types::Ref { nullable, heap }), i32],
+            output: vec![i32],
+        };
+
+        // Calls the function passed with an i32
+        let mut lib = module(
+            vec![
+                i32_function(0, 0),
+                i32_function(1, 1),
+                apply(true, types::Heap::Type(1)),
+            ],
+            vec![],
+        );
+        lib.funcs.push(Func {
+            functype: 2,
+            locals: vec![],
+            body: vec![
+                Instr::LocalGet(1),
+                Instr::LocalGet(0),
+                Instr::RefAsNonNull,
+                Instr::CallRef(1),
+            ],
+        });
+        lib.exports.push(Export {
+            name: "apply".to_string(),
+            desc: ExportDesc::Func(0),
+        });
+        let lib = linker.instantiate(&mut store, &lib)?;
+        linker.instance(&store, "lib", lib)?;
+
+        // Types are compared by structure, and only match equivalent ones
+        let import = |types: Vec<types::Function>| {
+            let idx = types.len() - 1;
+            module(types, vec![import("lib", "apply", ImportDesc::Func(idx))])
+        };
+        let same = import(vec![i32_function(1, 1), apply(true, types::Heap::Type(0))]);
+        let narrower = import(vec![i32_function(1, 1), apply(false, types::Heap::Type(0))]);
+        let wider = import(vec![apply(true, types::Heap::Func)]);
+        let other = import(vec![i32_function(0, 0), apply(true, types::Heap::Type(0))]);
+        assert!(linker.resolve(&store, &same).is_ok());
+        for module in [narrower, wider, other] {
+            assert!(matches!(
+                linker.resolve(&store, &module),
+                Err(Err::UnresolvedImports(_))
+            ));
+        }
+
+        // Types may only refer to the ones defined before them
+        let forward = module(
+            vec![apply(true, types::Heap::Type(1)), i32_function(1, 1)],
+            vec![],
+        );
+        assert!(matches!(
+            linker.instantiate(&mut store, &forward),
+            Err(Err::InvalidIndex(1))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,786 @@
+use core::{cell, marker::PhantomData, ops::Range};
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    err,
+    runtime::{Mem, MemData, MemId, Slot, Store, PAGE_SIZE},
+    shared::SharedMemory,
+    typed::{WasmTy, WasmValType},
+    types,
+};
+
+// Values stored in linear memory, encoded in little-endian as wasm loads and stores them
+pub trait LeBytes: Copy {
+    const SIZE: usize;
+    fn from_le_slice(bytes: &[u8]) -> Self; // `bytes` is exactly `SIZE` long
+    fn write_le_slice(self, bytes: &mut [u8]);
+}
+
+// Plain data with a fixed layout in guest memory, read and written field by field so that no
+// host layout or byte order is assumed. Structs implement it with `impl_pod!`, which lays out
+// their fields as `#[repr(C)]` does on wasm32.
+pub trait Pod: Copy {
+    const SIZE: usize;
+    const ALIGN: usize;
+    fn from_bytes(bytes: &[u8]) -> Self; // `bytes` is exactly `SIZE` long
+    fn write_bytes(self, bytes: &mut [u8]);
+}
+
+pub const fn align_up(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
+
+macro_rules! impl_le_bytes {
+    ($($t:ty),*) => {
+        $(
+            impl LeBytes for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes.try_into().unwrap())
+                }
+                fn write_le_slice(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+
+            impl Pod for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+                const ALIGN: usize = core::mem::size_of::<$t>();
+                fn from_bytes(bytes: &[u8]) -> Self {
+                    Self::from_le_slice(bytes)
+                }
+                fn write_bytes(self, bytes: &mut [u8]) {
+                    self.write_le_slice(bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_le_bytes!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+impl<T: Pod, const N: usize> Pod for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+    const ALIGN: usize = T::ALIGN;
+    fn from_bytes(bytes: &[u8]) -> Self {
+        core::array::from_fn(|i| T::from_bytes(&bytes[i * T::SIZE..(i + 1) * T::SIZE]))
+    }
+    fn write_bytes(self, bytes: &mut [u8]) {
+        for (value, bytes) in self.into_iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+            value.write_bytes(bytes);
+        }
+    }
+}
+
+impl<T> Pod for WasmPtr<T> {
+    const SIZE: usize = 4;
+    const ALIGN: usize = 4;
+    fn from_bytes(bytes: &[u8]) -> Self {
+        WasmPtr::new(u32::from_bytes(bytes))
+    }
+    fn write_bytes(self, bytes: &mut [u8]) {
+        self.offset.write_bytes(bytes)
+    }
+}
+
+// Implements `Pod` for a struct whose fields are all `Pod`, listed in declaration order:
+// `impl_pod!(Iovec { base: WasmPtr<u8>, len: u32 });`
+#[macro_export]
+macro_rules! impl_pod {
+    ($name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        impl $crate::memory::Pod for $name {
+            const ALIGN: usize = {
+                #[allow(unused_mut)]
+                let mut align = 1;
+                $(
+                    if <$ty as $crate::memory::Pod>::ALIGN > align {
+                        align = <$ty as $crate::memory::Pod>::ALIGN;
+                    }
+                )*
+                align
+            };
+            const SIZE: usize = {
+                #[allow(unused_mut)]
+                let mut end = 0;
+                $(
+                    end = $crate::memory::align_up(end, <$ty as $crate::memory::Pod>::ALIGN)
+                        + <$ty as $crate::memory::Pod>::SIZE;
+                )*
+                $crate::memory::align_up(end, <Self as $crate::memory::Pod>::ALIGN)
+            };
+            #[allow(unused_variables, unused_assignments)]
+            fn from_bytes(bytes: &[u8]) -> Self {
+                let mut offset = 0;
+                $(
+                    offset = $crate::memory::align_up(offset, <$ty as $crate::memory::Pod>::ALIGN);
+                    let end = offset + <$ty as $crate::memory::Pod>::SIZE;
+                    let $field = <$ty as $crate::memory::Pod>::from_bytes(&bytes[offset..end]);
+                    offset = end;
+                )*
+                $name { $($field),* }
+            }
+            #[allow(unused_variables, unused_assignments)]
+            fn write_bytes(self, bytes: &mut [u8]) {
+                let mut offset = 0;
+                $(
+                    offset = $crate::memory::align_up(offset, <$ty as $crate::memory::Pod>::ALIGN);
+                    let end = offset + <$ty as $crate::memory::Pod>::SIZE;
+                    $crate::memory::Pod::write_bytes(self.$field, &mut bytes[offset..end]);
+                    offset = end;
+                )*
+            }
+        }
+    };
+}
+
+// Accesses are checked against the current size of the memory, which may change as it grows.
+// Shared memories cannot be borrowed as slices, as other threads may access them concurrently.
+impl Mem {
+    pub fn new(memtype: types::Mem) -> Result<Mem, err::Err> {
+        let data = match memtype.shared {
+            true => MemData::Shared(SharedMemory::new(memtype)?),
+            false => {
+                let len = memtype.limits.min.checked_mul(PAGE_SIZE);
+                let len = len.ok_or(err::Err::InvalidLimit(memtype.limits))?;
+                let mut data = Vec::new();
+                data.try_reserve_exact(len)
+                    .map_err(|_| err::Err::MemoryLimitExceeded)?;
+                data.resize(len, 0);
+                MemData::Owned(data)
+            }
+        };
+        Result::Ok(Mem { memtype, data })
+    }
+
+    pub fn size(&self) -> usize {
+        match &self.data {
+            MemData::Owned(data) => data.len(),
+            MemData::Shared(shared) => shared.size(),
+        }
+    }
+
+    // Bytes allocated for the memory, beyond its size for shared memories
+    pub fn reserved(&self) -> usize {
+        match &self.data {
+            MemData::Owned(data) => data.len(),
+            MemData::Shared(shared) => shared.reserved(),
+        }
+    }
+
+    fn range(&self, offset: usize, len: usize) -> Result<Range<usize>, err::Err> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size() => Result::Ok(offset..end),
+            _ => Result::Err(err::Err::OutOfBoundMemoryAccess),
+        }
+    }
+
+    pub fn slice(&self, offset: usize, len: usize) -> Result<&[u8], err::Err> {
+        let range = self.range(offset, len)?;
+        match &self.data {
+            MemData::Owned(data) => Result::Ok(&data[range]),
+            MemData::Shared(_) => Result::Err(err::Err::MemoryShared),
+        }
+    }
+
+    pub fn slice_mut(&mut self, offset: usize, len: usize) -> Result<&mut [u8], err::Err> {
+        let range = self.range(offset, len)?;
+        match &mut self.data {
+            MemData::Owned(data) => Result::Ok(&mut data[range]),
+            MemData::Shared(_) => Result::Err(err::Err::MemoryShared),
+        }
+    }
+
+    // Copies `len` bytes from `src` to `dst`, the ranges may overlap
+    pub fn copy_within(&mut self, src: usize, dst: usize, len: usize) -> Result<(), err::Err> {
+        let range = self.range(src, len)?;
+        self.range(dst, len)?;
+        match &mut self.data {
+            MemData::Owned(data) => data.copy_within(range, dst),
+            MemData::Shared(shared) => shared.copy_within(src, dst, len)?,
+        }
+        Result::Ok(())
+    }
+
+    pub fn fill(&mut self, offset: usize, len: usize, value: u8) -> Result<(), err::Err> {
+        let range = self.range(offset, len)?;
+        match &mut self.data {
+            MemData::Owned(data) => data[range].fill(value),
+            MemData::Shared(shared) => shared.write(offset, &vec![value; len])?,
+        }
+        Result::Ok(())
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), err::Err> {
+        let range = self.range(offset, buf.len())?;
+        match &self.data {
+            MemData::Owned(data) => buf.copy_from_slice(&data[range]),
+            MemData::Shared(shared) => shared.read(offset, buf)?,
+        }
+        Result::Ok(())
+    }
+
+    pub fn write(&mut self, offset: usize, bytes: &[u8]) -> Result<(), err::Err> {
+        let range = self.range(offset, bytes.len())?;
+        match &mut self.data {
+            MemData::Owned(data) => data[range].copy_from_slice(bytes),
+            MemData::Shared(shared) => shared.write(offset, bytes)?,
+        }
+        Result::Ok(())
+    }
+
+    pub fn load<T: LeBytes>(&self, offset: usize) -> Result<T, err::Err> {
+        let mut bytes = [0; 8];
+        self.read(offset, &mut bytes[..T::SIZE])?;
+        Result::Ok(T::from_le_slice(&bytes[..T::SIZE]))
+    }
+
+    pub fn store<T: LeBytes>(&mut self, offset: usize, value: T) -> Result<(), err::Err> {
+        let mut bytes = [0; 8];
+        value.write_le_slice(&mut bytes[..T::SIZE]);
+        self.write(offset, &bytes[..T::SIZE])
+    }
+
+    // Atomic accesses of `size` bytes, which must be naturally aligned. Values are zero-extended.
+
+    fn check_atomic(&self, offset: usize, size: usize) -> Result<(), err::Err> {
+        self.range(offset, size)?;
+        if !offset.is_multiple_of(size) {
+            return Result::Err(err::Err::TrapUnalignedAtomic);
+        }
+        Result::Ok(())
+    }
+
+    pub fn atomic_load(&self, offset: usize, size: usize) -> Result<u64, err::Err> {
+        if let MemData::Shared(shared) = &self.data {
+            return shared.atomic_load(offset, size);
+        }
+        self.check_atomic(offset, size)?;
+        let mut bytes = [0; 8];
+        self.read(offset, &mut bytes[..size])?;
+        Result::Ok(u64::from_le_bytes(bytes))
+    }
+
+    // Replaces the value by `f` of it, returning the previous one
+    pub fn atomic_rmw(
+        &mut self,
+        offset: usize,
+        size: usize,
+        f: impl Fn(u64) -> u64,
+    ) -> Result<u64, err::Err> {
+        if let MemData::Shared(shared) = &self.data {
+            return shared.atomic_rmw(offset, size, f);
+        }
+        let old = self.atomic_load(offset, size)?;
+        self.write(offset, &f(old).to_le_bytes()[..size])?;
+        Result::Ok(old)
+    }
+
+    // Unshared memories have no waiters to notify, but the address is still checked
+    pub fn atomic_notify(&self, offset: usize, count: u32) -> Result<u32, err::Err> {
+        match &self.data {
+            MemData::Owned(_) => self.check_atomic(offset, 4).map(|_| 0),
+            MemData::Shared(shared) => shared.notify(offset, count),
+        }
+    }
+
+    pub fn shared(&self) -> Option<&SharedMemory> {
+        match &self.data {
+            MemData::Owned(_) => None,
+            MemData::Shared(shared) => Some(shared),
+        }
+    }
+}
+
+fn borrow(store: &Store, mem: MemId) -> Result<cell::Ref<'_, Mem>, err::Err> {
+    store
+        .mem(mem)?
+        .try_borrow()
+        .map_err(|_| err::Err::MemoryBorrowed)
+}
+
+fn borrow_mut(store: &Store, mem: MemId) -> Result<cell::RefMut<'_, Mem>, err::Err> {
+    store
+        .mem(mem)?
+        .try_borrow_mut()
+        .map_err(|_| err::Err::MemoryBorrowed)
+}
+
+// Guest pointer to a `T`, an offset in the memory it is used with. Host functions can take it as
+// an `i32` parameter.
+pub struct WasmPtr<T> {
+    offset: u32,
+    marker: PhantomData<T>,
+}
+
+impl<T> Clone for WasmPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for WasmPtr<T> {}
+
+impl<T> PartialEq for WasmPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> core::fmt::Debug for WasmPtr<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "WasmPtr({:#x})", self.offset)
+    }
+}
+
+impl<T> WasmTy for WasmPtr<T> {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == Self::VALTYPE
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self.offset as Slot)
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        WasmPtr::new(slot as u32)
+    }
+}
+
+impl<T> WasmValType for WasmPtr<T> {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::I32);
+}
+
+impl<T> WasmPtr<T> {
+    pub fn new(offset: u32) -> Self {
+        WasmPtr {
+            offset,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.offset == 0
+    }
+
+    pub fn cast<U>(self) -> WasmPtr<U> {
+        WasmPtr::new(self.offset)
+    }
+
+    // The `len` elements starting at this pointer
+    pub fn slice(self, len: u32) -> WasmSlice<T> {
+        WasmSlice { ptr: self, len }
+    }
+}
+
+impl<T: Pod> WasmPtr<T> {
+    // Pointer to the `index`-th `T` from this one
+    pub fn offset_by(self, index: u32) -> Result<Self, err::Err> {
+        (index as usize)
+            .checked_mul(T::SIZE)
+            .and_then(|len| u32::try_from(len).ok())
+            .and_then(|len| self.offset.checked_add(len))
+            .map(WasmPtr::new)
+            .ok_or(err::Err::OutOfBoundMemoryAccess)
+    }
+
+    // Values go through a buffer, shared memories cannot be sliced
+    pub fn read(self, store: &Store, mem: MemId) -> Result<T, err::Err> {
+        let mut bytes = vec![0; T::SIZE];
+        borrow(store, mem)?.read(self.offset as usize, &mut bytes)?;
+        Result::Ok(T::from_bytes(&bytes))
+    }
+
+    pub fn write(self, store: &Store, mem: MemId, value: T) -> Result<(), err::Err> {
+        let mut bytes = vec![0; T::SIZE];
+        value.write_bytes(&mut bytes);
+        borrow_mut(store, mem)?.write(self.offset as usize, &bytes)
+    }
+}
+
+impl WasmPtr<u8> {
+    // Reads the NUL-terminated UTF-8 string at this pointer
+    pub fn read_c_str(self, store: &Store, mem: MemId) -> Result<String, err::Err> {
+        let mem = borrow(store, mem)?;
+        let mut offset = self.offset as usize;
+        let mut bytes = vec![];
+        // Read by chunks until the terminator or the end of memory
+        let mut chunk = [0; 64];
+        loop {
+            let len = chunk.len().min(mem.size().saturating_sub(offset));
+            if len == 0 {
+                return Result::Err(err::Err::OutOfBoundMemoryAccess);
+            }
+            mem.read(offset, &mut chunk[..len])?;
+            match chunk[..len].iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&chunk[..end]);
+                    break;
+                }
+                None => bytes.extend_from_slice(&chunk[..len]),
+            }
+            offset += len;
+        }
+        String::from_utf8(bytes).map_err(|_| err::Err::InvalidUtf8)
+    }
+}
+
+// Guest array of `len` elements of type `T`, as described by a `(ptr, len)` pair
+pub struct WasmSlice<T> {
+    ptr: WasmPtr<T>,
+    len: u32,
+}
+
+impl<T> Clone for WasmSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for WasmSlice<T> {}
+
+impl<T> core::fmt::Debug for WasmSlice<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "WasmSlice({:#x}, {})", self.ptr.offset, self.len)
+    }
+}
+
+impl<T> WasmSlice<T> {
+    pub fn new(offset: u32, len: u32) -> Self {
+        WasmPtr::new(offset).slice(len)
+    }
+
+    pub fn ptr(&self) -> WasmPtr<T> {
+        self.ptr
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: Pod> WasmSlice<T> {
+    fn byte_len(&self) -> Result<usize, err::Err> {
+        (self.len as usize)
+            .checked_mul(T::SIZE)
+            .ok_or(err::Err::OutOfBoundMemoryAccess)
+    }
+
+    fn with_bytes<R>(
+        &self,
+        store: &Store,
+        mem: MemId,
+        f: impl FnOnce(&[u8]) -> Result<R, err::Err>,
+    ) -> Result<R, err::Err> {
+        let mem = borrow(store, mem)?;
+        // Checked before the buffer is allocated
+        let range = mem.range(self.ptr.offset as usize, self.byte_len()?)?;
+        let mut bytes = vec![0; range.len()];
+        mem.read(range.start, &mut bytes)?;
+        f(&bytes)
+    }
+
+    pub fn get(&self, index: u32) -> Result<WasmPtr<T>, err::Err> {
+        if index >= self.len {
+            return Result::Err(err::Err::OutOfBoundMemoryAccess);
+        }
+        self.ptr.offset_by(index)
+    }
+
+    pub fn read(&self, store: &Store, mem: MemId, index: u32) -> Result<T, err::Err> {
+        self.get(index)?.read(store, mem)
+    }
+
+    pub fn write(&self, store: &Store, mem: MemId, index: u32, value: T) -> Result<(), err::Err> {
+        self.get(index)?.write(store, mem, value)
+    }
+
+    pub fn read_to_vec(&self, store: &Store, mem: MemId) -> Result<Vec<T>, err::Err> {
+        self.with_bytes(store, mem, |bytes| {
+            Result::Ok(bytes.chunks_exact(T::SIZE).map(T::from_bytes).collect())
+        })
+    }
+
+    // Writes `values` at the start of the slice, which must be long enough to hold them
+    pub fn write_slice(&self, store: &Store, mem: MemId, values: &[T]) -> Result<(), err::Err> {
+        if values.len() > self.len as usize {
+            return Result::Err(err::Err::OutOfBoundMemoryAccess);
+        }
+        let mut bytes = vec![0; values.len() * T::SIZE];
+        for (value, bytes) in values.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+            value.write_bytes(bytes);
+        }
+        borrow_mut(store, mem)?.write(self.ptr.offset as usize, &bytes)
+    }
+}
+
+impl WasmSlice<u8> {
+    pub fn read_utf8(&self, store: &Store, mem: MemId) -> Result<String, err::Err> {
+        self.with_bytes(store, mem, |bytes| {
+            let s = core::str::from_utf8(bytes).map_err(|_| err::Err::InvalidUtf8)?;
+            Result::Ok(String::from(s))
+        })
+    }
+}
+
+impl WasmSlice<u16> {
+    // Reads the little-endian UTF-16 string of `len` code units
+    pub fn read_utf16(&self, store: &Store, mem: MemId) -> Result<String, err::Err> {
+        self.with_bytes(store, mem, |bytes| {
+            let units = bytes.chunks_exact(2).map(u16::from_bytes);
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .map_err(|_| err::Err::InvalidUtf16)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        embedding::Store as _,
+        host::Caller,
+        runtime::{Num, Val, PAGE_SIZE},
+    };
+
+    use super::*;
+
+    fn mem(pages: usize) -> Mem {
+        Mem {
+            memtype: types::Mem {
+                limits: types::Limits {
+                    min: pages,
+                    max: None,
+                },
+                index: types::IndexType::I32,
+                shared: false,
+            },
+            data: MemData::Owned(vec![0; pages * PAGE_SIZE]),
+        }
+    }
+
+    #[test]
+    fn typed_access() -> Result<(), err::Err> {
+        let mut mem = mem(1);
+        mem.store(8, 0x0102_0304u32)?;
+        assert_eq!(mem.slice(8, 4)?, &[4, 3, 2, 1]);
+        assert_eq!(mem.load::<u16>(9)?, 0x0203);
+        mem.store(16, -1.5f64)?;
+        assert_eq!(mem.load::<f64>(16)?, -1.5);
+        mem.store(PAGE_SIZE - 1, -2i8)?;
+        assert_eq!(mem.load::<u8>(PAGE_SIZE - 1)?, 0xfe);
+        Ok(())
+    }
+
+    #[test]
+    fn bounds() -> Result<(), err::Err> {
+        let mut mem = mem(1);
+        mem.write(PAGE_SIZE - 3, b"abc")?;
+        let mut buf = [0; 3];
+        mem.read(PAGE_SIZE - 3, &mut buf)?;
+        assert_eq!(&buf, b"abc");
+
+        assert!(matches!(
+            mem.write(PAGE_SIZE - 2, b"abc"),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            mem.load::<u32>(PAGE_SIZE - 3),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            mem.slice(usize::MAX, 2),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        let empty = self::mem(0);
+        assert!(matches!(
+            empty.load::<u8>(0),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        // Nothing was written by the failed access
+        assert_eq!(mem.slice(PAGE_SIZE - 2, 2)?, b"bc");
+        Ok(())
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Iovec {
+        base: WasmPtr<u8>,
+        len: u32,
+    }
+
+    impl_pod!(Iovec { base: WasmPtr<u8>, len: u32 });
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Mixed {
+        a: u8,
+        b: u64,
+        c: [u16; 3],
+    }
+
+    impl_pod!(Mixed {
+        a: u8,
+        b: u64,
+        c: [u16; 3],
+    });
+
+    fn store_with_memory() -> (Store, MemId) {
+        let mut store = Store::new();
+        let memory = store
+            .mem_alloc(types::Mem {
+                limits: types::Limits { min: 1, max: None },
+                index: types::IndexType::I32,
+                shared: false,
+            })
+            .unwrap();
+        (store, memory)
+    }
+
+    #[test]
+    fn struct_layout() -> Result<(), err::Err> {
+        assert_eq!(Mixed::SIZE, 24);
+        assert_eq!(Mixed::ALIGN, 8);
+        assert_eq!(Iovec::SIZE, 8);
+
+        let (store, memory) = store_with_memory();
+        let mixed = Mixed {
+            a: 1,
+            b: 0x0203_0405_0607_0809,
+            c: [10, 11, 12],
+        };
+        let ptr = WasmPtr::<Mixed>::new(64);
+        ptr.write(&store, memory, mixed)?;
+        assert_eq!(ptr.read(&store, memory)?, mixed);
+        let mut bytes = [0; 24];
+        store.mem_read_bytes(memory, 64, &mut bytes)?;
+        assert_eq!(
+            bytes,
+            [1, 0, 0, 0, 0, 0, 0, 0, 9, 8, 7, 6, 5, 4, 3, 2, 10, 0, 11, 0, 12, 0, 0, 0]
+        );
+
+        let iovecs = WasmSlice::<Iovec>::new(128, 2);
+        let values = [
+            Iovec {
+                base: WasmPtr::new(256),
+                len: 5,
+            },
+            Iovec {
+                base: WasmPtr::new(512),
+                len: 3,
+            },
+        ];
+        iovecs.write_slice(&store, memory, &values)?;
+        assert_eq!(iovecs.read_to_vec(&store, memory)?, values);
+        assert_eq!(iovecs.read(&store, memory, 1)?, values[1]);
+        assert!(matches!(
+            iovecs.read(&store, memory, 2),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            WasmPtr::<Mixed>::new(PAGE_SIZE as u32 - 16).read(&store, memory),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            WasmSlice::<u64>::new(0, u32::MAX).read_to_vec(&store, memory),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn strings() -> Result<(), err::Err> {
+        let (store, memory) = store_with_memory();
+        let end = PAGE_SIZE as u32;
+        store.mem_write_bytes(memory, 0, "héllo\0".as_bytes())?;
+        store.mem_write_bytes(memory, 16, &[0xff, 0xfe, 0])?;
+        let utf16: Vec<u16> = "wörld".encode_utf16().collect();
+        WasmSlice::new(32, 5).write_slice(&store, memory, &utf16)?;
+        WasmSlice::new(48, 1).write_slice(&store, memory, &[0xd800u16])?;
+        store.mem_write_bytes(memory, end as usize - 2, b"ab")?;
+
+        assert_eq!(WasmSlice::new(0, 6).read_utf8(&store, memory)?, "héllo");
+        assert_eq!(WasmPtr::new(0).read_c_str(&store, memory)?, "héllo");
+        assert_eq!(WasmSlice::new(32, 5).read_utf16(&store, memory)?, "wörld");
+
+        assert!(matches!(
+            WasmSlice::new(16, 2).read_utf8(&store, memory),
+            Err(err::Err::InvalidUtf8)
+        ));
+        assert!(matches!(
+            WasmPtr::new(16).read_c_str(&store, memory),
+            Err(err::Err::InvalidUtf8)
+        ));
+        assert!(matches!(
+            WasmSlice::new(48, 1).read_utf16(&store, memory),
+            Err(err::Err::InvalidUtf16)
+        ));
+        // Unterminated or out of bounds
+        assert!(matches!(
+            WasmPtr::new(end - 2).read_c_str(&store, memory),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            WasmPtr::new(end + 1).read_c_str(&store, memory),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert!(matches!(
+            WasmSlice::new(end - 1, 2).read_utf8(&store, memory),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn shared_memory_helpers() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let memory = store.mem_alloc(types::Mem {
+            limits: types::Limits {
+                min: 1,
+                max: Some(1),
+            },
+            index: types::IndexType::I32,
+            shared: true,
+        })?;
+        let ptr = WasmPtr::<u64>::new(8);
+        ptr.write(&store, memory, 0x0102_0304_0506_0708)?;
+        assert_eq!(ptr.read(&store, memory)?, 0x0102_0304_0506_0708);
+        let slice = WasmSlice::<u16>::new(32, 3);
+        slice.write_slice(&store, memory, &[1, 2, 3])?;
+        assert_eq!(slice.read_to_vec(&store, memory)?, [1, 2, 3]);
+
+        // Strings longer than a chunk
+        let long = "a".repeat(100);
+        store.mem_write_bytes(memory, 64, long.as_bytes())?;
+        assert_eq!(WasmPtr::new(64).read_c_str(&store, memory)?, long);
+        Ok(())
+    }
+
+    #[test]
+    fn host_function_arguments() -> Result<(), err::Err> {
+        let (mut store, memory) = store_with_memory();
+        store.mem_write_bytes(memory, 100, b"guest")?;
+        let greet = store.func_wrap(move |caller: Caller, ptr: WasmPtr<u8>, len: u32| {
+            let name = ptr.slice(len).read_utf8(caller.store(), memory)?;
+            let len = name.len() as u32 + 6;
+            WasmSlice::new(200, len).write_slice(
+                caller.store(),
+                memory,
+                format!("hello {name}").as_bytes(),
+            )?;
+            Ok(len)
+        });
+        let res = store.invoke(greet, vec![Val::Num(Num::I32(100)), Val::Num(Num::I32(5))])?;
+        assert_eq!(res, vec![Val::Num(Num::I32(11))]);
+        assert_eq!(
+            WasmSlice::new(200, 11).read_utf8(&store, memory)?,
+            "hello guest"
+        );
+        Ok(())
+    }
+}
@@ -1,11 +1,19 @@
+use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use crate::binary;
+use crate::bytecode;
+use crate::config::{Feature, Features};
 use crate::embedding;
+use crate::err;
+use crate::host::Caller;
 use crate::instr;
+use crate::runtime::Slot;
 use crate::types;
 use crate::types::Index;
-use crate::validation::Validable;
+use crate::validation::{Context, Validable};
+use crate::vm::Trap;
 
 pub struct Module {
     pub types: Vec<types::Function>,
@@ -13,6 +21,7 @@ pub struct Module {
     pub tables: Vec<Table>,
     pub mems: Vec<Mem>,
     pub globals: Vec<Global>,
+    pub tags: Vec<Tag>,
     pub elems: Vec<Element>,
     pub datas: Vec<Data>,
     pub start: Option<Index>,
@@ -27,8 +36,23 @@ pub struct Func {
     pub body: instr::Expr,
 }
 
-#[derive(Clone, Copy)]
-pub struct HostFunc {}
+// The signature a host function is called with: its arguments are the topmost slots, which
+// it replaces with its results. Values are untagged, their types are those the host function
+// is allocated with.
+pub type HostFn = dyn Fn(Caller, &mut Vec<Slot>) -> Result<(), Trap>;
+
+#[derive(Clone)]
+pub struct HostFunc {
+    pub func: Rc<HostFn>,
+}
+
+impl HostFunc {
+    pub fn new(func: impl Fn(Caller, &mut Vec<Slot>) -> Result<(), Trap> + 'static) -> HostFunc {
+        HostFunc {
+            func: Rc::new(func),
+        }
+    }
+}
 
 pub struct Table {
     pub tabletype: types::Table,
@@ -40,7 +64,12 @@ pub struct Mem {
 
 pub struct Global {
     pub globaltype: types::Global,
-    pub init: instr::Expr, // TODO Validation: constant expression
+    pub init: instr::Expr,
+}
+
+// Exceptions are thrown with a tag, whose type gives their payload
+pub struct Tag {
+    pub tagtype: Index,
 }
 
 pub enum ElemMode {
@@ -70,6 +99,7 @@ pub enum ExportDesc {
     Table(Index),
     Mem(Index),
     Global(Index),
+    Tag(Index),
 }
 
 pub struct Export {
@@ -82,32 +112,375 @@ pub enum ImportDesc {
     Table(types::Table),
     Mem(types::Mem),
     Global(types::Global),
+    Tag(Index),
 }
 
 pub struct Import {
-    module: String,
-    name: String,
-    desc: ImportDesc,
+    pub module: String,
+    pub name: String,
+    pub desc: ImportDesc,
 }
 
-impl embedding::Module for Module {
-    fn decode(bytes_: &Vec<u8>) -> Result<Self, crate::err::Err> {
-        todo!()
+// An import of a module, with the type of the external value it expects
+#[derive(Clone, Debug)]
+pub struct ImportType<'m> {
+    pub module: &'m str,
+    pub name: &'m str,
+    pub ty: types::Extern,
+}
+
+// An export of a module, with the type of the external value it provides
+#[derive(Clone, Debug)]
+pub struct ExportType<'m> {
+    pub name: &'m str,
+    pub ty: types::Extern,
+}
+
+impl Module {
+    // Type indices are resolved, so that imports can be checked before instantiation. Modules are
+    // not validated, an out of range index is reported for the entry it appears in.
+    pub fn imports(&self) -> impl Iterator<Item = Result<ImportType<'_>, err::Err>> {
+        self.imports.iter().map(|import| {
+            let ty = match import.desc {
+                ImportDesc::Func(idx) => types::Extern::Func(self.functype(idx)?),
+                ImportDesc::Table(tabletype) => types::Extern::Table(tabletype),
+                ImportDesc::Mem(memtype) => types::Extern::Mem(memtype),
+                ImportDesc::Global(globaltype) => types::Extern::Global(globaltype),
+                ImportDesc::Tag(idx) => types::Extern::Tag(self.functype(idx)?),
+            };
+            Result::Ok(ImportType {
+                module: &import.module,
+                name: &import.name,
+                ty,
+            })
+        })
+    }
+
+    pub fn exports(&self) -> impl Iterator<Item = Result<ExportType<'_>, err::Err>> {
+        self.exports.iter().map(|export| {
+            let ty = match export.desc {
+                ExportDesc::Func(idx) => types::Extern::Func(self.func_type(idx)?),
+                ExportDesc::Table(idx) => types::Extern::Table(self.table_type(idx)?),
+                ExportDesc::Mem(idx) => types::Extern::Mem(self.mem_type(idx)?),
+                ExportDesc::Global(idx) => types::Extern::Global(self.global_type(idx)?),
+                ExportDesc::Tag(idx) => types::Extern::Tag(self.tag_type(idx)?),
+            };
+            Result::Ok(ExportType {
+                name: &export.name,
+                ty,
+            })
+        })
+    }
+
+    fn functype(&self, idx: Index) -> Result<types::Function, err::Err> {
+        let functype = self.types.get(idx).ok_or(err::Err::InvalidIndex(idx))?;
+        Result::Ok(functype.clone())
+    }
+
+    // Types by index in each index space, imports coming first
+
+    pub fn func_type(&self, idx: Index) -> Result<types::Function, err::Err> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Func(idx) => Some(idx),
+            _ => None,
+        });
+        let defined = self.funcs.iter().map(|func| func.functype);
+        let functype = imported.chain(defined).nth(idx);
+        self.functype(functype.ok_or(err::Err::InvalidIndex(idx))?)
     }
 
-    fn parse(source: &str) -> Result<Self, crate::err::Err> {
-        todo!()
+    pub fn table_type(&self, idx: Index) -> Result<types::Table, err::Err> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Table(tabletype) => Some(tabletype),
+            _ => None,
+        });
+        let defined = self.tables.iter().map(|table| table.tabletype);
+        imported
+            .chain(defined)
+            .nth(idx)
+            .ok_or(err::Err::InvalidIndex(idx))
     }
 
-    fn validate(&self) -> Result<(), crate::err::Err> {
-        todo!()
+    pub fn mem_type(&self, idx: Index) -> Result<types::Mem, err::Err> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Mem(memtype) => Some(memtype),
+            _ => None,
+        });
+        let defined = self.mems.iter().map(|mem| mem.memtype);
+        imported
+            .chain(defined)
+            .nth(idx)
+            .ok_or(err::Err::InvalidIndex(idx))
+    }
+
+    pub fn global_type(&self, idx: Index) -> Result<types::Global, err::Err> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Global(globaltype) => Some(globaltype),
+            _ => None,
+        });
+        let defined = self.globals.iter().map(|global| global.globaltype);
+        imported
+            .chain(defined)
+            .nth(idx)
+            .ok_or(err::Err::InvalidIndex(idx))
+    }
+
+    pub fn tag_type(&self, idx: Index) -> Result<types::Function, err::Err> {
+        let imported = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Tag(idx) => Some(idx),
+            _ => None,
+        });
+        let defined = self.tags.iter().map(|tag| tag.tagtype);
+        let tagtype = imported.chain(defined).nth(idx);
+        self.functype(tagtype.ok_or(err::Err::InvalidIndex(idx))?)
+    }
+
+    // Proposals the module makes use of
+    pub fn features(&self) -> Features {
+        let mut features = Features::mvp();
+        let mut require = |feature| {
+            features.set(feature, true);
+        };
+
+        let valtype_feature = |valtype: &types::Value| match valtype {
+            types::Value::Num(_) => None,
+            types::Value::Vec(_) => Some(Feature::Simd),
+            types::Value::Ref(types::Ref {
+                nullable: false, ..
+            })
+            | types::Value::Ref(types::Ref {
+                heap: types::Heap::Type(_),
+                ..
+            }) => Some(Feature::FunctionReferences),
+            types::Value::Ref(types::Ref::EXN) => Some(Feature::ExceptionHandling),
+            types::Value::Ref(_) => Some(Feature::ReferenceTypes),
+        };
+        let globaltypes = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Global(globaltype) => Some(globaltype),
+            _ => None,
+        });
+        let valtypes = self
+            .types
+            .iter()
+            .flat_map(|functype| functype.input.iter().chain(&functype.output))
+            .chain(self.funcs.iter().flat_map(|func| &func.locals))
+            .copied()
+            .chain(globaltypes.map(|globaltype| globaltype.val))
+            .chain(self.globals.iter().map(|global| global.globaltype.val));
+        valtypes
+            .filter_map(|valtype| valtype_feature(&valtype))
+            .for_each(&mut require);
+        if self.types.iter().any(|functype| functype.output.len() > 1) {
+            require(Feature::MultiValue);
+        }
+
+        // The MVP allows a single table of functions and a single memory
+        let tables = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Table(tabletype) => Some(tabletype),
+            _ => None,
+        });
+        let tables: Vec<types::Table> = tables
+            .chain(self.tables.iter().map(|table| table.tabletype))
+            .collect();
+        if tables.len() > 1 || tables.iter().any(|table| table.reftype != types::Ref::FUNC) {
+            require(Feature::ReferenceTypes);
+        }
+        let mems = self.imports.iter().filter_map(|import| match import.desc {
+            ImportDesc::Mem(memtype) => Some(memtype),
+            _ => None,
+        });
+        let mems: Vec<types::Mem> = mems
+            .chain(self.mems.iter().map(|mem| mem.memtype))
+            .collect();
+        if mems.len() > 1 {
+            require(Feature::MultiMemory);
+        }
+        if mems.iter().any(|mem| mem.index == types::IndexType::I64) {
+            require(Feature::Memory64);
+        }
+        if mems.iter().any(|mem| mem.shared) {
+            require(Feature::Threads);
+        }
+
+        let imports_tag = self
+            .imports
+            .iter()
+            .any(|import| matches!(import.desc, ImportDesc::Tag(_)));
+        if imports_tag || !self.tags.is_empty() {
+            require(Feature::ExceptionHandling);
+        }
+
+        for func in &self.funcs {
+            func.body
+                .iter()
+                .filter_map(instr::Instr::feature)
+                .for_each(&mut require);
+        }
+
+        // Constant expressions are extended by arithmetic instructions
+        let offsets = self.elems.iter().filter_map(|elem| match &elem.mode {
+            ElemMode::Active(_, offset) => Some(offset),
+            _ => None,
+        });
+        let offsets = offsets.chain(self.datas.iter().filter_map(|data| match &data.mode {
+            DataMode::Active(_, offset) => Some(offset),
+            _ => None,
+        }));
+        let exprs = self
+            .globals
+            .iter()
+            .map(|global| &global.init)
+            .chain(self.elems.iter().flat_map(|elem| &elem.init))
+            .chain(offsets);
+        for expr in exprs {
+            if expr.iter().any(|instr| {
+                matches!(
+                    instr,
+                    instr::Instr::I32Add
+                        | instr::Instr::I32Sub
+                        | instr::Instr::I32Mul
+                        | instr::Instr::I64Add
+                        | instr::Instr::I64Sub
+                        | instr::Instr::I64Mul
+                )
+            }) {
+                require(Feature::ExtendedConst);
+            }
+            expr.iter()
+                .filter_map(instr::Instr::feature)
+                .for_each(&mut require);
+        }
+
+        // Passive segments come with bulk memory, declarative ones with reference types
+        for elem in &self.elems {
+            match elem.mode {
+                ElemMode::Passive => require(Feature::BulkMemory),
+                ElemMode::Declarative => require(Feature::ReferenceTypes),
+                ElemMode::Active(..) => {}
+            }
+        }
+        if self
+            .datas
+            .iter()
+            .any(|data| matches!(data.mode, DataMode::Passive))
+        {
+            require(Feature::BulkMemory);
+        }
+        features
+    }
+
+    pub fn check_features(&self, enabled: &Features) -> Result<(), err::Err> {
+        match enabled.missing(&self.features()) {
+            Some(feature) => Result::Err(err::Err::FeatureDisabled(feature)),
+            None => Result::Ok(()),
+        }
+    }
+
+    // Types of the entities of the module by index in each index space, imports coming first.
+    // Type indices must be in range.
+    pub fn context(&self) -> Context {
+        let mut context = Context {
+            types: self.types.clone(),
+            ..Context::default()
+        };
+        for import in &self.imports {
+            match import.desc {
+                ImportDesc::Func(idx) => context.funcs.push(self.types[idx].clone()),
+                ImportDesc::Table(tabletype) => context.tables.push(tabletype),
+                ImportDesc::Mem(memtype) => context.mems.push(memtype),
+                ImportDesc::Global(globaltype) => context.globals.push(globaltype),
+                ImportDesc::Tag(idx) => context.tags.push(self.types[idx].clone()),
+            }
+        }
+        let funcs = self.funcs.iter().map(|func| &self.types[func.functype]);
+        context.funcs.extend(funcs.cloned());
+        let tables = self.tables.iter().map(|table| table.tabletype);
+        context.tables.extend(tables);
+        context.mems.extend(self.mems.iter().map(|mem| mem.memtype));
+        let globals = self.globals.iter().map(|global| global.globaltype);
+        context.globals.extend(globals);
+        let tags = self.tags.iter().map(|tag| &self.types[tag.tagtype]);
+        context.tags.extend(tags.cloned());
+        context.elems = self.elems.iter().map(|elem| elem.elemtype).collect();
+        context.data = vec![(); self.datas.len()];
+        context
     }
 }
 
-// Validation
+impl embedding::Module for Module {
+    fn decode(bytes_: &[u8], features: &Features) -> Result<Self, crate::err::Err> {
+        binary::decode(bytes_, features)
+    }
+
+    // The text format is encoded to the binary one first
+    fn parse(source: &str, features: &Features) -> Result<Self, crate::err::Err> {
+        let buffer = wast::parser::ParseBuffer::new(source).map_err(|_| err::Err::ModuleParse)?;
+        let mut wat =
+            wast::parser::parse::<wast::Wat>(&buffer).map_err(|_| err::Err::ModuleParse)?;
+        let bytes_ = wat.encode().map_err(|_| err::Err::ModuleParse)?;
+        binary::decode(&bytes_, features)
+    }
+
+    // Indices must be in range and function bodies must be well-typed
+    fn validate(&self, features: &Features) -> Result<(), crate::err::Err> {
+        self.check_features(features)?;
+        for (idx, functype) in self.types.iter().enumerate() {
+            // Types may only refer to those defined before them
+            let earlier: Vec<Index> = (0..idx).collect();
+            functype.remap(&earlier)?;
+        }
+        for import in self.imports() {
+            import?;
+        }
+        for func in &self.funcs {
+            self.functype(func.functype)?;
+        }
+        for tag in &self.tags {
+            self.functype(tag.tagtype)?;
+        }
+        for export in self.exports() {
+            export?;
+        }
+        if let Some(start) = self.start {
+            self.func_type(start)?;
+        }
 
-impl Validable for Module {
-    fn is_valid(&self, context: &crate::validation::Context, k: Option<types::Int>) -> bool {
-        todo!()
+        let context = self.context();
+        // Sizes must be addressable, shared memories declaring their maximum
+        for table in &context.tables {
+            if !table.limits.is_valid(&context, Some(u32::MAX as usize)) {
+                return Result::Err(err::Err::InvalidLimit(table.limits));
+            }
+        }
+        for mem in &context.mems {
+            if !mem.is_valid(&context, None) {
+                return Result::Err(err::Err::InvalidLimit(mem.limits));
+            }
+        }
+        // Initializers are constant and may only read the globals preceding theirs
+        let imported = context.globals.len() - self.globals.len();
+        for (idx, global) in self.globals.iter().enumerate() {
+            let context = Context {
+                globals: context.globals[..imported + idx].to_vec(),
+                ..context.clone()
+            };
+            if !global.init.iter().all(|instr| instr.is_constant(&context)) {
+                return Result::Err(err::Err::InvalidCode);
+            }
+            let functype = types::Function {
+                input: vec![],
+                output: vec![global.globaltype.val],
+            };
+            bytecode::compile(&context, &functype, &[], &global.init)?;
+        }
+        for func in &self.funcs {
+            bytecode::compile(
+                &context,
+                &self.types[func.functype],
+                &func.locals,
+                &func.body,
+            )?;
+        }
+        Result::Ok(())
     }
 }
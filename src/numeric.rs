@@ -27,12 +27,11 @@ pub trait SupportedInteger<
     fn zero() -> Self;
     fn one() -> Self;
     fn len() -> Self;
-    fn max() -> Self;
     fn to_signed(self) -> Signed;
     fn from_signed(val: Signed) -> Self;
     fn clz(self) -> Self {
         if self == Self::zero() {
-            return Self::len();
+            Self::len()
         } else {
             let mask = Self::one() << (Self::len() - Self::one());
             let mut x = self;
@@ -41,12 +40,12 @@ pub trait SupportedInteger<
                 count += Self::one();
                 x = x << Self::one();
             }
-            return count;
+            count
         }
     }
     fn ctz(self) -> Self {
         if self == Self::zero() {
-            return Self::len();
+            Self::len()
         } else {
             let mask = Self::one();
             let mut x = self;
@@ -55,7 +54,7 @@ pub trait SupportedInteger<
                 count += Self::one();
                 x = x >> Self::one();
             }
-            return count;
+            count
         }
     }
     fn popcnt(self) -> Self {
@@ -69,7 +68,7 @@ pub trait SupportedInteger<
             }
             count += Self::one();
         }
-        return count;
+        count
     }
     fn eqz(self) -> bool {
         Self::eq(&self, &Self::zero())
@@ -124,11 +123,11 @@ pub trait SupportedInteger<
     }
     fn rotl(v1: Self, v2: Self) -> Self {
         let n = v2 % Self::len();
-        (v1 << n) | (v1 >> (Self::len() - n) % Self::len())
+        (v1 << n) | (v1 >> ((Self::len() - n) % Self::len()))
     }
     fn rotr(v1: Self, v2: Self) -> Self {
         let n = v2 % Self::len();
-        (v1 >> n) | (v1 << (Self::len() - n) % Self::len())
+        (v1 >> n) | (v1 << ((Self::len() - n) % Self::len()))
     }
 }
 
@@ -145,10 +144,6 @@ impl SupportedInteger<i32> for u32 {
         32
     }
 
-    fn max() -> Self {
-        u32::MAX
-    }
-
     fn to_signed(self) -> i32 {
         self as i32
     }
@@ -171,10 +166,6 @@ impl SupportedInteger<i64> for u64 {
         64
     }
 
-    fn max() -> Self {
-        u64::MAX
-    }
-
     fn to_signed(self) -> i64 {
         self as i64
     }
@@ -1,11 +1,23 @@
-use core::cell::RefCell;
+use core::{
+    any::Any,
+    cell::{self, Cell, RefCell},
+    ops::{Index, IndexMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use alloc::{string::String, vec::Vec};
+use alloc::{boxed::Box, rc::Rc, string::String, vec::Vec};
 
 use crate::{
-    instr::Instr,
-    modules::Func,
+    bytecode,
+    config::Config,
+    err,
+    instr::InstrClass,
+    modules::{Func, HostFunc},
+    shared::SharedMemory,
+    typed::ExternRef,
     types::{self, Addr},
+    validation::{Context, Subtypable},
+    vm::Trap,
 };
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -16,11 +28,20 @@ pub enum Num {
     F64(f64),
 }
 
+// References to objects are handles, so that one outliving its object is told apart from a
+// reference to the object reusing its slot
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Ref {
-    Null(types::Ref),
-    Func(Addr),
-    Extern(Addr),
+    Null(types::Heap),
+    Func(Handle),
+    Extern(Handle),
+    Exn(Handle),
+}
+
+impl From<FuncId> for Ref {
+    fn from(func: FuncId) -> Self {
+        Ref::Func(func.0)
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -31,6 +52,16 @@ pub enum Val {
 }
 
 impl Val {
+    pub fn default(valtype: types::Value) -> Val {
+        match valtype {
+            types::Value::Num(types::Number::I32) => Val::default_i32(),
+            types::Value::Num(types::Number::I64) => Val::default_i64(),
+            types::Value::Num(types::Number::F32) => Val::default_f32(),
+            types::Value::Num(types::Number::F64) => Val::default_f64(),
+            types::Value::Vec(_) => Val::default_vec(),
+            types::Value::Ref(reftype) => Val::default_ref(reftype),
+        }
+    }
     fn default_i32() -> Val {
         Val::Num(Num::I32(0))
     }
@@ -46,51 +77,1080 @@ impl Val {
     fn default_vec() -> Val {
         Val::Vec(0)
     }
+    // Non-nullable references have no default, validation ensures they are set before use
     fn default_ref(reftype: types::Ref) -> Val {
-        Val::Ref(Ref::Null(reftype))
+        Val::Ref(Ref::Null(reftype.heap))
+    }
+
+    pub fn valtype(&self) -> types::Value {
+        match self {
+            Val::Num(Num::I32(_)) => types::Value::Num(types::Number::I32),
+            Val::Num(Num::I64(_)) => types::Value::Num(types::Number::I64),
+            Val::Num(Num::F32(_)) => types::Value::Num(types::Number::F32),
+            Val::Num(Num::F64(_)) => types::Value::Num(types::Number::F64),
+            Val::Vec(_) => types::Value::Vec(types::Vector::Unimplemented),
+            Val::Ref(Ref::Null(heap)) => types::Value::Ref(types::Ref::null(*heap)),
+            Val::Ref(Ref::Func(_)) => types::Value::Ref(types::Ref::non_null(types::Heap::Func)),
+            Val::Ref(Ref::Extern(_)) => {
+                types::Value::Ref(types::Ref::non_null(types::Heap::Extern))
+            }
+            Val::Ref(Ref::Exn(_)) => types::Value::Ref(types::Ref::non_null(types::Heap::Exn)),
+        }
+    }
+
+    // Erases the type of the value
+    pub fn to_slot(self) -> Result<Slot, err::Err> {
+        match self {
+            Val::Num(Num::I32(val)) => Result::Ok(val as Slot),
+            Val::Num(Num::I64(val)) => Result::Ok(val),
+            Val::Num(Num::F32(val)) => Result::Ok(val.to_bits() as Slot),
+            Val::Num(Num::F64(val)) => Result::Ok(val.to_bits()),
+            Val::Vec(_) => Result::Err(err::Err::UnsupportedValueType(self.valtype())),
+            Val::Ref(Ref::Null(_)) => Result::Ok(NULL_REF),
+            Val::Ref(Ref::Func(handle) | Ref::Extern(handle) | Ref::Exn(handle)) => {
+                Result::Ok(handle.addr as Slot)
+            }
+        }
+    }
+
+    // Recovers a value of type `valtype` from its slot, references to objects of `store`
+    pub fn from_slot(slot: Slot, valtype: types::Value, store: &Store) -> Val {
+        match valtype {
+            types::Value::Num(types::Number::I32) => Val::Num(Num::I32(slot as u32)),
+            types::Value::Num(types::Number::I64) => Val::Num(Num::I64(slot)),
+            types::Value::Num(types::Number::F32) => {
+                Val::Num(Num::F32(f32::from_bits(slot as u32)))
+            }
+            types::Value::Num(types::Number::F64) => Val::Num(Num::F64(f64::from_bits(slot))),
+            types::Value::Vec(_) => Val::Vec(slot as u128),
+            types::Value::Ref(reftype) if slot == NULL_REF => Val::Ref(Ref::Null(reftype.heap)),
+            types::Value::Ref(reftype) => Val::Ref(store.ref_at(reftype.heap, slot as Addr)),
+        }
     }
 }
 
+// Untagged representation of a value during execution. Numbers are kept as their bit pattern,
+// zero-extended, and references as the address they refer to or `NULL_REF`. Validated code
+// cannot mistype its operands, so types are only needed again at the embedding boundary.
+// Vector types are not implemented yet and have no slot representation.
+pub type Slot = u64;
+
+pub const NULL_REF: Slot = Slot::MAX;
+
 pub enum Res {
     Res(Vec<Val>),
     Trap,
 }
 
-pub struct Store<'a> {
-    pub modules: Vec<RefCell<ModuleInstance>>, // To guarantee soundness, the Store need to own the instantiated modules
-    pub funcinstances: Vec<FuncInstance<'a>>,
-    pub tables: Vec<RefCell<Table>>,
-    pub mems: Vec<RefCell<Mem>>,
-    pub globals: Vec<RefCell<Global>>,
-    pub elems: Vec<RefCell<Elem>>,
-    pub datas: Vec<RefCell<Data>>,
+// Objects of one kind, addressed by their index. Each slot records the generation of the object
+// it holds, so that a handle outliving its object can be told apart from a handle to a newer one.
+// Freed slots are reused by later allocations.
+pub struct Arena<T> {
+    entries: Vec<Entry<T>>,
+    free: Vec<Addr>,
+}
+
+struct Entry<T> {
+    generation: Generation,
+    value: Option<T>,
+    pinned: bool, // Kept alive by the embedder rather than by other objects
+}
+
+pub type Generation = u32;
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Arena<T> {
+        Arena {
+            entries: vec![],
+            free: vec![],
+        }
+    }
+
+    pub fn push(&mut self, value: T) -> Addr {
+        match self.free.pop() {
+            Some(addr) => {
+                self.entries[addr].value = Some(value);
+                addr
+            }
+            None => {
+                self.entries.push(Entry {
+                    generation: 0,
+                    value: Some(value),
+                    pinned: false,
+                });
+                self.entries.len() - 1
+            }
+        }
+    }
+
+    // Address the next pushed value will be stored at
+    pub fn next_addr(&self) -> Addr {
+        self.free.last().copied().unwrap_or(self.entries.len())
+    }
+
+    pub fn remove(&mut self, addr: Addr) -> Option<T> {
+        let entry = self.entries.get_mut(addr)?;
+        let value = entry.value.take()?;
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.pinned = false;
+        self.free.push(addr);
+        Some(value)
+    }
+
+    pub fn pin(&mut self, addr: Addr) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.pinned = true;
+        }
+    }
+
+    pub fn unpin(&mut self, addr: Addr) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.pinned = false;
+        }
+    }
+
+    // Number of slots, including free ones
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Number of slots holding an object
+    pub fn live(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub fn get(&self, addr: Addr) -> Option<&T> {
+        self.entries.get(addr)?.value.as_ref()
+    }
+
+    pub fn generation(&self, addr: Addr) -> Option<Generation> {
+        self.entries.get(addr).map(|entry| entry.generation)
+    }
+
+    fn pinned(&self) -> impl Iterator<Item = Addr> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.pinned && entry.value.is_some())
+            .map(|(addr, _)| addr)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        self.entries.iter().filter_map(|entry| entry.value.as_ref())
+    }
+
+    fn sweep(&mut self, reachable: &[bool]) {
+        for (addr, reachable) in reachable.iter().enumerate() {
+            if !reachable {
+                self.remove(addr);
+            }
+        }
+    }
+}
+
+// Objects only refer to live addresses, so indexing a free slot is a bug in the store
+impl<T> Index<Addr> for Arena<T> {
+    type Output = T;
+
+    fn index(&self, addr: Addr) -> &T {
+        self.entries[addr].value.as_ref().expect("free store slot")
+    }
+}
+
+impl<T> IndexMut<Addr> for Arena<T> {
+    fn index_mut(&mut self, addr: Addr) -> &mut T {
+        self.entries[addr].value.as_mut().expect("free store slot")
+    }
+}
+
+// An object of the store, as followed when looking for the reachable ones
+#[derive(Clone, Copy)]
+enum Object {
+    Module(Addr),
+    Func(Addr),
+    Table(Addr),
+    Mem(Addr),
+    Global(Addr),
+    Tag(Addr),
+    Elem(Addr),
+    Data(Addr),
+    Extern(Addr),
+    Exn(Addr),
+}
+
+// Object a slot of type `valtype` refers to, if it holds a non-null reference
+fn slot_object(slot: Slot, valtype: types::Value) -> Option<Object> {
+    match valtype {
+        types::Value::Ref(reftype) if slot != NULL_REF => match reftype.heap {
+            types::Heap::Func | types::Heap::Type(_) => Some(Object::Func(slot as Addr)),
+            types::Heap::Extern => Some(Object::Extern(slot as Addr)),
+            types::Heap::Exn => Some(Object::Exn(slot as Addr)),
+        },
+        _ => None,
+    }
+}
+
+pub type StoreId = usize;
+
+static NEXT_STORE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Objects refer to each other by address, so that the store can be extended while references
+// to it are held. The embedder uses the typed handles below instead.
+pub struct Store {
+    id: StoreId,
+    pub(crate) modules: Arena<ModuleInstance>,
+    pub(crate) funcinstances: Arena<FuncInstance>,
+    pub(crate) tables: Arena<RefCell<Table>>,
+    pub(crate) mems: Arena<RefCell<Mem>>,
+    pub(crate) globals: Arena<RefCell<Global>>,
+    pub(crate) tags: Arena<TagInstance>,
+    pub(crate) elems: Arena<RefCell<Elem>>,
+    pub(crate) datas: Arena<RefCell<Data>>,
+    // Function types the types of store objects refer to by index. Each type is registered
+    // once, so that equivalent types of different modules share their index.
+    pub(crate) types: RefCell<Vec<types::Function>>,
+    externs: RefCell<Arena<Rc<dyn Any>>>, // Host data behind extern references
+    exns: RefCell<Arena<ExnInstance>>,    // Freed when unreachable as the outermost call returns
+    pub(crate) invocations: Cell<usize>,  // Calls into wasm code in progress, nested by the host
+    pub config: Config,
+    fuel: Cell<Option<u64>>, // None means execution is not metered
+    fuel_consumed: Cell<u64>,
+    limiter: RefCell<Option<Box<dyn ResourceLimiter>>>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<'a> Store<'a> {
-    pub fn new() -> Store<'a> {
+impl Store {
+    pub fn new() -> Store {
+        Store::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Store {
         Store {
-            modules: vec![],
-            funcinstances: vec![],
-            tables: vec![],
-            mems: vec![],
-            globals: vec![],
-            elems: vec![],
-            datas: vec![],
+            id: NEXT_STORE_ID.fetch_add(1, Ordering::Relaxed),
+            modules: Arena::new(),
+            funcinstances: Arena::new(),
+            tables: Arena::new(),
+            mems: Arena::new(),
+            globals: Arena::new(),
+            tags: Arena::new(),
+            elems: Arena::new(),
+            datas: Arena::new(),
+            types: RefCell::new(vec![]),
+            externs: RefCell::new(Arena::new()),
+            exns: RefCell::new(Arena::new()),
+            invocations: Cell::new(0),
+            config,
+            fuel: Cell::new(config.fuel),
+            fuel_consumed: Cell::new(0),
+            limiter: RefCell::new(None),
+        }
+    }
+
+    // Handles
+
+    pub fn id(&self) -> StoreId {
+        self.id
+    }
+
+    fn resolve<'s, T>(
+        &self,
+        arena: &'s Arena<T>,
+        handle: Handle,
+        undefined: fn(Addr) -> err::Err,
+    ) -> Result<&'s T, err::Err> {
+        if handle.store != self.id {
+            return Result::Err(err::Err::HandleFromOtherStore(handle.store));
+        }
+        match arena.entries.get(handle.addr) {
+            None => Result::Err(undefined(handle.addr)),
+            Some(entry) => match &entry.value {
+                Some(value) if entry.generation == handle.generation => Ok(value),
+                _ => Result::Err(err::Err::StaleHandle(handle.addr)),
+            },
+        }
+    }
+
+    fn handle<T>(&self, arena: &Arena<T>, addr: Addr) -> Handle {
+        Handle {
+            store: self.id,
+            generation: arena.generation(addr).unwrap_or(0),
+            addr,
+        }
+    }
+
+    pub fn instance(&self, id: InstanceId) -> Result<&ModuleInstance, err::Err> {
+        self.resolve(&self.modules, id.0, err::Err::UndefinedInstance)
+    }
+
+    pub fn func(&self, id: FuncId) -> Result<&FuncInstance, err::Err> {
+        self.resolve(&self.funcinstances, id.0, err::Err::UndefinedFunction)
+    }
+
+    pub fn table(&self, id: TableId) -> Result<&RefCell<Table>, err::Err> {
+        self.resolve(&self.tables, id.0, err::Err::UndefinedTable)
+    }
+
+    pub fn mem(&self, id: MemId) -> Result<&RefCell<Mem>, err::Err> {
+        self.resolve(&self.mems, id.0, err::Err::UndefinedMem)
+    }
+
+    pub fn global(&self, id: GlobalId) -> Result<&RefCell<Global>, err::Err> {
+        self.resolve(&self.globals, id.0, err::Err::UndefinedGlobal)
+    }
+
+    pub fn tag(&self, id: TagId) -> Result<&TagInstance, err::Err> {
+        self.resolve(&self.tags, id.0, err::Err::UndefinedTag)
+    }
+
+    pub(crate) fn instance_id(&self, addr: Addr) -> InstanceId {
+        InstanceId(self.handle(&self.modules, addr))
+    }
+
+    pub(crate) fn func_id(&self, addr: Addr) -> FuncId {
+        FuncId(self.handle(&self.funcinstances, addr))
+    }
+
+    pub(crate) fn table_id(&self, addr: Addr) -> TableId {
+        TableId(self.handle(&self.tables, addr))
+    }
+
+    pub(crate) fn mem_id(&self, addr: Addr) -> MemId {
+        MemId(self.handle(&self.mems, addr))
+    }
+
+    pub(crate) fn global_id(&self, addr: Addr) -> GlobalId {
+        GlobalId(self.handle(&self.globals, addr))
+    }
+
+    pub(crate) fn tag_id(&self, addr: Addr) -> TagId {
+        TagId(self.handle(&self.tags, addr))
+    }
+
+    // Reclamation
+
+    // Removes an instance, then frees every object that is no longer reachable. Objects the
+    // instance shares with other instances, such as exported functions and the instance data they
+    // run against, stay alive as long as those instances do.
+    pub fn remove_instance(&mut self, id: InstanceId) -> Result<(), err::Err> {
+        self.instance(id)?;
+        let entry = &mut self.modules.entries[id.0.addr];
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.pinned = false;
+        self.collect();
+        Ok(())
+    }
+
+    // Frees the objects that cannot be reached from a live instance or from an object allocated by
+    // the embedder. References the embedder holds are not followed, and fail once freed.
+    pub fn collect(&mut self) {
+        let mut modules = vec![false; self.modules.len()];
+        let mut funcs = vec![false; self.funcinstances.len()];
+        let mut tables = vec![false; self.tables.len()];
+        let mut mems = vec![false; self.mems.len()];
+        let mut globals = vec![false; self.globals.len()];
+        let mut tags = vec![false; self.tags.len()];
+        let mut elems = vec![false; self.elems.len()];
+        let mut datas = vec![false; self.datas.len()];
+        let mut externs = vec![false; self.externs.get_mut().len()];
+        let mut exns = vec![false; self.exns.get_mut().len()];
+
+        let mut pending: Vec<Object> = self.modules.pinned().map(Object::Module).collect();
+        pending.extend(self.funcinstances.pinned().map(Object::Func));
+        pending.extend(self.tables.pinned().map(Object::Table));
+        pending.extend(self.mems.pinned().map(Object::Mem));
+        pending.extend(self.globals.pinned().map(Object::Global));
+        pending.extend(self.tags.pinned().map(Object::Tag));
+        pending.extend(self.elems.pinned().map(Object::Elem));
+        pending.extend(self.datas.pinned().map(Object::Data));
+        pending.extend(self.externs.get_mut().pinned().map(Object::Extern));
+
+        let refs = |refs: &[Ref], pending: &mut Vec<Object>| {
+            pending.extend(refs.iter().filter_map(|r| match r {
+                Ref::Func(handle) => Some(Object::Func(handle.addr)),
+                Ref::Extern(handle) => Some(Object::Extern(handle.addr)),
+                Ref::Exn(handle) => Some(Object::Exn(handle.addr)),
+                Ref::Null(_) => None,
+            }))
+        };
+        while let Some(object) = pending.pop() {
+            match object {
+                Object::Module(addr) if !modules[addr] => {
+                    modules[addr] = true;
+                    let module = &self.modules[addr];
+                    pending.extend(module.funct.iter().map(|addr| Object::Func(*addr)));
+                    pending.extend(module.tables.iter().map(|addr| Object::Table(*addr)));
+                    pending.extend(module.mems.iter().map(|addr| Object::Mem(*addr)));
+                    pending.extend(module.globals.iter().map(|addr| Object::Global(*addr)));
+                    pending.extend(module.tags.iter().map(|addr| Object::Tag(*addr)));
+                    pending.extend(module.elems.iter().map(|addr| Object::Elem(*addr)));
+                    pending.extend(module.datas.iter().map(|addr| Object::Data(*addr)));
+                }
+                Object::Func(addr) if !funcs[addr] => {
+                    funcs[addr] = true;
+                    if let FuncInstance::Internal(func) = &self.funcinstances[addr] {
+                        pending.push(Object::Module(func.module));
+                    }
+                }
+                Object::Table(addr) if !tables[addr] => {
+                    tables[addr] = true;
+                    refs(&self.tables[addr].borrow().elem, &mut pending);
+                }
+                Object::Mem(addr) => mems[addr] = true,
+                Object::Global(addr) if !globals[addr] => {
+                    globals[addr] = true;
+                    if let Val::Ref(r) = self.globals[addr].borrow().value {
+                        refs(&[r], &mut pending);
+                    }
+                }
+                Object::Elem(addr) if !elems[addr] => {
+                    elems[addr] = true;
+                    refs(&self.elems[addr].borrow().elem, &mut pending);
+                }
+                Object::Tag(addr) => tags[addr] = true,
+                Object::Data(addr) => datas[addr] = true,
+                // Extern references the store did not allocate are left alone
+                Object::Extern(addr) if addr < externs.len() => externs[addr] = true,
+                Object::Exn(addr) if !exns[addr] => {
+                    exns[addr] = true;
+                    let exn = &self.exns.get_mut()[addr];
+                    pending.push(Object::Tag(exn.tag));
+                    let tagtype = &self.tags[exn.tag].tagtype;
+                    let payload = exn.payload.iter().zip(&tagtype.input);
+                    pending
+                        .extend(payload.filter_map(|(slot, valtype)| slot_object(*slot, *valtype)));
+                }
+                _ => {}
+            }
+        }
+
+        self.modules.sweep(&modules);
+        self.funcinstances.sweep(&funcs);
+        self.tables.sweep(&tables);
+        self.mems.sweep(&mems);
+        self.globals.sweep(&globals);
+        self.tags.sweep(&tags);
+        self.elems.sweep(&elems);
+        self.datas.sweep(&datas);
+        self.externs.get_mut().sweep(&externs);
+        self.exns.get_mut().sweep(&exns);
+    }
+
+    // Extern references
+
+    // Wraps host data in a reference that wasm code can pass around and store. The data stays
+    // alive while the host holds the reference, until `extern_release`, and afterwards while a
+    // table, global or element segment refers to it.
+    pub fn extern_new(&self, data: impl Any) -> ExternRef {
+        let mut externs = self.externs.borrow_mut();
+        let addr = externs.push(Rc::new(data));
+        externs.pin(addr);
+        ExternRef(Some(self.handle(&externs, addr)))
+    }
+
+    // The data behind `r`, None if it is null, released, from another store or of another type
+    // than `T`
+    pub fn extern_data<T: Any>(&self, r: ExternRef) -> Option<Rc<T>> {
+        let externs = self.externs.borrow();
+        let data = self.resolve(&externs, r.0?, err::Err::StaleHandle).ok()?;
+        data.clone().downcast().ok()
+    }
+
+    // The address of `r` in this store, checked like the other handles
+    pub(crate) fn extern_addr(&self, r: ExternRef) -> Result<Option<Addr>, err::Err> {
+        match r.0 {
+            Some(handle) => {
+                self.resolve(&self.externs.borrow(), handle, err::Err::StaleHandle)?;
+                Result::Ok(Some(handle.addr))
+            }
+            None => Result::Ok(None),
+        }
+    }
+
+    pub(crate) fn extern_ref(&self, addr: Option<Addr>) -> ExternRef {
+        ExternRef(addr.map(|addr| self.handle(&self.externs.borrow(), addr)))
+    }
+
+    // Tells the store that the host no longer holds `r`, so that it is freed by the next
+    // collection unless wasm still refers to it
+    pub fn extern_release(&self, r: ExternRef) {
+        if let Ok(Some(addr)) = self.extern_addr(r) {
+            self.externs.borrow_mut().unpin(addr);
+        }
+    }
+
+    // Exceptions
+
+    pub(crate) fn exn_alloc(&self, tag: Addr, payload: Vec<Slot>) -> Addr {
+        self.exns.borrow_mut().push(ExnInstance { tag, payload })
+    }
+
+    pub(crate) fn exn(&self, addr: Addr) -> Result<(Addr, Vec<Slot>), err::Err> {
+        let exns = self.exns.borrow();
+        let exn = exns.get(addr).ok_or(err::Err::UndefinedExn(addr))?;
+        Result::Ok((exn.tag, exn.payload.clone()))
+    }
+
+    // Frees the exceptions that tables, globals and element segments do not refer to, nor `roots`
+    // or the exceptions they carry. Called once no wasm code runs, when no operand stack holds
+    // exceptions. Nothing is freed while the host borrows a table or a global.
+    pub(crate) fn collect_exns(&self, roots: impl IntoIterator<Item = Addr>) {
+        let Result::Ok(mut exns) = self.exns.try_borrow_mut() else {
+            return;
+        };
+        let mut pending: Vec<Addr> = roots.into_iter().collect();
+        let refs = |refs: &[Ref], pending: &mut Vec<Addr>| {
+            pending.extend(refs.iter().filter_map(|r| match r {
+                Ref::Exn(handle) => Some(handle.addr),
+                _ => None,
+            }))
+        };
+        for table in self.tables.values() {
+            let Result::Ok(table) = table.try_borrow() else {
+                return;
+            };
+            refs(&table.elem, &mut pending);
+        }
+        for global in self.globals.values() {
+            let Result::Ok(global) = global.try_borrow() else {
+                return;
+            };
+            if let Val::Ref(r) = global.value {
+                refs(&[r], &mut pending);
+            }
+        }
+        for elem in self.elems.values() {
+            let Result::Ok(elem) = elem.try_borrow() else {
+                return;
+            };
+            refs(&elem.elem, &mut pending);
+        }
+
+        let mut reachable = vec![false; exns.len()];
+        while let Some(addr) = pending.pop() {
+            let Some(exn) = exns.get(addr) else {
+                continue;
+            };
+            if reachable[addr] {
+                continue;
+            }
+            reachable[addr] = true;
+            let payload = exn.payload.iter().zip(&self.tags[exn.tag].tagtype.input);
+            for (slot, valtype) in payload {
+                if let Some(Object::Exn(addr)) = slot_object(*slot, *valtype) {
+                    pending.push(addr);
+                }
+            }
+        }
+        exns.sweep(&reachable);
+    }
+
+    // The exception as seen by the host, its payload being typed by its tag
+    pub(crate) fn exception(&self, addr: Addr) -> Result<Exception, err::Err> {
+        let (tag, payload) = self.exn(addr)?;
+        let tagtype = &self.tags[tag].tagtype;
+        Result::Ok(Exception {
+            tag: self.tag_id(tag),
+            payload: payload
+                .iter()
+                .zip(&tagtype.input)
+                .map(|(slot, valtype)| Val::from_slot(*slot, *valtype, self))
+                .collect(),
+        })
+    }
+
+    // Allocates an exception thrown by the host, whose payload must match its tag
+    pub(crate) fn exn_from(&self, exception: &Exception) -> Result<Addr, err::Err> {
+        let tagtype = &self.tag(exception.tag)?.tagtype;
+        if exception.payload.len() != tagtype.input.len()
+            || !exception
+                .payload
+                .iter()
+                .zip(&tagtype.input)
+                .all(|(val, valtype)| self.val_matches(val, valtype))
+        {
+            return Result::Err(err::Err::FuncTypeMismatch);
+        }
+        let payload = exception.payload.iter().map(|val| val.to_slot());
+        let payload = payload.collect::<Result<_, _>>()?;
+        Result::Ok(self.exn_alloc(exception.tag.0.addr, payload))
+    }
+
+    // Types
+
+    // Registers the types of a module, returning the index in the store of each of them.
+    // Types may only refer to the ones before them.
+    pub fn register_types(&self, functypes: &[types::Function]) -> Result<Vec<Addr>, err::Err> {
+        let mut types = self.types.borrow_mut();
+        let mut map = vec![];
+        for functype in functypes {
+            let functype = functype.remap(&map)?;
+            let idx = match types.iter().position(|registered| *registered == functype) {
+                Some(idx) => idx,
+                None => {
+                    types.push(functype);
+                    types.len() - 1
+                }
+            };
+            map.push(idx);
+        }
+        Result::Ok(map)
+    }
+
+    // Whether `val` has type `valtype`, function references being checked against the type
+    // of the function they refer to. Null references match any nullable type of their kind, as
+    // the embedder cannot name the types of modules. References given by the embedder may be
+    // stale, they must refer to a live object.
+    pub fn val_matches(&self, val: &Val, valtype: &types::Value) -> bool {
+        let types = self.types.borrow();
+        let is_func = |heap| matches!(heap, types::Heap::Func | types::Heap::Type(_));
+        match (val, valtype) {
+            (Val::Ref(r), _) if !self.is_live(r) => false,
+            (Val::Ref(Ref::Null(heap)), types::Value::Ref(reftype)) => {
+                reftype.nullable
+                    && (*heap == reftype.heap || is_func(*heap) && is_func(reftype.heap))
+            }
+            (Val::Ref(Ref::Func(func)), types::Value::Ref(reftype)) => match reftype.heap {
+                types::Heap::Func => true,
+                types::Heap::Type(idx) => match (self.funcinstances.get(func.addr), types.get(idx))
+                {
+                    (Some(func), Some(functype)) => func.functype().is_subtype(functype, &types),
+                    _ => false,
+                },
+                _ => false,
+            },
+            _ => val.valtype().is_subtype(valtype, &types),
+        }
+    }
+
+    pub(crate) fn is_live(&self, r: &Ref) -> bool {
+        self.check_live(r).is_ok()
+    }
+
+    // Fails for references to objects of another store, or to freed ones
+    pub(crate) fn check_live(&self, r: &Ref) -> Result<(), err::Err> {
+        let stale = err::Err::StaleHandle;
+        match *r {
+            Ref::Null(_) => Result::Ok(()),
+            Ref::Func(handle) => self.resolve(&self.funcinstances, handle, stale).map(drop),
+            Ref::Extern(handle) => self
+                .resolve(&self.externs.borrow(), handle, stale)
+                .map(drop),
+            Ref::Exn(handle) => self.resolve(&self.exns.borrow(), handle, stale).map(drop),
+        }
+    }
+
+    // Reference to the object at `addr` of the kind `heap` designates
+    pub(crate) fn ref_at(&self, heap: types::Heap, addr: Addr) -> Ref {
+        match heap {
+            types::Heap::Func | types::Heap::Type(_) => {
+                Ref::Func(self.handle(&self.funcinstances, addr))
+            }
+            types::Heap::Extern => Ref::Extern(self.handle(&self.externs.borrow(), addr)),
+            types::Heap::Exn => Ref::Exn(self.handle(&self.exns.borrow(), addr)),
+        }
+    }
+
+    // Checks a reference given by the embedder before it is stored as `reftype`
+    pub(crate) fn check_ref(&self, r: Ref, reftype: types::Ref) -> Result<(), err::Err> {
+        self.check_live(&r)?;
+        match self.val_matches(&Val::Ref(r), &types::Value::Ref(reftype)) {
+            true => Result::Ok(()),
+            false => Result::Err(err::Err::RefTypeMismatch),
+        }
+    }
+
+    // Resources
+
+    pub fn set_limiter(&mut self, limiter: impl ResourceLimiter + 'static) {
+        self.limiter = RefCell::new(Some(Box::new(limiter)));
+    }
+
+    // Objects and bytes currently held by the store. Memories are accounted as the bytes they
+    // reserve, shared ones being allocated up to their maximum, and tables as the size of their
+    // elements.
+    pub fn usage(&self) -> ResourceUsage {
+        let mems = self
+            .mems
+            .entries
+            .iter()
+            .filter_map(|entry| entry.value.as_ref());
+        let tables = self
+            .tables
+            .entries
+            .iter()
+            .filter_map(|entry| entry.value.as_ref());
+        ResourceUsage {
+            instances: self.modules.live(),
+            memories: self.mems.live(),
+            tables: self.tables.live(),
+            memory_bytes: mems.map(|mem| mem.borrow().reserved()).sum(),
+            table_bytes: tables
+                .map(|table| table.borrow().elem.len() * core::mem::size_of::<Ref>())
+                .sum(),
+        }
+    }
+
+    // Checks that the given numbers of instances, memories and tables can be added
+    pub(crate) fn check_counts(
+        &self,
+        instances: usize,
+        memories: usize,
+        tables: usize,
+    ) -> Result<(), err::Err> {
+        if let Some(limiter) = self.limiter.borrow().as_ref() {
+            if self.modules.live() + instances > limiter.instances() {
+                return Result::Err(err::Err::InstanceLimitExceeded);
+            }
+            if self.mems.live() + memories > limiter.memories() {
+                return Result::Err(err::Err::MemoryLimitExceeded);
+            }
+            if self.tables.live() + tables > limiter.tables() {
+                return Result::Err(err::Err::TableLimitExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn memory_growing(
+        &self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool, Trap> {
+        match self.limiter.borrow_mut().as_mut() {
+            Some(limiter) => limiter.memory_growing(current, desired, maximum),
+            None => Ok(true),
+        }
+    }
+
+    pub(crate) fn table_growing(
+        &self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool, Trap> {
+        match self.limiter.borrow_mut().as_mut() {
+            Some(limiter) => limiter.table_growing(current, desired, maximum),
+            None => Ok(true),
+        }
+    }
+
+    // The embedder may hold a view of the memory while guest code runs, accessing it then is
+    // an error rather than a panic
+    pub(crate) fn mem_at(&self, addr: Addr) -> Result<cell::Ref<'_, Mem>, err::Err> {
+        self.mems[addr]
+            .try_borrow()
+            .map_err(|_| err::Err::MemoryBorrowed)
+    }
+
+    pub(crate) fn mem_at_mut(&self, addr: Addr) -> Result<cell::RefMut<'_, Mem>, err::Err> {
+        self.mems[addr]
+            .try_borrow_mut()
+            .map_err(|_| err::Err::MemoryBorrowed)
+    }
+
+    // Grows the memory at `addr` by `delta` pages, returning its previous size in pages, or None
+    // if the memory cannot grow that much or the limiter denies it
+    pub(crate) fn grow_memory(&self, addr: Addr, delta: usize) -> Result<Option<usize>, err::Err> {
+        let mut mem = self.mem_at_mut(addr)?;
+        let current = mem.size() / PAGE_SIZE;
+        let max_pages = mem.memtype.index.max_pages();
+        let max = mem.memtype.limits.max.unwrap_or(max_pages).min(max_pages);
+        let desired = match current.checked_add(delta) {
+            Some(desired) if desired <= max => desired,
+            _ => return Ok(None),
+        };
+        let maximum = mem
+            .memtype
+            .limits
+            .max
+            .map(|max| max.saturating_mul(PAGE_SIZE));
+        if !self.memory_growing(mem.size(), desired * PAGE_SIZE, maximum)? {
+            return Ok(None);
+        }
+        let current = match &mut mem.data {
+            // Failing to allocate is reported to the guest as for any other failed growth
+            MemData::Owned(data) => {
+                if data
+                    .try_reserve_exact(desired * PAGE_SIZE - data.len())
+                    .is_err()
+                {
+                    return Ok(None);
+                }
+                data.resize(desired * PAGE_SIZE, 0);
+                current
+            }
+            // Other threads may have grown it since
+            MemData::Shared(shared) => match shared.grow(delta) {
+                Some(current) => current,
+                None => return Ok(None),
+            },
+        };
+        mem.memtype.limits.min = current + delta;
+        Ok(Some(current))
+    }
+
+    // Grows the table at `addr` by `delta` elements set to `init`, returning its previous size,
+    // or None if the table cannot grow that much, the limiter denies it or allocating fails
+    pub(crate) fn grow_table(
+        &self,
+        addr: Addr,
+        delta: usize,
+        init: Ref,
+    ) -> Result<Option<usize>, err::Err> {
+        let mut table = self.tables[addr].borrow_mut();
+        let current = table.elem.len();
+        let max = table
+            .tabletype
+            .limits
+            .max
+            .unwrap_or(MAX_TABLE_SIZE)
+            .min(MAX_TABLE_SIZE);
+        let desired = match current.checked_add(delta) {
+            Some(desired) if desired <= max => desired,
+            _ => return Ok(None),
+        };
+        if !self.table_growing(current, desired, table.tabletype.limits.max)? {
+            return Ok(None);
+        }
+        // Failing to allocate is reported to the guest as for any other failed growth
+        if table.elem.try_reserve_exact(delta).is_err() {
+            return Ok(None);
+        }
+        table.elem.resize(desired, init);
+        table.tabletype.limits.min = desired;
+        Ok(Some(current))
+    }
+
+    // Fuel
+
+    pub fn add_fuel(&self, fuel: u64) -> Result<(), err::Err> {
+        let remaining = self.fuel.get().unwrap_or(0);
+        match remaining.checked_add(fuel) {
+            None => Result::Err(err::Err::IntegerOverflow),
+            Some(total) => {
+                self.fuel.set(Some(total));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn set_fuel(&self, fuel: u64) {
+        self.fuel.set(Some(fuel));
+    }
+
+    // Stops metering, execution is then unbounded
+    pub fn clear_fuel(&self) {
+        self.fuel.set(None);
+    }
+
+    pub fn fuel_remaining(&self) -> Option<u64> {
+        self.fuel.get()
+    }
+
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed.get()
+    }
+
+    // Charges the cost of an instruction before it is executed.
+    // Running out of fuel leaves the remaining fuel untouched, so that the same
+    // program with the same fuel always traps at the same instruction.
+    pub fn consume_fuel(&self, cost: u64) -> Result<(), err::Err> {
+        if let Some(remaining) = self.fuel.get() {
+            if remaining < cost {
+                return Result::Err(err::Err::TrapOutOfFuel);
+            }
+            self.fuel.set(Some(remaining - cost));
+        }
+        self.fuel_consumed
+            .set(self.fuel_consumed.get().saturating_add(cost));
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FuelCosts {
+    pub numeric: u64,
+    pub reference: u64,
+    pub variable: u64,
+    pub table: u64,
+    pub memory: u64,
+    pub control: u64,
+}
+
+impl Default for FuelCosts {
+    fn default() -> Self {
+        FuelCosts {
+            numeric: 1,
+            reference: 1,
+            variable: 1,
+            table: 1,
+            memory: 1,
+            control: 1,
+        }
+    }
+}
+
+impl FuelCosts {
+    pub fn cost(&self, class: InstrClass) -> u64 {
+        match class {
+            InstrClass::Numeric => self.numeric,
+            InstrClass::Reference => self.reference,
+            InstrClass::Variable => self.variable,
+            InstrClass::Table => self.table,
+            InstrClass::Memory => self.memory,
+            InstrClass::Control => self.control,
+        }
+    }
+}
+
+// Bounds on execution resources, exceeding any of them traps with `TrapCallStackExhausted`.
+// Limits are counted in calls and value slots (locals and operands) rather than host bytes,
+// so that the same program exhausts at the same point on every platform.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackLimits {
+    pub max_call_depth: usize,
+    pub max_values: usize,
+}
+
+impl Default for StackLimits {
+    fn default() -> Self {
+        StackLimits {
+            max_call_depth: 1 << 14,
+            max_values: 1 << 20,
+        }
+    }
+}
+
+// Consulted by the store before a memory or a table grows, including when it is allocated at
+// instantiation, and before instantiating a module. Memory sizes are in bytes and table sizes in
+// elements, `maximum` being the limit declared by their type. Denied growth is reported as -1 by
+// `memory.grow` and `table.grow`, and fails instantiation, while a trap aborts execution.
+pub trait ResourceLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool, Trap>;
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool, Trap>;
+
+    // Maximum number of instances, memories and tables in the store
+    fn instances(&self) -> usize {
+        usize::MAX
+    }
+
+    fn memories(&self) -> usize {
+        usize::MAX
+    }
+
+    fn tables(&self) -> usize {
+        usize::MAX
+    }
+}
+
+// Limiter with fixed caps, None meaning unlimited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StoreLimits {
+    pub memory_size: Option<usize>,    // Bytes per memory
+    pub table_elements: Option<usize>, // Elements per table
+    pub instances: usize,
+    pub memories: usize,
+    pub tables: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        StoreLimits {
+            memory_size: None,
+            table_elements: None,
+            instances: usize::MAX,
+            memories: usize::MAX,
+            tables: usize::MAX,
         }
     }
 }
 
+impl ResourceLimiter for StoreLimits {
+    fn memory_growing(&mut self, _: usize, desired: usize, _: Option<usize>) -> Result<bool, Trap> {
+        Ok(self.memory_size.is_none_or(|size| desired <= size))
+    }
+
+    fn table_growing(&mut self, _: usize, desired: usize, _: Option<usize>) -> Result<bool, Trap> {
+        Ok(self.table_elements.is_none_or(|size| desired <= size))
+    }
+
+    fn instances(&self) -> usize {
+        self.instances
+    }
+
+    fn memories(&self) -> usize {
+        self.memories
+    }
+
+    fn tables(&self) -> usize {
+        self.tables
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub instances: usize,
+    pub memories: usize,
+    pub tables: usize,
+    pub memory_bytes: usize,
+    pub table_bytes: usize,
+}
+
+// Code format functions are compiled to when they are instantiated.
+// `Register` additionally fuses common instruction sequences into superinstructions
+// operating on local slots and immediates, which runs faster for compute-heavy code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Engine {
+    #[default]
+    Stack,
+    Register,
+}
+
 pub struct ModuleInstance {
     pub types: Vec<types::Function>,
     pub funct: Vec<types::Addr>,
     pub tables: Vec<types::Addr>,
     pub mems: Vec<types::Addr>,
     pub globals: Vec<types::Addr>,
+    pub tags: Vec<types::Addr>,
     pub elems: Vec<types::Addr>,
     pub datas: Vec<types::Addr>,
     pub exports: Vec<Export>,
 }
 
+impl Default for ModuleInstance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ModuleInstance {
     pub fn new() -> ModuleInstance {
         ModuleInstance {
@@ -99,6 +1159,7 @@ impl ModuleInstance {
             tables: vec![],
             mems: vec![],
             globals: vec![],
+            tags: vec![],
             elems: vec![],
             datas: vec![],
             exports: vec![],
@@ -106,33 +1167,90 @@ impl ModuleInstance {
     }
 }
 
-pub enum FuncInstance<'a> {
-    Internal(InternalFuncInstance<'a>),
+pub enum FuncInstance {
+    Internal(InternalFuncInstance),
     Host(HostFuncInstance),
 }
 
-pub struct InternalFuncInstance<'a> {
+impl FuncInstance {
+    pub fn functype(&self) -> &types::Function {
+        match self {
+            FuncInstance::Internal(func) => &func.functype,
+            FuncInstance::Host(func) => &func.functype,
+        }
+    }
+}
+
+pub struct InternalFuncInstance {
     pub functype: types::Function,
-    pub module: &'a RefCell<ModuleInstance>,
+    pub module: Addr,
     pub code: Func,
+    pub bytecode: bytecode::Code,
+}
+
+impl InternalFuncInstance {
+    // Compiles `code` for `engine`, `context` giving the types of the entities of `module` by
+    // index. `functype` is the type of the function in the store, `code` being checked against
+    // its type in the module.
+    pub fn new(
+        functype: types::Function,
+        module: Addr,
+        code: Func,
+        context: &Context,
+        engine: Engine,
+    ) -> Result<InternalFuncInstance, err::Err> {
+        let bytecode = bytecode::compile_for(
+            engine,
+            context,
+            context
+                .types
+                .get(code.functype)
+                .ok_or(err::Err::InvalidIndex(code.functype))?,
+            &code.locals,
+            &code.body,
+        )?;
+        Result::Ok(InternalFuncInstance {
+            functype,
+            module,
+            code,
+            bytecode,
+        })
+    }
 }
 pub struct HostFuncInstance {
     pub functype: types::Function,
-    //pub extrnfunc: fn(usize) -> usize, // TODO define Host function invocation mechanism
+    pub hostfunc: HostFunc,
 }
 
 pub struct Table {
     pub tabletype: types::Table,
     pub elem: Vec<Ref>,
 }
+pub const PAGE_SIZE: usize = 65536;
+pub const MAX_PAGES: usize = 65536;
+pub const MAX_TABLE_SIZE: usize = u32::MAX as usize;
+
 pub struct Mem {
     pub memtype: types::Mem,
-    pub data: Vec<types::Byte>,
+    pub data: MemData,
+}
+// Shared memories are owned by all the stores they were allocated into
+pub enum MemData {
+    Owned(Vec<types::Byte>),
+    Shared(SharedMemory),
 }
 pub struct Global {
     pub globaltype: types::Global,
     pub value: Val,
 }
+pub struct TagInstance {
+    pub tagtype: types::Function,
+}
+// An exception thrown by wasm code, referred to by `exnref` values once caught
+pub struct ExnInstance {
+    pub tag: Addr,
+    pub payload: Vec<Slot>,
+}
 pub struct Elem {
     pub elemtype: types::Ref,
     pub elem: Vec<Ref>,
@@ -145,87 +1263,68 @@ pub struct Export {
     pub value: ExternalVal,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ExternalVal {
-    Fun(Addr),
-    Table(Addr),
-    Mem(Addr),
-    Global(Addr),
+    Fun(FuncId),
+    Table(TableId),
+    Mem(MemId),
+    Global(GlobalId),
+    Tag(TagId),
 }
 
-pub struct Label<'a> {
-    pub arity: usize,
-    pub instr: &'a Vec<Instr>,
+// Address of an object together with the store it belongs to and the generation of its slot,
+// so that a handle cannot reach into another store or a reused slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Handle {
+    pub(crate) store: StoreId,
+    pub(crate) generation: Generation,
+    pub(crate) addr: Addr,
 }
 
-pub struct FrameState<'a> {
-    pub locals: Vec<Val>,
-    pub module: &'a RefCell<ModuleInstance>,
-}
+// Handles to the objects of a store
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstanceId(pub(crate) Handle);
 
-pub struct Frame<'a> {
-    pub arity: usize,
-    pub framestate: RefCell<FrameState<'a>>,
-}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuncId(pub(crate) Handle);
 
-impl<'a> Frame<'a> {
-    pub fn new(module: &'a RefCell<ModuleInstance>) -> Frame<'a> {
-        Frame {
-            arity: 0,
-            framestate: RefCell::new(FrameState {
-                locals: vec![],
-                module,
-            }),
-        }
-    }
-}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableId(pub(crate) Handle);
 
-pub enum StackEntry<'a> {
-    Value(Val),
-    Label(Label<'a>),
-    Activation(i64, &'a Frame<'a>),
-}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemId(pub(crate) Handle);
 
-impl<'a> From<StackEntry<'a>> for u32 {
-    fn from(entry: StackEntry) -> Self {
-        // TODO validate?
-        match entry {
-            StackEntry::Value(Val::Num(Num::I32(val))) => val,
-            _ => panic!("not a u32 value"),
-        }
-    }
-}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GlobalId(pub(crate) Handle);
 
-impl<'a> Into<StackEntry<'a>> for u32 {
-    fn into(self) -> StackEntry<'a> {
-        StackEntry::Value(Val::Num(Num::I32(self)))
-    }
-}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagId(pub(crate) Handle);
 
-impl<'a> From<StackEntry<'a>> for u64 {
-    fn from(entry: StackEntry) -> Self {
-        // TODO validate?
-        match entry {
-            StackEntry::Value(Val::Num(Num::I64(val))) => val,
-            _ => panic!("not a u64 value"),
-        }
-    }
+// An exception escaping wasm code to the host, or thrown by a host function
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exception {
+    pub tag: TagId,
+    pub payload: Vec<Val>,
 }
 
-impl<'a> Into<StackEntry<'a>> for u64 {
-    fn into(self) -> StackEntry<'a> {
-        StackEntry::Value(Val::Num(Num::I64(self)))
+impl Exception {
+    pub fn new(tag: TagId, payload: Vec<Val>) -> Exception {
+        Exception { tag, payload }
     }
 }
 
-impl<'a> Into<StackEntry<'a>> for f32 {
-    fn into(self) -> StackEntry<'a> {
-        StackEntry::Value(Val::Num(Num::F32(self)))
-    }
+pub struct Frame {
+    pub arity: usize,
+    pub locals: Vec<Val>,
+    pub module: Addr,
 }
 
-impl<'a> Into<StackEntry<'a>> for f64 {
-    fn into(self) -> StackEntry<'a> {
-        StackEntry::Value(Val::Num(Num::F64(self)))
+impl Frame {
+    pub fn new(module: Addr) -> Frame {
+        Frame {
+            arity: 0,
+            locals: vec![],
+            module,
+        }
     }
 }
@@ -0,0 +1,311 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{err, runtime::PAGE_SIZE, types};
+
+// Linear memory shared between stores, which may run on different host threads.
+// Its bytes are allocated up to its maximum when it is created, so that growing it never moves
+// them, and are held in atomic words so that concurrent accesses are well defined.
+#[derive(Clone)]
+pub struct SharedMemory(Arc<Shared>);
+
+struct Shared {
+    memtype: types::Mem,
+    words: Vec<AtomicU64>,
+    size: AtomicUsize, // In bytes
+    #[cfg(feature = "std")]
+    parking: parking::Parking,
+}
+
+// Outcome of `memory.atomic.wait`, as returned to wasm code
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitResult {
+    Ok = 0,
+    NotEqual = 1,
+    TimedOut = 2,
+}
+
+impl SharedMemory {
+    pub fn new(memtype: types::Mem) -> Result<SharedMemory, err::Err> {
+        let limits = memtype.limits;
+        let bytes = match limits.max {
+            Some(max)
+                if memtype.shared && limits.min <= max && max <= memtype.index.max_pages() =>
+            {
+                max * PAGE_SIZE
+            }
+            _ => return Result::Err(err::Err::InvalidLimit(limits)),
+        };
+        let mut words = Vec::new();
+        words
+            .try_reserve_exact(bytes / 8)
+            .map_err(|_| err::Err::MemoryLimitExceeded)?;
+        words.extend((0..bytes / 8).map(|_| AtomicU64::new(0)));
+        Result::Ok(SharedMemory(Arc::new(Shared {
+            memtype,
+            words,
+            size: AtomicUsize::new(limits.min * PAGE_SIZE),
+            #[cfg(feature = "std")]
+            parking: parking::Parking::default(),
+        })))
+    }
+
+    pub fn ty(&self) -> types::Mem {
+        self.0.memtype
+    }
+
+    // Current size in bytes
+    pub fn size(&self) -> usize {
+        self.0.size.load(Ordering::SeqCst)
+    }
+
+    // Bytes allocated for the memory, that of its maximum size
+    pub fn reserved(&self) -> usize {
+        self.0.words.len() * 8
+    }
+
+    // Grows the memory by `delta` pages, returning its previous size in pages, or None if that
+    // exceeds its maximum
+    pub fn grow(&self, delta: usize) -> Option<usize> {
+        let max = self.0.words.len() * 8;
+        let grown = self
+            .0
+            .size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                let bytes = delta.checked_mul(PAGE_SIZE)?;
+                size.checked_add(bytes).filter(|size| *size <= max)
+            });
+        grown.ok().map(|size| size / PAGE_SIZE)
+    }
+
+    pub fn ptr_eq(&self, other: &SharedMemory) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), err::Err> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size() => Result::Ok(()),
+            _ => Result::Err(err::Err::OutOfBoundMemoryAccess),
+        }
+    }
+
+    // Accesses to bytes are not atomic as a whole, only each byte is
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), err::Err> {
+        self.check(offset, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.load_word(offset + i, 1, Ordering::Relaxed) as u8;
+        }
+        Result::Ok(())
+    }
+
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<(), err::Err> {
+        self.check(offset, bytes.len())?;
+        for (i, byte) in bytes.iter().enumerate() {
+            self.update_word(offset + i, 1, Ordering::Relaxed, |_| *byte as u64);
+        }
+        Result::Ok(())
+    }
+
+    // Copies `len` bytes from `src` to `dst`, the ranges may overlap
+    pub fn copy_within(&self, src: usize, dst: usize, len: usize) -> Result<(), err::Err> {
+        self.check(src, len)?;
+        self.check(dst, len)?;
+        let mut bytes = vec![0; len];
+        self.read(src, &mut bytes)?;
+        self.write(dst, &bytes)
+    }
+
+    // Atomic accesses of `size` bytes, which must be naturally aligned. Values are zero-extended.
+
+    fn check_atomic(&self, offset: usize, size: usize) -> Result<(), err::Err> {
+        self.check(offset, size)?;
+        if !offset.is_multiple_of(size) {
+            return Result::Err(err::Err::TrapUnalignedAtomic);
+        }
+        Result::Ok(())
+    }
+
+    pub fn atomic_load(&self, offset: usize, size: usize) -> Result<u64, err::Err> {
+        self.check_atomic(offset, size)?;
+        Result::Ok(self.load_word(offset, size, Ordering::SeqCst))
+    }
+
+    // Replaces the value by `f` of it, returning the previous one
+    pub fn atomic_rmw(
+        &self,
+        offset: usize,
+        size: usize,
+        f: impl Fn(u64) -> u64,
+    ) -> Result<u64, err::Err> {
+        self.check_atomic(offset, size)?;
+        Result::Ok(self.update_word(offset, size, Ordering::SeqCst, f))
+    }
+
+    // Wakes up to `count` threads waiting at `offset`, returning how many were woken
+    pub fn notify(&self, offset: usize, count: u32) -> Result<u32, err::Err> {
+        self.check_atomic(offset, 4)?;
+        #[cfg(feature = "std")]
+        let woken = self.0.parking.notify(offset, count);
+        #[cfg(not(feature = "std"))]
+        let woken = {
+            let _ = count;
+            0
+        };
+        Result::Ok(woken)
+    }
+
+    // Blocks the thread at `offset` while it holds `expected`, until notified or after `timeout`
+    // nanoseconds, None waiting indefinitely
+    pub fn wait(
+        &self,
+        offset: usize,
+        size: usize,
+        expected: u64,
+        timeout: Option<u64>,
+    ) -> Result<WaitResult, err::Err> {
+        self.check_atomic(offset, size)?;
+        let holds_expected = || self.load_word(offset, size, Ordering::SeqCst) == expected;
+        #[cfg(feature = "std")]
+        let res = self.0.parking.wait(offset, timeout, holds_expected);
+        // Threads cannot be parked without the standard library
+        #[cfg(not(feature = "std"))]
+        let res = {
+            let _ = timeout;
+            match holds_expected() {
+                true => return Result::Err(err::Err::TrapAtomicWaitUnsupported),
+                false => WaitResult::NotEqual,
+            }
+        };
+        Result::Ok(res)
+    }
+
+    // Values are located in the word holding them, as accesses never span two words
+
+    fn word(&self, offset: usize, size: usize) -> (&AtomicU64, u32, u64) {
+        let shift = (offset % 8) as u32 * 8;
+        let mask = match size {
+            8 => u64::MAX,
+            _ => (1 << (size * 8)) - 1,
+        };
+        (&self.0.words[offset / 8], shift, mask)
+    }
+
+    fn load_word(&self, offset: usize, size: usize, order: Ordering) -> u64 {
+        let (word, shift, mask) = self.word(offset, size);
+        (word.load(order) >> shift) & mask
+    }
+
+    fn update_word(
+        &self,
+        offset: usize,
+        size: usize,
+        order: Ordering,
+        f: impl Fn(u64) -> u64,
+    ) -> u64 {
+        let (word, shift, mask) = self.word(offset, size);
+        let previous = word.fetch_update(order, order, |word| {
+            let value = f((word >> shift) & mask) & mask;
+            Some(word & !(mask << shift) | value << shift)
+        });
+        (previous.unwrap() >> shift) & mask
+    }
+}
+
+#[cfg(feature = "std")]
+mod parking {
+    use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc};
+    use std::{
+        sync::{Condvar, Mutex},
+        time::Duration,
+    };
+
+    use super::WaitResult;
+
+    // Threads waiting in a memory, by address in the order they started waiting
+    #[derive(Default)]
+    pub(super) struct Parking {
+        waiters: Mutex<BTreeMap<usize, VecDeque<Arc<Waiter>>>>,
+    }
+
+    #[derive(Default)]
+    struct Waiter {
+        notified: Mutex<bool>,
+        condvar: Condvar,
+    }
+
+    impl Parking {
+        // The value is checked with the queues locked, so that a notification following a store
+        // cannot be missed
+        pub(super) fn wait(
+            &self,
+            offset: usize,
+            timeout: Option<u64>,
+            holds_expected: impl Fn() -> bool,
+        ) -> WaitResult {
+            let waiter = {
+                let mut waiters = self.waiters.lock().unwrap();
+                if !holds_expected() {
+                    return WaitResult::NotEqual;
+                }
+                let waiter = Arc::new(Waiter::default());
+                waiters.entry(offset).or_default().push_back(waiter.clone());
+                waiter
+            };
+
+            let notified = waiter.notified.lock().unwrap();
+            let notified = match timeout {
+                None => waiter
+                    .condvar
+                    .wait_while(notified, |notified| !*notified)
+                    .unwrap(),
+                Some(nanos) => {
+                    let timeout = Duration::from_nanos(nanos);
+                    let waited = waiter
+                        .condvar
+                        .wait_timeout_while(notified, timeout, |notified| !*notified);
+                    waited.unwrap().0
+                }
+            };
+            if *notified {
+                return WaitResult::Ok;
+            }
+            drop(notified);
+
+            // Timed out, unless notified in the meantime
+            let mut waiters = self.waiters.lock().unwrap();
+            let queue = waiters.entry(offset).or_default();
+            match queue.iter().position(|other| Arc::ptr_eq(other, &waiter)) {
+                Some(pos) => {
+                    queue.remove(pos);
+                    if queue.is_empty() {
+                        waiters.remove(&offset);
+                    }
+                    WaitResult::TimedOut
+                }
+                None => WaitResult::Ok,
+            }
+        }
+
+        pub(super) fn notify(&self, offset: usize, count: u32) -> u32 {
+            let mut waiters = self.waiters.lock().unwrap();
+            let Some(queue) = waiters.get_mut(&offset) else {
+                return 0;
+            };
+            let mut woken = 0;
+            while woken < count {
+                let Some(waiter) = queue.pop_front() else {
+                    break;
+                };
+                *waiter.notified.lock().unwrap() = true;
+                waiter.condvar.notify_one();
+                woken += 1;
+            }
+            if queue.is_empty() {
+                waiters.remove(&offset);
+            }
+            woken
+        }
+    }
+}
@@ -0,0 +1,560 @@
+use core::{cell::Cell, marker::PhantomData};
+
+use alloc::vec::Vec;
+
+use crate::{
+    embedding::Instanciable,
+    err,
+    runtime::{FuncId, Handle, InstanceId, Ref, Slot, Store, Val, NULL_REF},
+    types::{self, Addr},
+    vm::Thread,
+};
+
+// Rust types that can be passed to and returned from wasm functions
+pub trait WasmTy: Copy {
+    // Whether the values of `valtype` are represented by this type
+    fn matches(valtype: types::Value) -> bool;
+    // References are checked against the store they are passed to, or taken from
+    fn into_slot(self, store: &Store) -> Result<Slot, err::Err>;
+    fn from_slot(slot: Slot, valtype: types::Value, store: &Store) -> Self;
+}
+
+impl WasmTy for u32 {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == types::Value::Num(types::Number::I32)
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self as Slot)
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        slot as u32
+    }
+}
+
+impl WasmTy for i32 {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == types::Value::Num(types::Number::I32)
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self as u32 as Slot)
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        slot as u32 as i32
+    }
+}
+
+impl WasmTy for u64 {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == types::Value::Num(types::Number::I64)
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self)
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        slot
+    }
+}
+
+impl WasmTy for i64 {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == types::Value::Num(types::Number::I64)
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self as Slot)
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        slot as i64
+    }
+}
+
+impl WasmTy for f32 {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == types::Value::Num(types::Number::F32)
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self.to_bits() as Slot)
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        f32::from_bits(slot as u32)
+    }
+}
+
+impl WasmTy for f64 {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == types::Value::Num(types::Number::F64)
+    }
+    fn into_slot(self, _store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(self.to_bits())
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, _store: &Store) -> Self {
+        f64::from_bits(slot)
+    }
+}
+
+// References of both types, the actual type is taken from the function signature
+impl WasmTy for Ref {
+    fn matches(valtype: types::Value) -> bool {
+        matches!(valtype, types::Value::Ref(_))
+    }
+    fn into_slot(self, store: &Store) -> Result<Slot, err::Err> {
+        match self {
+            Ref::Null(_) => Result::Ok(NULL_REF),
+            Ref::Func(handle) | Ref::Extern(handle) | Ref::Exn(handle) => {
+                store.check_live(&self)?;
+                Result::Ok(handle.addr as Slot)
+            }
+        }
+    }
+    fn from_slot(slot: Slot, valtype: types::Value, store: &Store) -> Self {
+        match Val::from_slot(slot, valtype, store) {
+            Val::Ref(reference) => reference,
+            _ => unreachable!(),
+        }
+    }
+}
+
+// Types of a single wasm value type, which signatures can be derived from.
+// `Ref` stands for either reference type, `FuncRef` and `ExternRef` are used instead.
+pub trait WasmValType: WasmTy {
+    const VALTYPE: types::Value;
+}
+
+impl WasmValType for u32 {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::I32);
+}
+
+impl WasmValType for i32 {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::I32);
+}
+
+impl WasmValType for u64 {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::I64);
+}
+
+impl WasmValType for i64 {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::I64);
+}
+
+impl WasmValType for f32 {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::F32);
+}
+
+impl WasmValType for f64 {
+    const VALTYPE: types::Value = types::Value::Num(types::Number::F64);
+}
+
+// A `funcref` to a function of a store, `None` being null
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FuncRef(pub(crate) Option<Handle>);
+
+impl FuncRef {
+    pub fn null() -> Self {
+        FuncRef(None)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl From<FuncId> for FuncRef {
+    fn from(func: FuncId) -> Self {
+        FuncRef(Some(func.0))
+    }
+}
+
+// Functions of the store the reference was taken from
+impl TryFrom<FuncRef> for FuncId {
+    type Error = err::Err;
+
+    fn try_from(r: FuncRef) -> Result<Self, Self::Error> {
+        r.0.map(FuncId).ok_or(err::Err::TrapNullReference)
+    }
+}
+
+// An `externref` to host data of a store, `None` being null
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExternRef(pub(crate) Option<Handle>);
+
+impl ExternRef {
+    pub fn null() -> Self {
+        ExternRef(None)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl From<ExternRef> for Ref {
+    fn from(r: ExternRef) -> Self {
+        r.0.map_or(Ref::Null(types::Heap::Extern), Ref::Extern)
+    }
+}
+
+impl From<ExternRef> for Val {
+    fn from(r: ExternRef) -> Self {
+        Val::Ref(r.into())
+    }
+}
+
+impl WasmTy for FuncRef {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == Self::VALTYPE
+    }
+    fn into_slot(self, store: &Store) -> Result<Slot, err::Err> {
+        let r = self.0.map_or(Ref::Null(types::Heap::Func), Ref::Func);
+        Ref::into_slot(r, store)
+    }
+    fn from_slot(slot: Slot, valtype: types::Value, store: &Store) -> Self {
+        match Ref::from_slot(slot, valtype, store) {
+            Ref::Func(handle) => FuncRef(Some(handle)),
+            _ => FuncRef(None),
+        }
+    }
+}
+
+impl WasmValType for FuncRef {
+    const VALTYPE: types::Value = types::Value::Ref(types::Ref::FUNC);
+}
+
+impl WasmTy for ExternRef {
+    fn matches(valtype: types::Value) -> bool {
+        valtype == Self::VALTYPE
+    }
+    fn into_slot(self, store: &Store) -> Result<Slot, err::Err> {
+        Result::Ok(
+            store
+                .extern_addr(self)?
+                .map_or(NULL_REF, |addr| addr as Slot),
+        )
+    }
+    fn from_slot(slot: Slot, _valtype: types::Value, store: &Store) -> Self {
+        store.extern_ref((slot != NULL_REF).then_some(slot as Addr))
+    }
+}
+
+impl WasmValType for ExternRef {
+    const VALTYPE: types::Value = types::Value::Ref(types::Ref::EXTERN);
+}
+
+// The parameters of a function, a single `WasmTy` or a tuple of them
+pub trait WasmParams {
+    fn matches(valtypes: &[types::Value]) -> bool;
+    fn push_slots(self, slots: &mut Vec<Slot>, store: &Store) -> Result<(), err::Err>;
+}
+
+// The results of a function, a single `WasmTy` or a tuple of them
+pub trait WasmResults: Sized {
+    fn matches(valtypes: &[types::Value]) -> bool;
+    fn from_slots(slots: &[Slot], valtypes: &[types::Value], store: &Store) -> Self;
+}
+
+impl<T: WasmTy> WasmParams for T {
+    fn matches(valtypes: &[types::Value]) -> bool {
+        matches!(valtypes, [valtype] if T::matches(*valtype))
+    }
+    fn push_slots(self, slots: &mut Vec<Slot>, store: &Store) -> Result<(), err::Err> {
+        slots.push(self.into_slot(store)?);
+        Result::Ok(())
+    }
+}
+
+impl<T: WasmTy> WasmResults for T {
+    fn matches(valtypes: &[types::Value]) -> bool {
+        matches!(valtypes, [valtype] if T::matches(*valtype))
+    }
+    fn from_slots(slots: &[Slot], valtypes: &[types::Value], store: &Store) -> Self {
+        T::from_slot(slots[0], valtypes[0], store)
+    }
+}
+
+macro_rules! impl_wasm_tuple {
+    ($len:expr; $($T:ident $idx:tt),*) => {
+        impl<$($T: WasmTy),*> WasmParams for ($($T,)*) {
+            fn matches(valtypes: &[types::Value]) -> bool {
+                valtypes.len() == $len $(&& $T::matches(valtypes[$idx]))*
+            }
+            #[allow(unused_variables)]
+            fn push_slots(self, slots: &mut Vec<Slot>, store: &Store) -> Result<(), err::Err> {
+                $(slots.push(self.$idx.into_slot(store)?);)*
+                Result::Ok(())
+            }
+        }
+
+        impl<$($T: WasmTy),*> WasmResults for ($($T,)*) {
+            fn matches(valtypes: &[types::Value]) -> bool {
+                valtypes.len() == $len $(&& $T::matches(valtypes[$idx]))*
+            }
+            #[allow(unused_variables, clippy::unused_unit)]
+            fn from_slots(slots: &[Slot], valtypes: &[types::Value], store: &Store) -> Self {
+                ($($T::from_slot(slots[$idx], valtypes[$idx], store),)*)
+            }
+        }
+    };
+}
+
+impl_wasm_tuple!(0;);
+impl_wasm_tuple!(1; A 0);
+impl_wasm_tuple!(2; A 0, B 1);
+impl_wasm_tuple!(3; A 0, B 1, C 2);
+impl_wasm_tuple!(4; A 0, B 1, C 2, D 3);
+impl_wasm_tuple!(5; A 0, B 1, C 2, D 3, E 4);
+impl_wasm_tuple!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_wasm_tuple!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_wasm_tuple!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+// A function whose signature was checked against `Params` and `Results`, so that it can be
+// called with native values. The thread it runs on is kept between calls, which therefore
+// allocate nothing once its stacks have grown.
+pub struct TypedFunc<'s, Params, Results> {
+    store: &'s Store,
+    func: FuncId,
+    thread: Cell<Thread<'s>>,
+    _signature: PhantomData<fn(Params) -> Results>,
+}
+
+impl<'s, Params: WasmParams, Results: WasmResults> TypedFunc<'s, Params, Results> {
+    pub fn new(store: &'s Store, func: FuncId) -> Result<Self, err::Err> {
+        let functype = store.func(func)?.functype();
+        if !Params::matches(&functype.input) || !Results::matches(&functype.output) {
+            return Result::Err(err::Err::FuncTypeMismatch);
+        }
+        Result::Ok(TypedFunc {
+            store,
+            func,
+            thread: Cell::new(Thread::default()),
+            _signature: PhantomData,
+        })
+    }
+
+    pub fn func(&self) -> FuncId {
+        self.func
+    }
+
+    pub fn call(&self, params: Params) -> Result<Results, err::Err> {
+        // A call reentering this function from the host runs on a fresh thread
+        let mut thread = self.thread.take();
+        thread.slots.clear();
+        let res = params
+            .push_slots(&mut thread.slots, self.store)
+            .and_then(|()| thread.invoke(self.store, self.func.0.addr))
+            .map(|()| {
+                let functype = self.store.funcinstances[self.func.0.addr].functype();
+                Results::from_slots(&thread.slots, &functype.output, self.store)
+            });
+        self.thread.set(thread);
+        res
+    }
+}
+
+// Looks up the function exported by `instance` as `name`, checking its signature
+pub fn get_typed_func<'s, Params: WasmParams, Results: WasmResults>(
+    store: &'s Store,
+    instance: InstanceId,
+    name: &str,
+) -> Result<TypedFunc<'s, Params, Results>, err::Err> {
+    TypedFunc::new(store, store.instance(instance)?.export_func(name)?)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use alloc::string::ToString;
+
+    use crate::{
+        instr::{BlockType, Instr},
+        modules::Func,
+        runtime::{Export, ExternalVal, FuncInstance, InternalFuncInstance, ModuleInstance},
+        validation::Context,
+    };
+
+    use super::*;
+
+    fn exporting_instance(store: &Store) -> ModuleInstance {
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        instance.exports.push(Export {
+            name: "f".to_string(),
+            value: ExternalVal::Fun(store.func_id(0)),
+        });
+        instance.exports.push(Export {
+            name: "table".to_string(),
+            value: ExternalVal::Table(store.table_id(0)),
+        });
+        instance
+    }
+
+    #[test]
+    fn call() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(exporting_instance(&store));
+        let module = 0;
+        // Rotates its parameters
+        let functype = types::Function {
+            input: vec![
+                types::Value::Num(types::Number::I32),
+                types::Value::Num(types::Number::I64),
+                types::Value::Num(types::Number::F64),
+                types::Value::Ref(types::Ref::EXTERN),
+            ],
+            output: vec![
+                types::Value::Num(types::Number::I64),
+                types::Value::Num(types::Number::F64),
+                types::Value::Ref(types::Ref::EXTERN),
+                types::Value::Num(types::Number::I32),
+            ],
+        };
+        let types = [functype];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![
+                    Instr::LocalGet(1),
+                    Instr::LocalGet(2),
+                    Instr::LocalGet(3),
+                    Instr::LocalGet(0),
+                ],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        let f = get_typed_func::<(i32, u64, f64, Ref), (u64, f64, Ref, i32)>(
+            &store,
+            store.instance_id(module),
+            "f",
+        )?;
+        let r = store.extern_new(3).into();
+        assert_eq!(f.call((-1, u64::MAX, 0.5, r))?, (u64::MAX, 0.5, r, -1));
+        // References must refer to live objects of the store
+        let dangling = Handle {
+            store: store.id(),
+            generation: 0,
+            addr: 12345,
+        };
+        assert!(matches!(
+            f.call((-1, u64::MAX, 0.5, Ref::Extern(dangling))),
+            Err(err::Err::StaleHandle(12345))
+        ));
+        let other = Store::new().extern_new(3).into();
+        assert!(matches!(
+            f.call((-1, u64::MAX, 0.5, other)),
+            Err(err::Err::HandleFromOtherStore(_))
+        ));
+        assert_eq!(
+            f.call((7, 0, -0., Ref::Null(types::Heap::Extern)))?,
+            (0, -0., Ref::Null(types::Heap::Extern), 7)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signature_mismatch() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(exporting_instance(&store));
+        let module = 0;
+        let functype = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+        let types = [functype];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0)],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+        let instance = store.instance_id(module);
+
+        assert_eq!(
+            get_typed_func::<u32, i32>(&store, instance, "f")?.call(5)?,
+            5
+        );
+        assert_eq!(
+            get_typed_func::<(i32,), (u32,)>(&store, instance, "f")?.call((5,))?,
+            (5,)
+        );
+        assert!(matches!(
+            get_typed_func::<u64, u32>(&store, instance, "f"),
+            Err(err::Err::FuncTypeMismatch)
+        ));
+        assert!(matches!(
+            get_typed_func::<u32, ()>(&store, instance, "f"),
+            Err(err::Err::FuncTypeMismatch)
+        ));
+        assert!(matches!(
+            get_typed_func::<(u32, u32), u32>(&store, instance, "f"),
+            Err(err::Err::FuncTypeMismatch)
+        ));
+        assert!(matches!(
+            get_typed_func::<u32, u32>(&store, instance, "table"),
+            Err(err::Err::ModuleInstanceExportNotAFunction(_))
+        ));
+        assert!(matches!(
+            get_typed_func::<u32, u32>(&store, instance, "g"),
+            Err(err::Err::ModuleInstanceExportNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn call_after_trap() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(exporting_instance(&store));
+        let module = 0;
+        // Traps on 0
+        let functype = types::Function {
+            input: vec![types::Value::Num(types::Number::I32)],
+            output: vec![types::Value::Num(types::Number::I32)],
+        };
+        let types = [functype];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![
+                    Instr::LocalGet(0),
+                    Instr::I32Eqz,
+                    Instr::If(BlockType::Empty),
+                    Instr::Unreachable,
+                    Instr::End,
+                    Instr::LocalGet(0),
+                ],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        let f = get_typed_func::<u32, u32>(&store, store.instance_id(module), "f")?;
+        assert!(matches!(f.call(0), Err(err::Err::TrapUnreachable)));
+        assert_eq!(f.call(1)?, 1);
+        Ok(())
+    }
+}
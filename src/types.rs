@@ -1,3 +1,4 @@
+use crate::err;
 use crate::validation::{Context, Subtypable, Validable};
 use alloc::vec::Vec;
 
@@ -21,10 +22,40 @@ pub enum Vector {
     Unimplemented,
 }
 
+// Heap types, the kinds of objects references point to
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Ref {
+pub enum Heap {
     Func,
     Extern,
+    Exn,         // Caught exceptions, which can be thrown again
+    Type(Index), // Functions of the given type
+}
+
+// `(ref null? heap)`, only nullable references have a default value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ref {
+    pub nullable: bool,
+    pub heap: Heap,
+}
+
+impl Ref {
+    pub const FUNC: Ref = Ref::null(Heap::Func);
+    pub const EXTERN: Ref = Ref::null(Heap::Extern);
+    pub const EXN: Ref = Ref::null(Heap::Exn);
+
+    pub const fn null(heap: Heap) -> Ref {
+        Ref {
+            nullable: true,
+            heap,
+        }
+    }
+
+    pub const fn non_null(heap: Heap) -> Ref {
+        Ref {
+            nullable: false,
+            heap,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -34,14 +65,81 @@ pub enum Value {
     Ref(Ref),
 }
 
+impl Value {
+    // Whether locals of this type start with a value, or must be set before being read
+    pub fn is_defaultable(&self) -> bool {
+        !matches!(
+            self,
+            Value::Ref(Ref {
+                nullable: false,
+                ..
+            })
+        )
+    }
+}
+
 pub type Result = Vec<Value>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Function {
     pub input: Result,
     pub output: Result,
 }
 
+// Type indices are relative to a list of function types, those of a module or of a store.
+// `remap` moves a type to another list, `map` giving the new index of each type.
+
+impl Heap {
+    pub fn remap(self, map: &[Index]) -> core::result::Result<Heap, err::Err> {
+        match self {
+            Heap::Type(idx) => match map.get(idx) {
+                Some(idx) => Ok(Heap::Type(*idx)),
+                None => Err(err::Err::InvalidIndex(idx)),
+            },
+            heap => Ok(heap),
+        }
+    }
+}
+
+impl Ref {
+    pub fn remap(self, map: &[Index]) -> core::result::Result<Ref, err::Err> {
+        Ok(Ref {
+            heap: self.heap.remap(map)?,
+            ..self
+        })
+    }
+}
+
+impl Value {
+    pub fn remap(self, map: &[Index]) -> core::result::Result<Value, err::Err> {
+        match self {
+            Value::Ref(reftype) => Ok(Value::Ref(reftype.remap(map)?)),
+            valtype => Ok(valtype),
+        }
+    }
+}
+
+impl Function {
+    pub fn remap(&self, map: &[Index]) -> core::result::Result<Function, err::Err> {
+        let remap = |valtypes: &Result| -> core::result::Result<Result, err::Err> {
+            valtypes.iter().map(|valtype| valtype.remap(map)).collect()
+        };
+        Ok(Function {
+            input: remap(&self.input)?,
+            output: remap(&self.output)?,
+        })
+    }
+}
+
+// Type of functions taking `input` i32 parameters and returning `output` i32 results
+#[cfg(test)]
+pub(crate) fn i32_function(input: usize, output: usize) -> Function {
+    Function {
+        input: vec![Value::Num(Number::I32); input],
+        output: vec![Value::Num(Number::I32); output],
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Limits {
     pub min: Int,
@@ -51,14 +149,34 @@ pub struct Limits {
 #[derive(Clone, Copy, Debug)]
 pub struct Mem {
     pub limits: Limits, // Note limits are given in units of page size (sec 2.3.8)
+    pub index: IndexType,
+    pub shared: bool, // Shared memories must declare a maximum
+}
+
+// Type of the addresses of a memory, i64 ones coming with the memory64 proposal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexType {
+    I32,
+    I64,
+}
+
+impl IndexType {
+    // Maximum number of pages of a memory, bounded by the address space of the host
+    pub fn max_pages(&self) -> Int {
+        match self {
+            IndexType::I32 => 1 << 16,
+            IndexType::I64 => (1u64 << 48).min(Int::MAX as u64 >> 16) as Int,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Table {
     pub limits: Limits,
+    pub reftype: Ref,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Mut {
     Const,
     Var,
@@ -76,6 +194,7 @@ pub enum Extern {
     Table(Table),
     Mem(Mem),
     Global(Global),
+    Tag(Function), // Types of the payload of exceptions, as the parameters of a function
 }
 
 // Validation
@@ -98,12 +217,66 @@ impl Validable for Value {
     }
 }
 
+// Defined types are all function types, below `func`. Distinct type indices only match when
+// their types are equivalent, which terminates as types only refer to the ones defined before
+// them.
+impl Subtypable for Heap {
+    fn is_subtype(&self, other: &Heap, types: &[Function]) -> bool {
+        match (self, other) {
+            (Heap::Type(_), Heap::Func) => true,
+            (Heap::Type(idx1), Heap::Type(idx2)) if idx1 != idx2 => {
+                match (types.get(*idx1), types.get(*idx2)) {
+                    (Some(functype1), Some(functype2)) => functype1.is_subtype(functype2, types),
+                    _ => false,
+                }
+            }
+            _ => self == other,
+        }
+    }
+}
+
+impl Subtypable for Ref {
+    fn is_subtype(&self, other: &Ref, types: &[Function]) -> bool {
+        (!self.nullable || other.nullable) && self.heap.is_subtype(&other.heap, types)
+    }
+}
+
+impl Subtypable for Value {
+    fn is_subtype(&self, other: &Value, types: &[Function]) -> bool {
+        match (self, other) {
+            (Value::Ref(reftype1), Value::Ref(reftype2)) => reftype1.is_subtype(reftype2, types),
+            _ => self == other,
+        }
+    }
+}
+
+impl Subtypable for Result {
+    fn is_subtype(&self, other: &Result, types: &[Function]) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .zip(other)
+                .all(|(valtype1, valtype2)| valtype1.is_subtype(valtype2, types))
+    }
+}
+
 impl Validable for Function {
     fn is_valid(&self, _: &Context, _: Option<Int>) -> bool {
         true
     }
 }
 
+// Function types are final, they only match equivalent types: those with the same parameters
+// and results, type indices in them referring to equivalent types
+impl Subtypable for Function {
+    fn is_subtype(&self, other: &Function, types: &[Function]) -> bool {
+        let equivalent = |valtypes1: &Result, valtypes2: &Result| {
+            valtypes1.is_subtype(valtypes2, types) && valtypes2.is_subtype(valtypes1, types)
+        };
+        equivalent(&self.input, &other.input) && equivalent(&self.output, &other.output)
+    }
+}
+
 impl Validable for Limits {
     fn is_valid(&self, _: &Context, k: Option<Int>) -> bool {
         match (self.max, k) {
@@ -116,7 +289,7 @@ impl Validable for Limits {
 }
 
 impl Subtypable for Limits {
-    fn is_subtype(&self, other: &Limits) -> bool {
+    fn is_subtype(&self, other: &Limits, _: &[Function]) -> bool {
         match (self.max, other.max) {
             (_, None) => self.min >= other.min,
             (None, Some(_)) => false,
@@ -127,13 +300,24 @@ impl Subtypable for Limits {
 
 impl Validable for Mem {
     fn is_valid(&self, context: &Context, _: Option<Int>) -> bool {
-        self.limits.is_valid(context, Some(2_u32.pow(16) as usize))
+        self.limits.is_valid(context, Some(self.index.max_pages()))
+            && (!self.shared || self.limits.max.is_some())
     }
 }
 
 impl Validable for Table {
     fn is_valid(&self, context: &Context, _: Option<Int>) -> bool {
-        self.limits.is_valid(context, Some(u32::MAX as usize))
+        // Tables are filled with null references, having no initializer
+        self.limits.is_valid(context, Some(u32::MAX as usize)) && self.reftype.nullable
+    }
+}
+
+// Elements are both read and written, their types must be equivalent
+impl Subtypable for Table {
+    fn is_subtype(&self, other: &Table, types: &[Function]) -> bool {
+        self.limits.is_subtype(&other.limits, types)
+            && self.reftype.is_subtype(&other.reftype, types)
+            && other.reftype.is_subtype(&self.reftype, types)
     }
 }
 
@@ -149,6 +333,15 @@ impl Validable for Global {
     }
 }
 
+// Mutable globals are both read and written, their types must be equivalent
+impl Subtypable for Global {
+    fn is_subtype(&self, other: &Global, types: &[Function]) -> bool {
+        self.mutable == other.mutable
+            && self.val.is_subtype(&other.val, types)
+            && (self.mutable == Mut::Const || other.val.is_subtype(&self.val, types))
+    }
+}
+
 impl Validable for Extern {
     fn is_valid(&self, context: &Context, k: Option<Int>) -> bool {
         match self {
@@ -156,6 +349,7 @@ impl Validable for Extern {
             Extern::Table(table) => table.is_valid(context, k),
             Extern::Mem(mem) => mem.is_valid(context, k),
             Extern::Global(glob) => glob.is_valid(context, k),
+            Extern::Tag(tag) => tag.is_valid(context, k) && tag.output.is_empty(),
         }
     }
 }
@@ -2,12 +2,15 @@ use alloc::vec::Vec;
 
 use crate::types;
 
+// Types of the entities code can refer to, by index in each index space
+#[derive(Clone, Debug, Default)]
 pub struct Context {
     pub types: Vec<types::Function>,
     pub funcs: Vec<types::Function>,
     pub tables: Vec<types::Table>,
     pub mems: Vec<types::Mem>,
     pub globals: Vec<types::Global>,
+    pub tags: Vec<types::Function>,
     pub elems: Vec<types::Ref>,
     pub data: Vec<()>,
     pub locals: Vec<types::Value>,
@@ -20,6 +23,7 @@ pub trait Validable {
     fn is_valid(&self, context: &Context, k: Option<types::Int>) -> bool;
 }
 
+// Type indices in both types refer to `types`
 pub trait Subtypable {
-    fn is_subtype(&self, other: &Self) -> bool;
+    fn is_subtype(&self, other: &Self, types: &[types::Function]) -> bool;
 }
@@ -1,347 +1,2213 @@
-use alloc::vec::Vec;
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::{
-    cell::RefCell,
     ops::{BitAnd, BitOr, BitXor, Not},
+    sync::atomic,
 };
 
 use crate::{
+    bytecode::{BinOp, Branch, Handler, HandlerCatch, Op, RelOp},
     err,
-    instr::Instr,
+    host::Caller,
+    instr::{Access, Atomic},
     numeric::SupportedInteger,
     runtime::{
-        Frame, FrameState, FuncInstance, HostFuncInstance, InternalFuncInstance, Label, Ref,
-        StackEntry, Store, Val,
+        Exception, FuncInstance, HostFuncInstance, InternalFuncInstance, Ref, Slot, Store, Val,
+        NULL_REF, PAGE_SIZE,
     },
+    types::{self, Addr, IndexType},
+    validation::Subtypable,
 };
 
-trait Stack<'a> {
-    fn push(&mut self, entry: StackEntry<'a>);
-    fn pop(&mut self) -> Option<StackEntry<'a>>;
-    fn push_into<T: Into<StackEntry<'a>>>(&mut self, val: T);
-    fn pop_from<T: From<StackEntry<'a>>>(&mut self) -> T;
-    fn peek(&self) -> Option<StackEntry<'a>>;
-    fn unop<T: From<StackEntry<'a>> + Into<StackEntry<'a>>>(&mut self, f: &dyn Fn(T) -> T);
-    fn binop<T: From<StackEntry<'a>> + Into<StackEntry<'a>>>(&mut self, f: &dyn Fn(T, T) -> T);
-    fn testop<T: From<bool> + From<StackEntry<'a>> + Into<StackEntry<'a>>>(
-        &mut self,
-        f: &dyn Fn(T) -> bool,
-    );
-    fn relop<T: From<bool> + From<StackEntry<'a>> + Into<StackEntry<'a>>>(
-        &mut self,
-        f: &dyn Fn(T, T) -> bool,
-    );
+// Native types of the operands, stored in a slot as their bit pattern
+trait Untagged: Copy {
+    fn from_slot(slot: Slot) -> Self;
+    fn into_slot(self) -> Slot;
 }
 
-impl<'a> Stack<'a> for Vec<StackEntry<'a>> {
-    fn push(&mut self, entry: StackEntry<'a>) {
-        self.push(entry)
+impl Untagged for u32 {
+    fn from_slot(slot: Slot) -> Self {
+        slot as u32
+    }
+    fn into_slot(self) -> Slot {
+        self as Slot
     }
+}
 
-    fn push_into<T: Into<StackEntry<'a>>>(&mut self, val: T) {
-        self.push(val.into());
+impl Untagged for u64 {
+    fn from_slot(slot: Slot) -> Self {
+        slot
+    }
+    fn into_slot(self) -> Slot {
+        self
     }
+}
 
-    fn pop_from<T: From<StackEntry<'a>>>(&mut self) -> T {
-        // TODO validate top of stack
-        match self.pop().unwrap() {
-            entry => T::from(entry),
-            _ => panic!("not a value"),
-        }
+impl Untagged for f32 {
+    fn from_slot(slot: Slot) -> Self {
+        f32::from_bits(slot as u32)
+    }
+    fn into_slot(self) -> Slot {
+        self.to_bits() as Slot
     }
+}
 
-    fn pop(&mut self) -> Option<StackEntry<'a>> {
-        todo!()
+impl Untagged for f64 {
+    fn from_slot(slot: Slot) -> Self {
+        f64::from_bits(slot)
     }
+    fn into_slot(self) -> Slot {
+        self.to_bits()
+    }
+}
 
-    fn peek(&self) -> Option<StackEntry<'a>> {
-        todo!()
+// Operand counts are checked when code is compiled, so popping never underflows the operands
+// of the current frame
+trait Stack {
+    fn push_into<T: Untagged>(&mut self, val: T);
+    fn pop_from<T: Untagged>(&mut self) -> T;
+    fn unop<T: Untagged>(&mut self, f: impl Fn(T) -> T);
+    fn binop<T: Untagged>(&mut self, f: impl Fn(T, T) -> T);
+    fn testop<T: Untagged>(&mut self, f: impl Fn(T) -> bool);
+    fn relop<T: Untagged>(&mut self, f: impl Fn(T, T) -> bool);
+    fn convert<T: Untagged, U: Untagged>(&mut self, f: impl Fn(T) -> U);
+}
+
+impl Stack for Vec<Slot> {
+    fn push_into<T: Untagged>(&mut self, val: T) {
+        self.push(val.into_slot());
+    }
+
+    fn pop_from<T: Untagged>(&mut self) -> T {
+        T::from_slot(self.pop().unwrap())
     }
 
-    fn unop<T: From<StackEntry<'a>> + Into<StackEntry<'a>>>(&mut self, f: &dyn Fn(T) -> T) {
-        // TODO validate top of stack
-        let val = self.pop_from();
-        let res = f(val);
-        self.push_into(res);
+    fn unop<T: Untagged>(&mut self, f: impl Fn(T) -> T) {
+        let top = self.last_mut().unwrap();
+        *top = f(T::from_slot(*top)).into_slot();
     }
 
-    fn binop<T: From<StackEntry<'a>> + Into<StackEntry<'a>>>(&mut self, f: &dyn Fn(T, T) -> T) {
-        // TODO validate top of stack
-        let val1 = self.pop_from();
+    fn binop<T: Untagged>(&mut self, f: impl Fn(T, T) -> T) {
         let val2 = self.pop_from();
-        let res = f(val1, val2);
-        self.push_into(res);
+        let top = self.last_mut().unwrap();
+        *top = f(T::from_slot(*top), val2).into_slot();
     }
-    fn testop<T: From<bool> + From<StackEntry<'a>> + Into<StackEntry<'a>>>(
-        &mut self,
-        f: &dyn Fn(T) -> bool,
-    ) {
-        // TODO validate top of stack
-        let val = self.pop_from();
-        let res = f(val);
-        if res {
-            self.push_into(1u32);
-        } else {
-            self.push_into(0u32);
-        }
-    }
-    fn relop<T: From<bool> + From<StackEntry<'a>> + Into<StackEntry<'a>>>(
-        &mut self,
-        f: &dyn Fn(T, T) -> bool,
-    ) {
-        // TODO validate top of stack
-        let val1 = self.pop_from();
+
+    fn testop<T: Untagged>(&mut self, f: impl Fn(T) -> bool) {
+        let top = self.last_mut().unwrap();
+        *top = f(T::from_slot(*top)) as Slot;
+    }
+
+    fn relop<T: Untagged>(&mut self, f: impl Fn(T, T) -> bool) {
         let val2 = self.pop_from();
-        if f(val1, val2) {
-            self.push_into(1u32);
-        } else {
-            self.push_into(0u32);
-        }
+        let top = self.last_mut().unwrap();
+        *top = f(T::from_slot(*top), val2) as Slot;
+    }
+
+    fn convert<T: Untagged, U: Untagged>(&mut self, f: impl Fn(T) -> U) {
+        let top = self.last_mut().unwrap();
+        *top = f(T::from_slot(*top)).into_slot();
     }
 }
 
-struct Thread<'a> {
-    frame: Frame<'a>,
-    program: &'a Vec<Instr>,
+// A function activation. Its locals are the slots starting at `base`, followed by its operands.
+struct Activation<'a> {
+    arity: usize,
+    module: Addr,
+    code: &'a [Op],
+    handlers: &'a [Handler],
+    ip: usize,
+    base: usize,
+}
+
+// The stack of a wasm thread: a flat stack of untagged slots holding the locals and operands
+// of every activation, and the activations themselves kept apart. Both live on the heap, so
+// that calls, returns and branches never recurse on the host stack.
+// A thread can be reused across invocations to keep its allocations.
+#[derive(Default)]
+pub struct Thread<'a> {
+    pub(crate) slots: Vec<Slot>,
+    frames: Vec<Activation<'a>>, // Innermost last
 }
 
 trait InstrStack {
     fn incr_ip(&mut self);
-    fn curr_op(&self) -> Instr;
+    fn curr_op(&self) -> Op;
     fn jump(&mut self, target: usize);
 }
 
-pub struct Trap {} // TODO
+impl<'a> InstrStack for Activation<'a> {
+    fn incr_ip(&mut self) {
+        self.ip += 1;
+    }
+
+    fn curr_op(&self) -> Op {
+        self.code[self.ip]
+    }
+
+    fn jump(&mut self, target: usize) {
+        self.ip = target;
+    }
+}
+
+impl BinOp {
+    fn apply(self, lhs: Slot, rhs: Slot) -> Slot {
+        let (lhs32, rhs32) = (lhs as u32, rhs as u32);
+        match self {
+            BinOp::I32Add => lhs32.wrapping_add(rhs32) as Slot,
+            BinOp::I32Sub => lhs32.wrapping_sub(rhs32) as Slot,
+            BinOp::I32Mul => lhs32.wrapping_mul(rhs32) as Slot,
+            BinOp::I32And => (lhs32 & rhs32) as Slot,
+            BinOp::I32Or => (lhs32 | rhs32) as Slot,
+            BinOp::I32Xor => (lhs32 ^ rhs32) as Slot,
+            BinOp::I32Shl => SupportedInteger::shl(lhs32, rhs32) as Slot,
+            BinOp::I32ShrU => SupportedInteger::shr_u(lhs32, rhs32) as Slot,
+            BinOp::I32ShrS => SupportedInteger::shr_s(lhs32, rhs32) as Slot,
+            BinOp::I64Add => lhs.wrapping_add(rhs),
+            BinOp::I64Sub => lhs.wrapping_sub(rhs),
+            BinOp::I64Mul => lhs.wrapping_mul(rhs),
+            BinOp::I64And => lhs & rhs,
+            BinOp::I64Or => lhs | rhs,
+            BinOp::I64Xor => lhs ^ rhs,
+            BinOp::I64Shl => SupportedInteger::shl(lhs, rhs),
+            BinOp::I64ShrU => SupportedInteger::shr_u(lhs, rhs),
+            BinOp::I64ShrS => SupportedInteger::shr_s(lhs, rhs),
+        }
+    }
+}
+
+impl RelOp {
+    fn apply(self, lhs: Slot, rhs: Slot) -> bool {
+        let (lhs32, rhs32) = (lhs as u32, rhs as u32);
+        match self {
+            RelOp::I32Eq => u32::eq_(lhs32, rhs32),
+            RelOp::I32Ne => u32::ne_(lhs32, rhs32),
+            RelOp::I32LtU => u32::ltu(lhs32, rhs32),
+            RelOp::I32LtS => u32::lts(lhs32, rhs32),
+            RelOp::I32GtU => u32::gtu(lhs32, rhs32),
+            RelOp::I32GtS => u32::gts(lhs32, rhs32),
+            RelOp::I32LeU => u32::leu(lhs32, rhs32),
+            RelOp::I32LeS => u32::les(lhs32, rhs32),
+            RelOp::I32GeU => u32::geu(lhs32, rhs32),
+            RelOp::I32GeS => u32::ges(lhs32, rhs32),
+            RelOp::I64Eq => u64::eq_(lhs, rhs),
+            RelOp::I64Ne => u64::ne_(lhs, rhs),
+            RelOp::I64LtU => u64::ltu(lhs, rhs),
+            RelOp::I64LtS => u64::lts(lhs, rhs),
+            RelOp::I64GtU => u64::gtu(lhs, rhs),
+            RelOp::I64GtS => u64::gts(lhs, rhs),
+            RelOp::I64LeU => u64::leu(lhs, rhs),
+            RelOp::I64LeS => u64::les(lhs, rhs),
+            RelOp::I64GeU => u64::geu(lhs, rhs),
+            RelOp::I64GeS => u64::ges(lhs, rhs),
+        }
+    }
+}
+
+// A trap raised by the host, aborting the execution of the wasm code that called it, or an
+// exception thrown by the host, which wasm code can catch
+#[derive(Debug)]
+pub struct Trap {
+    message: String,
+    exception: Option<Exception>,
+}
+
+impl Trap {
+    pub fn new(message: &str) -> Trap {
+        Trap {
+            message: message.to_string(),
+            exception: None,
+        }
+    }
+
+    pub fn throw(exception: Exception) -> Trap {
+        Trap {
+            message: "uncaught exception".to_string(),
+            exception: Some(exception),
+        }
+    }
 
-pub fn run(store: &Store, frame: &Frame, program: &Vec<Instr>) -> Result<Vec<Val>, err::Err> {
-    let mut stack: Vec<StackEntry> = vec![];
-    let mut ip = 0;
-    while ip < program.len() && program.len() > 0 {
-        let op = program[ip];
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn exception(&self) -> Option<&Exception> {
+        self.exception.as_ref()
+    }
+}
+
+impl From<Trap> for err::Err {
+    fn from(trap: Trap) -> Self {
+        match trap.exception {
+            Some(exception) => err::Err::UncaughtException(exception),
+            None => err::Err::TrapHost(trap.message),
+        }
+    }
+}
+
+// Lets host functions propagate errors, such as out of bounds memory accesses, as traps, and
+// exceptions escaping the wasm code they call as exceptions
+impl From<err::Err> for Trap {
+    fn from(err: err::Err) -> Self {
+        match err {
+            err::Err::TrapHost(message) => Trap::new(&message),
+            err::Err::UncaughtException(exception) => Trap::throw(exception),
+            err => Trap::new(&format!("{err:?}")),
+        }
+    }
+}
+
+// Calls the function at `addr`. Arguments and results are untagged, their types are those of
+// the function.
+pub(crate) fn invoke(store: &Store, addr: Addr, args: &[Slot]) -> Result<Vec<Slot>, err::Err> {
+    let functype = store
+        .funcinstances
+        .get(addr)
+        .ok_or(err::Err::UndefinedFunction(addr))?
+        .functype();
+    if args.len() != functype.input.len() {
+        return Result::Err(err::Err::InvokeArgumentsMismatch);
+    }
+    let mut thread = Thread {
+        slots: args.to_vec(),
+        frames: vec![],
+    };
+    thread.invoke(store, addr)?;
+    Result::Ok(thread.slots)
+}
+
+impl<'a> Thread<'a> {
+    // Calls the function at `addr` with the slots of the thread as arguments, which are replaced
+    // by its results on return
+    pub(crate) fn invoke(&mut self, store: &'a Store, addr: Addr) -> Result<(), err::Err> {
+        self.frames.clear();
+        store.invocations.set(store.invocations.get() + 1);
+        let res = self.call(store, addr).and_then(|()| self.execute(store));
+        store.invocations.set(store.invocations.get() - 1);
+        let output = &store.funcinstances[addr].functype().output;
+        if res.is_ok() {
+            self.slots.drain(..self.slots.len() - output.len());
+        }
+        // Exceptions thrown during the outermost call are freed when it returns, unless it
+        // returns them
+        if store.invocations.get() == 0 {
+            let vals: Vec<Val> = match &res {
+                Result::Ok(()) => self
+                    .slots
+                    .iter()
+                    .zip(output)
+                    .map(|(slot, valtype)| Val::from_slot(*slot, *valtype, store))
+                    .collect(),
+                Result::Err(err::Err::UncaughtException(exception)) => exception.payload.clone(),
+                Result::Err(_) => vec![],
+            };
+            store.collect_exns(vals.iter().filter_map(|val| match val {
+                Val::Ref(Ref::Exn(handle)) => Some(handle.addr),
+                _ => None,
+            }));
+        }
+        res
+    }
+
+    fn execute(&mut self, store: &'a Store) -> Result<(), err::Err> {
+        while let Some(frame) = self.frames.last_mut() {
+            if frame.ip >= frame.code.len() {
+                // Falling off the end of a function returns from it
+                self.return_()?;
+                continue;
+            }
+            let op = frame.curr_op();
+            frame.incr_ip();
+            store.consume_fuel(op.cost(&store.config.fuel_costs))?;
+            self.step(store, op)?;
+        }
+        Result::Ok(())
+    }
+
+    fn step(&mut self, store: &'a Store, op: Op) -> Result<(), err::Err> {
         match op {
             // Numeric
-            Instr::I32Const(val) => stack.push_into(val),
-            Instr::I32Clz => stack.unop(&u32::clz),
-            Instr::I32Ctz => stack.unop(&u32::ctz),
-            Instr::I32PopCnt => stack.unop(&u32::popcnt),
-            Instr::I32Eqz => stack.testop(&u32::eqz),
-            Instr::I32Eq => stack.relop(&u32::eq_),
-            Instr::I32Ne => stack.relop(&u32::ne_),
-            Instr::I32LtU => stack.relop(&u32::ltu),
-            Instr::I32LtS => stack.relop(&u32::lts),
-            Instr::I32GtU => stack.relop(&u32::gtu),
-            Instr::I32GtS => stack.relop(&u32::gts),
-            Instr::I32LeU => stack.relop(&u32::leu),
-            Instr::I32LeS => stack.relop(&u32::les),
-            Instr::I32GeU => stack.relop(&u32::geu),
-            Instr::I32GeS => stack.relop(&u32::ges),
-            Instr::I32Add => stack.binop(&u32::wrapping_add),
-            Instr::I32Sub => stack.binop(&u32::wrapping_sub),
-            Instr::I32Mul => stack.binop(&u32::wrapping_mul),
-            Instr::I32DivU => stack.binop(&u32::wrapping_div),
-            Instr::I32DivS => stack.binop(&u32::div_s),
-            Instr::I32RemU => stack.binop(&u32::wrapping_rem),
-            Instr::I32RemS => stack.binop(&u32::rem_s),
-            Instr::I32Not => stack.unop(&u32::not),
-            Instr::I32And => stack.binop(&u32::bitand),
-            Instr::I32Or => stack.binop(&u32::bitor),
-            Instr::I32Xor => stack.binop(&u32::bitxor),
-            Instr::I32Shl => stack.binop(&u32::shl),
-            Instr::I32ShrU => stack.binop(&u32::shr_u),
-            Instr::I32ShrS => stack.binop(&u32::shr_s),
-            Instr::I32Rotl => stack.binop(&u32::rotl),
-            Instr::I32Rotr => stack.binop(&u32::rotr),
-
-            Instr::I64Const(val) => stack.push_into(val),
-            Instr::I64Clz => stack.unop(&u64::clz),
-            Instr::I64Ctz => stack.unop(&u64::ctz),
-            Instr::I64PopCnt => stack.unop(&u64::popcnt),
-            Instr::I64Eqz => stack.testop(&u64::eqz),
-            Instr::I64Eq => stack.relop(&u64::eq_),
-            Instr::I64Ne => stack.relop(&u64::ne_),
-            Instr::I64LtU => stack.relop(&u64::ltu),
-            Instr::I64LtS => stack.relop(&u64::lts),
-            Instr::I64GtU => stack.relop(&u64::gtu),
-            Instr::I64GtS => stack.relop(&u64::gts),
-            Instr::I64LeU => stack.relop(&u64::leu),
-            Instr::I64LeS => stack.relop(&u64::les),
-            Instr::I64GeU => stack.relop(&u64::geu),
-            Instr::I64GeS => stack.relop(&u64::ges),
-            Instr::I64Add => stack.binop(&u64::wrapping_add),
-            Instr::I64Sub => stack.binop(&u64::wrapping_sub),
-            Instr::I64Mul => stack.binop(&u64::wrapping_mul),
-            Instr::I64DivU => stack.binop(&u64::wrapping_div),
-            Instr::I64DivS => stack.binop(&u64::div_s),
-            Instr::I64RemU => stack.binop(&u64::wrapping_rem),
-            Instr::I64RemS => stack.binop(&u64::rem_s),
-            Instr::I64Not => stack.unop(&u64::not),
-            Instr::I64And => stack.binop(&u64::bitand),
-            Instr::I64Or => stack.binop(&u64::bitor),
-            Instr::I64Xor => stack.binop(&u64::bitxor),
-            Instr::I64Shl => stack.binop(&u64::shl),
-            Instr::I64ShrU => stack.binop(&u64::shr_u),
-            Instr::I64ShrS => stack.binop(&u64::shr_s),
-            Instr::I64Rotl => stack.binop(&u64::rotl),
-            Instr::I64Rotr => stack.binop(&u64::rotr),
-
-            Instr::F32Const(val) => stack.push_into(val),
-            Instr::F64Const(val) => stack.push_into(val),
+            Op::I32Const(val) => self.slots.push_into(val),
+            Op::I32Clz => self.slots.unop(u32::clz),
+            Op::I32Ctz => self.slots.unop(u32::ctz),
+            Op::I32PopCnt => self.slots.unop(u32::popcnt),
+            Op::I32Eqz => self.slots.testop(u32::eqz),
+            Op::I32Eq => self.slots.relop(u32::eq_),
+            Op::I32Ne => self.slots.relop(u32::ne_),
+            Op::I32LtU => self.slots.relop(u32::ltu),
+            Op::I32LtS => self.slots.relop(u32::lts),
+            Op::I32GtU => self.slots.relop(u32::gtu),
+            Op::I32GtS => self.slots.relop(u32::gts),
+            Op::I32LeU => self.slots.relop(u32::leu),
+            Op::I32LeS => self.slots.relop(u32::les),
+            Op::I32GeU => self.slots.relop(u32::geu),
+            Op::I32GeS => self.slots.relop(u32::ges),
+            Op::I32Add => self.slots.binop(u32::wrapping_add),
+            Op::I32Sub => self.slots.binop(u32::wrapping_sub),
+            Op::I32Mul => self.slots.binop(u32::wrapping_mul),
+            Op::I32DivU => self.slots.binop(u32::wrapping_div),
+            Op::I32DivS => self.slots.binop(u32::div_s),
+            Op::I32RemU => self.slots.binop(u32::wrapping_rem),
+            Op::I32RemS => self.slots.binop(u32::rem_s),
+            Op::I32Not => self.slots.unop(u32::not),
+            Op::I32And => self.slots.binop(u32::bitand),
+            Op::I32Or => self.slots.binop(u32::bitor),
+            Op::I32Xor => self.slots.binop(u32::bitxor),
+            Op::I32Shl => self.slots.binop(u32::shl),
+            Op::I32ShrU => self.slots.binop(u32::shr_u),
+            Op::I32ShrS => self.slots.binop(u32::shr_s),
+            Op::I32Rotl => self.slots.binop(u32::rotl),
+            Op::I32Rotr => self.slots.binop(u32::rotr),
+
+            Op::I64Const(val) => self.slots.push_into(val),
+            Op::I64Clz => self.slots.unop(u64::clz),
+            Op::I64Ctz => self.slots.unop(u64::ctz),
+            Op::I64PopCnt => self.slots.unop(u64::popcnt),
+            Op::I64Eqz => self.slots.testop(u64::eqz),
+            Op::I64Eq => self.slots.relop(u64::eq_),
+            Op::I64Ne => self.slots.relop(u64::ne_),
+            Op::I64LtU => self.slots.relop(u64::ltu),
+            Op::I64LtS => self.slots.relop(u64::lts),
+            Op::I64GtU => self.slots.relop(u64::gtu),
+            Op::I64GtS => self.slots.relop(u64::gts),
+            Op::I64LeU => self.slots.relop(u64::leu),
+            Op::I64LeS => self.slots.relop(u64::les),
+            Op::I64GeU => self.slots.relop(u64::geu),
+            Op::I64GeS => self.slots.relop(u64::ges),
+            Op::I64Add => self.slots.binop(u64::wrapping_add),
+            Op::I64Sub => self.slots.binop(u64::wrapping_sub),
+            Op::I64Mul => self.slots.binop(u64::wrapping_mul),
+            Op::I64DivU => self.slots.binop(u64::wrapping_div),
+            Op::I64DivS => self.slots.binop(u64::div_s),
+            Op::I64RemU => self.slots.binop(u64::wrapping_rem),
+            Op::I64RemS => self.slots.binop(u64::rem_s),
+            Op::I64Not => self.slots.unop(u64::not),
+            Op::I64And => self.slots.binop(u64::bitand),
+            Op::I64Or => self.slots.binop(u64::bitor),
+            Op::I64Xor => self.slots.binop(u64::bitxor),
+            Op::I64Shl => self.slots.binop(u64::shl),
+            Op::I64ShrU => self.slots.binop(u64::shr_u),
+            Op::I64ShrS => self.slots.binop(u64::shr_s),
+            Op::I64Rotl => self.slots.binop(u64::rotl),
+            Op::I64Rotr => self.slots.binop(u64::rotr),
+
+            Op::F32Const(val) => self.slots.push_into(val),
+            Op::F64Const(val) => self.slots.push_into(val),
+
+            Op::I32Extend8S => self.slots.unop(|val: u32| val as i8 as u32),
+            Op::I32Extend16S => self.slots.unop(|val: u32| val as i16 as u32),
+            Op::I64Extend8S => self.slots.unop(|val: u64| val as i8 as u64),
+            Op::I64Extend16S => self.slots.unop(|val: u64| val as i16 as u64),
+            Op::I64Extend32S => self.slots.unop(|val: u64| val as i32 as u64),
+            // Float to int casts saturate, NaN giving 0
+            Op::I32TruncSatF32U => self.slots.convert(|val: f32| val as u32),
+            Op::I32TruncSatF32S => self.slots.convert(|val: f32| val as i32 as u32),
+            Op::I32TruncSatF64U => self.slots.convert(|val: f64| val as u32),
+            Op::I32TruncSatF64S => self.slots.convert(|val: f64| val as i32 as u32),
+            Op::I64TruncSatF32U => self.slots.convert(|val: f32| val as u64),
+            Op::I64TruncSatF32S => self.slots.convert(|val: f32| val as i64 as u64),
+            Op::I64TruncSatF64U => self.slots.convert(|val: f64| val as u64),
+            Op::I64TruncSatF64S => self.slots.convert(|val: f64| val as i64 as u64),
             // Ref
-            Instr::RefNull(reftype) => stack.push(StackEntry::Value(Val::Ref(Ref::Null(reftype)))),
-            Instr::RefFunc(func_idx) => {
-                // TODO validate index
-                let func_addr = frame.framestate.borrow_mut().module.borrow().funct[func_idx];
-                stack.push(StackEntry::Value(Val::Ref(Ref::Func(func_addr))))
+            Op::RefNull => self.slots.push(NULL_REF),
+            Op::RefFunc(func_idx) => {
+                let func_addr = store.modules[self.frame().module].funct[func_idx as usize];
+                self.slots.push(func_addr as Slot)
+            }
+            Op::RefAsNonNull => {
+                if *self.slots.last().unwrap() == NULL_REF {
+                    return Result::Err(err::Err::TrapNullReference);
+                }
             }
             // Var
-            Instr::LocalGet(local_idx) => {
-                // TODO validate index
-                stack.push(StackEntry::Value(
-                    frame.framestate.borrow_mut().locals[local_idx],
-                ));
-            }
-            Instr::LocalSet(local_idx) => {
-                // TODO validate top of stack is value
-                match stack.pop().unwrap() {
-                    StackEntry::Value(val) => {
-                        // TODO validate index
-                        frame.framestate.borrow_mut().locals[local_idx] = val;
-                    }
+            Op::LocalGet(idx) => {
+                let val = *self.local(idx);
+                self.slots.push(val);
+            }
+            Op::LocalSet(idx) => {
+                let val = self.slots.pop().unwrap();
+                *self.local(idx) = val;
+            }
+            Op::LocalTee(idx) => {
+                let val = *self.slots.last().unwrap();
+                *self.local(idx) = val;
+            }
+            Op::GlobalGet(global_idx) => {
+                let glob_addr = store.modules[self.frame().module].globals[global_idx as usize];
+                let val = store.globals[glob_addr].borrow().value.to_slot()?;
+                self.slots.push(val);
+            }
+            Op::GlobalSet(global_idx) => {
+                let glob_addr = store.modules[self.frame().module].globals[global_idx as usize];
+                let slot = self.slots.pop().unwrap();
+                let mut global = store.globals[glob_addr].borrow_mut();
+                global.value = Val::from_slot(slot, global.globaltype.val, store);
+            }
+            // Table
+            Op::TableSize(table_idx) => {
+                let table_addr = self.table_addr(store, table_idx)?;
+                let size = store.tables[table_addr].borrow().elem.len() as u32;
+                self.slots.push_into(size);
+            }
+            Op::TableGrow(table_idx) => {
+                let table_addr = self.table_addr(store, table_idx)?;
+                let n: u32 = self.slots.pop_from();
+                let slot = self.slots.pop().unwrap();
+                let reftype = store.tables[table_addr].borrow().tabletype.reftype;
+                let init = match Val::from_slot(slot, types::Value::Ref(reftype), store) {
+                    Val::Ref(init) => init,
                     _ => unreachable!(),
+                };
+                let res = store.grow_table(table_addr, n as usize, init)?;
+                self.slots
+                    .push_into(res.map_or(u32::MAX, |size| size as u32));
+            }
+            // Memory
+            Op::Load {
+                access,
+                mem,
+                offset,
+            } => {
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let mem = store.mem_at(mem_addr)?;
+                match access {
+                    Access::I32 => self.slots.push_into(mem.load::<u32>(address)?),
+                    Access::I64 => self.slots.push_into(mem.load::<u64>(address)?),
+                    Access::F32 => self.slots.push_into(mem.load::<f32>(address)?),
+                    Access::F64 => self.slots.push_into(mem.load::<f64>(address)?),
+                    Access::I32S8 => self.slots.push_into(mem.load::<i8>(address)? as u32),
+                    Access::I32U8 => self.slots.push_into(mem.load::<u8>(address)? as u32),
+                    Access::I32S16 => self.slots.push_into(mem.load::<i16>(address)? as u32),
+                    Access::I32U16 => self.slots.push_into(mem.load::<u16>(address)? as u32),
+                    Access::I64S8 => self.slots.push_into(mem.load::<i8>(address)? as u64),
+                    Access::I64U8 => self.slots.push_into(mem.load::<u8>(address)? as u64),
+                    Access::I64S16 => self.slots.push_into(mem.load::<i16>(address)? as u64),
+                    Access::I64U16 => self.slots.push_into(mem.load::<u16>(address)? as u64),
+                    Access::I64S32 => self.slots.push_into(mem.load::<i32>(address)? as u64),
+                    Access::I64U32 => self.slots.push_into(mem.load::<u32>(address)? as u64),
                 }
             }
-            Instr::LocalTee(local_idx) => {
-                // TODO validate top of stack is value
-                match stack.peek().unwrap() {
-                    StackEntry::Value(val) => {
-                        frame.framestate.borrow_mut().locals[local_idx] = val;
+            Op::Store {
+                access,
+                mem,
+                offset,
+            } => {
+                // Floats are stored as their bit pattern, narrow stores wrap the value
+                let value = match access {
+                    Access::I32 | Access::F32 | Access::I32U8 | Access::I32U16 => {
+                        self.slots.pop_from::<u32>() as u64
                     }
-                    _ => unreachable!(),
-                }
+                    _ => self.slots.pop_from::<u64>(),
+                };
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let mut mem = store.mem_at_mut(mem_addr)?;
+                let bytes = value.to_le_bytes();
+                mem.write(address, &bytes[..access.size()])?;
+            }
+            // Sizes are i64 for memories with i64 addresses
+            Op::MemorySize(idx) => {
+                let mem_addr = self.mem_addr(store, idx)?;
+                let mem = store.mem_at(mem_addr)?;
+                let size = mem.size() / PAGE_SIZE;
+                self.push_address(mem.memtype.index, size as u64);
             }
-            Instr::GlobalGet(global_idx) => {
-                // TODO validate index
-                let glob_addr = frame.framestate.borrow_mut().module.borrow().globals[global_idx];
-                stack.push(StackEntry::Value(
-                    store.globals[glob_addr].borrow_mut().value,
-                ));
-            }
-            Instr::GlobalSet(global_idx) => {
-                // TODO validate top of stack is value
-                match stack.pop().unwrap() {
-                    StackEntry::Value(val) => {
-                        // TODO validate index
-                        let glob_addr =
-                            frame.framestate.borrow().module.borrow().globals[global_idx];
-                        store.globals[glob_addr].borrow_mut().value = val;
+            Op::MemoryGrow(idx) => {
+                let mem_addr = self.mem_addr(store, idx)?;
+                let index = store.mem_at(mem_addr)?.memtype.index;
+                // A delta beyond the address space cannot be satisfied
+                let res = match usize::try_from(self.pop_address(index)) {
+                    Result::Ok(n) => store.grow_memory(mem_addr, n)?,
+                    Result::Err(_) => None,
+                };
+                let res = res.map_or(u64::MAX, |size| size as u64);
+                self.push_address(index, res);
+            }
+            Op::MemoryFill(idx) => {
+                let mem_addr = self.mem_addr(store, idx)?;
+                let index = store.mem_at(mem_addr)?.memtype.index;
+                let bounded = |address: u64| {
+                    usize::try_from(address).map_err(|_| err::Err::OutOfBoundMemoryAccess)
+                };
+                let len = bounded(self.pop_address(index))?;
+                let value: u32 = self.slots.pop_from();
+                let offset = bounded(self.pop_address(index))?;
+                store.mem_at_mut(mem_addr)?.fill(offset, len, value as u8)?;
+            }
+            Op::MemoryCopy { dst, src } => {
+                let (dst, src) = (self.mem_addr(store, dst)?, self.mem_addr(store, src)?);
+                let dst_index = store.mem_at(dst)?.memtype.index;
+                let src_index = store.mem_at(src)?.memtype.index;
+                // The length is i64 only when both memories have i64 addresses
+                let len_index = match (dst_index, src_index) {
+                    (IndexType::I64, IndexType::I64) => IndexType::I64,
+                    _ => IndexType::I32,
+                };
+                let bounded = |address: u64| {
+                    usize::try_from(address).map_err(|_| err::Err::OutOfBoundMemoryAccess)
+                };
+                let len = bounded(self.pop_address(len_index))?;
+                let src_offset = bounded(self.pop_address(src_index))?;
+                let dst_offset = bounded(self.pop_address(dst_index))?;
+                if dst == src {
+                    let mut mem = store.mem_at_mut(dst)?;
+                    mem.copy_within(src_offset, dst_offset, len)?;
+                } else {
+                    let from = store.mem_at(src)?;
+                    let mut to = store.mem_at_mut(dst)?;
+                    match from.slice(src_offset, len) {
+                        Result::Ok(bytes) => to.write(dst_offset, bytes)?,
+                        // Shared memories are copied through a buffer
+                        Result::Err(err::Err::MemoryShared) => {
+                            let mut bytes = vec![0; len];
+                            from.read(src_offset, &mut bytes)?;
+                            to.write(dst_offset, &bytes)?;
+                        }
+                        Result::Err(err) => return Result::Err(err),
                     }
-                    _ => unreachable!(),
                 }
             }
-            Instr::Nop => {
+            // Dropped segments are empty, only empty ranges of them can be copied
+            Op::MemoryInit { data, mem } => {
+                let data_addr = self.data_addr(store, data)?;
+                let mem_addr = self.mem_addr(store, mem)?;
+                let index = store.mem_at(mem_addr)?.memtype.index;
+                let len = self.slots.pop_from::<u32>() as usize;
+                let src = self.slots.pop_from::<u32>() as usize;
+                let dst = usize::try_from(self.pop_address(index))
+                    .map_err(|_| err::Err::OutOfBoundMemoryAccess)?;
+                let data = store.datas[data_addr].borrow();
+                let bytes = src
+                    .checked_add(len)
+                    .and_then(|end| data.data.get(src..end))
+                    .ok_or(err::Err::OutOfBoundMemoryAccess)?;
+                store.mem_at_mut(mem_addr)?.write(dst, bytes)?;
+            }
+            Op::DataDrop(idx) => {
+                let data_addr = self.data_addr(store, idx)?;
+                store.datas[data_addr].borrow_mut().data = vec![];
+            }
+            // Atomic accesses are sequentially consistent
+            Op::AtomicLoad {
+                atomic,
+                mem,
+                offset,
+            } => {
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let mem = store.mem_at(mem_addr)?;
+                let value = mem.atomic_load(address, atomic.size())?;
+                self.push_atomic(atomic, value);
+            }
+            Op::AtomicStore {
+                atomic,
+                mem,
+                offset,
+            } => {
+                let value = self.pop_atomic(atomic);
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let mut mem = store.mem_at_mut(mem_addr)?;
+                mem.atomic_rmw(address, atomic.size(), |_| value)?;
+            }
+            Op::AtomicRmw {
+                rmw,
+                atomic,
+                mem,
+                offset,
+            } => {
+                let operand = self.pop_atomic(atomic);
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let mut mem = store.mem_at_mut(mem_addr)?;
+                let old = mem.atomic_rmw(address, atomic.size(), |old| rmw.apply(old, operand))?;
+                self.push_atomic(atomic, old);
+            }
+            Op::AtomicCmpxchg {
+                atomic,
+                mem,
+                offset,
+            } => {
+                let replacement = self.pop_atomic(atomic);
+                // Compared at the width of the access
+                let expected = self.pop_atomic(atomic) & (u64::MAX >> (64 - 8 * atomic.size()));
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let mut mem = store.mem_at_mut(mem_addr)?;
+                let old = mem.atomic_rmw(address, atomic.size(), |old| match old == expected {
+                    true => replacement,
+                    false => old,
+                })?;
+                self.push_atomic(atomic, old);
+            }
+            Op::MemoryAtomicNotify { mem, offset } => {
+                let count: u32 = self.slots.pop_from();
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                let woken = store.mem_at(mem_addr)?.atomic_notify(address, count)?;
+                self.slots.push_into(woken);
+            }
+            Op::MemoryAtomicWait { wide, mem, offset } => {
+                // A negative timeout waits indefinitely
+                let timeout = u64::try_from(self.slots.pop_from::<u64>() as i64).ok();
+                let (expected, size) = match wide {
+                    true => (self.slots.pop_from::<u64>(), 8),
+                    false => (self.slots.pop_from::<u32>() as u64, 4),
+                };
+                let (mem_addr, address) = self.effective_address(store, mem, offset)?;
+                // The memory is not borrowed while the thread is parked
+                let shared = store.mem_at(mem_addr)?.shared().cloned();
+                let shared = shared.ok_or(err::Err::TrapExpectedSharedMemory)?;
+                let res = shared.wait(address, size, expected, timeout)?;
+                self.slots.push_into(res as u32);
+            }
+            Op::AtomicFence => atomic::fence(atomic::Ordering::SeqCst),
+            // Control
+            Op::Nop => {
                 // Do nothing
             }
-            Instr::Unreachable => return Result::Err(err::Err::TrapUnreachable),
-            Instr::Return => {
-                if frame.arity > stack.len() {
-                    return Result::Err(err::Err::AssertFailedEnoughVauesToReturn);
+            Op::Unreachable => return Result::Err(err::Err::TrapUnreachable),
+            Op::Br(branch) => self.branch(branch),
+            Op::BrIf(branch) => {
+                let cond: u32 = self.slots.pop_from();
+                if cond != 0 {
+                    self.branch(branch);
                 }
-                let mut values = vec![];
-                for _ in 0..frame.arity {
-                    match stack.pop().unwrap() {
-                        StackEntry::Value(val) => values.push(val),
-                        _ => return Result::Err(err::Err::InvariantViolatedAllResultsAreValues),
-                    }
+            }
+            Op::BrIfEqz(target) => {
+                let cond: u32 = self.slots.pop_from();
+                if cond == 0 {
+                    self.frame().jump(target as usize);
                 }
-
-                match stack.pop() {
-                    Option::Some(StackEntry::Activation(_, _)) => {
-                        return Result::Ok(values);
-                    }
-                    _ => {
-                        return Result::Err(err::Err::AssertFailedFrameOnTopOfStack);
-                    }
+            }
+            Op::BrOnNull(branch) => {
+                if *self.slots.last().unwrap() == NULL_REF {
+                    self.slots.pop();
+                    self.branch(branch);
+                }
+            }
+            Op::BrOnNonNull(branch) => {
+                if *self.slots.last().unwrap() == NULL_REF {
+                    self.slots.pop();
+                } else {
+                    self.branch(branch);
                 }
             }
+            Op::Return => self.return_()?,
+            Op::Call(idx) => {
+                let faddr = store.modules[self.frame().module].funct[idx as usize];
+                self.call(store, faddr)?;
+            }
+            Op::CallRef(typeidx) => {
+                let faddr = self.func_ref(store, typeidx)?;
+                self.call(store, faddr)?;
+            }
+            Op::ReturnCall(idx) => {
+                let faddr = store.modules[self.frame().module].funct[idx as usize];
+                self.tail_call(store, faddr)?;
+            }
+            Op::ReturnCallIndirect { typeidx, table } => {
+                let faddr = self.indirect(store, typeidx, table)?;
+                self.tail_call(store, faddr)?;
+            }
+            Op::ReturnCallRef(typeidx) => {
+                let faddr = self.func_ref(store, typeidx)?;
+                self.tail_call(store, faddr)?;
+            }
+            Op::Throw(idx) => {
+                let tag = store.modules[self.frame().module].tags[idx as usize];
+                let len = store.tags[tag].tagtype.input.len();
+                let payload = self.slots.split_off(self.slots.len() - len);
+                let exn = store.exn_alloc(tag, payload);
+                self.throw(store, exn)?;
+            }
+            Op::ThrowRef => match self.slots.pop().unwrap() {
+                NULL_REF => return Result::Err(err::Err::TrapNullReference),
+                exn => self.throw(store, exn as Addr)?,
+            },
+            // Superinstructions
+            Op::BinLL { op, lhs, rhs } => {
+                let res = op.apply(*self.local(lhs), *self.local(rhs));
+                self.slots.push(res);
+            }
+            Op::BinLLSet { op, lhs, rhs, dst } => {
+                *self.local(dst) = op.apply(*self.local(lhs), *self.local(rhs));
+            }
+            Op::I32BinLC { op, lhs, imm } => {
+                let res = op.apply(*self.local(lhs), imm as Slot);
+                self.slots.push(res);
+            }
+            Op::I32BinLCSet { op, lhs, imm, dst } => {
+                *self.local(dst) = op.apply(*self.local(lhs), imm as Slot);
+            }
+            Op::I32BinC { op, imm } => {
+                let top = self.slots.last_mut().unwrap();
+                *top = op.apply(*top, imm as Slot);
+            }
+            Op::BrIfRel { op, branch } => {
+                let rhs = self.slots.pop().unwrap();
+                let lhs = self.slots.pop().unwrap();
+                if op.apply(lhs, rhs) {
+                    self.branch(branch);
+                }
+            }
+            Op::BrIfNotRel { op, target } => {
+                let rhs = self.slots.pop().unwrap();
+                let lhs = self.slots.pop().unwrap();
+                if !op.apply(lhs, rhs) {
+                    self.frame().jump(target as usize);
+                }
+            }
+            Op::BrUnless(branch) => {
+                let cond: u32 = self.slots.pop_from();
+                if cond == 0 {
+                    self.branch(branch);
+                }
+            }
+        }
+        Result::Ok(())
+    }
+
+    fn frame(&mut self) -> &mut Activation<'a> {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn table_addr(&mut self, store: &Store, table_idx: u32) -> Result<Addr, err::Err> {
+        let module = &store.modules[self.frame().module];
+        let addr = module.tables.get(table_idx as usize);
+        addr.copied()
+            .ok_or(err::Err::UndefinedTable(table_idx as usize))
+    }
+
+    fn mem_addr(&mut self, store: &Store, mem_idx: u32) -> Result<Addr, err::Err> {
+        let module = &store.modules[self.frame().module];
+        let addr = module.mems.get(mem_idx as usize);
+        addr.copied()
+            .ok_or(err::Err::UndefinedMem(mem_idx as usize))
+    }
+
+    fn data_addr(&mut self, store: &Store, data_idx: u32) -> Result<Addr, err::Err> {
+        let module = &store.modules[self.frame().module];
+        let addr = module.datas.get(data_idx as usize);
+        addr.copied()
+            .ok_or(err::Err::UndefinedData(data_idx as usize))
+    }
+
+    // Addresses and sizes are i32 or i64 depending on the index type of the memory
+    fn pop_address(&mut self, index: IndexType) -> u64 {
+        match index {
+            IndexType::I32 => self.slots.pop_from::<u32>() as u64,
+            IndexType::I64 => self.slots.pop_from::<u64>(),
+        }
+    }
+
+    fn push_address(&mut self, index: IndexType, address: u64) {
+        match index {
+            IndexType::I32 => self.slots.push_into(address as u32),
+            IndexType::I64 => self.slots.push_into(address),
+        }
+    }
+
+    // Memory accessed by a load, store or atomic instruction and its effective address, which is
+    // out of bounds if it overflows
+    fn effective_address(
+        &mut self,
+        store: &Store,
+        mem_idx: u32,
+        offset: u64,
+    ) -> Result<(Addr, usize), err::Err> {
+        let mem_addr = self.mem_addr(store, mem_idx)?;
+        let index = store.mem_at(mem_addr)?.memtype.index;
+        let address = self.pop_address(index).checked_add(offset);
+        let address = address.and_then(|address| usize::try_from(address).ok());
+        Result::Ok((mem_addr, address.ok_or(err::Err::OutOfBoundMemoryAccess)?))
+    }
 
-            Instr::Call(idx) => {
-                if frame.framestate.borrow_mut().module.borrow().funct.len() < idx {
-                    return Result::Err(err::Err::AssertFailedFuncInstanceExists);
+    // Operands of atomic instructions are i32 or i64 depending on the access
+    fn pop_atomic(&mut self, atomic: Atomic) -> u64 {
+        match atomic.is_i64() {
+            true => self.slots.pop_from::<u64>(),
+            false => self.slots.pop_from::<u32>() as u64,
+        }
+    }
+
+    fn push_atomic(&mut self, atomic: Atomic, value: u64) {
+        match atomic.is_i64() {
+            true => self.slots.push_into(value),
+            false => self.slots.push_into(value as u32),
+        }
+    }
+
+    fn local(&mut self, idx: u32) -> &mut Slot {
+        let base = self.frame().base;
+        &mut self.slots[base + idx as usize]
+    }
+
+    // Pushes an activation whose parameters are already on the stack, followed by its `locals`
+    // set to their default, and whose code needs up to `max_height` operands. The stack must
+    // have room for both before anything is pushed.
+    fn push_frame(
+        &mut self,
+        store: &Store,
+        frame: Activation<'a>,
+        locals: &[types::Value],
+        max_height: usize,
+    ) -> Result<(), err::Err> {
+        let needed = self.slots.len() + locals.len() + max_height;
+        if needed > store.config.stack_limits.max_values {
+            return Result::Err(err::Err::TrapCallStackExhausted);
+        }
+        for local in locals {
+            self.slots.push(Val::default(*local).to_slot()?);
+        }
+        self.frames.push(frame);
+        Result::Ok(())
+    }
+
+    // Calls the function at `addr`, its arguments being the topmost operands
+    fn call(&mut self, store: &'a Store, addr: Addr) -> Result<(), err::Err> {
+        if self.frames.len() > store.config.stack_limits.max_call_depth {
+            return Result::Err(err::Err::TrapCallStackExhausted);
+        }
+        match &store.funcinstances[addr] {
+            FuncInstance::Internal(InternalFuncInstance {
+                functype,
+                module,
+                code,
+                bytecode,
+            }) => {
+                if self.slots.len() < functype.input.len() {
+                    return Result::Err(err::Err::AssertFailedEnoughStackValuesForFunctionCall);
                 }
-                let faddr = &frame.framestate.borrow_mut().module.borrow().funct[idx];
-                let finstance = &store.funcinstances[*faddr];
-
-                match finstance {
-                    FuncInstance::Internal(InternalFuncInstance {
-                        functype,
-                        module,
-                        code,
+                let base = self.slots.len() - functype.input.len();
+                self.push_frame(
+                    store,
+                    Activation {
+                        arity: functype.output.len(),
+                        module: *module,
+                        code: &bytecode.ops,
+                        handlers: &bytecode.handlers,
+                        ip: 0,
+                        base,
+                    },
+                    &code.locals,
+                    bytecode.max_height,
+                )
+            }
+            FuncInstance::Host(HostFuncInstance { functype, hostfunc }) => {
+                if self.slots.len() < functype.input.len() {
+                    return Result::Err(err::Err::AssertFailedEnoughStackValuesForFunctionCall);
+                }
+                let instance = self
+                    .frames
+                    .last()
+                    .map(|frame| store.instance_id(frame.module));
+                let caller = Caller::new(store, instance);
+                match (hostfunc.func)(caller, &mut self.slots) {
+                    Result::Ok(()) => Result::Ok(()),
+                    // Exceptions thrown by the host unwind like those thrown by wasm code
+                    Result::Err(Trap {
+                        exception: Some(exception),
+                        ..
                     }) => {
-                        if stack.len() < functype.input.len() {
-                            return Result::Err(
-                                err::Err::AssertFailedEnoughStackValuesForFunctionCall,
-                            );
-                        }
-                        let inner_frame = Frame {
-                            arity: functype.output.len(),
-                            framestate: RefCell::new(FrameState {
-                                locals: vec![], // TODO pop functype.input.len() values from stack
-                                module,
-                            }),
-                        };
-                        stack.push(StackEntry::Activation(
-                            0, // TODO ???
-                            &inner_frame,
-                        ));
-                        let label = Label {
-                            arity: functype.output.len(),
-                            instr: &code.body,
-                        };
-                        //run(store, label.instr, &inner_frame);
-                        unimplemented!()
-                    }
-                    FuncInstance::Host(HostFuncInstance { functype }) => {
-                        unimplemented!()
+                        let exn = store.exn_from(&exception)?;
+                        self.throw(store, exn)
                     }
+                    Result::Err(trap) => Result::Err(trap.into()),
                 }
             }
         }
-        ip += 1;
     }
 
-    let mut res = vec![];
-    for entry in stack {
-        match entry {
-            StackEntry::Value(val) => {
-                res.push(val);
+    // Replaces the current frame by a call to the function at `addr`, its arguments being moved
+    // over the locals of the frame, so that tail calls run in constant stack space
+    fn tail_call(&mut self, store: &'a Store, addr: Addr) -> Result<(), err::Err> {
+        let frame = self.frames.pop().unwrap();
+        let input = store.funcinstances[addr].functype().input.len();
+        if self.slots.len() < frame.base + input {
+            return Result::Err(err::Err::AssertFailedEnoughStackValuesForFunctionCall);
+        }
+        let args = self.slots.len() - input;
+        self.slots.copy_within(args.., frame.base);
+        self.slots.truncate(frame.base + input);
+        self.call(store, addr)
+    }
+
+    // Pops the reference to the function called by `call_ref`
+    fn func_ref(&mut self, store: &Store, typeidx: u32) -> Result<Addr, err::Err> {
+        match self.slots.pop().unwrap() {
+            NULL_REF => Result::Err(err::Err::TrapNullReference),
+            faddr => self.typed_callee(store, faddr as Addr, typeidx),
+        }
+    }
+
+    // Resolves the function called through the table, whose index is popped
+    fn indirect(&mut self, store: &Store, typeidx: u32, table: u32) -> Result<Addr, err::Err> {
+        let idx = self.slots.pop().unwrap() as u32;
+        let table = store.tables[self.table_addr(store, table)?].borrow();
+        let faddr = match table.elem.get(idx as usize) {
+            None => return Result::Err(err::Err::TrapUndefinedElement),
+            Some(Ref::Null(_)) => return Result::Err(err::Err::TrapUninitializedElement),
+            Some(Ref::Func(func)) => func.addr,
+            Some(Ref::Extern(_) | Ref::Exn(_)) => {
+                return Result::Err(err::Err::TrapIndirectCallTypeMismatch)
+            }
+        };
+        self.typed_callee(store, faddr, typeidx)
+    }
+
+    // Checks that the function at `faddr` has the type at `typeidx` in the current module
+    fn typed_callee(&mut self, store: &Store, faddr: Addr, typeidx: u32) -> Result<Addr, err::Err> {
+        let expected = &store.modules[self.frame().module].types[typeidx as usize];
+        let types = store.types.borrow();
+        match store.funcinstances.get(faddr) {
+            Some(func) if func.functype().is_subtype(expected, &types) => Result::Ok(faddr),
+            _ => Result::Err(err::Err::TrapIndirectCallTypeMismatch),
+        }
+    }
+
+    // Pops the current frame, moving its results over its locals.
+    // The outermost frame leaves the stack as is for the caller of `execute`.
+    fn return_(&mut self) -> Result<(), err::Err> {
+        let frame = self.frames.pop().unwrap();
+        if self.slots.len() < frame.base + frame.arity {
+            return Result::Err(err::Err::AssertFailedEnoughVauesToReturn);
+        }
+        if !self.frames.is_empty() {
+            let results = self.slots.len() - frame.arity;
+            self.slots.copy_within(results.., frame.base);
+            self.slots.truncate(frame.base + frame.arity);
+        }
+        Result::Ok(())
+    }
+
+    // Unwinds to the innermost handler catching the exception at `exn`, through the frames of the
+    // thread. Exceptions no handler catches escape to the caller of the thread.
+    fn throw(&mut self, store: &Store, exn: Addr) -> Result<(), err::Err> {
+        let (tag, payload) = store.exn(exn)?;
+        while let Some(frame) = self.frames.last_mut() {
+            // The op that threw, or the call the exception was thrown through
+            let pos = frame.ip as u32 - 1;
+            let tags = &store.modules[frame.module].tags;
+            let handlers = frame
+                .handlers
+                .iter()
+                .filter(|handler| handler.start <= pos && pos < handler.end);
+            for handler in handlers {
+                let catches =
+                    |catch: &&HandlerCatch| catch.tag.is_none_or(|idx| tags[idx as usize] == tag);
+                if let Some(catch) = handler.catches.iter().find(catches) {
+                    self.slots.truncate(frame.base + handler.height as usize);
+                    if catch.tag.is_some() {
+                        self.slots.extend(&payload);
+                    }
+                    if catch.with_ref {
+                        self.slots.push(exn as Slot);
+                    }
+                    frame.jump(catch.landing as usize);
+                    return Result::Ok(());
+                }
             }
-            _ => return Result::Err(err::Err::InvariantViolatedAllResultsAreValues),
+            let frame = self.frames.pop().unwrap();
+            self.slots.truncate(frame.base);
+        }
+        Result::Err(err::Err::UncaughtException(store.exception(exn)?))
+    }
+
+    fn branch(&mut self, branch: Branch) {
+        if branch.drop > 0 {
+            let len = self.slots.len();
+            let end = len - branch.keep as usize;
+            self.slots.copy_within(end.., end - branch.drop as usize);
+            self.slots.truncate(len - branch.drop as usize);
         }
+        self.frame().jump(branch.target as usize);
     }
-    return Result::Ok(res);
 }
 
 #[cfg(test)]
 mod tests {
     extern crate std;
 
-    use crate::runtime::{self, ModuleInstance};
+    use crate::{
+        bytecode,
+        embedding::Store as _,
+        instr::{BlockType, Instr, MemArg, RmwOp},
+        modules::Func,
+        runtime::{self, Engine, Frame, ModuleInstance},
+        shared::SharedMemory,
+        types::{self, i32_function},
+        validation::Context,
+    };
 
     use super::*;
 
+    // Executes `program` in `frame`. The values left on the stack by `program` are returned
+    // untagged.
+    fn run(store: &Store, frame: Frame, program: &[Instr]) -> Result<Vec<Slot>, err::Err> {
+        let instance = store
+            .modules
+            .get(frame.module)
+            .ok_or(err::Err::UndefinedInstance(frame.module))?;
+        let context = Context {
+            types: instance.types.clone(),
+            funcs: instance
+                .funct
+                .iter()
+                .map(|addr| store.funcinstances[*addr].functype().clone())
+                .collect(),
+            tables: instance
+                .tables
+                .iter()
+                .map(|addr| store.tables[*addr].borrow().tabletype)
+                .collect(),
+            mems: instance
+                .mems
+                .iter()
+                .map(|addr| store.mems[*addr].borrow().memtype)
+                .collect(),
+            globals: instance
+                .globals
+                .iter()
+                .map(|addr| store.globals[*addr].borrow().globaltype)
+                .collect(),
+            tags: instance
+                .tags
+                .iter()
+                .map(|addr| store.tags[*addr].tagtype.clone())
+                .collect(),
+            elems: instance
+                .elems
+                .iter()
+                .map(|addr| store.elems[*addr].borrow().elemtype)
+                .collect(),
+            data: vec![(); instance.datas.len()],
+            ..Context::default()
+        };
+        let params: Vec<types::Value> = frame.locals.iter().map(Val::valtype).collect();
+        let code = bytecode::compile_program(store.config.engine, &context, &params, program)?;
+        let mut thread = Thread {
+            slots: vec![],
+            frames: vec![],
+        };
+        for local in &frame.locals {
+            if let Val::Ref(r) = local {
+                store.check_live(r)?;
+            }
+            thread.slots.push(local.to_slot()?);
+        }
+        thread.push_frame(
+            store,
+            Activation {
+                arity: frame.arity,
+                module: frame.module,
+                code: &code.ops,
+                handlers: &code.handlers,
+                ip: 0,
+                base: 0,
+            },
+            &[],
+            code.max_height,
+        )?;
+        thread.execute(store)?;
+        Result::Ok(thread.slots.split_off(frame.locals.len()))
+    }
+
     #[test]
     fn add_two() -> Result<(), err::Err> {
         let mut store = Store::new();
-        store.modules.push(RefCell::new(ModuleInstance::new()));
-        let frame = Frame::new(&store.modules[0]);
+        store.modules.push(ModuleInstance::new());
 
         let res = run(
             &store,
-            &frame,
-            &vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
+            Frame::new(0),
+            &[Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
         )?;
 
         assert_eq!(res.len(), 1);
-        assert_eq!(res[0], runtime::Val::Num(runtime::Num::I32(2)));
+        assert_eq!(res[0], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_fuel() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(ModuleInstance::new());
+        store.set_fuel(2);
+
+        let res = run(
+            &store,
+            Frame::new(0),
+            &[Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
+        );
+
+        assert!(matches!(res, Err(err::Err::TrapOutOfFuel)));
+        assert_eq!(store.fuel_remaining(), Some(0));
+        assert_eq!(store.fuel_consumed(), 2);
+
+        store.add_fuel(1)?;
+        run(&store, Frame::new(0), &[Instr::I32Const(1)])?;
+        assert_eq!(store.fuel_remaining(), Some(0));
+        assert_eq!(store.fuel_consumed(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn fuel_costs_per_class() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(ModuleInstance::new());
+        store.config.fuel_costs.numeric = 2;
+        store.config.fuel_costs.control = 5;
+        store.set_fuel(10);
+
+        run(&store, Frame::new(0), &[Instr::I32Const(1), Instr::Nop])?;
+        assert_eq!(store.fuel_remaining(), Some(3));
+
+        let res = run(&store, Frame::new(0), &[Instr::I32Const(1), Instr::Nop]);
+        assert!(matches!(res, Err(err::Err::TrapOutOfFuel)));
+        assert_eq!(store.fuel_remaining(), Some(1));
+        assert_eq!(store.fuel_consumed(), 9);
+        Ok(())
+    }
+
+    #[test]
+    fn unmetered_by_default() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(ModuleInstance::new());
+
+        run(&store, Frame::new(0), &[Instr::I32Const(1), Instr::Nop])?;
+        assert_eq!(store.fuel_remaining(), None);
+        assert_eq!(store.fuel_consumed(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn call() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let module = 0;
+        let types = [i32_function(1, 1)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::LocalGet(0), Instr::I32Const(1), Instr::I32Add],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        let res = run(
+            &store,
+            Frame::new(0),
+            &[Instr::I32Const(41), Instr::Call(0)],
+        )?;
+
+        assert_eq!(res, vec![42]);
+        Ok(())
+    }
+
+    #[test]
+    fn call_stack_exhausted() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let module = 0;
+        let types = [i32_function(0, 0)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::Call(0)],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+        store.config.stack_limits.max_call_depth = 100;
+
+        let res = run(&store, Frame::new(0), &[Instr::Call(0)]);
+
+        assert!(matches!(res, Err(err::Err::TrapCallStackExhausted)));
+        assert_eq!(store.fuel_consumed(), 101);
+        Ok(())
+    }
+
+    #[test]
+    fn tail_calls() -> Result<(), err::Err> {
+        // Sums 1 to n in an accumulator, calling itself in tail position
+        let sum = |call| Func {
+            functype: 0,
+            locals: vec![],
+            body: vec![
+                Instr::LocalGet(0),
+                Instr::I32Eqz,
+                Instr::If(BlockType::Value(types::Value::Num(types::Number::I32))),
+                Instr::LocalGet(1),
+                Instr::Else,
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::I32Sub,
+                Instr::LocalGet(1),
+                Instr::LocalGet(0),
+                Instr::I32Add,
+                call,
+                Instr::End,
+            ],
+        };
+        let n = 100_000u32;
+        let expected = (1..=n).fold(0u32, |acc, i| acc.wrapping_add(i));
+
+        for engine in [Engine::Stack, Engine::Register] {
+            let mut store = Store::new();
+            store.config.engine = engine;
+            store.config.stack_limits.max_call_depth = 100;
+            store.config.stack_limits.max_values = 100;
+            let tabletype = types::Table {
+                limits: types::Limits { min: 0, max: None },
+                reftype: types::Ref::FUNC,
+            };
+            let table = store.table_alloc(tabletype);
+            let functype = i32_function(2, 1);
+            let mut instance = ModuleInstance::new();
+            let types = vec![functype.clone(), i32_function(3, 1)];
+            instance.types = types.clone();
+            instance.funct = vec![0, 1, 2];
+            instance.tables.push(table.0.addr);
+            store.modules.push(instance);
+            let module = 0;
+
+            let funcs = [
+                sum(Instr::ReturnCall(0)),
+                sum(Instr::Call(1)),
+                // Calls the function at the index given by local 2 of the table
+                Func {
+                    functype: 1,
+                    locals: vec![],
+                    body: vec![
+                        Instr::LocalGet(0),
+                        Instr::LocalGet(1),
+                        Instr::LocalGet(2),
+                        Instr::ReturnCallIndirect(0, 0),
+                    ],
+                },
+            ];
+            let functypes = [functype.clone(), functype.clone(), types[1].clone()];
+            for (func, functype) in funcs.into_iter().zip(&functypes) {
+                let func = InternalFuncInstance::new(
+                    functype.clone(),
+                    module,
+                    func,
+                    &Context {
+                        types: types.clone(),
+                        funcs: functypes.to_vec(),
+                        tables: vec![tabletype],
+                        ..Context::default()
+                    },
+                    store.config.engine,
+                )?;
+                store.funcinstances.push(FuncInstance::Internal(func));
+            }
+            let nop = store.func_wrap(|| {});
+            store.tables[table.0.addr].borrow_mut().elem = vec![
+                store.func_id(0).into(),
+                Ref::Null(types::Heap::Func),
+                nop.into(),
+            ];
+
+            let call = |func, args: &[u32]| {
+                let mut program: Vec<Instr> =
+                    args.iter().map(|arg| Instr::I32Const(*arg)).collect();
+                program.push(Instr::Call(func));
+                run(&store, Frame::new(module), &program)
+            };
+            assert_eq!(call(0, &[n, 0])?, vec![expected as Slot]);
+            assert!(matches!(
+                call(1, &[n, 0]),
+                Err(err::Err::TrapCallStackExhausted)
+            ));
+            assert_eq!(call(2, &[n, 0, 0])?, vec![expected as Slot]);
+            assert!(matches!(
+                call(2, &[n, 0, 1]),
+                Err(err::Err::TrapUninitializedElement)
+            ));
+            assert!(matches!(
+                call(2, &[n, 0, 2]),
+                Err(err::Err::TrapIndirectCallTypeMismatch)
+            ));
+            assert!(matches!(
+                call(2, &[n, 0, 3]),
+                Err(err::Err::TrapUndefinedElement)
+            ));
+        }
+
+        // The results of the callee must be those of the caller
+        let context = Context {
+            funcs: vec![i32_function(0, 0)],
+            ..Context::default()
+        };
+        let code = bytecode::compile(&context, &i32_function(0, 1), &[], &[Instr::ReturnCall(0)]);
+        assert!(matches!(code, Err(err::Err::InvalidCode)));
+        Ok(())
+    }
+
+    #[test]
+    fn call_stack_exhausted_with_default_limits() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let module = 0;
+        let types = [i32_function(0, 0)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::Call(0)],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        let res = run(&store, Frame::new(0), &[Instr::Call(0)]);
+
+        assert!(matches!(res, Err(err::Err::TrapCallStackExhausted)));
+        Ok(())
+    }
+
+    #[test]
+    fn value_stack_exhausted() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let module = 0;
+        let types = [i32_function(0, 1)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![Instr::I32Const(7), Instr::Call(0), Instr::I32Add],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+        store.config.stack_limits.max_values = 50;
+
+        let res = run(&store, Frame::new(0), &[Instr::Call(0)]);
+
+        // Each activation leaves one value behind, activations themselves take no slot
+        assert!(matches!(res, Err(err::Err::TrapCallStackExhausted)));
+        assert_eq!(store.fuel_consumed(), 99);
+        Ok(())
+    }
+
+    #[test]
+    fn locals_exhaust_value_stack() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let types = [i32_function(0, 1)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            0,
+            Func {
+                functype: 0,
+                locals: vec![types::Value::Num(types::Number::I32); 100],
+                body: vec![Instr::LocalGet(99)],
+            },
+            &Context {
+                types: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        // Locals are counted against the limit before they are pushed
+        store.config.stack_limits.max_values = 100;
+        let res = run(&store, Frame::new(0), &[Instr::Call(0)]);
+        assert!(matches!(res, Err(err::Err::TrapCallStackExhausted)));
+        store.config.stack_limits.max_values = 101;
+        assert_eq!(run(&store, Frame::new(0), &[Instr::Call(0)])?, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn loop_block() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let module = 0;
+        let types = [i32_function(1, 1)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![types::Value::Num(types::Number::I32)],
+                body: vec![
+                    Instr::Block(BlockType::Empty),
+                    Instr::Loop(BlockType::Empty),
+                    Instr::LocalGet(0),
+                    Instr::I32Eqz,
+                    Instr::BrIf(1),
+                    Instr::LocalGet(1),
+                    Instr::LocalGet(0),
+                    Instr::I32Add,
+                    Instr::LocalSet(1),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::I32Sub,
+                    Instr::LocalSet(0),
+                    Instr::Br(0),
+                    Instr::End,
+                    Instr::End,
+                    Instr::LocalGet(1),
+                ],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+
+        let res = run(
+            &store,
+            Frame::new(module),
+            &[Instr::I32Const(10), Instr::Call(0)],
+        )?;
+
+        assert_eq!(res, vec![55]);
+        Ok(())
+    }
+
+    #[test]
+    fn if_else() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        store.modules.push(ModuleInstance::new());
+        let result = BlockType::Value(types::Value::Num(types::Number::I32));
+        let program = |cond| {
+            vec![
+                Instr::I32Const(cond),
+                Instr::If(result),
+                Instr::I32Const(1),
+                Instr::Else,
+                Instr::I32Const(2),
+                Instr::End,
+                Instr::I32Const(10),
+                Instr::I32Add,
+            ]
+        };
+
+        let res = run(&store, Frame::new(0), &program(1))?;
+        assert_eq!(res, vec![11]);
+        let res = run(&store, Frame::new(0), &program(0))?;
+        assert_eq!(res, vec![12]);
+        Ok(())
+    }
+
+    #[test]
+    fn deep_recursion() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mut instance = ModuleInstance::new();
+        instance.funct.push(0);
+        store.modules.push(instance);
+        let module = 0;
+        let types = [i32_function(1, 1)];
+        let func = InternalFuncInstance::new(
+            types[0].clone(),
+            module,
+            Func {
+                functype: 0,
+                locals: vec![],
+                body: vec![
+                    Instr::LocalGet(0),
+                    Instr::I32Eqz,
+                    Instr::If(BlockType::Value(types::Value::Num(types::Number::I32))),
+                    Instr::I32Const(0),
+                    Instr::Else,
+                    Instr::LocalGet(0),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::I32Sub,
+                    Instr::Call(0),
+                    Instr::I32Add,
+                    Instr::End,
+                ],
+            },
+            &Context {
+                types: types.to_vec(),
+                funcs: types.to_vec(),
+                ..Context::default()
+            },
+            store.config.engine,
+        )?;
+        store.funcinstances.push(FuncInstance::Internal(func));
+        store.config.stack_limits.max_call_depth = 200_000;
+
+        let res = run(
+            &store,
+            Frame::new(module),
+            &[Instr::I32Const(100_000), Instr::Call(0)],
+        )?;
+
+        assert_eq!(res, vec![(100_000u64 * 100_001 / 2) as u32 as Slot]);
+        Ok(())
+    }
+
+    fn run_engine(engine: Engine, locals: &[runtime::Val], program: &[Instr]) -> (Vec<Slot>, u64) {
+        let mut store = Store::new();
+        store.config.engine = engine;
+        store.modules.push(ModuleInstance::new());
+        let mut frame = Frame::new(0);
+        frame.locals = locals.to_vec();
+        let res = run(&store, frame, program).unwrap();
+        (res, store.fuel_consumed())
+    }
+
+    #[test]
+    fn register_engine_matches_stack_engine() {
+        let i32_val = |val| runtime::Val::Num(runtime::Num::I32(val));
+        let i64_val = |val| runtime::Val::Num(runtime::Num::I64(val));
+        let programs = [
+            // Counts down local 0 while accumulating i64 shifts and xors in local 1
+            (
+                vec![i32_val(20), i64_val(0)],
+                vec![
+                    Instr::Block(BlockType::Empty),
+                    Instr::Loop(BlockType::Empty),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(0),
+                    Instr::I32LeS,
+                    Instr::BrIf(1),
+                    Instr::LocalGet(1),
+                    Instr::I64Const(3),
+                    Instr::I64Shl,
+                    Instr::LocalGet(1),
+                    Instr::I64Xor,
+                    Instr::I64Const(1),
+                    Instr::I64Add,
+                    Instr::LocalSet(1),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::I32Sub,
+                    Instr::LocalSet(0),
+                    Instr::Br(0),
+                    Instr::End,
+                    Instr::End,
+                    Instr::LocalGet(1),
+                ],
+            ),
+            // Counts up local 0 to local 1, summing odd values in local 2
+            (
+                vec![i32_val(0), i32_val(100), i32_val(0)],
+                vec![
+                    Instr::Loop(BlockType::Empty),
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::I32And,
+                    Instr::I32Eqz,
+                    Instr::If(BlockType::Empty),
+                    Instr::Else,
+                    Instr::LocalGet(2),
+                    Instr::LocalGet(0),
+                    Instr::I32Add,
+                    Instr::LocalSet(2),
+                    Instr::End,
+                    Instr::LocalGet(0),
+                    Instr::I32Const(1),
+                    Instr::I32Add,
+                    Instr::LocalTee(0),
+                    Instr::LocalGet(1),
+                    Instr::I32LtU,
+                    Instr::BrIf(0),
+                    Instr::End,
+                    Instr::LocalGet(2),
+                    Instr::LocalGet(0),
+                    Instr::LocalGet(1),
+                    Instr::I32Ne,
+                    Instr::If(BlockType::Value(types::Value::Num(types::Number::I32))),
+                    Instr::I32Const(1),
+                    Instr::Else,
+                    Instr::I32Const(2),
+                    Instr::End,
+                    Instr::I32Const(5),
+                    Instr::I32Mul,
+                ],
+            ),
+        ];
+
+        for (locals, program) in programs {
+            let stack = run_engine(Engine::Stack, &locals, &program);
+            let register = run_engine(Engine::Register, &locals, &program);
+            assert_eq!(stack, register);
+        }
+    }
+
+    #[test]
+    fn multi_value_blocks() -> Result<(), err::Err> {
+        let i32_val = |val| runtime::Val::Num(runtime::Num::I32(val));
+        for engine in [Engine::Stack, Engine::Register] {
+            let mut store = Store::new();
+            store.config.engine = engine;
+            let mut instance = ModuleInstance::new();
+            instance.types = vec![i32_function(2, 2), i32_function(1, 2), i32_function(1, 1)];
+            store.modules.push(instance);
+            let frame = || {
+                let mut frame = Frame::new(0);
+                frame.locals = vec![i32_val(10), i32_val(0), i32_val(0)];
+                frame
+            };
+
+            // Fibonacci, the last two numbers being the parameters of the loop
+            let fib = [
+                Instr::I32Const(0),
+                Instr::I32Const(1),
+                Instr::Loop(BlockType::Index(0)),
+                Instr::LocalSet(1),
+                Instr::LocalSet(2),
+                Instr::LocalGet(1),
+                Instr::LocalGet(2),
+                Instr::LocalGet(1),
+                Instr::I32Add,
+                Instr::LocalGet(0),
+                Instr::I32Const(1),
+                Instr::I32Sub,
+                Instr::LocalTee(0),
+                Instr::BrIf(0),
+                Instr::End,
+            ];
+            assert_eq!(run(&store, frame(), &fib)?, vec![55, 89]);
+
+            // Branching out of a block drops its parameter below the results
+            let block = [
+                Instr::I32Const(7),
+                Instr::Block(BlockType::Index(1)),
+                Instr::I32Const(5),
+                Instr::I32Const(2),
+                Instr::Br(0),
+                Instr::End,
+                Instr::I32Sub,
+            ];
+            assert_eq!(run(&store, frame(), &block)?, vec![3]);
+
+            let cond = |val| {
+                [
+                    Instr::I32Const(10),
+                    Instr::I32Const(val),
+                    Instr::If(BlockType::Index(2)),
+                    Instr::I32Const(1),
+                    Instr::I32Add,
+                    Instr::Else,
+                    Instr::I32Const(1),
+                    Instr::I32Sub,
+                    Instr::End,
+                ]
+            };
+            assert_eq!(run(&store, frame(), &cond(1))?, vec![11]);
+            assert_eq!(run(&store, frame(), &cond(0))?, vec![9]);
+
+            // Parameters must be on the stack
+            let missing = [
+                Instr::I32Const(1),
+                Instr::Block(BlockType::Index(0)),
+                Instr::End,
+            ];
+            assert!(run(&store, frame(), &missing).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn register_engine_calls() -> Result<(), err::Err> {
+        let mut consumed = vec![];
+        for engine in [Engine::Stack, Engine::Register] {
+            let mut store = Store::new();
+            store.config.engine = engine;
+            let mut instance = ModuleInstance::new();
+            instance.funct.push(0);
+            store.modules.push(instance);
+            let module = 0;
+            // Recursive fibonacci
+            let functype = i32_function(1, 1);
+            let types = [functype];
+            let func = InternalFuncInstance::new(
+                types[0].clone(),
+                module,
+                Func {
+                    functype: 0,
+                    locals: vec![],
+                    body: vec![
+                        Instr::LocalGet(0),
+                        Instr::I32Const(2),
+                        Instr::I32LtU,
+                        Instr::If(BlockType::Value(types::Value::Num(types::Number::I32))),
+                        Instr::LocalGet(0),
+                        Instr::Else,
+                        Instr::LocalGet(0),
+                        Instr::I32Const(1),
+                        Instr::I32Sub,
+                        Instr::Call(0),
+                        Instr::LocalGet(0),
+                        Instr::I32Const(2),
+                        Instr::I32Sub,
+                        Instr::Call(0),
+                        Instr::I32Add,
+                        Instr::End,
+                    ],
+                },
+                &Context {
+                    types: types.to_vec(),
+                    funcs: types.to_vec(),
+                    ..Context::default()
+                },
+                store.config.engine,
+            )?;
+            store.funcinstances.push(FuncInstance::Internal(func));
+
+            let res = run(
+                &store,
+                Frame::new(module),
+                &[Instr::I32Const(15), Instr::Call(0)],
+            )?;
+
+            assert_eq!(res, vec![610]);
+            consumed.push(store.fuel_consumed());
+        }
+        assert_eq!(consumed[0], consumed[1]);
+        Ok(())
+    }
+
+    fn with_memory_and_table(store: &mut Store) {
+        let mem = store
+            .mem_alloc(types::Mem {
+                limits: types::Limits {
+                    min: 1,
+                    max: Some(4),
+                },
+                index: types::IndexType::I32,
+                shared: false,
+            })
+            .unwrap();
+        let table = store.table_alloc(types::Table {
+            limits: types::Limits { min: 0, max: None },
+            reftype: types::Ref::FUNC,
+        });
+        let mut instance = ModuleInstance::new();
+        instance.mems.push(mem.0.addr);
+        instance.tables.push(table.0.addr);
+        store.modules.push(instance);
+    }
+
+    #[test]
+    fn memory64_size_and_grow() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mem = store.mem_alloc(types::Mem {
+            limits: types::Limits { min: 1, max: None },
+            index: types::IndexType::I64,
+            shared: false,
+        })?;
+        let mut instance = ModuleInstance::new();
+        instance.mems.push(mem.0.addr);
+        store.modules.push(instance);
+        let grow = |n: u64| {
+            run(
+                &store,
+                Frame::new(0),
+                &[
+                    Instr::I64Const(n),
+                    Instr::MemoryGrow(0),
+                    Instr::MemorySize(0),
+                ],
+            )
+        };
+
+        // Sizes and deltas are i64, failure being reported as -1 over 64 bits
+        assert_eq!(grow(2)?, vec![1, 3]);
+        assert_eq!(grow(u64::MAX)?, vec![u64::MAX, 3]);
+        assert_eq!(grow(1 << 48)?, vec![u64::MAX, 3]);
+        // As is an allocation the host cannot satisfy
+        assert_eq!(grow(1 << 40)?, vec![u64::MAX, 3]);
+        assert_eq!(store.mems[mem.0.addr].borrow().size(), 3 * PAGE_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_memories() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        let mem = |store: &mut Store, min| {
+            store.mem_alloc(types::Mem {
+                limits: types::Limits { min, max: None },
+                index: types::IndexType::I32,
+                shared: false,
+            })
+        };
+        let main = mem(&mut store, 1)?;
+        let scratch = mem(&mut store, 2)?;
+        let mut instance = ModuleInstance::new();
+        instance.mems = vec![main.0.addr, scratch.0.addr];
+        store.modules.push(instance);
+        store.mem_write_bytes(scratch, PAGE_SIZE, b"shared")?;
+        let copy = |store: &Store, dst, src, args: [u32; 3]| {
+            let mut program: Vec<Instr> = args.into_iter().map(Instr::I32Const).collect();
+            program.push(Instr::MemoryCopy(dst, src));
+            run(store, Frame::new(0), &program)
+        };
+
+        assert_eq!(
+            run(
+                &store,
+                Frame::new(0),
+                &[Instr::MemorySize(0), Instr::MemorySize(1)]
+            )?,
+            vec![1, 2]
+        );
+        copy(&store, 0, 1, [8, PAGE_SIZE as u32, 6])?;
+        let mut buf = [0; 6];
+        store.mem_read_bytes(main, 8, &mut buf)?;
+        assert_eq!(&buf, b"shared");
+
+        // Overlapping copy within a memory
+        copy(&store, 0, 0, [10, 8, 6])?;
+        let mut buf = [0; 8];
+        store.mem_read_bytes(main, 8, &mut buf)?;
+        assert_eq!(&buf, b"shshared");
+
+        // Out of bounds copies trap without writing
+        assert!(matches!(
+            copy(&store, 1, 0, [2 * PAGE_SIZE as u32 - 4, 8, 6]),
+            Err(err::Err::OutOfBoundMemoryAccess)
+        ));
+        assert_eq!(store.mem_load::<u32>(scratch, 2 * PAGE_SIZE - 4)?, 0);
+        // Memories out of range are rejected before running
+        assert!(matches!(
+            copy(&store, 2, 0, [0, 0, 0]),
+            Err(err::Err::InvalidCode)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_and_table_grow() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        with_memory_and_table(&mut store);
+        let grow_memory = |store: &Store, n: u32| {
+            run(
+                store,
+                Frame::new(0),
+                &[
+                    Instr::I32Const(n),
+                    Instr::MemoryGrow(0),
+                    Instr::MemorySize(0),
+                ],
+            )
+        };
+
+        assert_eq!(grow_memory(&store, 2)?, vec![1, 3]);
+        assert_eq!(grow_memory(&store, 2)?, vec![u32::MAX as Slot, 3]);
+        assert_eq!(grow_memory(&store, 1)?, vec![3, 4]);
+        assert_eq!(store.mems[0].borrow().size(), 4 * PAGE_SIZE);
+
+        let res = run(
+            &store,
+            Frame::new(0),
+            &[
+                Instr::RefNull(types::Heap::Func),
+                Instr::I32Const(3),
+                Instr::TableGrow(0),
+                Instr::TableSize(0),
+            ],
+        )?;
+        assert_eq!(res, vec![0, 3]);
+        assert_eq!(
+            store.tables[0].borrow().elem,
+            vec![runtime::Ref::Null(types::Heap::Func); 3]
+        );
+        Ok(())
+    }
+
+    struct DenyAbove(usize);
+
+    impl runtime::ResourceLimiter for DenyAbove {
+        fn memory_growing(
+            &mut self,
+            _: usize,
+            desired: usize,
+            _: Option<usize>,
+        ) -> Result<bool, Trap> {
+            if desired > 3 * PAGE_SIZE {
+                return Err(Trap::new("memory limit"));
+            }
+            Ok(desired <= self.0 * PAGE_SIZE)
+        }
+
+        fn table_growing(
+            &mut self,
+            _: usize,
+            desired: usize,
+            _: Option<usize>,
+        ) -> Result<bool, Trap> {
+            Ok(desired <= self.0)
+        }
+    }
+
+    #[test]
+    fn limited_growth() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        with_memory_and_table(&mut store);
+        store.set_limiter(DenyAbove(2));
+        let grow = |store: &Store, instr: Instr, n: u32| {
+            let mut body = vec![];
+            if let Instr::TableGrow(_) = instr {
+                body.push(Instr::RefNull(types::Heap::Func));
+            }
+            body.extend([Instr::I32Const(n), instr]);
+            run(store, Frame::new(0), &body)
+        };
+
+        // Denied growth is reported to the guest
+        assert_eq!(grow(&store, Instr::MemoryGrow(0), 1)?, vec![1]);
+        assert_eq!(
+            grow(&store, Instr::MemoryGrow(0), 1)?,
+            vec![u32::MAX as Slot]
+        );
+        assert_eq!(
+            grow(&store, Instr::TableGrow(0), 3)?,
+            vec![u32::MAX as Slot]
+        );
+        assert_eq!(grow(&store, Instr::TableGrow(0), 2)?, vec![0]);
+
+        // While an error from the limiter traps
+        let res = grow(&store, Instr::MemoryGrow(0), 2);
+        assert!(matches!(res, Err(err::Err::TrapHost(message)) if message == "memory limit"));
+        assert_eq!(store.mems[0].borrow().size(), 2 * PAGE_SIZE);
+        Ok(())
+    }
+
+    fn memarg(offset: u64, align: u32) -> MemArg {
+        MemArg {
+            offset,
+            align,
+            mem: 0,
+        }
+    }
+
+    // A store per thread, all accessing the same memory
+    fn store_with_shared(memory: &SharedMemory) -> Store {
+        let mut store = Store::new();
+        let mem = store.mem_alloc_shared(memory.clone());
+        let mut instance = ModuleInstance::new();
+        instance.mems.push(mem.0.addr);
+        store.modules.push(instance);
+        store
+    }
+
+    fn shared_memory() -> SharedMemory {
+        let memory = SharedMemory::new(types::Mem {
+            limits: types::Limits {
+                min: 1,
+                max: Some(2),
+            },
+            index: types::IndexType::I32,
+            shared: true,
+        });
+        memory.unwrap()
+    }
+
+    #[test]
+    fn atomic_accesses() -> Result<(), err::Err> {
+        let mut store = Store::new();
+        with_memory_and_table(&mut store);
+        let memory = shared_memory();
+        let shared = store_with_shared(&memory);
+        for store in [&store, &shared] {
+            let run = |program: &[Instr]| run(store, Frame::new(0), program);
+            run(&[
+                Instr::I32Const(8),
+                Instr::I64Const(0x1122_3344_5566_7788),
+                Instr::AtomicStore(Atomic::I64, memarg(0, 3)),
+            ])?;
+            // Narrow accesses are zero-extended, and results truncated to their width
+            assert_eq!(
+                run(&[
+                    Instr::I32Const(4),
+                    Instr::AtomicLoad(Atomic::I32U8, memarg(5, 0)),
+                    Instr::I32Const(8),
+                    Instr::I64Const(0xff),
+                    Instr::AtomicRmw(RmwOp::Add, Atomic::I64U16, memarg(2, 1)),
+                    Instr::I32Const(8),
+                    Instr::AtomicLoad(Atomic::I64, memarg(0, 3)),
+                ])?,
+                vec![0x77, 0x5566, 0x1122_3344_5665_7788]
+            );
+            // The exchange only happens if the expected value matches
+            assert_eq!(
+                run(&[
+                    Instr::I32Const(8),
+                    Instr::I32Const(0),
+                    Instr::I32Const(1),
+                    Instr::AtomicCmpxchg(Atomic::I32, memarg(0, 2)),
+                    Instr::I32Const(8),
+                    Instr::I32Const(0x5665_7788),
+                    Instr::I32Const(1),
+                    Instr::AtomicCmpxchg(Atomic::I32, memarg(0, 2)),
+                    Instr::I32Const(8),
+                    Instr::AtomicLoad(Atomic::I32, memarg(0, 2)),
+                    Instr::AtomicFence,
+                ])?,
+                vec![0x5665_7788, 0x5665_7788, 1]
+            );
+
+            // Misaligned accesses trap, as do accesses beyond the memory
+            assert!(matches!(
+                run(&[
+                    Instr::I32Const(2),
+                    Instr::AtomicLoad(Atomic::I32, memarg(0, 2)),
+                ]),
+                Err(err::Err::TrapUnalignedAtomic)
+            ));
+            assert!(matches!(
+                run(&[
+                    Instr::I32Const(u32::MAX),
+                    Instr::AtomicLoad(Atomic::I32U8, memarg(1, 0)),
+                ]),
+                Err(err::Err::OutOfBoundMemoryAccess)
+            ));
+            // The alignment of atomic instructions must be their natural one
+            assert!(matches!(
+                run(&[
+                    Instr::I32Const(0),
+                    Instr::AtomicLoad(Atomic::I32, memarg(0, 1)),
+                ]),
+                Err(err::Err::InvalidCode)
+            ));
+        }
+
+        // Unshared memories have no waiters
+        let wait = [
+            Instr::I32Const(0),
+            Instr::I32Const(0),
+            Instr::I64Const(0),
+            Instr::MemoryAtomicWait32(memarg(0, 2)),
+        ];
+        assert!(matches!(
+            run(&store, Frame::new(0), &wait),
+            Err(err::Err::TrapExpectedSharedMemory)
+        ));
+        let notify = [
+            Instr::I32Const(0),
+            Instr::I32Const(1),
+            Instr::MemoryAtomicNotify(memarg(0, 2)),
+        ];
+        assert_eq!(run(&store, Frame::new(0), &notify)?, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_memory_across_threads() -> Result<(), err::Err> {
+        let memory = shared_memory();
+        let increment = [
+            Instr::I32Const(0),
+            Instr::I32Const(1),
+            Instr::AtomicRmw(RmwOp::Add, Atomic::I32, memarg(0, 2)),
+        ];
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let memory = memory.clone();
+                std::thread::spawn(move || {
+                    let store = store_with_shared(&memory);
+                    for _ in 0..1000 {
+                        run(&store, Frame::new(0), &increment).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let store = store_with_shared(&memory);
+        let load = [
+            Instr::I32Const(0),
+            Instr::AtomicLoad(Atomic::I32, memarg(0, 2)),
+        ];
+        assert_eq!(run(&store, Frame::new(0), &load)?, vec![4000]);
+
+        // Growth is visible to all stores
+        let grown = [Instr::I32Const(1), Instr::MemoryGrow(0)];
+        assert_eq!(run(&store, Frame::new(0), &grown)?, vec![1]);
+        let other = store_with_shared(&memory);
+        let size = [Instr::MemorySize(0)];
+        assert_eq!(run(&other, Frame::new(0), &size)?, vec![2]);
+        assert_eq!(run(&store, Frame::new(0), &grown)?, vec![u32::MAX as Slot]);
+        Ok(())
+    }
+
+    #[test]
+    fn wait_and_notify() -> Result<(), err::Err> {
+        let memory = shared_memory();
+        let wait = |expected: u32, timeout: i64| {
+            [
+                Instr::I32Const(16),
+                Instr::I32Const(expected),
+                Instr::I64Const(timeout as u64),
+                Instr::MemoryAtomicWait32(memarg(0, 2)),
+            ]
+        };
+        let store = store_with_shared(&memory);
+        assert_eq!(run(&store, Frame::new(0), &wait(1, -1))?, vec![1]);
+        assert_eq!(run(&store, Frame::new(0), &wait(0, 1_000_000))?, vec![2]);
+
+        // The waiter blocks indefinitely until notified
+        let waiter = {
+            let memory = memory.clone();
+            std::thread::spawn(move || {
+                let store = store_with_shared(&memory);
+                run(&store, Frame::new(0), &wait(0, -1))
+            })
+        };
+        let notify = [
+            Instr::I32Const(16),
+            Instr::I32Const(u32::MAX),
+            Instr::MemoryAtomicNotify(memarg(0, 2)),
+        ];
+        while run(&store, Frame::new(0), &notify)? == vec![0] {
+            std::thread::yield_now();
+        }
+        assert_eq!(waiter.join().unwrap()?, vec![0]);
+        assert_eq!(run(&store, Frame::new(0), &notify)?, vec![0]);
         Ok(())
     }
 }